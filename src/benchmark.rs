@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::common::Random;
+use crate::stats::{Operation, ServerStats};
+use crate::{ClientPool, OpStats, Result};
+
+/// A workload for `run_benchmark`: which requests to send, and how many at once for how long.
+///
+/// `Workload::default()` sends 90% gets and 10% sets of 100-byte values over 1000 keys, on 4
+/// connections for 10 seconds; each method changes one of them.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    read_ratio: f64,
+    key_space: u64,
+    value_size: usize,
+    concurrency: usize,
+    duration: Duration,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            read_ratio: 0.9,
+            key_space: 1000,
+            value_size: 100,
+            concurrency: 4,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Workload {
+    /// Sets the share of requests that are gets, between 0 and 1; the others are sets.
+    pub fn read_ratio(mut self, ratio: f64) -> Self {
+        self.read_ratio = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets how many distinct keys the requests pick from, uniformly at random.
+    ///
+    /// The keys are not written up front, so gets of keys no set has reached yet find
+    /// nothing.
+    pub fn key_space(mut self, keys: u64) -> Self {
+        self.key_space = keys.max(1);
+        self
+    }
+
+    /// Sets the length of the values set, in bytes.
+    pub fn value_size(mut self, bytes: usize) -> Self {
+        self.value_size = bytes;
+        self
+    }
+
+    /// Sets how many connections send requests at once, each waiting for its response before
+    /// sending the next one.
+    ///
+    /// The server's thread pools serve a connection on one worker thread for as long as it is
+    /// open, so this shouldn't be more than the server's worker threads.
+    pub fn concurrency(mut self, connections: usize) -> Self {
+        self.concurrency = connections.max(1);
+        self
+    }
+
+    /// Sets how long to send requests for.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+}
+
+/// Results of a `run_benchmark`.
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    /// How long requests were sent for
+    pub elapsed: Duration,
+
+    /// Count and latency percentiles of the successful gets, as seen by the client
+    pub get: OpStats,
+
+    /// Count and latency percentiles of the successful sets, as seen by the client
+    pub set: OpStats,
+
+    /// Number of requests that failed
+    pub errors: u64,
+}
+
+impl BenchmarkReport {
+    /// Returns the successful requests per second.
+    pub fn throughput(&self) -> f64 {
+        (self.get.count + self.set.count) as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Sends `workload` to the server of `pool` and measures the latency of every request.
+///
+/// Failed requests are counted in `BenchmarkReport::errors` and the run goes on; a client
+/// whose connection broke reconnects on its next request.
+///
+/// # Errors
+///
+/// It fails if a connection can't be opened at the start of the run.
+pub fn run_benchmark(pool: &ClientPool, workload: &Workload) -> Result<BenchmarkReport> {
+    let clients = (0..workload.concurrency)
+        .map(|_| pool.get())
+        .collect::<Result<Vec<_>>>()?;
+    let stats = ServerStats::new();
+    let errors = AtomicU64::new(0);
+    let value = "x".repeat(workload.value_size);
+
+    let start = Instant::now();
+    let deadline = start + workload.duration;
+    thread::scope(|scope| {
+        for mut client in clients {
+            let (stats, errors, value) = (&stats, &errors, &value);
+            scope.spawn(move || {
+                let random = Random::new();
+                let mut request = 0u64;
+                while Instant::now() < deadline {
+                    request += 1;
+                    let key = format!("key{}", random.u64((request, 0)) % workload.key_space);
+                    let is_read = random.fraction((request, 1)) < workload.read_ratio;
+
+                    let sent = Instant::now();
+                    let (operation, result) = if is_read {
+                        (Operation::Get, client.get(key).map(drop))
+                    } else {
+                        (Operation::Set, client.set(key, value.clone()))
+                    };
+                    match result {
+                        Ok(()) => stats.record(operation, sent.elapsed()),
+                        Err(_) => {
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let snapshot = stats.snapshot();
+    Ok(BenchmarkReport {
+        elapsed: start.elapsed(),
+        get: snapshot.get,
+        set: snapshot.set,
+        errors: errors.into_inner(),
+    })
+}
@@ -0,0 +1,63 @@
+use clap::{Parser, Subcommand};
+use kvs::{KvStore, Result};
+use std::path::PathBuf;
+use std::process::exit;
+
+#[derive(Parser, Debug)]
+#[clap(name = "kvs-admin", disable_help_subcommand = true)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(
+        name = "migrate",
+        about = "Rewrite a kvs data directory in the current record format"
+    )]
+    Migrate {
+        #[clap(name = "DIR", help = "The kvs data directory")]
+        dir: PathBuf,
+    },
+
+    #[clap(
+        name = "format",
+        about = "Print the on-disk format of each log file in a kvs data directory"
+    )]
+    Format {
+        #[clap(name = "DIR", help = "The kvs data directory")]
+        dir: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = Opt::parse();
+    if let Err(e) = run(opt) {
+        eprintln!("{:?}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Migrate { dir } => {
+            let report = KvStore::migrate(dir)?;
+            println!("Generations rewritten: {}", report.generations);
+            println!("Live records: {}", report.live_records);
+            println!("Upgraded records: {}", report.upgraded_records);
+        }
+        Command::Format { dir } => {
+            for (generation, format) in KvStore::log_file_formats(dir)? {
+                println!("{}.log:", generation);
+                println!("  Framing: {}", format.framing);
+                println!("  Endianness: {}", format.endianness);
+                println!("  Record checksum: {}", format.record_checksum);
+                println!("  File checksum: {}", format.file_checksum.unwrap_or("none"));
+                println!("  Compression: {}", format.compression);
+                println!("  Schema version: {}", format.schema_version);
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,34 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use kvs::{run_benchmark, ClientPool, KvsClient, Result, SetCondition, Workload};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const WATCH_RECONNECT_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Parser, Debug)]
 #[clap(name = "kvs-client", disable_help_subcommand = true)]
 struct Opt {
     #[clap(subcommand)]
     command: Command,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Turns on TCP keep-alive, probing an idle server every SECS seconds",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    keepalive_secs: Option<u64>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -19,6 +38,23 @@ enum Command {
         #[clap(name = "KEY", help = "A string key")]
         key: String,
 
+        #[clap(
+            long,
+            help = "Waits until the server has applied every write up to this sequence number",
+            value_name = "SEQUENCE"
+        )]
+        min_sequence: Option<u64>,
+
+        #[clap(long, help = "Pretty-prints the value if it is JSON")]
+        pretty: bool,
+
+        #[clap(
+            short = 'n',
+            long,
+            help = "Prints the value without a trailing newline, and \"Key not found\" only to stderr"
+        )]
+        no_newline: bool,
+
         #[clap(
             long,
             help = "Sets the server address",
@@ -36,6 +72,38 @@ enum Command {
         #[clap(name = "VALUE", help = "The string value of the key")]
         value: String,
 
+        #[clap(
+            long,
+            conflicts_with = "xx",
+            help = "Only set if the key doesn't exist; exits with status 2 otherwise"
+        )]
+        nx: bool,
+
+        #[clap(
+            long,
+            help = "Only set if the key already exists; exits with status 2 otherwise"
+        )]
+        xx: bool,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "mset", about = "Set several keys at once; either all are set or none")]
+    SetMany {
+        #[clap(
+            name = "KEY VALUE",
+            help = "Key/value pairs",
+            num_args = 2..,
+            required = true
+        )]
+        pairs: Vec<String>,
+
         #[clap(
             long,
             help = "Sets the server address",
@@ -58,6 +126,225 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+
+    #[clap(
+        name = "undelete",
+        about = "Restore a soft-deleted key; exits with status 1 if there is nothing to restore"
+    )]
+    Undelete {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(
+        name = "exists",
+        about = "Check which of several keys exist; prints 1 or 0 for each, in order"
+    )]
+    ExistsMany {
+        #[clap(name = "KEY", help = "String keys", required = true)]
+        keys: Vec<String>,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "strlen", about = "Get the length in bytes of the value of a given key")]
+    Strlen {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "scan", about = "Print the key/value pairs with a given key prefix, one per line")]
+    Scan {
+        #[clap(name = "PREFIX", help = "Key prefix to scan, empty for all keys", default_value = "")]
+        prefix: String,
+
+        #[clap(
+            long,
+            help = "Also prints each key's sequence number, write timestamp, value size and \
+                    expiry before its value [kvs engine]"
+        )]
+        verbose: bool,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "randomkey", about = "Print a key picked at random")]
+    RandomKey {
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "sequence", about = "Print the sequence number of the latest write")]
+    Sequence {
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "watch", about = "Print changes to keys with a given prefix as they happen")]
+    Watch {
+        #[clap(name = "PREFIX", help = "Key prefix to watch, empty for all keys", default_value = "")]
+        prefix: String,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "export", about = "Write every key/value pair on the server to a file")]
+    Export {
+        #[clap(name = "FILE", help = "File to write the pairs to")]
+        file: PathBuf,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "import", about = "Set every key/value pair from a file written by export")]
+    Import {
+        #[clap(name = "FILE", help = "File to read the pairs from")]
+        file: PathBuf,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(
+        name = "checkpoint",
+        about = "Compact the server's store and make every write so far durable"
+    )]
+    Checkpoint {
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "explain", about = "Show where the server's store keeps the value of a key")]
+    Explain {
+        #[clap(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(
+        name = "benchmark",
+        about = "Send gets and sets of random keys for a while, then print throughput and latencies"
+    )]
+    Benchmark {
+        #[clap(long, help = "Sets the share of requests that are gets", default_value_t = 0.9)]
+        read_ratio: f64,
+
+        #[clap(long, help = "Sets how many distinct keys to pick from", default_value_t = 1000)]
+        keys: u64,
+
+        #[clap(
+            long,
+            help = "Sets the length of the values set",
+            value_name = "BYTES",
+            default_value_t = 100
+        )]
+        value_size: usize,
+
+        #[clap(
+            long,
+            help = "Sets how many connections send requests at once, at most the server's \
+                    worker threads",
+            default_value_t = 4
+        )]
+        concurrency: usize,
+
+        #[clap(
+            long,
+            help = "Sets how long to send requests for",
+            value_name = "SECS",
+            default_value_t = 10
+        )]
+        duration_secs: u64,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+
+    #[clap(name = "stats", about = "Show operation counts and latency percentiles")]
+    Stats {
+        #[clap(long, help = "Clears the counts and latencies after showing them")]
+        reset: bool,
+
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -69,23 +356,240 @@ fn main() {
 }
 
 fn run(opt: Opt) -> Result<()> {
+    let keepalive = opt.keepalive_secs.map(Duration::from_secs);
     match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+        Command::Get { key, min_sequence, pretty, no_newline, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            let value = match min_sequence {
+                Some(min_sequence) => client.get_at_least(key, min_sequence)?,
+                None => client.get(key)?,
+            };
+            let value = if pretty { value.map(pretty_json) } else { value };
+            match value {
+                Some(value) if no_newline => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(value.as_bytes())?;
+                    stdout.flush()?;
+                }
+                Some(value) => println!("{}", value),
+                None if no_newline => eprintln!("Key not found"),
+                None => println!("Key not found"),
+            }
+        }
+        Command::Set { key, value, nx, xx, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            let condition = match (nx, xx) {
+                (true, _) => Some(SetCondition::IfAbsent),
+                (_, true) => Some(SetCondition::IfPresent),
+                _ => None,
+            };
+            match condition {
+                Some(condition) => {
+                    if !client.set_if(key, value, condition)? {
+                        match condition {
+                            SetCondition::IfAbsent => eprintln!("Key already exists"),
+                            SetCondition::IfPresent => eprintln!("Key not found"),
+                        }
+                        exit(2);
+                    }
+                }
+                None => client.set(key, value)?,
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+        Command::SetMany { pairs, addr } => {
+            if pairs.len() % 2 != 0 {
+                Opt::command()
+                    .error(ErrorKind::WrongNumberOfValues, "mset takes KEY VALUE pairs")
+                    .exit();
+            }
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            let mut client = connect(addr, keepalive)?;
+            client.set_many(pairs)?;
         }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
+            let mut client = connect(addr, keepalive)?;
             client.remove(key)?;
         }
+        Command::Undelete { key, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            if !client.undelete(key)? {
+                eprintln!("Key not found");
+                exit(1);
+            }
+        }
+        Command::ExistsMany { keys, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            for exists in client.exists_many(keys)? {
+                println!("{}", u8::from(exists));
+            }
+        }
+        Command::Strlen { key, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            if let Some(size) = client.value_size(key)? {
+                println!("{}", size);
+            } else {
+                println!("Key not found");
+            }
+        }
+        Command::Scan { prefix, verbose, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            if verbose {
+                for entry in client.scan_prefix_verbose(prefix)? {
+                    let expires_at = entry
+                        .expires_at
+                        .map_or(String::new(), |at| format!(" expires_at={}", at));
+                    println!(
+                        "{} sequence={} timestamp={} size={}{} {}",
+                        entry.key, entry.sequence, entry.timestamp, entry.size, expires_at, entry.value
+                    );
+                }
+            } else {
+                for (key, value) in client.scan_prefix(prefix)? {
+                    println!("{} {}", key, value);
+                }
+            }
+        }
+        Command::RandomKey { addr } => {
+            let mut client = connect(addr, keepalive)?;
+            if let Some(key) = client.random_key()? {
+                println!("{}", key);
+            } else {
+                println!("No keys");
+            }
+        }
+        Command::Sequence { addr } => {
+            let mut client = connect(addr, keepalive)?;
+            match client.latest_sequence()? {
+                Some(sequence) => println!("{}", sequence),
+                None => {
+                    eprintln!("The engine doesn't number its writes");
+                    exit(1);
+                }
+            }
+        }
+        Command::Watch { prefix, addr } => watch(addr, prefix, keepalive)?,
+        Command::Export { file, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            let count = client.export(BufWriter::new(File::create(file)?))?;
+            println!("Exported {} keys", count);
+        }
+        Command::Import { file, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            let count = client.import(BufReader::new(File::open(file)?))?;
+            println!("Imported {} keys", count);
+        }
+        Command::Checkpoint { addr } => {
+            let mut client = connect(addr, keepalive)?;
+            println!("Checkpoint {}", client.checkpoint()?);
+        }
+        Command::Explain { key, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            match client.explain_get(key)? {
+                Some(plan) => {
+                    println!("generation: {}", plan.generation);
+                    println!("position: {}", plan.pos);
+                    println!("length: {} bytes", plan.len);
+                    println!("sequence: {}", plan.sequence);
+                    println!("timestamp: {}", plan.timestamp);
+                    println!("cached: {}", plan.cached);
+                }
+                None => println!("Key not found"),
+            }
+        }
+        Command::Benchmark { read_ratio, keys, value_size, concurrency, duration_secs, addr } => {
+            let mut pool = ClientPool::new(addr)?;
+            if let Some(idle) = keepalive {
+                pool = pool.keepalive(idle);
+            }
+            let workload = Workload::default()
+                .read_ratio(read_ratio)
+                .key_space(keys)
+                .value_size(value_size)
+                .concurrency(concurrency)
+                .duration(Duration::from_secs(duration_secs));
+            let report = run_benchmark(&pool, &workload)?;
+            println!(
+                "{:.0} ops/s over {:.1}s, {} errors",
+                report.throughput(),
+                report.elapsed.as_secs_f64(),
+                report.errors
+            );
+            for (name, op) in [("get", report.get), ("set", report.set)] {
+                println!(
+                    "{}: count={} p50={}ns p95={}ns p99={}ns",
+                    name, op.count, op.p50_ns, op.p95_ns, op.p99_ns
+                );
+            }
+        }
+        Command::Stats { reset, addr } => {
+            let mut client = connect(addr, keepalive)?;
+            let stats = if reset { client.stats_reset()? } else { client.stats()? };
+            for (name, op) in [("get", stats.get), ("set", stats.set), ("rm", stats.remove)] {
+                println!(
+                    "{}: count={} p50={}ns p95={}ns p99={}ns",
+                    name, op.count, op.p50_ns, op.p95_ns, op.p99_ns
+                );
+            }
+            if let Some(bytes) = stats.index_memory {
+                println!("index memory: ~{} bytes", bytes);
+            }
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Reformats `value` as indented JSON, or returns it as is if it isn't JSON.
+fn pretty_json(value: String) -> String {
+    serde_json::from_str::<serde_json::Value>(&value)
+        .and_then(|json| serde_json::to_string_pretty(&json))
+        .unwrap_or(value)
+}
+
+/// Connects to the server, with TCP keep-alive if `keepalive` is set.
+fn connect(addr: SocketAddr, keepalive: Option<Duration>) -> Result<KvsClient> {
+    let mut client = KvsClient::connect(addr)?;
+    client.set_keepalive(keepalive)?;
+    Ok(client)
+}
+
+/// Prints change events until interrupted, resubscribing whenever the connection drops.
+///
+/// Changes made while reconnecting are not replayed.
+fn watch(addr: SocketAddr, prefix: String, keepalive: Option<Duration>) -> Result<()> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&interrupted))?;
+
+    while !interrupted.load(Ordering::SeqCst) {
+        let mut subscription =
+            match connect(addr, keepalive).and_then(|client| client.subscribe(prefix.clone())) {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    eprintln!("Cannot subscribe, retrying: {:?}", e);
+                    thread::sleep(WATCH_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+
+        while !interrupted.load(Ordering::SeqCst) {
+            match subscription.next_event() {
+                Ok(Some(event)) => match event.value {
+                    Some(value) => println!("set {} {}", event.key, value),
+                    None => println!("rm {}", event.key),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Subscription lost, reconnecting: {:?}", e);
+                    break;
+                }
+            }
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            subscription.close();
+        }
+    }
+    Ok(())
+}
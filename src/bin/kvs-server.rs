@@ -1,18 +1,33 @@
 use clap::{Parser, ValueEnum};
+use kvs::thread_pool::{
+    default_thread_count, NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+    FALLBACK_THREADS,
+};
 use kvs::*;
-use log::LevelFilter;
+use log::{Level, LevelFilter};
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 const CONFIG_FILE_NAME: &str = "kvs_config.toml";
+const DEFAULT_COMPACTION_HARD_CAP: u64 = 64 * 1024 * 1024;
+const BENCH_BUFFER_SIZE: usize = 1024 * 1024;
+// From this many worker threads on, the kvs engine shares its log readers between threads
+const SHARED_READERS_MIN_THREADS: u32 = 16;
+const DEFAULT_SHARED_READERS: usize = 4;
+// sled's own default
+const SLED_FLUSH_EVERY_MS: u64 = 500;
 
 #[derive(Parser, Debug)]
 #[clap(name = "kvs-server")]
@@ -32,11 +47,333 @@ struct Opt {
         value_enum
     )]
     engine: Option<Engine>,
+
+    #[clap(
+        long,
+        help = "Sets the number of worker threads [default: number of logical CPUs]",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    threads: Option<u32>,
+
+    #[clap(
+        long,
+        help = "Sets the thread pool implementation",
+        value_name = "POOL-NAME",
+        value_enum,
+        default_value_t = Pool::SharedQueue,
+    )]
+    pool: Pool,
+
+    #[clap(
+        long,
+        help = "Sets how many change events may queue up for a subscriber",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        default_value_t = 1024,
+    )]
+    subscriber_buffer: u32,
+
+    #[clap(
+        long,
+        help = "Sets what happens to a subscriber that falls further behind than its buffer",
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = Lag::Disconnect,
+    )]
+    lag_policy: Lag,
+
+    #[clap(
+        long,
+        help = "Sets how many connections a single client IP may have open at once [default: no limit]",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    max_connections_per_ip: Option<u32>,
+
+    #[clap(
+        long,
+        help = "Closes connections that send a request larger than this",
+        value_name = "BYTES",
+        default_value_t = DEFAULT_MAX_REQUEST_SIZE,
+    )]
+    max_request_size: usize,
+
+    #[clap(
+        long,
+        help = "Turns on TCP keep-alive, probing idle clients every SECS seconds",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    keepalive_secs: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Logs every request (client, operation, key, latency, result) at this level",
+        value_name = "LEVEL"
+    )]
+    request_log: Option<Level>,
+
+    #[clap(
+        long,
+        help = "Logs values longer than this as their length only in the request log",
+        value_name = "BYTES",
+        default_value_t = 64
+    )]
+    request_log_max_value: usize,
+
+    #[clap(
+        long,
+        help = "Rejects set values that aren't of this type",
+        value_name = "TYPE",
+        value_enum,
+        default_value_t = Values::String,
+    )]
+    value_type: Values,
+
+    #[cfg(feature = "http")]
+    #[clap(
+        long,
+        help = "Also serves the engine over HTTP on this address",
+        value_name = "IP-PORT"
+    )]
+    http_addr: Option<SocketAddr>,
+
+    #[clap(
+        long,
+        help = "Logs a warning when the key index is estimated to use more memory than this [kvs engine]",
+        value_name = "BYTES"
+    )]
+    index_memory_warning: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Seals compacted log files with a checksum of the whole file [kvs engine]"
+    )]
+    file_checksums: bool,
+
+    #[clap(
+        long,
+        help = "Saves the key index on shutdown and loads it on the next start instead of \
+                replaying the log, unless the log files changed in between [kvs engine]"
+    )]
+    index_hints: bool,
+
+    #[clap(
+        long,
+        help = "Writes the log with direct I/O, bypassing the page cache, for bulk loads \
+                (Linux only; falls back to buffered I/O where unsupported) [kvs engine]"
+    )]
+    direct_writes: bool,
+
+    #[clap(
+        long,
+        help = "Reads the log files sealed by --file-checksums through memory mappings instead \
+                of buffers (Linux only) [kvs engine]"
+    )]
+    mmap_reads: bool,
+
+    #[clap(
+        long,
+        help = "Makes removes soft deletes, restorable with `kvs-client undelete` for SECS \
+                seconds [kvs engine]",
+        value_name = "SECS"
+    )]
+    soft_delete_retention_secs: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Rejects sets that would take the log files past this many bytes [kvs engine]",
+        value_name = "BYTES"
+    )]
+    max_disk_bytes: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Sets whether a set over --max-disk-bytes compacts before it is rejected",
+        value_name = "POLICY",
+        value_enum,
+        default_value_t = Quota::CompactFirst,
+    )]
+    disk_quota_policy: Quota,
+
+    #[clap(
+        long,
+        help = "Shares log readers between worker threads, keeping up to N idle per log file \
+                [kvs engine] [default: 4 with 16 or more threads, else one per thread]",
+        value_name = "N"
+    )]
+    shared_readers: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Caps sled's page cache at about this many bytes [sled engine]",
+        value_name = "BYTES"
+    )]
+    sled_cache_bytes: Option<u64>,
+
+    #[clap(
+        long,
+        help = "DANGEROUS: stops flushing writes and uses large buffers, for benchmarks only; \
+                writes may be lost if the server dies [kvs engine]"
+    )]
+    bench_mode: bool,
+
+    #[clap(
+        long,
+        help = "Only compact automatically between these times (UTC), e.g. 02:00-04:00 [kvs engine]",
+        value_name = "HH:MM-HH:MM",
+        value_parser = parse_compaction_window,
+    )]
+    compaction_window: Option<(Duration, Duration)>,
+
+    #[clap(
+        long,
+        help = "Compacts outside the compaction window once this many bytes are stale",
+        value_name = "BYTES",
+        default_value_t = DEFAULT_COMPACTION_HARD_CAP,
+        requires = "compaction_window",
+    )]
+    compaction_hard_cap: u64,
+
+    #[clap(
+        long,
+        help = "Compacts once this share of the log is stale, e.g. 0.3, rather than once 1MB is [kvs engine]",
+        value_name = "RATIO",
+        value_parser = parse_ratio,
+    )]
+    compaction_stale_ratio: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Falls back to the default configuration if the config file is invalid"
+    )]
+    ignore_bad_config: bool,
+
+    #[clap(
+        long,
+        help = "Replays and validates the data directory, prints a summary and exits without listening"
+    )]
+    replay_only: bool,
+
+    #[cfg(unix)]
+    #[clap(
+        long,
+        help = "Serves on the sockets passed by systemd socket activation, if any, instead of binding --addr"
+    )]
+    systemd: bool,
+}
+
+/// Parses a share between 0 and 1.
+fn parse_ratio(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err("expected a number between 0 and 1".to_owned()),
+    }
+}
+
+/// Parses a `HH:MM-HH:MM` window into times since midnight.
+fn parse_compaction_window(s: &str) -> std::result::Result<(Duration, Duration), String> {
+    let parse_time = |time: &str| {
+        let (hours, minutes) = time.split_once(':').ok_or("expected HH:MM")?;
+        let hours: u64 = hours.parse().map_err(|_| "invalid hours")?;
+        let minutes: u64 = minutes.parse().map_err(|_| "invalid minutes")?;
+        if hours > 23 || minutes > 59 {
+            return Err("time out of range");
+        }
+        Ok(Duration::from_secs((hours * 60 + minutes) * 60))
+    };
+    let (start, end) = s.split_once('-').ok_or("expected HH:MM-HH:MM")?;
+    Ok((parse_time(start)?, parse_time(end)?))
+}
+
+/// Thread pool used to serve connections.
+///
+/// - `naive` spawns a new OS thread per connection and ignores `--threads`. No queueing,
+///   but thread creation cost on every connection and no bound on thread count.
+/// - `shared-queue` runs a fixed set of workers pulling from one locked queue. Bounded and
+///   simple; the queue lock can become a point of contention under many short connections.
+/// - `rayon` uses rayon's work-stealing pool. Bounded, with per-worker queues that avoid a
+///   single contended lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl std::fmt::Display for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Pool::Naive => write!(f, "naive"),
+            Pool::SharedQueue => write!(f, "shared-queue"),
+            Pool::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
+/// Lag policy for subscribers, see `LagPolicy`.
+///
+/// - `disconnect` ends the subscription; the client is told it lagged.
+/// - `resync` skips the missed events and sends the current values of the subscribed keys
+///   once the client catches up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Lag {
+    Disconnect,
+    Resync,
+}
+
+impl From<Lag> for LagPolicy {
+    fn from(lag: Lag) -> LagPolicy {
+        match lag {
+            Lag::Disconnect => LagPolicy::Disconnect,
+            Lag::Resync => LagPolicy::Resync,
+        }
+    }
+}
+
+/// Values the server accepts, see `ValueType`.
+///
+/// - `string` accepts any value.
+/// - `json` only accepts valid JSON.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Values {
+    String,
+    Json,
+}
+
+impl From<Values> for ValueType {
+    fn from(values: Values) -> ValueType {
+        match values {
+            Values::String => ValueType::String,
+            Values::Json => ValueType::Json,
+        }
+    }
+}
+
+/// Disk quota policy, see `QuotaPolicy`.
+///
+/// - `compact-first` compacts, and rejects the set only if it still doesn't fit.
+/// - `reject` rejects the set right away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Quota {
+    CompactFirst,
+    Reject,
+}
+
+impl From<Quota> for QuotaPolicy {
+    fn from(quota: Quota) -> QuotaPolicy {
+        match quota {
+            Quota::CompactFirst => QuotaPolicy::CompactFirst,
+            Quota::Reject => QuotaPolicy::Reject,
+        }
+    }
 }
 
 // The Engine enum definition
 #[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
+#[allow(non_camel_case_types)]
 enum Engine {
     kvs,
     sled
@@ -79,14 +416,20 @@ impl Default for ServerConfig {
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
     let opt = Opt::parse();
+    let mut logger = env_logger::builder();
+    logger.filter_level(LevelFilter::Info);
+    if let Some(level) = opt.request_log {
+        // The request log may use a more verbose level than the rest of the server
+        logger.filter_module("kvs::requests", level.to_level_filter());
+    }
+    logger.init();
 
-    let res = load_config()
+    let res = load_config(opt.ignore_bad_config)
         .and_then(|config| validate_and_run(config, opt));
 
     if let Err(e) = res {
-        KvsError::StringError(format!("{:?}", e));
+        error!("{:?}", e);
         exit(1);
     }
 }
@@ -107,35 +450,260 @@ fn validate_and_run(mut config: ServerConfig, opt: Opt) -> Result<()> {
         config.data_dir = Some(current_dir()?);
     }
 
+    if opt.replay_only {
+        return replay_only(&config);
+    }
+
     // Save the updated configuration
     save_config(&config)?;
 
-    run(config, opt.addr)
+    let threads = match opt.threads {
+        Some(threads) => threads,
+        None => default_threads(),
+    };
+
+    run(config, &opt, threads)
+}
+
+/// Replays the data directory and prints a summary, failing if any record is corrupt.
+fn replay_only(config: &ServerConfig) -> Result<()> {
+    let data_dir = config.data_dir.as_ref().unwrap();
+    match config.engine {
+        Engine::kvs => {
+            let report = KvStore::verify(data_dir)?;
+            println!("Generations: {}", report.generations);
+            println!("Records: {}", report.records);
+            println!("Keys: {}", report.keys);
+            println!("Corrupt records: {}", report.corrupt_records.len());
+            println!("Uncompacted bytes: {}", report.uncompacted);
+            if report.corrupt_records.is_empty() {
+                return Ok(());
+            }
+            for record in &report.corrupt_records {
+                error!("Corrupt record in {}", record);
+            }
+            Err(KvsError::CorruptedData)
+        }
+        Engine::sled => {
+            // sled validates its files when opening them
+            let engine = SledKvsEngine::new(sled::open(data_dir)?);
+            println!("Keys: {}", engine.count()?);
+            Ok(())
+        }
+    }
+}
+
+/// One worker per logical CPU, so the server scales to the host without tuning.
+fn default_threads() -> u32 {
+    let detected = std::thread::available_parallelism();
+    if let Err(e) = &detected {
+        warn!(
+            "Cannot detect the number of logical CPUs ({}), using {} worker threads",
+            e, FALLBACK_THREADS
+        );
+    }
+    default_thread_count(detected)
 }
 
-fn run(config: ServerConfig, addr: SocketAddr) -> Result<()> {
+fn run(config: ServerConfig, opt: &Opt, threads: u32) -> Result<()> {
     let data_dir = config.data_dir.unwrap();
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", config.engine);
-    info!("Listening on {}", addr);
+    info!("Thread pool: {}", opt.pool);
+    info!("Worker threads: {}", threads);
+    info!("Listening on {}", opt.addr);
+    if opt.compaction_window.is_some() && config.engine != Engine::kvs {
+        warn!("--compaction-window only applies to the kvs engine");
+    }
+    if opt.compaction_stale_ratio.is_some() && config.engine != Engine::kvs {
+        warn!("--compaction-stale-ratio only applies to the kvs engine");
+    }
+    if opt.file_checksums && config.engine != Engine::kvs {
+        warn!("--file-checksums only applies to the kvs engine");
+    }
+    if opt.index_hints && config.engine != Engine::kvs {
+        warn!("--index-hints only applies to the kvs engine");
+    }
+    if opt.direct_writes && config.engine != Engine::kvs {
+        warn!("--direct-writes only applies to the kvs engine");
+    }
+    if opt.mmap_reads && config.engine != Engine::kvs {
+        warn!("--mmap-reads only applies to the kvs engine");
+    }
+    if opt.mmap_reads && !opt.file_checksums {
+        warn!("--mmap-reads only maps log files sealed by --file-checksums");
+    }
+    if opt.soft_delete_retention_secs.is_some() && config.engine != Engine::kvs {
+        warn!("--soft-delete-retention-secs only applies to the kvs engine");
+    }
+    if opt.max_disk_bytes.is_some() && config.engine != Engine::kvs {
+        warn!("--max-disk-bytes only applies to the kvs engine");
+    }
+    if opt.shared_readers.is_some() && config.engine != Engine::kvs {
+        warn!("--shared-readers only applies to the kvs engine");
+    }
+    if opt.index_memory_warning.is_some() && config.engine != Engine::kvs {
+        warn!("--index-memory-warning only applies to the kvs engine");
+    }
+    if opt.sled_cache_bytes.is_some() && config.engine != Engine::sled {
+        warn!("--sled-cache-bytes only applies to the sled engine");
+    }
+    if opt.bench_mode && config.engine != Engine::kvs {
+        warn!("--bench-mode only applies to the kvs engine");
+    }
 
     match config.engine {
-        Engine::kvs => run_with_engine(KvStore::open(data_dir, None, None)?, addr),
-        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(data_dir)?), addr),
+        Engine::kvs => {
+            let mut store = if opt.bench_mode {
+                warn!("**********************************************************************");
+                warn!("BENCH MODE: writes are not flushed. Data WILL be lost if the server dies.");
+                warn!("Never use --bench-mode for data you want to keep.");
+                warn!("**********************************************************************");
+                KvStore::open(data_dir, Some(BENCH_BUFFER_SIZE), Some(BENCH_BUFFER_SIZE))?
+                    .with_buffered_writes()
+            } else {
+                KvStore::open(data_dir, None, None)?
+            };
+            if let Some((start, end)) = opt.compaction_window {
+                info!(
+                    "Compaction window: {}-{} UTC",
+                    format_time_of_day(start),
+                    format_time_of_day(end)
+                );
+                store = store.with_compaction_window(CompactionWindow::new(
+                    start,
+                    end,
+                    opt.compaction_hard_cap,
+                ));
+            }
+            if let Some(ratio) = opt.compaction_stale_ratio {
+                info!("Compacting once {:.0}% of the log is stale", ratio * 100.0);
+                store = store.with_adaptive_compaction(ratio);
+            }
+            if opt.file_checksums {
+                store = store.with_file_checksums();
+            }
+            if opt.index_hints {
+                store = store.with_index_hints();
+            }
+            if opt.direct_writes {
+                store = store.with_direct_writes();
+            }
+            if opt.mmap_reads {
+                store = store.with_mmap_reads();
+            }
+            if let Some(secs) = opt.soft_delete_retention_secs {
+                info!("Soft deletes, retained for {}s", secs);
+                store = store.with_soft_deletes(Duration::from_secs(secs));
+            }
+            if let Some(bytes) = opt.max_disk_bytes {
+                info!("Disk quota: {} bytes, {:?}", bytes, opt.disk_quota_policy);
+                store = store.with_disk_quota(bytes, opt.disk_quota_policy.into());
+            }
+            if let Some(bytes) = opt.index_memory_warning {
+                store = store.with_index_memory_warning(bytes);
+            }
+            let shared_readers = opt.shared_readers.or(
+                (threads >= SHARED_READERS_MIN_THREADS).then_some(DEFAULT_SHARED_READERS),
+            );
+            if let Some(per_generation) = shared_readers {
+                info!("Shared log readers: up to {} idle per log file", per_generation);
+                store = store.with_shared_readers(per_generation);
+            }
+            run_with_pool(store, opt, threads)
+        }
+        Engine::sled => {
+            let engine = match opt.sled_cache_bytes {
+                Some(bytes) => {
+                    info!("sled cache: {} bytes", bytes);
+                    SledKvsEngine::open_with_config(data_dir, bytes, Some(SLED_FLUSH_EVERY_MS))?
+                }
+                None => SledKvsEngine::new(sled::open(data_dir)?),
+            };
+            run_with_pool(engine, opt, threads)
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn format_time_of_day(time: Duration) -> String {
+    let minutes = time.as_secs() / 60;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn run_with_pool<E: KvsEngine>(engine: E, opt: &Opt, threads: u32) -> Result<()> {
+    match opt.pool {
+        Pool::Naive => run_with_engine::<E, NaiveThreadPool>(engine, opt, threads),
+        Pool::SharedQueue => run_with_engine::<E, SharedQueueThreadPool>(engine, opt, threads),
+        Pool::Rayon => run_with_engine::<E, RayonThreadPool>(engine, opt, threads),
+    }
+}
+
+fn run_with_engine<E: KvsEngine, P: ThreadPool>(engine: E, opt: &Opt, threads: u32) -> Result<()> {
+    let pool = P::new(threads)?;
+    let mut options = ServerOptions::default()
+        .subscriber_buffer(opt.subscriber_buffer as usize, opt.lag_policy.into())
+        .max_request_size(opt.max_request_size)
+        .value_type(opt.value_type.into());
+    if opt.value_type != Values::String {
+        info!("Value type: {:?}", opt.value_type);
+    }
+    if let Some(max) = opt.max_connections_per_ip {
+        info!("Max connections per client IP: {}", max);
+        options = options.max_connections_per_ip(max as usize);
+    }
+    if let Some(secs) = opt.keepalive_secs {
+        info!("TCP keep-alive: {}s", secs);
+        options = options.keepalive(Duration::from_secs(secs));
+    }
+    if let Some(level) = opt.request_log {
+        info!("Request log: {}", level);
+        options = options.request_log(level, opt.request_log_max_value);
+    }
+    #[cfg(feature = "http")]
+    if let Some(http_addr) = opt.http_addr {
+        info!("HTTP listening on {}", http_addr);
+        options = options.http_addr(http_addr);
+    }
+    let server = KvsServer::with_options(engine, pool, options);
+
+    // The first SIGINT/SIGTERM shuts down gracefully, a second one exits right away
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&shutdown))?;
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+    #[cfg(unix)]
+    if opt.systemd {
+        // SAFETY: the server never closes or wraps the descriptors systemd passes, which
+        // stay open from the start, so nothing else owns them
+        let listeners = unsafe { systemd_listeners()? };
+        if !listeners.is_empty() {
+            info!(
+                "Serving on {} socket(s) passed by systemd instead of {}",
+                listeners.len(),
+                opt.addr
+            );
+            server.run_listeners_with_shutdown(listeners, shutdown)?;
+            info!("Server stopped");
+            return Ok(());
+        }
+        info!("No socket passed by systemd, binding {}", opt.addr);
+    }
+    server.run_with_shutdown(opt.addr, shutdown)?;
+    info!("Server stopped");
+    Ok(())
 }
 
 fn config_path() -> PathBuf {
     current_dir().unwrap_or_default().join(CONFIG_FILE_NAME)
 }
 
-fn load_config() -> Result<ServerConfig> {
+/// Loads the persisted server configuration.
+///
+/// An unparsable config file is an error unless `ignore_bad_config` is set, because silently
+/// falling back to the defaults could open the data directory with the wrong engine.
+fn load_config(ignore_bad_config: bool) -> Result<ServerConfig> {
     let config_path = config_path();
 
     if !config_path.exists() {
@@ -145,10 +713,11 @@ fn load_config() -> Result<ServerConfig> {
     let config_content = fs::read_to_string(config_path)?;
     match toml::from_str(&config_content) {
         Ok(config) => Ok(config),
-        Err(e) => {
+        Err(e) if ignore_bad_config => {
             warn!("Invalid configuration file: {}", e);
             Ok(ServerConfig::default())
         }
+        Err(e) => Err(KvsError::BadConfig(e.to_string())),
     }
 }
 
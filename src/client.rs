@@ -1,79 +1,518 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    set_keepalive, CheckpointResponse, ConditionalSetResponse, ExistsManyResponse,
+    ExplainResponse, ExportFrame, GetResponse, ImportResponse, PingResponse, RandomKeyResponse,
+    RemoveResponse, Request, ScanFrame, ScanVerboseFrame, SequenceResponse,
+    SetManyResponse, SetResponse, StatsResponse, SubscribeResponse, UndeleteResponse,
+    ValueSizeResponse,
+};
+use crate::engines::{GetPlan, ScanEntry, SetCondition};
+use crate::compression::{compress, decompress};
+use crate::framed::{read_frame, write_frame, FramedConnection};
+use crate::stats::Stats;
+use crate::subscribe::{ChangeEvent, SubscriptionFrame};
 use crate::{KvsError, Result};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use socket2::SockRef;
+
+/// How long a request may wait on the socket before the connection is considered broken.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[allow(missing_docs)]
 pub struct KvsClient {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    addrs: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+
+    // `None` after the connection was reset; re-established on the next request
+    connection: Option<Connection>,
+
+    // Highest sequence number the server acknowledged for a set or remove of this client
+    acked_sequence: Option<u64>,
+
+    // Whether values are compressed on the way to the server, see `set_compression`
+    compress_values: bool,
+}
+
+/// A single TCP connection to the server.
+type Connection = FramedConnection<TcpStream>;
+
+impl Connection {
+    fn open(
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+        keepalive: Option<Duration>,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(addrs)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        if keepalive.is_some() {
+            set_keepalive(&stream, keepalive)?;
+        }
+        Ok(FramedConnection::new(stream))
+    }
+
+    fn shutdown(self) {
+        let _ = self.get_ref().shutdown(Shutdown::Both);
+    }
 }
 
 #[allow(missing_docs)]
 impl KvsClient {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let connection = Connection::open(&addrs, Some(DEFAULT_TIMEOUT), None)?;
         Ok(KvsClient {
-            reader: BufReader::new(tcp_reader),
-            writer: BufWriter::new(tcp_writer),
+            addrs,
+            timeout: Some(DEFAULT_TIMEOUT),
+            keepalive: None,
+            connection: Some(connection),
+            acked_sequence: None,
+            compress_values: false,
         })
     }
 
-    fn send_request<T: Serialize>(&mut self, request: T) -> Result<()>{
-        let serialized = bincode::serialize(&request)?;
-
-        // Send length prefix followed by data
-        let len = serialized.len() as u32;
-        self.writer.write_all(&len.to_be_bytes())?;
-        self.writer.write_all(&serialized)?;
-        self.writer.flush()?;
+    /// Sets how long a request may block on reading or writing before the
+    /// connection is reset. `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(connection) = &self.connection {
+            connection.get_ref().set_read_timeout(timeout)?;
+            connection.get_ref().set_write_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
 
+    /// Turns on TCP keep-alive with the given idle time and probe interval, so that a server
+    /// that went away without closing the connection is noticed by the OS. `None` turns it
+    /// off, which is the default.
+    pub fn set_keepalive(&mut self, idle: Option<Duration>) -> Result<()> {
+        if let Some(connection) = &self.connection {
+            set_keepalive(connection.get_ref(), idle)?;
+        }
+        self.keepalive = idle;
         Ok(())
     }
 
-    fn receive_request<T: for<'de> Deserialize<'de>>(&mut self) -> Result<T> {
-        // Read response
-        let mut len_bytes = [0u8; 4]; // 4 bytes == largest possible integer
-        self.reader.read_exact(&mut len_bytes)?;
-        let len = u32::from_be_bytes(len_bytes) as usize;
+    /// Compresses the values this client sets, and stores them with `COMPRESSED_VALUE_MARKER`
+    /// in front; values that don't get shorter, or are over `DEFAULT_MAX_REQUEST_SIZE`, are
+    /// stored as they are. `get`, `get_at_least` and the scans decompress the values that have
+    /// the marker and return the others as they are, so compressing clients read values set by
+    /// any client. A compressed value that would inflate past `DEFAULT_MAX_REQUEST_SIZE` is an
+    /// error. Off by default.
+    ///
+    /// The values in `subscribe` events are not decompressed: they carry the marker and the
+    /// compressed bytes like with compression off.
+    ///
+    /// With compression off, compressed values read as they are stored: the marker, then
+    /// the compressed bytes in base64. The server stores them like any other value:
+    /// `value_size` and verbose scans report the compressed length, and `export` and
+    /// `import` copy them as they are.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compress_values = enabled;
+    }
+
+    /// The value to send for `value`, compressed if compression is on.
+    fn encode_value(&self, value: String) -> String {
+        if self.compress_values {
+            compress(value)
+        } else {
+            value
+        }
+    }
+
+    /// The value `stored` stands for, decompressed if compression is on.
+    fn decode_value(&self, stored: String) -> Result<String> {
+        if self.compress_values {
+            decompress(stored)
+        } else {
+            Ok(stored)
+        }
+    }
+
+    /// Returns the keep-alive idle time set on the open connection's socket, or `None` if
+    /// keep-alive is off or no connection is open.
+    pub fn keepalive(&self) -> Result<Option<Duration>> {
+        let Some(connection) = &self.connection else {
+            return Ok(None);
+        };
+        let socket = SockRef::from(connection.get_ref());
+        if !socket.keepalive()? {
+            return Ok(None);
+        }
+        Ok(Some(socket.keepalive_time()?))
+    }
+
+    /// Sends a request and waits for its response.
+    fn round_trip<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        self.with_connection(|connection| {
+            connection.send(&request)?;
+            connection.recv()
+        })
+    }
+
+    /// Runs an exchange with the server on the open connection, opening one if needed.
+    ///
+    /// If the exchange fails the connection can no longer be trusted to be in sync (e.g. a
+    /// response may still arrive later), so it is torn down and a fresh one is opened on the
+    /// next request. I/O failures surface as `KvsError::ConnectionBroken`.
+    fn with_connection<T, F>(&mut self, exchange: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T>,
+    {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Connection::open(&self.addrs, self.timeout, self.keepalive)?,
+        };
 
-        // Read and deserialize the response
-        let mut buf = vec![0; len];
-        self.reader.read_exact(&mut buf)?;
-        let result= bincode::deserialize(&buf)?;
+        match exchange(&mut connection) {
+            Ok(response) => {
+                self.connection = Some(connection);
+                Ok(response)
+            }
+            Err(e) => {
+                connection.shutdown();
+                Err(match e {
+                    KvsError::IoError(e) => KvsError::ConnectionBroken(e),
+                    KvsError::Serialization(e) => KvsError::ConnectionBroken(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        e,
+                    )),
+                    e => e,
+                })
+            }
+        }
+    }
 
-        Ok(result)
+    /// Checks that the server is reachable and answering, without touching the store.
+    pub fn ping(&mut self) -> Result<()> {
+        let result: PingResponse = self.round_trip(Request::Ping)?;
+        match result {
+            PingResponse::Ok(_) => Ok(()),
+            PingResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.send_request(Request::Get { key })?;
+        let result: GetResponse = self.round_trip(Request::Get { key })?;
+        match result {
+            GetResponse::Ok(resp) => resp.map(|value| self.decode_value(value)).transpose(),
+            GetResponse::Err(e) => Err(KvsError::StringError(e)),
+        }
+    }
 
-        let result: GetResponse = self.receive_request()?;
+    /// Gets the value of `key` once the server has applied every write up to `min_sequence`,
+    /// e.g. the token of a `checkpoint` taken after the writes to be read back.
+    ///
+    /// The server waits up to a second for its store to catch up and answers with an error if
+    /// it doesn't; a single node only falls behind a sequence it handed out while a write is
+    /// still being applied. The sled engine doesn't number its writes, so with it this fails
+    /// for any `min_sequence` above 0.
+    pub fn get_at_least(&mut self, key: String, min_sequence: u64) -> Result<Option<String>> {
+        let result: GetResponse = self.round_trip(Request::GetAtLeast { key, min_sequence })?;
         match result {
-            GetResponse::Ok(resp) => Ok(resp),
+            GetResponse::Ok(resp) => resp.map(|value| self.decode_value(value)).transpose(),
             GetResponse::Err(e) => Err(KvsError::StringError(e)),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-       self.send_request(Request::Set {key, value})?;
-
-        let result: SetResponse = self.receive_request()?;
+        let value = self.encode_value(value);
+        let result: SetResponse = self.round_trip(Request::Set {key, value})?;
         match result {
-            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Ok(sequence) => {
+                self.ack(sequence);
+                Ok(())
+            }
             SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        self.send_request(Request::Remove { key })?;
+    /// Sets the value only if `condition` holds on the server. Returns whether it was set.
+    pub fn set_if(&mut self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        let value = self.encode_value(value);
+        let result: ConditionalSetResponse =
+            self.round_trip(Request::ConditionalSet { key, value, condition })?;
+        match result {
+            ConditionalSetResponse::Ok(set) => Ok(set),
+            ConditionalSetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
 
-        let result: RemoveResponse = self.receive_request()?;
+    /// Sets several keys atomically: either all pairs are set or, on error, none are.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (key, self.encode_value(value)))
+            .collect();
+        let result: SetManyResponse = self.round_trip(Request::SetMany { pairs })?;
         match result {
-            RemoveResponse::Ok(_) => Ok(()),
+            SetManyResponse::Ok(_) => Ok(()),
+            SetManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let result: RemoveResponse = self.round_trip(Request::Remove { key })?;
+        match result {
+            RemoveResponse::Ok(sequence) => {
+                self.ack(sequence);
+                Ok(())
+            }
             RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
-}
\ No newline at end of file
+
+    fn ack(&mut self, sequence: Option<u64>) {
+        self.acked_sequence = self.acked_sequence.max(sequence);
+    }
+
+    /// Returns the highest sequence number the server acknowledged for a `set` or `remove`
+    /// made through this client, or `None` if there was none or the engine doesn't number
+    /// its writes.
+    ///
+    /// Compare it with `latest_sequence` to find out whether a write whose response was lost,
+    /// e.g. to a dropped connection, was applied before retrying it: if nothing else writes
+    /// to the server, the write was applied exactly if the latest sequence is past the acked
+    /// one. With other writers, a latest sequence equal to the acked one still proves the
+    /// write wasn't applied.
+    pub fn acked_sequence(&self) -> Option<u64> {
+        self.acked_sequence
+    }
+
+    /// Returns the sequence number of the latest write the server applied, or `None` if its
+    /// engine doesn't number its writes. Unlike `checkpoint`, it neither compacts nor flushes.
+    pub fn latest_sequence(&mut self) -> Result<Option<u64>> {
+        let result: SequenceResponse = self.round_trip(Request::LatestSequence)?;
+        match result {
+            SequenceResponse::Ok(sequence) => Ok(sequence),
+            SequenceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Restores the value of a soft-deleted key, returning whether there was one to restore.
+    /// Only supported by the kvs engine with soft deletes.
+    pub fn undelete(&mut self, key: String) -> Result<bool> {
+        let result: UndeleteResponse = self.round_trip(Request::Undelete { key })?;
+        match result {
+            UndeleteResponse::Ok(restored) => Ok(restored),
+            UndeleteResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the length in bytes of the value of `key`, or `None` if it doesn't exist.
+    pub fn value_size(&mut self, key: String) -> Result<Option<u64>> {
+        let result: ValueSizeResponse = self.round_trip(Request::ValueSize { key })?;
+        match result {
+            ValueSizeResponse::Ok(size) => Ok(size),
+            ValueSizeResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns whether each of `keys` exists, in order, without fetching their values.
+    pub fn exists_many(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let result: ExistsManyResponse = self.round_trip(Request::ExistsMany { keys })?;
+        match result {
+            ExistsManyResponse::Ok(exists) => Ok(exists),
+            ExistsManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, sorted by key.
+    ///
+    /// The server sends them in chunks, which are gathered here.
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let pairs = self.with_connection(|connection| {
+            connection.send(&Request::Scan { prefix })?;
+            let mut pairs = Vec::new();
+            loop {
+                match connection.recv()? {
+                    ScanFrame::Chunk(chunk) => pairs.extend(chunk),
+                    ScanFrame::Done => return Ok(pairs),
+                    ScanFrame::Err(msg) => return Err(KvsError::StringError(msg)),
+                }
+            }
+        })?;
+        pairs
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.decode_value(value)?)))
+            .collect()
+    }
+
+    /// Like `scan_prefix`, along with the sequence number, timestamp, size and expiry of
+    /// each key's latest write. Only supported by the kvs engine.
+    pub fn scan_prefix_verbose(&mut self, prefix: String) -> Result<Vec<ScanEntry>> {
+        let entries = self.with_connection(|connection| {
+            connection.send(&Request::ScanVerbose { prefix })?;
+            let mut entries = Vec::new();
+            loop {
+                match connection.recv()? {
+                    ScanVerboseFrame::Chunk(chunk) => entries.extend(chunk),
+                    ScanVerboseFrame::Done => return Ok(entries),
+                    ScanVerboseFrame::Err(msg) => return Err(KvsError::StringError(msg)),
+                }
+            }
+        })?;
+        entries
+            .into_iter()
+            .map(|entry| {
+                Ok(ScanEntry {
+                    value: self.decode_value(entry.value)?,
+                    ..entry
+                })
+            })
+            .collect()
+    }
+
+    /// Returns a key picked uniformly at random, or `None` if the server has no keys.
+    pub fn random_key(&mut self) -> Result<Option<String>> {
+        let result: RandomKeyResponse = self.round_trip(Request::RandomKey)?;
+        match result {
+            RandomKeyResponse::Ok(key) => Ok(key),
+            RandomKeyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Fetches the server's operation counts and latency percentiles.
+    pub fn stats(&mut self) -> Result<Stats> {
+        let result: StatsResponse = self.round_trip(Request::Stats)?;
+        match result {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Clears the server's operation counts and latencies, returning them as they were just
+    /// before, so rates can be measured between two resets.
+    pub fn stats_reset(&mut self) -> Result<Stats> {
+        let result: StatsResponse = self.round_trip(Request::StatsReset)?;
+        match result {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Compacts the server's store and makes every write so far durable.
+    ///
+    /// Returns the checkpoint's token; with the kvs engine, the sequence number of the latest
+    /// write it covers.
+    pub fn checkpoint(&mut self) -> Result<u64> {
+        let result: CheckpointResponse = self.round_trip(Request::Checkpoint)?;
+        match result {
+            CheckpointResponse::Ok(token) => Ok(token),
+            CheckpointResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Reports where the server's store keeps the value of `key`, or `None` if it doesn't
+    /// exist. Only supported by the kvs engine.
+    pub fn explain_get(&mut self, key: String) -> Result<Option<GetPlan>> {
+        let result: ExplainResponse = self.round_trip(Request::Explain { key })?;
+        match result {
+            ExplainResponse::Ok(plan) => Ok(plan),
+            ExplainResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Streams every key/value pair on the server into `out`, in key order.
+    ///
+    /// The pairs arrive in chunks and are written as they come, so the dump never has to fit
+    /// in memory; each chunk is framed like a message on the wire, as
+    /// `[u32 BE length][bincode pairs]`. Returns how many pairs were written.
+    ///
+    /// # Errors
+    ///
+    /// Writing to `out` fails with `KvsError::IoError`, unlike a broken connection.
+    pub fn export<W: Write>(&mut self, mut out: W) -> Result<u64> {
+        let mut exported = 0;
+        let mut write_error = None;
+        self.with_connection(|connection| {
+            connection.send(&Request::Export)?;
+            loop {
+                match connection.recv()? {
+                    ExportFrame::Chunk(pairs) => {
+                        // Keep draining the stream so the connection stays in sync
+                        if write_error.is_none() {
+                            match write_frame(&mut out, &pairs) {
+                                Ok(()) => exported += pairs.len() as u64,
+                                Err(e) => write_error = Some(e),
+                            }
+                        }
+                    }
+                    ExportFrame::Done => return Ok(()),
+                    ExportFrame::Err(msg) => return Err(KvsError::StringError(msg)),
+                }
+            }
+        })?;
+
+        match write_error {
+            Some(e) => Err(e),
+            None => {
+                out.flush()?;
+                Ok(exported)
+            }
+        }
+    }
+
+    /// Loads pairs written by `export` from `input` into the server, one chunk per request.
+    /// Returns how many pairs were imported.
+    pub fn import<R: Read>(&mut self, mut input: R) -> Result<u64> {
+        let mut imported = 0;
+        while let Some(pairs) = read_frame::<_, Vec<(String, String)>>(&mut input, None)? {
+            let count = pairs.len() as u64;
+            let result: ImportResponse = self.round_trip(Request::Import { pairs })?;
+            match result {
+                ImportResponse::Ok(_) => imported += count,
+                ImportResponse::Err(msg) => return Err(KvsError::StringError(msg)),
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Subscribes to changes of keys starting with `prefix`.
+    ///
+    /// The connection is dedicated to the subscription afterwards, so this consumes the client.
+    pub fn subscribe(mut self, prefix: String) -> Result<Subscription> {
+        let result: SubscribeResponse = self.round_trip(Request::Subscribe { prefix })?;
+        match result {
+            SubscribeResponse::Ok(_) => Ok(Subscription {
+                connection: self.connection.take().expect("connection is open after a round trip"),
+            }),
+            SubscribeResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+}
+
+/// A stream of change events from the server.
+pub struct Subscription {
+    connection: Connection,
+}
+
+impl Subscription {
+    /// Waits for the next change event.
+    ///
+    /// Returns `Ok(None)` when the server sent a heartbeat instead, i.e. nothing changed for a
+    /// while; this gives callers a chance to stop watching without an event arriving.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConnectionBroken` if the connection dropped, or
+    /// `KvsError::SubscriberLagged` if the server gave up on a subscriber that fell too far
+    /// behind. The subscription is unusable afterwards; subscribe again on a new client.
+    pub fn next_event(&mut self) -> Result<Option<ChangeEvent>> {
+        match self.connection.recv() {
+            Ok(SubscriptionFrame::Change(event)) => Ok(Some(event)),
+            Ok(SubscriptionFrame::Heartbeat) => Ok(None),
+            Ok(SubscriptionFrame::Lagged) => Err(KvsError::SubscriberLagged),
+            Err(KvsError::IoError(e)) => Err(KvsError::ConnectionBroken(e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Ends the subscription and closes the connection.
+    pub fn close(self) {
+        self.connection.shutdown();
+    }
+}
@@ -1,10 +1,62 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::engines::{GetPlan, ScanEntry, SetCondition};
+use crate::stats::Stats;
 
+/// A request on the binary protocol, as seen by a server's validator, see
+/// `KvsServer::validator`.
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(missing_docs)]
 pub enum Request {
     Get { key: String },
+    GetAtLeast { key: String, min_sequence: u64 },
     Set { key: String, value: String },
     Remove { key: String },
+    ValueSize { key: String },
+    ExistsMany { keys: Vec<String> },
+    Stats,
+    StatsReset,
+    Subscribe { prefix: String },
+    ConditionalSet { key: String, value: String, condition: SetCondition },
+    SetMany { pairs: Vec<(String, String)> },
+    Export,
+    Import { pairs: Vec<(String, String)> },
+    Checkpoint,
+    Ping,
+    Explain { key: String },
+    Undelete { key: String },
+    Scan { prefix: String },
+    ScanVerbose { prefix: String },
+    RandomKey,
+    LatestSequence,
+}
+
+impl Request {
+    /// Whether the request changes the store's contents.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::ConditionalSet { .. }
+                | Request::SetMany { .. }
+                | Request::Remove { .. }
+                | Request::Undelete { .. }
+                | Request::Import { .. }
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Ok(()),
+    Err(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,12 +67,186 @@ pub enum GetResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
-    Ok(()),
+    /// The sequence number of the write, if the engine numbers its writes
+    Ok(Option<u64>),
     Err(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
+    /// The sequence number of the write, if the engine numbers its writes
+    Ok(Option<u64>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ValueSizeResponse {
+    Ok(Option<u64>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExistsManyResponse {
+    Ok(Vec<bool>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(Stats),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubscribeResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ConditionalSetResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetManyResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// Frames answering an `Export` request: any number of chunks, then `Done` or `Err`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExportFrame {
+    Chunk(Vec<(String, String)>),
+    Done,
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ImportResponse {
     Ok(()),
     Err(String),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CheckpointResponse {
+    Ok(u64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExplainResponse {
+    Ok(Option<GetPlan>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum UndeleteResponse {
+    Ok(bool),
+    Err(String),
+}
+
+/// Frames answering a `Scan` request: any number of chunks, then `Done` or `Err`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanFrame {
+    Chunk(Vec<(String, String)>),
+    Done,
+    Err(String),
+}
+
+/// Frames answering a `ScanVerbose` request, like `ScanFrame`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanVerboseFrame {
+    Chunk(Vec<ScanEntry>),
+    Done,
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RandomKeyResponse {
+    Ok(Option<String>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SequenceResponse {
+    Ok(Option<u64>),
+    Err(String),
+}
+
+/// A response answering a request with either its result or an error message.
+pub(crate) trait Response: Serialize {
+    /// The error message, if the request failed.
+    fn error(&self) -> Option<&str>;
+}
+
+macro_rules! impl_response {
+    ($($response:ty),* $(,)?) => {
+        $(impl Response for $response {
+            fn error(&self) -> Option<&str> {
+                match self {
+                    Self::Err(message) => Some(message),
+                    _ => None,
+                }
+            }
+        })*
+    };
+}
+
+impl_response!(
+    PingResponse,
+    GetResponse,
+    SetResponse,
+    RemoveResponse,
+    ValueSizeResponse,
+    ExistsManyResponse,
+    StatsResponse,
+    SubscribeResponse,
+    ConditionalSetResponse,
+    SetManyResponse,
+    ExportFrame,
+    ImportResponse,
+    CheckpointResponse,
+    ExplainResponse,
+    UndeleteResponse,
+    ScanFrame,
+    ScanVerboseFrame,
+    RandomKeyResponse,
+    SequenceResponse,
+);
+
+/// Turns TCP keep-alive on `stream` on or off.
+///
+/// With `Some(idle)`, once the connection has been idle for `idle` the OS probes the peer
+/// every `idle`, and resets the connection if the peer stops answering. This catches peers
+/// that vanished without closing the connection, e.g. behind a NAT that dropped it.
+pub(crate) fn set_keepalive(stream: &TcpStream, idle: Option<Duration>) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    match idle {
+        Some(idle) => {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(idle))
+        }
+        None => socket.set_keepalive(false),
+    }
+}
+
+/// Random numbers from a randomly keyed hasher, a cheap source of randomness without another
+/// dependency. Each input hashes to its own number, so callers draw with a counter.
+pub(crate) struct Random(RandomState);
+
+impl Random {
+    pub(crate) fn new() -> Random {
+        Random(RandomState::new())
+    }
+
+    /// Returns the random number drawn for `input`.
+    pub(crate) fn u64(&self, input: impl Hash) -> u64 {
+        self.0.hash_one(input)
+    }
+
+    /// Returns the random number drawn for `input`, scaled to a fraction from 0 to 1.
+    pub(crate) fn fraction(&self, input: impl Hash) -> f64 {
+        self.u64(input) as f64 / u64::MAX as f64
+    }
+}
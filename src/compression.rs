@@ -0,0 +1,60 @@
+use std::io::{Read, Write};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::{KvsError, Result, DEFAULT_MAX_REQUEST_SIZE};
+
+/// Prefix of the values a `KvsClient` with compression on stores compressed: the value's
+/// deflate stream follows in base64.
+///
+/// A client without compression reads such values as they are stored, marker included.
+pub const COMPRESSED_VALUE_MARKER: &str = "\u{1}kvs-deflate:";
+
+/// Longest value a compressed one may inflate to, so that a small stored value can't make a
+/// reader allocate without bound. Longer values are stored uncompressed.
+const MAX_DECOMPRESSED_LEN: usize = DEFAULT_MAX_REQUEST_SIZE;
+
+/// Returns what to store for `value`: its compressed form if that is shorter, `value`
+/// otherwise.
+///
+/// A value that starts with the marker itself is always compressed, so that it doesn't read
+/// back as a compressed one.
+pub(crate) fn compress(value: String) -> String {
+    if value.len() > MAX_DECOMPRESSED_LEN && !value.starts_with(COMPRESSED_VALUE_MARKER) {
+        return value;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    // Writes to a `Vec` don't fail
+    encoder.write_all(value.as_bytes()).unwrap();
+    let deflated = encoder.finish().unwrap();
+    let compressed = format!("{}{}", COMPRESSED_VALUE_MARKER, STANDARD.encode(deflated));
+    if compressed.len() < value.len() || value.starts_with(COMPRESSED_VALUE_MARKER) {
+        compressed
+    } else {
+        value
+    }
+}
+
+/// Returns the value `stored` was compressed from, or `stored` itself if it isn't compressed.
+pub(crate) fn decompress(stored: String) -> Result<String> {
+    let Some(encoded) = stored.strip_prefix(COMPRESSED_VALUE_MARKER) else {
+        return Ok(stored);
+    };
+    let invalid = |e: &dyn std::fmt::Display| {
+        KvsError::StringError(format!("invalid compressed value: {}", e))
+    };
+    let deflated = STANDARD.decode(encoded).map_err(|e| invalid(&e))?;
+    let mut value = String::new();
+    DeflateDecoder::new(&deflated[..])
+        .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+        .read_to_string(&mut value)
+        .map_err(|e| invalid(&e))?;
+    if value.len() > MAX_DECOMPRESSED_LEN {
+        return Err(invalid(&format!("inflates to over {} bytes", MAX_DECOMPRESSED_LEN)));
+    }
+    Ok(value)
+}
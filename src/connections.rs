@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Counts the server's open connections per client IP and caps them.
+///
+/// It also keeps a handle to every open connection, so they can be wound down on shutdown.
+pub(crate) struct ConnectionLimiter {
+    // `None` for no cap
+    max_per_ip: Option<usize>,
+    open: Mutex<HashMap<IpAddr, usize>>,
+
+    // Handles to the open connections by slot id, and a signal for when one closes
+    streams: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+    next_id: AtomicU64,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(max_per_ip: Option<usize>) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip,
+            open: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            closed: Condvar::new(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a new connection from `ip`, or returns `None` if `ip` already has the
+    /// maximum number of connections open.
+    ///
+    /// The connection counts as open until the returned slot is dropped.
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        stream: &TcpStream,
+    ) -> io::Result<Option<ConnectionSlot>> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|max| count >= max) {
+            return Ok(None);
+        }
+        let stream = stream.try_clone()?;
+        open.insert(ip, count + 1);
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams.lock().unwrap().insert(id, stream);
+        Ok(Some(ConnectionSlot {
+            limiter: Arc::clone(self),
+            ip,
+            id,
+        }))
+    }
+
+    /// Stops reading from every open connection.
+    ///
+    /// A connection waiting for its next request sees the client disconnect; one in the middle
+    /// of a request still sends its response.
+    pub(crate) fn close_reads(&self) {
+        for stream in self.streams.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Waits up to `timeout` for every open connection to close. Returns whether they did.
+    pub(crate) fn wait_closed(&self, timeout: Duration) -> bool {
+        let streams = self.streams.lock().unwrap();
+        let (streams, _) = self
+            .closed
+            .wait_timeout_while(streams, timeout, |streams| !streams.is_empty())
+            .unwrap();
+        streams.is_empty()
+    }
+}
+
+/// One open connection counted by a `ConnectionLimiter`.
+pub(crate) struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+    id: u64,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+        drop(open);
+
+        self.limiter.streams.lock().unwrap().remove(&self.id);
+        self.limiter.closed.notify_all();
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::thread;
+
+/// A cached value together with the location of the record it was decoded from.
+struct CachedValue {
+    geneeration: u64,
+    pos: u64,
+    value: String,
+}
+
+/// Sharded cache of decoded values for hot keys.
+///
+/// Each key hashes to one shard, so threads reading different keys rarely contend on the
+/// same lock. An entry is only served if it was decoded from the record the index currently
+/// points to: a value cached by a reader that raced with a write is never returned once the
+/// write is in the index, even if the reader inserts it after the write invalidated the key.
+pub(crate) struct ReadCache {
+    shards: Vec<Mutex<HashMap<String, CachedValue>>>,
+    shard_capacity: usize,
+}
+
+impl ReadCache {
+    /// Creates a cache holding about `capacity` values, with one shard per logical CPU.
+    pub(crate) fn new(capacity: usize) -> ReadCache {
+        let shard_count = thread::available_parallelism().map_or(1, |n| n.get());
+        ReadCache {
+            shards: (0..shard_count).map(|_| Mutex::new(HashMap::new())).collect(),
+            shard_capacity: capacity.div_ceil(shard_count).max(1),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, CachedValue>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Returns the cached value of `key` if it was read from the record at `geneeration`/`pos`.
+    pub(crate) fn get(&self, key: &str, geneeration: u64, pos: u64) -> Option<String> {
+        let shard = self.shard(key).lock().unwrap();
+        shard
+            .get(key)
+            .filter(|cached| cached.geneeration == geneeration && cached.pos == pos)
+            .map(|cached| cached.value.clone())
+    }
+
+    /// Whether `get` would return a value for `key` read from the record at `geneeration`/`pos`.
+    pub(crate) fn contains(&self, key: &str, geneeration: u64, pos: u64) -> bool {
+        let shard = self.shard(key).lock().unwrap();
+        shard
+            .get(key)
+            .is_some_and(|cached| cached.geneeration == geneeration && cached.pos == pos)
+    }
+
+    /// Caches the value of `key` read from the record at `geneeration`/`pos`.
+    pub(crate) fn insert(&self, key: String, geneeration: u64, pos: u64, value: String) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        if shard.len() >= self.shard_capacity && !shard.contains_key(&key) {
+            // Evict an arbitrary entry; hot keys are re-cached on their next read
+            if let Some(evicted) = shard.keys().next().cloned() {
+                shard.remove(&evicted);
+            }
+        }
+        shard.insert(key, CachedValue { geneeration, pos, value });
+    }
+
+    /// Drops the cached value of `key` after it was overwritten or removed.
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+}
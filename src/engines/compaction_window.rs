@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::clock::{Clock, SystemClock};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A daily time window, in UTC, during which a `KvStore` may compact automatically.
+///
+/// Outside the window the store lets stale data accumulate instead of compacting inline
+/// on a write, until it exceeds the hard cap. A background thread compacts once the window
+/// opens.
+#[derive(Clone)]
+pub struct CompactionWindow {
+    start: Duration,
+    end: Duration,
+    hard_cap: u64,
+    check_interval: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl CompactionWindow {
+    /// Creates a window from `start` to `end`, both as time since midnight UTC.
+    ///
+    /// A window whose end is before its start wraps around midnight. Outside the window,
+    /// compaction still runs once more than `hard_cap` bytes are stale.
+    pub fn new(start: Duration, end: Duration, hard_cap: u64) -> CompactionWindow {
+        CompactionWindow {
+            start: duration_of_day(start),
+            end: duration_of_day(end),
+            hard_cap,
+            check_interval: Duration::from_secs(60),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Uses `clock` instead of the system clock to tell the time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> CompactionWindow {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how often the background thread checks for deferred compaction (default 60s).
+    pub fn with_check_interval(mut self, check_interval: Duration) -> CompactionWindow {
+        self.check_interval = check_interval;
+        self
+    }
+
+    pub(crate) fn hard_cap(&self) -> u64 {
+        self.hard_cap
+    }
+
+    pub(crate) fn check_interval(&self) -> Duration {
+        self.check_interval
+    }
+
+    /// Whether the clock's current time of day falls in the window.
+    pub(crate) fn is_open(&self) -> bool {
+        let since_epoch = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let now = duration_of_day(since_epoch);
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            now >= self.start || now < self.end
+        }
+    }
+}
+
+fn duration_of_day(time: Duration) -> Duration {
+    Duration::from_secs(time.as_secs() % DAY.as_secs())
+}
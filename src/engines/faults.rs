@@ -0,0 +1,95 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Faults to inject into a store's log files, to test how it handles failing I/O; see
+/// `KvStore::inject_faults`. Only built with the `fault-injection` feature.
+///
+/// Each fault fires once, on the `n`th operation of its kind counted from the injection:
+///
+/// - a write hands half its bytes to the file before failing, like a torn write
+/// - a flush fails after the bytes were handed over; syncing to disk counts as a flush
+/// - a file operation fails before it is done: opening a log file, or truncating one
+///
+/// Failures are `io::ErrorKind::Other` errors unless `fail_with` picks another kind.
+/// `FaultConfig::default()` injects no faults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultConfig {
+    write: Option<u64>,
+    flush: Option<u64>,
+    file_op: Option<u64>,
+    kind: Option<io::ErrorKind>,
+}
+
+impl FaultConfig {
+    /// Makes the `n`th write to a log file fail, counting from 1.
+    pub fn fail_nth_write(mut self, n: u64) -> Self {
+        self.write = Some(n.max(1));
+        self
+    }
+
+    /// Makes the `n`th flush or sync of a log file fail, counting from 1.
+    pub fn fail_nth_flush(mut self, n: u64) -> Self {
+        self.flush = Some(n.max(1));
+        self
+    }
+
+    /// Makes the `n`th file operation on a log file fail, counting from 1.
+    pub fn fail_nth_file_op(mut self, n: u64) -> Self {
+        self.file_op = Some(n.max(1));
+        self
+    }
+
+    /// Makes the failing flushes and file operations fail with errors of `kind`, e.g.
+    /// `io::ErrorKind::ReadOnlyFilesystem` to stand in for a read-only mount.
+    pub fn fail_with(mut self, kind: io::ErrorKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+}
+
+/// The operations done so far under a `FaultConfig`, shared by the log files it is injected
+/// into.
+#[derive(Debug, Default)]
+pub(crate) struct Faults {
+    config: FaultConfig,
+    writes: AtomicU64,
+    flushes: AtomicU64,
+    file_ops: AtomicU64,
+}
+
+impl Faults {
+    pub(crate) fn new(config: FaultConfig) -> Faults {
+        Faults {
+            config,
+            ..Faults::default()
+        }
+    }
+
+    /// Counts a write; true if it is the one to fail.
+    pub(crate) fn write_fails(&self) -> bool {
+        Self::fires(self.config.write, &self.writes)
+    }
+
+    /// Counts a flush and fails if it is the one to fail.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.fail_if(self.config.flush, &self.flushes, "flush")
+    }
+
+    /// Counts a file operation and fails if it is the one to fail.
+    pub(crate) fn file_op(&self) -> io::Result<()> {
+        self.fail_if(self.config.file_op, &self.file_ops, "file operation")
+    }
+
+    fn fires(nth: Option<u64>, done: &AtomicU64) -> bool {
+        let count = done.fetch_add(1, Ordering::SeqCst) + 1;
+        nth == Some(count)
+    }
+
+    fn fail_if(&self, nth: Option<u64>, done: &AtomicU64, operation: &str) -> io::Result<()> {
+        if Self::fires(nth, done) {
+            let kind = self.config.kind.unwrap_or(io::ErrorKind::Other);
+            return Err(io::Error::new(kind, format!("injected {} failure", operation)));
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,81 @@
 use std::cell::RefCell;
 use std::cmp::max;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{btree_map, hash_map};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
 use std::path::{Path, PathBuf};
 
-use super::KvsEngine;
-use crate::kvs_command::{kvs_command, KvsCommand, KvsRemove, KvsSet};
+use super::cache::ReadCache;
+use super::compaction_window::CompactionWindow;
+#[cfg(feature = "fault-injection")]
+use super::faults::{FaultConfig, Faults};
+use super::log_file::LogFile;
+use super::mapped::{MappedLogs, Mapping};
+use super::reader_pool::ReaderPool;
+use super::{choose, GetPlan, KvsEngine, KvsEngineExt, ScanEntry, SetCondition};
+use crate::kvs_command::{kvs_command, KvsCommand, KvsRemove, KvsSequenceMark, KvsSet};
 use crate::{KvsError, Result};
 use crc32fast::Hasher;
+use log::{debug, error, warn};
 use prost::Message;
-use skiplist::SkipMap;
-use std::ffi::OsStr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use rayon::prelude::*;
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
-const CURRENT_SCHEMA_VERSION: u64 = 1;
+const CURRENT_SCHEMA_VERSION: u64 = 3;
+const COMPACTION_MARKER: &str = "compaction.marker";
+
+/// How many times `open_read_only` retries when the files change under it.
+const SNAPSHOT_ATTEMPTS: u32 = 10;
+
+/// Saved index of a store with index hints, read back by the next open.
+const INDEX_HINT: &str = "index.hint";
+
+/// Starts a sealed log file's header. Read as the length prefix of a record it would be over
+/// 1GB, which no record reaches, so sealed files can't be mistaken for plain ones.
+pub(super) const SEALED_MAGIC: [u8; 4] = [0xff, b'K', b'V', b'S'];
+
+/// Header flag: the file ends with a footer checksumming all of its records.
+const HEADER_FLAG_FOOTER: u32 = 1;
+
+/// Header: `SEALED_MAGIC`, then the flags as a u32 (little endian).
+const HEADER_LEN: u64 = 8;
+
+/// Starts the footer of a sealed log file.
+const FOOTER_MAGIC: [u8; 4] = *b"KVSF";
+
+/// Footer: `FOOTER_MAGIC`, then the length of the records as a u64 and their CRC32 as a u32
+/// (little endian).
+const FOOTER_LEN: u64 = 16;
+
+/// Largest read buffer kept around for reuse; larger ones are freed after the read.
+const MAX_SCRATCH_CAPACITY: usize = 64 * 1024;
+
+/// Approximate memory an index entry takes besides its key's buffer: the key's `String`, the
+/// position slot, and the skip list node's links and bookkeeping.
+const INDEX_ENTRY_OVERHEAD: usize =
+    size_of::<String>() + size_of::<RwLock<CommandPos>>() + 4 * size_of::<usize>();
+
+/// Share of the live keys a `remove_many` has to remove to compact right away, by default.
+const REMOVE_MANY_COMPACTION_RATIO: f64 = 0.5;
+
+/// How often a write with a deadline retries taking the writer lock.
+const WRITE_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How many bytes of records a parallel compaction reads before writing them out.
+const PARALLEL_COMPACTION_BATCH: u64 = 16 * 1024 * 1024;
+
+/// Buffer size of a log reader or writer when `KvStore::open` isn't given one, and of the
+/// readers of tools like `open_read_only` and `verify`.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
 
 /// For example, this sequence:
 /// store.set("key1", "value1")
@@ -42,16 +99,45 @@ const CURRENT_SCHEMA_VERSION: u64 = 1;
 /// Deleting the old log files
 ///
 /// This is why it's called "log-structured" - all operations are simply appended to a log, and compaction handles cleanup of old/stale data.
-
+///
+/// Ordering guarantees:
+///
+/// Writes are totally ordered by their sequence number, which is assigned while holding the
+/// writer lock. A write is committed once it is in the log and the index; only then is its
+/// sequence published through `latest_sequence`. So once `set`/`remove` returns, or once
+/// `latest_sequence` reports a write's sequence, every `get` on any clone reflects that write
+/// (or a later one).
 #[derive(Clone)]
 pub struct KvStore {
-    // Directory path for the log and other data files
-    // Shared between reader and writer components
-    path: Arc<PathBuf>,
+    // Sequence number of the latest committed write, shared by all clones
+    latest_sequence: Arc<AtomicU64>,
 
     // In-memory index mapping keys to their positions in log files
     // Using SkipMap for lock-free concurrent reads
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+
+    // Reader component for handling all read operations
+    reader: KvStoreReader,
+
+    // Writer component for handling all write operations
+    // Protected by Mutex to ensure exclusive access for writes
+    writer: Arc<Mutex<KvStoreWriter>>,
+
+    // Decoded values of recently read keys, if enabled with `with_read_cache`
+    cache: Option<Arc<ReadCache>>,
+
+    // Whether writes may sit in the writer's buffer, see `with_buffered_writes`
+    buffered_writes: bool,
+
+    // Held for reading by every read, and for writing while `replace_with` swaps the index,
+    // so a read sees either the old or the new dataset in full
+    swap_gate: Arc<RwLock<()>>,
+
+    // How long a write may take, see `with_write_deadline`
+    write_deadline: Option<Duration>,
+
+    // Whether reads skip keys whose record isn't a set, see `with_recovery_mode`
+    recovery_mode: bool,
 }
 
 /// Manages readonly access to the store.
@@ -75,6 +161,9 @@ pub struct KvStore {
 /// AtomicU64 Thread-safe integer that can be updated atomically Operations don't require locks
 /// Used for safe_point to track generation numbers across threads Enables wait-free coordination between readers and writer
 struct KvStoreReader {
+    // Directory path for the log files
+    path: Arc<PathBuf>,
+
     // Buffer size for file readers
     reader_buffer_size: usize,
 
@@ -82,16 +171,159 @@ struct KvStoreReader {
     // Uses RefCell for interior mutability without thread-safety overhead
     readers: RefCell<HashMap<u64, BufReaderWithPos<File>>>,
 
+    // File readers shared by all clones instead, if enabled with `with_shared_readers`
+    shared: Option<Arc<ReaderPool<BufReaderWithPos<File>>>>,
+
+    // Sealed generations mapped into memory, if enabled with `with_mmap_reads`
+    mapped: Option<Arc<MappedLogs>>,
+
     // Atomic generation number indicating the oldest generation that's safe to read
     // Updated during compaction to prevent readers from accessing compacted files
     safe_point: Arc<AtomicU64>,
 
-    // Reader component for handling all read operations
-    reader: KvStoreReader,
+    // Reused for the bytes of each record read, instead of allocating per read
+    scratch: RefCell<Vec<u8>>,
+}
 
-    // Writer component for handling all write operations
-    // Protected by Mutex to ensure exclusive access for writes
-    writer: Arc<Mutex<KvStoreWriter>>,
+impl KvStoreReader {
+    /// Closes file handles of generations older than the safe point.
+    ///
+    /// Those generations were compacted away and their files may already be removed.
+    fn close_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.readers
+            .borrow_mut()
+            .retain(|&generation, _| generation >= safe_point);
+        if let Some(shared) = &self.shared {
+            shared.close_before(safe_point);
+        }
+        if let Some(mapped) = &self.mapped {
+            mapped.close_before(safe_point);
+        }
+    }
+
+    /// Opens a reader for the log file of `generation`.
+    fn open_reader(&self, generation: u64) -> Result<BufReaderWithPos<File>> {
+        BufReaderWithPos::new(
+            File::open(log_path(&self.path, generation))?,
+            self.reader_buffer_size,
+        )
+    }
+
+    /// Reads the raw record bytes located at the given command position and passes them to
+    /// `decode`.
+    ///
+    /// The bytes live in the reader's scratch buffer, or in the mapping of a sealed generation,
+    /// so they are only valid within `decode`. Opens a reader for the generation lazily if
+    /// this thread doesn't hold one yet, or borrows one from the shared pool if there is one.
+    fn read_record<T>(
+        &self,
+        cmd_pos: &CommandPos,
+        decode: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let generation = cmd_pos.geneeration;
+        if let Some(mapped) = &self.mapped
+            && let Some(mapping) =
+                mapped.get(&log_path(&self.path, generation), generation, &self.safe_point)?
+        {
+            return read_mapped(&mapping, cmd_pos, decode);
+        }
+
+        if let Some(shared) = &self.shared {
+            let mut reader = match shared.take(cmd_pos.geneeration) {
+                Some(reader) => reader,
+                None => self.open_reader(cmd_pos.geneeration)?,
+            };
+            let result = self.read_record_with(&mut reader, cmd_pos, decode);
+            // A reader that failed part way may be left anywhere; it seeks before every read
+            shared.put(cmd_pos.geneeration, reader, &self.safe_point);
+            return result;
+        }
+
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(cmd_pos.geneeration) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(self.open_reader(cmd_pos.geneeration)?)
+            }
+        };
+        self.read_record_with(reader, cmd_pos, decode)
+    }
+
+    /// Reads the record at `cmd_pos` with `reader`, an open reader of its generation.
+    fn read_record_with<T>(
+        &self,
+        reader: &mut BufReaderWithPos<File>,
+        cmd_pos: &CommandPos,
+        decode: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+
+        // Prefix
+        let mut len_bytes = [0u8; 4];
+        reader.read_exact(&mut len_bytes)?;
+        let msg_len = u32::from_le_bytes(len_bytes) as usize;
+
+        // Read message
+        let mut scratch = self.scratch.borrow_mut();
+        read_message(reader, msg_len, &mut scratch)?;
+        let result = decode(&scratch);
+        // Don't hold on to the memory of an exceptionally large record
+        if scratch.capacity() > MAX_SCRATCH_CAPACITY {
+            *scratch = Vec::new();
+        }
+        result
+    }
+
+    /// Reads and verifies the set command at the given position, returning its value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the record isn't a set command.
+    fn read_value(&self, cmd_pos: &CommandPos) -> Result<Option<String>> {
+        let cmd = self.read_command(cmd_pos)?;
+        if let Some(command) = cmd.command {
+            if let kvs_command::Command::Set(set) = command {
+                Ok(Some(set.value))
+            } else {
+                Err(KvsError::UnexpectedCommandType)
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads and checks the record at `cmd_pos`, bringing it up to the current version.
+    fn read_command(&self, cmd_pos: &CommandPos) -> Result<KvsCommand> {
+        let cmd = self.read_record(cmd_pos, |msg_bytes| Ok(KvsCommand::decode(msg_bytes)?))?;
+        if !cmd.verify_checksum() {
+            return Err(KvsError::CorruptedData);
+        }
+        upgrade_command(cmd)
+    }
+
+    /// Whether the record at `cmd_pos` belongs to a generation that was compacted away.
+    fn is_compacted(&self, cmd_pos: &CommandPos) -> bool {
+        cmd_pos.geneeration < self.safe_point.load(Ordering::SeqCst)
+    }
+}
+
+impl Clone for KvStoreReader {
+    /// Each clone gets its own (initially empty) set of file readers,
+    /// so clones can be moved to other threads. The shared pool, if any, is shared.
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            reader_buffer_size: self.reader_buffer_size,
+            readers: RefCell::new(HashMap::new()),
+            shared: self.shared.clone(),
+            mapped: self.mapped.clone(),
+            safe_point: Arc::clone(&self.safe_point),
+            scratch: RefCell::new(Vec::new()),
+        }
+    }
 }
 
 /// Manages write operations to the store.
@@ -100,7 +332,7 @@ struct KvStoreWriter {
     writer_buffer_size: usize,
 
     // Current log file write with position tracking
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<LogFile>,
 
     // current generation for log being written
     current_generation: u64,
@@ -111,417 +343,3040 @@ struct KvStoreWriter {
     // Optional sequence number for transactions or entries
     current_sequence: Option<u64>,
 
+    // Published copy of `current_sequence`, updated once a write is committed
+    latest_sequence: Arc<AtomicU64>,
+
     // KvStore Reader
     reader: KvStoreReader,
 
     // In-memory index mapping keys to their positions in log files
     // Using SkipMap for lock-free concurrent reads
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
 
     path: Arc<PathBuf>,
+
+    // When set, automatic compaction is deferred to this window
+    compaction_window: Option<CompactionWindow>,
+
+    // Index memory estimate above which a warning is logged, and whether it is above it now
+    index_memory_warning: Option<usize>,
+    index_memory_warned: bool,
+
+    // Whether generations written by compaction get a header and a checksum footer
+    file_checksums: bool,
+
+    // Whether each write is flushed to the OS before it returns
+    flush_writes: bool,
+
+    // Whether new log files are written with direct I/O, see `with_direct_writes`
+    direct_writes: bool,
+
+    // Faults injected into the log files it opens, see `inject_faults`
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<Faults>>,
+
+    // Share of the live keys a `remove_many` has to remove to compact right away
+    remove_many_compaction_ratio: f64,
+
+    // Share of the log that has to be stale for writes to compact, on top of
+    // `COMPACTION_THRESHOLD`, see `with_adaptive_compaction`
+    stale_ratio: Option<f64>,
+
+    // Threads reading records during compaction; 1 reads them sequentially
+    compaction_threads: usize,
+
+    // How long removed values stay restorable, if removes are soft deletes
+    soft_delete_retention: Option<Duration>,
+
+    // Soft-deleted keys, kept apart from the index of live ones; some may be past retention
+    deleted: BTreeMap<String, Deleted>,
+
+    // How many versions of each key compaction keeps, see `with_version_history`
+    kept_versions: usize,
+
+    // Records of the versions of live keys before their latest, newest first
+    history: HashMap<String, VecDeque<CommandPos>>,
+
+    // Whether the index is saved for the next open when the last clone of the store drops
+    index_hints: bool,
+
+    // Deadline of the write holding the lock, past which it skips the compaction it triggers
+    deadline: Option<Instant>,
+
+    // Cap on the bytes of the log files that sets may take them to, and what a set that
+    // would exceed it does
+    disk_quota: Option<u64>,
+    quota_policy: QuotaPolicy,
+
+    // Bytes in the log files other than the one being written
+    closed_log_bytes: u64,
 }
 
 impl KvStoreWriter {
     /// Sets the value of a string key to a string.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// If the key already exists, the previous value will be overwritten. The key expires
+    /// at `expires_at` (milliseconds since the Unix epoch), or never if it is 0. Returns the
+    /// sequence number of the write.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, key: String, value: String, expires_at: u64) -> Result<u64> {
         let sequence = self.current_sequence.unwrap_or(0) + 1;
+        let cmd = KvsCommand::set(key, value, expires_at, sequence);
+        let cmd_bytes = cmd.encode_to_vec();
+        self.check_disk_quota(4 + cmd_bytes.len() as u64)?;
+
+        let pos = self.append_record(&cmd_bytes)?;
         self.current_sequence = Some(sequence);
 
-        let cmd = KvsCommand::set(key, value, sequence);
-        let pos = self.writer.pos;
+        // Update index and track uncompacted bytes
+        if let Some(kvs_command::Command::Set(set)) = cmd.command {
+            let cmd_pos = CommandPos {
+                geneeration: self.current_generation,
+                pos,
+                len: self.writer.pos - pos,
+                value_len: set.value.len() as u64,
+                expires_at: set.expires_at,
+            };
+            self.forget_deleted(&set.key);
+            if let Some(old_cmd) = self.index.insert(set.key.clone(), cmd_pos) {
+                self.retire_version(set.key, old_cmd);
+            }
+        }
+        self.latest_sequence.store(sequence, Ordering::SeqCst);
+        self.check_index_memory();
 
-        let cmd_bytes = cmd.encode_to_vec();
+        self.compact_if_due()?;
 
-        // Write length prefix (4 bytes, little endian)
-        self.writer
-            .write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+        Ok(sequence)
+    }
 
-        // Write actual message
-        self.writer.write_all(&cmd_bytes)?;
-        self.writer.flush()?;
+    /// Sets several keys as one unit, with a single flush.
+    ///
+    /// The index is only updated once every record of the batch is in the log, so readers see
+    /// either none or all of the pairs. If writing fails, the log is truncated back to where
+    /// the batch started.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let records = self.encode_sets(pairs);
+        self.check_disk_quota(records.iter().map(|(_, _, bytes)| 4 + bytes.len() as u64).sum())?;
+
+        let batch_start = self.writer.pos;
+        let last_sequence = self.current_sequence;
+
+        let positions = match self.write_batch(records) {
+            Ok(positions) => positions,
+            Err(e) => {
+                self.current_sequence = last_sequence;
+                self.discard_since(batch_start)?;
+                return Err(e);
+            }
+        };
 
-        // Update index and track uncompacted bytes
-        if let Some(kvs_command::Command::Set(set)) = cmd.command {
-            if let Some(old_cmd) = self.index.insert(
-                set.key,
-                CommandPos {
-                    geneeration: self.current_generation,
-                    pos,
-                    len: self.writer.pos - pos,
-                },
-            ) {
-                self.uncompacted += old_cmd.len;
+        for (key, cmd_pos) in positions {
+            self.forget_deleted(&key);
+            if let Some(old_cmd) = self.index.insert(key.clone(), cmd_pos) {
+                self.retire_version(key, old_cmd);
             }
         }
+        if let Some(sequence) = self.current_sequence {
+            self.latest_sequence.store(sequence, Ordering::SeqCst);
+        }
+        self.check_index_memory();
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        self.compact_if_due()?;
+
+        Ok(())
+    }
+
+    /// Encodes a set record for every pair, numbered from the next sequence number on.
+    ///
+    /// Returns each key with the length of its value and its encoded record.
+    fn encode_sets(&self, pairs: Vec<(String, String)>) -> Vec<(String, u64, Vec<u8>)> {
+        let first_sequence = self.current_sequence.unwrap_or(0) + 1;
+        pairs
+            .into_iter()
+            .zip(first_sequence..)
+            .map(|((key, value), sequence)| {
+                let value_len = value.len() as u64;
+                let cmd_bytes = KvsCommand::set(key.clone(), value, 0, sequence).encode_to_vec();
+                (key, value_len, cmd_bytes)
+            })
+            .collect()
+    }
+
+    /// Appends the records from `encode_sets` and flushes them together.
+    ///
+    /// Returns each key with the location of its record.
+    fn write_batch(
+        &mut self,
+        records: Vec<(String, u64, Vec<u8>)>,
+    ) -> Result<Vec<(String, CommandPos)>> {
+        let mut positions = Vec::with_capacity(records.len());
+        for (key, value_len, cmd_bytes) in records {
+            let sequence = self.current_sequence.unwrap_or(0) + 1;
+            self.current_sequence = Some(sequence);
+
+            let pos = self.writer.pos;
+            self.writer
+                .write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&cmd_bytes)?;
+            let cmd_pos = CommandPos {
+                geneeration: self.current_generation,
+                pos,
+                len: self.writer.pos - pos,
+                value_len,
+                expires_at: 0,
+            };
+            positions.push((key, cmd_pos));
+        }
+        self.flush_write()?;
+        Ok(positions)
+    }
+
+    /// Appends a record with its length prefix and hands it to the OS, returning where it
+    /// starts.
+    ///
+    /// If that fails, the log is cut back to where the record started. Otherwise the part of
+    /// the record still in the buffer would go out with the next write, behind the back of
+    /// the index and after its caller was told it failed.
+    fn append_record(&mut self, cmd_bytes: &[u8]) -> Result<u64> {
+        let pos = self.writer.pos;
+        let result = self
+            .writer
+            .write_all(&(cmd_bytes.len() as u32).to_le_bytes())
+            .and_then(|()| self.writer.write_all(cmd_bytes))
+            .and_then(|()| self.flush_write());
+        if let Err(e) = result {
+            self.discard_since(pos)?;
+            return Err(e.into());
         }
+        Ok(pos)
+    }
 
+    /// Hands a completed write to the OS, unless writes are left in the buffer.
+    fn flush_write(&mut self) -> io::Result<()> {
+        if self.flush_writes {
+            self.writer.flush()?;
+        }
         Ok(())
     }
 
-    /// Removes a given key.
+    /// Drops everything written to the current log from `pos` on, including buffered bytes.
+    ///
+    /// Buffered bytes before `pos`, i.e. earlier writes left in the buffer by
+    /// `with_buffered_writes`, are kept.
+    fn discard_since(&mut self, pos: u64) -> Result<()> {
+        let file = self.open_log(self.current_generation)?;
+        let committed = self.writer.committed();
+        let failed = std::mem::replace(
+            &mut self.writer,
+            BufWriterWithPos::new(file, self.writer_buffer_size)?,
+        );
+        // Take the failed writer's buffer rather than dropping it, which would try to flush it
+        let (_, buffer) = failed.writer.into_parts();
+        let buffer = buffer.unwrap_or_else(|panicked| panicked.into_inner());
+
+        self.writer.writer.get_mut().set_len(pos.min(committed))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        if pos > committed {
+            self.writer.write_all(&buffer[..(pos - committed) as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Removes a given key, returning the sequence number of the write.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&mut self, key: String) -> Result<u64> {
         if self.index.contains_key(&key) {
             let sequence = self.current_sequence.unwrap_or(0) + 1;
-            self.current_sequence = Some(sequence);
-
-            let cmd = KvsCommand::remove(key, sequence);
+            let deleted_at = self.deleted_at();
+            let cmd = KvsCommand::remove(key, deleted_at, sequence);
 
             let cmd_bytes = cmd.encode_to_vec();
+            self.append_record(&cmd_bytes)?;
+            self.current_sequence = Some(sequence);
 
-            // Write length prefix (4 bytes, little endian)
-            self.writer
-                .write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
-
-            // Write actual message
-            self.writer.write_all(&cmd_bytes)?;
-            self.writer.flush()?;
-
-            if let Some(command) = cmd.command {
-                if let kvs_command::Command::Remove(remove) = command {
-                    if let Some(old_cmd) = self.index.remove(&remove.key) {
-                        // The remove command itself will be deleted in compaction
-                        // once a key is removed, both the original set command and the remove command become "stale"
-                        // and can be eliminated during compaction.
-                        self.uncompacted += old_cmd.len;
-                    }
-                }
+            if let Some(kvs_command::Command::Remove(remove)) = cmd.command {
+                let tombstone = Tombstone {
+                    deleted_at,
+                    sequence,
+                    len: 4 + cmd_bytes.len() as u64,
+                };
+                self.record_remove(remove.key, tombstone);
             }
+            self.latest_sequence.store(sequence, Ordering::SeqCst);
+            self.check_index_memory();
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
+            self.compact_if_due()?;
 
-            Ok(())
+            Ok(sequence)
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    /// Clears stale entries in the log. And rewrites latest values in a new log file
-    pub fn compact(&mut self) -> Result<()> {
-        println!(
-            "Debug: Starting compaction. Current size: {}",
-            self.uncompacted
-        );
-
-        // Increase current generation by 2. current_generation + 1 is for the compaction file.
-        let compaction_generation = self.current_generation + 1;
-        self.current_generation += 2;
-        self.writer = self.new_log_file(self.current_generation)?;
+    /// Removes every existing key among `keys` in one batch, with a single flush.
+    ///
+    /// Keys that don't exist are skipped. A crash before the flush completes may leave only
+    /// some of the removes in the log. If the removed keys make up at least
+    /// `remove_many_compaction_ratio` of the live keys, the store compacts right away rather
+    /// than waiting for the stale data to reach the threshold, unless a compaction window
+    /// defers it. If writing fails, the log is truncated back to where the batch started.
+    ///
+    /// Returns how many keys were removed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_many(&mut self, mut keys: Vec<String>) -> Result<u64> {
+        let live = self.index.live_len();
+        keys.sort_unstable();
+        keys.dedup();
+        keys.retain(|key| self.index.contains_key(key));
+        if keys.is_empty() {
+            return Ok(0);
+        }
 
-        let mut compaction_writer = self.new_log_file(compaction_generation)?;
+        let batch_start = self.writer.pos;
+        let last_sequence = self.current_sequence;
+        let deleted_at = self.deleted_at();
+        let record_lens = match self.write_removes(&keys, deleted_at) {
+            Ok(record_lens) => record_lens,
+            Err(e) => {
+                self.current_sequence = last_sequence;
+                self.discard_since(batch_start)?;
+                return Err(e);
+            }
+        };
 
-        let mut new_pos = 0; // Position in the new log file
+        let removed = keys.len() as u64;
+        for (key, (sequence, len)) in keys.into_iter().zip(record_lens) {
+            self.record_remove(key, Tombstone { deleted_at, sequence, len });
+        }
+        if let Some(sequence) = self.current_sequence {
+            self.latest_sequence.store(sequence, Ordering::SeqCst);
+        }
+        self.check_index_memory();
 
-        // Create a vector to collect keys and positions we need to update
-        let mut pos_updates = Vec::new();
+        if removed as f64 >= live as f64 * self.remove_many_compaction_ratio
+            && self.compaction_allowed()
+        {
+            debug!("Removed {} of {} keys, compacting", removed, live);
+            self.compact()?;
+        } else {
+            self.compact_if_due()?;
+        }
+        Ok(removed)
+    }
 
-        // Iterate through all index entries
-        for (key, cmd_pos) in self.index.iter() {
-            // Get reader for this generation
-            let generation = cmd_pos.geneeration;
-            let pos = cmd_pos.pos;
-            let len = cmd_pos.len;
+    /// Appends a remove record for every key and flushes them together.
+    ///
+    /// Returns the sequence number and length of each record.
+    fn write_removes(&mut self, keys: &[String], deleted_at: u64) -> Result<Vec<(u64, u64)>> {
+        let mut records = Vec::with_capacity(keys.len());
+        for key in keys {
+            let sequence = self.current_sequence.unwrap_or(0) + 1;
+            self.current_sequence = Some(sequence);
 
-            // Access reader through the reader component
-            // Note: We need to borrow from RefCell
-            let mut readers_borrow = self.reader.readers.borrow_mut();
-            let reader = readers_borrow.entry(generation).or_insert_with(|| {
-                let path = log_path(&self.path, generation);
-                BufReaderWithPos::new(
-                    File::open(path).expect("Cannot open log file"),
-                    self.reader.reader_buffer_size,
-                )
-                .expect("Cannot create reader")
-            });
+            let pos = self.writer.pos;
+            let cmd_bytes = KvsCommand::remove(key.clone(), deleted_at, sequence).encode_to_vec();
+            self.writer
+                .write_all(&(cmd_bytes.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&cmd_bytes)?;
+            records.push((sequence, self.writer.pos - pos));
+        }
+        self.flush_write()?;
+        Ok(records)
+    }
 
-            if reader.pos != pos {
-                reader.seek(SeekFrom::Start(pos))?;
-            }
+    /// The `deleted_at` of a remove written now: the current time with soft deletes, else 0.
+    fn deleted_at(&self) -> u64 {
+        if self.soft_delete_retention.is_some() {
+            now_millis()
+        } else {
+            0
+        }
+    }
 
-            // Read length prefix
-            let mut len_bytes = [0u8; 4];
-            reader.read_exact(&mut len_bytes)?;
-            let msg_len = u32::from_le_bytes(len_bytes) as usize;
+    /// Takes `key` out of the index after its remove record was written.
+    ///
+    /// A soft delete keeps the key's last set record for the retention; a plain remove makes
+    /// it and the remove record stale, to be dropped by compaction, which only copies live
+    /// entries. `load_v2` counts them the same way when replaying the log.
+    fn record_remove(&mut self, key: String, tombstone: Tombstone) {
+        let Some(old_cmd) = self.index.remove(&key) else {
+            return;
+        };
+        if let Some(history) = self.history.remove(&key) {
+            self.uncompacted += history.iter().map(|cmd_pos| cmd_pos.len).sum::<u64>();
+        }
+        if tombstone.deleted_at != 0 {
+            self.deleted.insert(key, Deleted { pos: old_cmd, tombstone });
+        } else {
+            self.uncompacted += old_cmd.len + tombstone.len;
+        }
+    }
 
-            // Read the message
-            let mut msg_bytes = vec![0; msg_len];
-            reader.read_exact(&mut msg_bytes)?;
+    /// Keeps `old_cmd`, the record `key` was just set over, as the key's previous version if
+    /// versions are kept, pushing out its oldest one if it has too many. Records no longer
+    /// kept are stale.
+    fn retire_version(&mut self, key: String, old_cmd: CommandPos) {
+        if self.kept_versions <= 1 {
+            self.uncompacted += old_cmd.len;
+            return;
+        }
+        let history = self.history.entry(key).or_default();
+        history.push_front(old_cmd);
+        while history.len() >= self.kept_versions {
+            self.uncompacted += history.pop_back().map_or(0, |cmd_pos| cmd_pos.len);
+        }
+    }
 
-            // Write length prefix to compaction file
-            compaction_writer.write_all(&len_bytes)?;
+    /// Returns up to `n` of the latest values of `key`, newest first, or none if it doesn't
+    /// exist.
+    fn get_versions(&self, key: &str, n: usize) -> Result<Vec<String>> {
+        let Some(latest) = self.index.get(key) else {
+            return Ok(Vec::new());
+        };
+        let older = self.history.get(key).into_iter().flatten();
+        std::iter::once(&latest)
+            .chain(older)
+            .take(n)
+            .map(|cmd_pos| {
+                self.reader
+                    .read_value(cmd_pos)?
+                    .ok_or(KvsError::UnexpectedCommandType)
+            })
+            .collect()
+    }
 
-            // Write message bytes to compaction file
-            compaction_writer.write_all(&msg_bytes)?;
+    /// Rebuilds the older versions of every live key from the log, keeping `kept_versions`
+    /// in total per key.
+    ///
+    /// Replaying the log on open only keeps the latest version; the older ones are still in
+    /// the log until a compaction without version history drops them.
+    fn load_history(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let mut versions: HashMap<String, Vec<(u64, CommandPos)>> = HashMap::new();
+        for generation in sorted_geneeration_list(&self.path)? {
+            let cursor =
+                GenerationCursor::open(&self.path, generation, self.reader.reader_buffer_size)?;
+            for (key, sequence, cmd_pos) in load_versions(cursor)? {
+                let key_versions = versions.entry(key).or_default();
+                match cmd_pos {
+                    Some(cmd_pos) => key_versions.push((sequence, cmd_pos)),
+                    // Versions from before a remove aren't the key's history
+                    None => key_versions.retain(|&(set_sequence, _)| set_sequence > sequence),
+                }
+            }
+        }
 
-            // Store the update for this command position
-            pos_updates.push((
-                key.clone(),
-                CommandPos {
-                    geneeration: compaction_generation,
-                    pos: new_pos,
-                    len: 4 + msg_len as u64,
-                },
-            ));
+        let old_history = std::mem::take(&mut self.history);
+        self.uncompacted += old_history.values().flatten().map(|cmd_pos| cmd_pos.len).sum::<u64>();
+        for (key, latest) in self.index.entries() {
+            let Some(mut key_versions) = versions.remove(&key) else {
+                continue;
+            };
+            key_versions.sort_by_key(|&(sequence, _)| sequence);
+            let history: VecDeque<_> = key_versions
+                .into_iter()
+                .rev()
+                .map(|(_, cmd_pos)| cmd_pos)
+                .skip_while(|cmd_pos| (cmd_pos.geneeration, cmd_pos.pos) != (latest.geneeration, latest.pos))
+                .skip(1)
+                .take(self.kept_versions.saturating_sub(1))
+                .collect();
+            // Replay counted the kept versions as stale
+            self.uncompacted = self
+                .uncompacted
+                .saturating_sub(history.iter().map(|cmd_pos| cmd_pos.len).sum());
+            if !history.is_empty() {
+                self.history.insert(key, history);
+            }
+        }
+        Ok(())
+    }
 
-            new_pos += 4 + msg_len as u64;
+    /// Forgets the soft-deleted value of `key`, which is being set again or is past its
+    /// retention. Its records are stale from then on.
+    fn forget_deleted(&mut self, key: &str) {
+        if let Some(deleted) = self.deleted.remove(key) {
+            self.uncompacted += deleted.pos.len + deleted.tombstone.len;
         }
-        compaction_writer.flush()?;
+    }
 
-        // Update the index with the new positions
-        for (key, new_cmd_pos) in pos_updates {
-            self.index.insert(key, new_cmd_pos);
+    /// Forgets the soft-deleted values that are past their retention or have expired.
+    fn sweep_deleted(&mut self) {
+        let now = now_millis();
+        let gone: Vec<_> = self
+            .deleted
+            .iter()
+            .filter(|(_, deleted)| !self.is_retained(deleted, now) || deleted.pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in gone {
+            self.forget_deleted(&key);
         }
+    }
 
-        // Set the safe point to the compaction generation
-        // This is an atomic operation visible to all readers
-        let safe_point = Arc::clone(&self.reader.safe_point);
-        safe_point.store(compaction_generation, Ordering::SeqCst);
+    /// Whether the soft-deleted value of a key can still be read and restored at `now`.
+    fn is_retained(&self, deleted: &Deleted, now: u64) -> bool {
+        self.soft_delete_retention.is_some_and(|retention| {
+            now < deleted.tombstone.deleted_at.saturating_add(retention.as_millis() as u64)
+        })
+    }
 
-        // Remove stale log files
-        let stale_generations: Vec<_> = self
-            .reader
-            .readers
-            .borrow()
-            .keys()
-            .filter(|&&generation| generation < compaction_generation)
-            .cloned()
-            .collect();
+    /// Returns the soft-deleted value of `key` if it is still retained and hasn't expired.
+    fn get_deleted(&self, key: &str) -> Result<Option<String>> {
+        let now = now_millis();
+        match self.deleted.get(key) {
+            Some(deleted) if self.is_retained(deleted, now) && !deleted.pos.is_expired(now) => {
+                self.reader.read_value(&deleted.pos)
+            }
+            _ => Ok(None),
+        }
+    }
 
-        for stale_generation in stale_generations {
-            // Remove the reader
-            self.reader.readers.borrow_mut().remove(&stale_generation);
+    /// Sets `key` back to its soft-deleted value, with the expiry it had, if it is still
+    /// retained. Returns whether it was restored.
+    fn undelete(&mut self, key: String) -> Result<bool> {
+        let Some(value) = self.get_deleted(&key)? else {
+            return Ok(false);
+        };
+        let expires_at = self.deleted[&key].pos.expires_at;
+        self.set(key, value, expires_at)?;
+        Ok(true)
+    }
 
-            // Remove the file
-            fs::remove_file(log_path(&self.path, stale_generation))?;
+    /// Rebuilds the index from its unexpired entries, see `KvStore::compact_index`. The
+    /// expired keys' records become stale.
+    fn compact_index(&mut self, swap_gate: &RwLock<()>) {
+        let now = now_millis();
+        let mut live = Vec::with_capacity(self.index.len());
+        for (key, cmd_pos) in self.index.entries() {
+            if !cmd_pos.is_expired(now) {
+                live.push((key, cmd_pos));
+                continue;
+            }
+            self.uncompacted += cmd_pos.len;
+            if let Some(history) = self.history.remove(&key) {
+                self.uncompacted += history.iter().map(|cmd_pos| cmd_pos.len).sum::<u64>();
+            }
+        }
+        {
+            let _gate = swap_gate.write().unwrap();
+            self.index.replace_all(live);
         }
+        self.check_index_memory();
+    }
 
-        self.uncompacted = 0;
+    /// Logs a warning when the index's estimated memory use crosses the configured threshold.
+    ///
+    /// Warns once per crossing, not on every write while it stays above.
+    fn check_index_memory(&mut self) {
+        let Some(threshold) = self.index_memory_warning else {
+            return;
+        };
+        let estimate = self.index.memory_estimate();
+        let above = estimate > threshold;
+        if above && !self.index_memory_warned {
+            warn!(
+                "Key index uses about {} bytes of memory, above the warning threshold of {} bytes",
+                estimate, threshold
+            );
+        }
+        self.index_memory_warned = above;
+    }
 
+    /// Compacts if enough data is stale and, with a compaction window configured, either the
+    /// window is open or the stale data exceeds its hard cap.
+    fn compact_if_due(&mut self) -> Result<()> {
+        // Values past their retention only count towards compaction once swept
+        self.sweep_deleted();
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            debug!("Write deadline passed, leaving compaction to a later write");
+            return Ok(());
+        }
+        if self.enough_stale() && self.compaction_allowed() {
+            self.compact()?;
+        }
         Ok(())
     }
-}
 
-impl KvStore {
-    /// Opens a `KvStore` with the given path.
+    /// Whether more than `COMPACTION_THRESHOLD` bytes are stale and, in adaptive mode, they
+    /// are more than the configured share of the log.
+    fn enough_stale(&self) -> bool {
+        if self.uncompacted <= COMPACTION_THRESHOLD {
+            return false;
+        }
+        match self.stale_ratio {
+            None => true,
+            Some(ratio) => self.uncompacted as f64 > ratio * self.disk_usage() as f64,
+        }
+    }
+
+    /// Bytes taken up by the log files.
+    fn disk_usage(&self) -> u64 {
+        self.closed_log_bytes + self.writer.pos
+    }
+
+    /// Checks that `bytes` more fit in the disk quota, if there is one.
     ///
-    /// This will create a new directory if the given one does not exist.
+    /// With `QuotaPolicy::CompactFirst`, a write that doesn't fit compacts first if part of
+    /// the log is stale, regardless of any compaction window.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or deserialization errors during the log replay.
-    pub fn open(
-        path: impl Into<PathBuf>,
-        reader_buffer_size: Option<usize>,
-        writer_buffer_size: Option<usize>,
-    ) -> Result<KvStore> {
-        let reader_buffer_size = reader_buffer_size.unwrap_or(8 * 1024); // 8kb
-        let writer_buffer_size = writer_buffer_size.unwrap_or(8 * 1024);
-        let path = path.into();
-        fs::create_dir_all(&path)?;
+    /// It returns `KvsError::DiskQuotaExceeded` if the write doesn't fit.
+    fn check_disk_quota(&mut self, bytes: u64) -> Result<()> {
+        let Some(quota) = self.disk_quota else {
+            return Ok(());
+        };
+        if self.disk_usage() + bytes > quota
+            && self.quota_policy == QuotaPolicy::CompactFirst
+            && self.uncompacted > 0
+        {
+            debug!("Write would exceed the disk quota, compacting first");
+            self.compact()?;
+        }
+        let used = self.disk_usage();
+        if used + bytes > quota {
+            return Err(KvsError::DiskQuotaExceeded { used, quota });
+        }
+        Ok(())
+    }
 
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+    /// Whether the compaction window, if any, lets an automatic compaction run now.
+    fn compaction_allowed(&self) -> bool {
+        match &self.compaction_window {
+            None => true,
+            Some(window) => window.is_open() || self.uncompacted > window.hard_cap(),
+        }
+    }
 
-        let mut highest_seq = 0;
+    /// Clears stale entries in the log. And rewrites latest values in a new log file
+    pub fn compact(&mut self) -> Result<()> {
+        debug!("Starting compaction, {} bytes are stale", self.uncompacted);
 
-        let geneeration_list = sorted_geneeration_list(&path)?;
-        let mut uncompacted = 0;
+        self.rewrite_live_records(false)?;
+        Ok(())
+    }
 
-        for &geneeration in &geneeration_list {
+    /// Forces a compaction, then syncs the active log and the data directory.
+    ///
+    /// Returns the sequence number of the latest write.
+    fn checkpoint(&mut self) -> Result<u64> {
+        self.compact()?;
+        self.writer.sync()?;
+        // Persist the creation and removal of log files
+        File::open(&*self.path)?.sync_all()?;
+        Ok(self.current_sequence.unwrap_or(0))
+    }
+
+    /// Copies every live record into a fresh generation and removes the stale ones.
+    ///
+    /// Keys that have expired are dropped rather than copied, as are soft-deleted values past
+    /// their retention. With `upgrade` set, records older than `CURRENT_SCHEMA_VERSION` are
+    /// re-encoded in the current version instead of being copied verbatim.
+    ///
+    /// Returns how many records were upgraded.
+    fn rewrite_live_records(&mut self, upgrade: bool) -> Result<u64> {
+        let index = Arc::clone(&self.index);
+        let now = now_millis();
+        let mut expired = Vec::new();
+        let mut history = std::mem::take(&mut self.history);
+        // Each key's older versions go first, oldest first, so that replaying the new
+        // generation ends on the latest
+        let live = index
+            .entries()
+            .filter(|(key, cmd_pos)| {
+                let is_expired = cmd_pos.is_expired(now);
+                if is_expired {
+                    expired.push(key.clone());
+                }
+                !is_expired
+            })
+            .flat_map(|(key, cmd_pos)| {
+                let older = history.remove(&key).unwrap_or_default();
+                let older: Vec<_> = older.into_iter().rev().map(|old| (key.clone(), old)).collect();
+                older.into_iter().chain(std::iter::once((key, cmd_pos)))
+            });
+        let mut retained: BTreeMap<_, _> = std::mem::take(&mut self.deleted)
+            .into_iter()
+            .filter(|(_, deleted)| self.is_retained(deleted, now) && !deleted.pos.is_expired(now))
+            .collect();
+        let (compaction_generation, pos_updates, upgraded) =
+            self.copy_records(live, &mut retained, None, upgrade)?;
+        self.deleted = retained;
+
+        // Update the index with the new positions. A key's copied records are next to each
+        // other, its latest last.
+        let mut pos_updates = pos_updates.into_iter().peekable();
+        while let Some((key, new_cmd_pos)) = pos_updates.next() {
+            if pos_updates.peek().is_some_and(|(next_key, _)| *next_key == key) {
+                self.history.entry(key).or_default().push_front(new_cmd_pos);
+            } else {
+                self.index.insert(key, new_cmd_pos);
+            }
+        }
+        // The expired keys' records go away with their generations
+        for key in expired {
+            self.index.remove(&key);
+        }
+
+        self.finish_rewrite(compaction_generation)?;
+        Ok(upgraded)
+    }
+
+    /// Replaces the whole contents of the store with the live records of the store in
+    /// `new_dir`, which is left untouched.
+    ///
+    /// The records are copied into a fresh generation like in a compaction, so a crash
+    /// leaves either the old or the new dataset. `swap_gate` is held while the index is
+    /// swapped.
+    fn replace_with(&mut self, new_dir: &Path, swap_gate: &RwLock<()>) -> Result<()> {
+        if new_dir.join(COMPACTION_MARKER).exists() {
+            return Err(KvsError::StringError(format!(
+                "{} has an unfinished compaction; open it once to recover it",
+                new_dir.display()
+            )));
+        }
+
+        let mut generation_indexes = Vec::new();
+        let mut highest_seq = 0;
+        for generation in sorted_geneeration_list(new_dir)? {
             let mut reader = BufReaderWithPos::new(
-                File::open(log_path(&path, geneeration))?,
-                reader_buffer_size,
+                File::open(log_path(new_dir, generation))?,
+                self.reader.reader_buffer_size,
             )?;
+            let mut generation_index = BTreeMap::new();
+            let (_, seq, _, _) = load_v2(generation, &mut reader, &mut generation_index, None)?;
+            highest_seq = max(highest_seq, seq);
+            generation_indexes.push(generation_index);
+        }
+        // Soft-deleted values aren't carried over: they belong to the replaced dataset
+        let (new_index, _, _) = merge_generation_indexes(generation_indexes);
+        self.deleted.clear();
+        self.history.clear();
+
+        let (compaction_generation, positions, _) =
+            self.copy_records(new_index.into_iter(), &mut BTreeMap::new(), Some(new_dir), false)?;
+
+        {
+            let _gate = swap_gate.write().unwrap();
+            self.index.replace_all(positions);
+            // Later writes must win over the copied records when the log is replayed
+            let sequence = max(self.current_sequence.unwrap_or(0), highest_seq);
+            self.current_sequence = Some(sequence);
+            self.latest_sequence.store(sequence, Ordering::SeqCst);
+        }
 
-            let (uncompat, seq) = load_v2(geneeration, &mut reader, &mut index)?;
+        self.finish_rewrite(compaction_generation)?;
+        self.check_index_memory();
+        Ok(())
+    }
 
-            uncompacted += uncompat;
-            readers.insert(geneeration, reader);
-            highest_seq = max(highest_seq, seq);
+    /// Copies the records at the given index entries into a fresh generation, reading them
+    /// from the log files in `source_dir`, or this store's if `None`. The writer moves on to
+    /// the generation after it.
+    ///
+    /// The soft-deleted values in `retained` are copied too, each followed by a new remove
+    /// record with the same `deleted_at` and sequence number, and their positions updated.
+    ///
+    /// The copy is recorded with a compaction marker, marked complete once it is on disk; the
+    /// caller updates the index and then calls `finish_rewrite`.
+    ///
+    /// Returns the new generation, each key with the position of its copied record, and how
+    /// many records were upgraded.
+    #[allow(clippy::type_complexity)]
+    fn copy_records(
+        &mut self,
+        entries: impl Iterator<Item = (String, CommandPos)>,
+        retained: &mut BTreeMap<String, Deleted>,
+        source_dir: Option<&Path>,
+        upgrade: bool,
+    ) -> Result<(u64, Vec<(String, CommandPos)>, u64)> {
+        // Increase current generation by 2. current_generation + 1 is for the compaction file.
+        let compaction_generation = self.current_generation + 1;
+        let writer_generation = self.current_generation + 2;
+
+        // Record the compaction first so a crash part way through can be recovered on open
+        write_compaction_marker(&self.path, compaction_generation, CompactionState::Started)?;
+        // Only move on to the new generation once its log is open, or writes would be indexed
+        // under a generation they aren't in
+        self.writer = self.new_log_file(writer_generation)?;
+        self.current_generation = writer_generation;
+        let mut output = CompactionOutput::new(
+            self.new_log_file(compaction_generation)?,
+            compaction_generation,
+            self.file_checksums,
+            upgrade,
+        )?;
+
+        let retained_entries: Vec<_> = retained
+            .iter()
+            .map(|(key, deleted)| (key.clone(), deleted.pos))
+            .collect();
+        let entries = entries.chain(retained_entries);
+
+        let source_dir = source_dir.unwrap_or(&self.path);
+        if self.compaction_threads > 1 {
+            self.copy_records_parallel(entries, source_dir, &mut output)?;
+        } else {
+            // Readers of another store's files aren't kept past the copy
+            let mut source_readers = HashMap::new();
+            let mut readers_borrow = self.reader.readers.borrow_mut();
+            let readers = if source_dir == self.path.as_path() {
+                &mut *readers_borrow
+            } else {
+                &mut source_readers
+            };
+            let mut msg_bytes = Vec::new();
+            for (key, cmd_pos) in entries {
+                let reader = match readers.entry(cmd_pos.geneeration) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => entry.insert(BufReaderWithPos::new(
+                        File::open(log_path(source_dir, cmd_pos.geneeration))?,
+                        self.reader.reader_buffer_size,
+                    )?),
+                };
+                read_record_at(reader, cmd_pos.pos, &mut msg_bytes)?;
+                output.push(key, cmd_pos, &mut msg_bytes)?;
+            }
+        }
+
+        for (key, deleted) in retained.iter_mut() {
+            deleted.tombstone.len = output.push_tombstone(key.clone(), &deleted.tombstone)?;
+        }
+        // The record of the latest write may not have been copied, a remove's never is: keep
+        // its sequence number so that the sequence doesn't go backwards once reopened
+        if let Some(sequence) = self.current_sequence {
+            output.push_mark(sequence)?;
+        }
+
+        let (mut pos_updates, upgraded) = output.finish()?;
+        write_compaction_marker(&self.path, compaction_generation, CompactionState::Complete)?;
+
+        pos_updates.retain(|(key, new_cmd_pos)| match retained.get_mut(key) {
+            Some(deleted) => {
+                deleted.pos = *new_cmd_pos;
+                false
+            }
+            None => true,
+        });
+        Ok((compaction_generation, pos_updates, upgraded))
+    }
+
+    /// Copies records like `copy_records`, reading the generations they are in concurrently
+    /// on `compaction_threads` threads.
+    ///
+    /// Records are read a batch of about `PARALLEL_COMPACTION_BATCH` bytes at a time, each
+    /// generation's share of the batch in file order, and written in the order of `entries`,
+    /// so the result is the same as a sequential copy.
+    fn copy_records_parallel(
+        &self,
+        entries: impl Iterator<Item = (String, CommandPos)>,
+        source_dir: &Path,
+        output: &mut CompactionOutput,
+    ) -> Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.compaction_threads)
+            .build()
+            .map_err(|e| KvsError::StringError(e.to_string()))?;
+        let reader_buffer_size = self.reader.reader_buffer_size;
+
+        let mut entries = entries.peekable();
+        while entries.peek().is_some() {
+            let mut batch = Vec::new();
+            let mut batch_bytes = 0;
+            while batch_bytes < PARALLEL_COMPACTION_BATCH
+                && let Some((key, cmd_pos)) = entries.next()
+            {
+                batch_bytes += cmd_pos.len;
+                batch.push((key, cmd_pos));
+            }
+
+            // Slots in the batch, grouped by generation and sorted by position
+            let mut by_generation: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+            for (slot, (_, cmd_pos)) in batch.iter().enumerate() {
+                by_generation.entry(cmd_pos.geneeration).or_default().push(slot);
+            }
+            let groups: Vec<_> = by_generation.into_iter().collect();
+            let read = pool.install(|| {
+                groups
+                    .par_iter()
+                    .map(|(generation, slots)| -> Result<Vec<(usize, Vec<u8>)>> {
+                        let mut reader = BufReaderWithPos::new(
+                            File::open(log_path(source_dir, *generation))?,
+                            reader_buffer_size,
+                        )?;
+                        let mut slots = slots.clone();
+                        slots.sort_by_key(|&slot| batch[slot].1.pos);
+                        slots
+                            .into_iter()
+                            .map(|slot| {
+                                let mut msg_bytes = Vec::new();
+                                read_record_at(&mut reader, batch[slot].1.pos, &mut msg_bytes)?;
+                                Ok((slot, msg_bytes))
+                            })
+                            .collect()
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?;
+
+            let mut records = vec![Vec::new(); batch.len()];
+            for (slot, msg_bytes) in read.into_iter().flatten() {
+                records[slot] = msg_bytes;
+            }
+            for ((key, cmd_pos), mut msg_bytes) in batch.into_iter().zip(records) {
+                output.push(key, cmd_pos, &mut msg_bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the generations older than `compaction_generation`, once the index no longer
+    /// points into them, and clears the compaction marker.
+    fn finish_rewrite(&mut self, compaction_generation: u64) -> Result<()> {
+        // Set the safe point to the compaction generation
+        // This is an atomic operation visible to all readers
+        let safe_point = Arc::clone(&self.reader.safe_point);
+        safe_point.store(compaction_generation, Ordering::SeqCst);
+
+        // Remove stale log files. Go by the files on disk: this thread's readers only cover
+        // the generations it happened to read. Other clones close their handles once they
+        // see the safe point, and retry reads that raced with the removal.
+        self.reader.close_stale_handles();
+        for stale_generation in sorted_geneeration_list(&self.path)?
+            .into_iter()
+            .filter(|&generation| generation < compaction_generation)
+        {
+            fs::remove_file(log_path(&self.path, stale_generation))?;
+        }
+
+        fs::remove_file(self.path.join(COMPACTION_MARKER))?;
+        self.uncompacted = 0;
+        self.closed_log_bytes = closed_log_bytes(&self.path, self.current_generation)?;
+
+        Ok(())
+    }
+}
+
+impl KvStoreWriter {
+    /// Flushes the log and saves the index along with a fingerprint of the log files.
+    fn save_index_hint(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let generations = sorted_geneeration_list(&self.path)?;
+        let hint = IndexHint {
+            generations: fingerprint_generations(&self.path, &generations)?,
+            highest_sequence: self.current_sequence.unwrap_or(0),
+            uncompacted: self.uncompacted,
+            entries: self.index.entries().collect(),
+            deleted: self.deleted.iter().map(|(key, deleted)| (key.clone(), *deleted)).collect(),
+        };
+        write_index_hint(&self.path, &hint)
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if self.index_hints
+            && let Err(e) = self.save_index_hint()
+        {
+            warn!("Cannot save the index hint, the next open replays the log: {:?}", e);
+        }
+    }
+}
+
+impl KvStoreWriter {
+    /// Create a new log file with given geneerationeration number and add the reader to the readers map.
+    ///
+    /// Returns the writer to the log.
+    fn new_log_file(&mut self, geneeration: u64) -> Result<BufWriterWithPos<LogFile>> {
+        let writer = BufWriterWithPos::new(self.open_log(geneeration)?, self.writer_buffer_size)?;
+        let reader = BufReaderWithPos::new(
+            File::open(log_path(&self.path, geneeration))?,
+            self.reader.reader_buffer_size,
+        )?;
+        self.reader.readers.get_mut().insert(geneeration, reader);
+        Ok(writer)
+    }
+
+    /// Reopens the current log, e.g. after switching to direct I/O.
+    fn reopen_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let file = self.open_log(self.current_generation)?;
+        self.writer = BufWriterWithPos::new(file, self.writer_buffer_size)?;
+        Ok(())
+    }
+
+    /// Opens the log of `geneeration` for appending, with the injected faults if any.
+    fn open_log(&self, geneeration: u64) -> Result<LogFile> {
+        writable(&self.path, self.open_log_file(geneeration))
+    }
+
+    fn open_log_file(&self, geneeration: u64) -> Result<LogFile> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &self.faults {
+            faults.file_op()?;
+            let file = LogFile::open(&log_path(&self.path, geneeration), self.direct_writes)?;
+            return Ok(LogFile::Faulty(Box::new(file), Arc::clone(faults)));
         }
+        Ok(LogFile::open(&log_path(&self.path, geneeration), self.direct_writes)?)
+    }
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay. If the directory
+    /// can't be written, e.g. because it is on a read-only file system, it returns
+    /// `KvsError::ReadOnlyFilesystem`; use `open_read_only` to read such a store.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        reader_buffer_size: Option<usize>,
+        writer_buffer_size: Option<usize>,
+    ) -> Result<KvStore> {
+        let reader_buffer_size = reader_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        let writer_buffer_size = writer_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+        let path = Arc::new(path.into());
+        writable(&path, fs::create_dir_all(&*path).map_err(KvsError::from))?;
+        writable(&path, recover_compaction(&path))?;
+
+        let geneeration_list = sorted_geneeration_list(&path)?;
+        let (mut readers, index, deleted, uncompacted, highest_seq) =
+            match take_index_hint(&path, &geneeration_list)? {
+                Some(hint) => {
+                    debug!("Loaded the index of {} keys from its hint", hint.entries.len());
+                    let highest_sequence = hint.highest_sequence;
+                    let (index, deleted, uncompacted) = hint.into_index();
+                    (HashMap::new(), index, deleted, uncompacted, highest_sequence)
+                }
+                None => replay(&path, &geneeration_list, reader_buffer_size)?,
+            };
 
         let current_geneeration = geneeration_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(
+        let writer = writable(
             &path,
-            current_geneeration,
-            &mut readers,
-            reader_buffer_size,
-            writer_buffer_size,
+            new_log_file(
+                &path,
+                current_geneeration,
+                &mut readers,
+                reader_buffer_size,
+                writer_buffer_size,
+                false,
+            ),
         )?;
 
-        Ok(KvStore {
-            path,
-            readers,
+        let closed_log_bytes = closed_log_bytes(&path, current_geneeration)?;
+        let index = Arc::new(index);
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let latest_sequence = Arc::new(AtomicU64::new(highest_seq));
+
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            reader_buffer_size,
+            readers: RefCell::new(readers),
+            shared: None,
+            mapped: None,
+            safe_point,
+            scratch: RefCell::new(Vec::new()),
+        };
+
+        let writer = KvStoreWriter {
+            writer_buffer_size,
             writer,
-            current_geneeration,
-            index,
+            current_generation: current_geneeration,
             uncompacted,
             current_sequence: Some(highest_seq),
-            reader_buffer_size,
-            writer_buffer_size,
+            latest_sequence: Arc::clone(&latest_sequence),
+            reader: reader.clone(),
+            index: Arc::clone(&index),
+            path: Arc::clone(&path),
+            compaction_window: None,
+            index_memory_warning: None,
+            index_memory_warned: false,
+            file_checksums: false,
+            flush_writes: true,
+            direct_writes: false,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            remove_many_compaction_ratio: REMOVE_MANY_COMPACTION_RATIO,
+            stale_ratio: None,
+            compaction_threads: 1,
+            soft_delete_retention: None,
+            deleted,
+            kept_versions: 1,
+            history: HashMap::new(),
+            index_hints: false,
+            deadline: None,
+            disk_quota: None,
+            quota_policy: QuotaPolicy::default(),
+            closed_log_bytes,
+        };
+
+        Ok(KvStore {
+            latest_sequence,
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            cache: None,
+            buffered_writes: false,
+            swap_gate: Arc::new(RwLock::new(())),
+            write_deadline: None,
+            recovery_mode: false,
         })
     }
 
-    /// Create a new log file with given geneerationeration number and add the reader to the readers map.
+    /// Enables a cache of about `capacity` decoded values in front of the log.
     ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, geneeration: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(
-            &self.path,
-            geneeration,
-            &mut self.readers,
-            self.writer_buffer_size,
-            self.reader_buffer_size,
-        )
+    /// Repeated reads of the same hot keys then skip reading and decoding their records. The
+    /// cache is sharded per CPU and shared by all clones of the returned store. Reads stay
+    /// consistent with concurrent writes: a cached value is only served while the index
+    /// still points at the record it came from.
+    pub fn with_read_cache(mut self, capacity: usize) -> KvStore {
+        self.cache = Some(Arc::new(ReadCache::new(capacity)));
+        self
+    }
+}
+
+impl KvStore {
+    /// Returns the sequence number of the latest committed write.
+    ///
+    /// It never decreases. Any write with a sequence up to the returned value is visible to
+    /// `get` on every clone of this store.
+    pub fn latest_sequence(&self) -> u64 {
+        self.latest_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Reads the value of `key` from `cmd_pos`, its position as just looked up in the index.
+    ///
+    /// A compaction on another clone may have moved the record and removed its generation
+    /// since the lookup. The index is updated before the safe point moves past the old
+    /// generation, so in that case the lookup is retried.
+    fn read_indexed(&self, key: &str, mut cmd_pos: CommandPos) -> Result<Option<String>> {
+        loop {
+            match self.read_cached(key, &cmd_pos) {
+                Err(e) if self.reader.is_compacted(&cmd_pos) => {
+                    debug!("Retrying read of a compacted record: {:?}", e);
+                    match self.index.get(key) {
+                        Some(current) => cmd_pos = current,
+                        None => return Ok(None),
+                    }
+                }
+                Err(KvsError::UnexpectedCommandType) => return self.misindexed(key, &cmd_pos),
+                result => return result,
+            }
+        }
+    }
+
+    /// Handles an index entry pointing at a record that isn't a set, which only corruption
+    /// of the log or the index hint can cause.
+    ///
+    /// Fails with `KvsError::CorruptRecord` locating the record, or in recovery mode logs it
+    /// and reads the key as absent.
+    fn misindexed<T>(&self, key: &str, cmd_pos: &CommandPos) -> Result<Option<T>> {
+        let corrupt = CorruptRecord {
+            generation: cmd_pos.geneeration,
+            offset: cmd_pos.pos,
+            reason: format!("key {:?} is indexed at a record that isn't a set", key),
+        };
+        if self.recovery_mode {
+            error!("Skipping corrupt record in {}", corrupt);
+            return Ok(None);
+        }
+        Err(KvsError::CorruptRecord(corrupt))
+    }
+
+    /// Reads the value at `cmd_pos`, going through the read cache if it is enabled.
+    fn read_cached(&self, key: &str, cmd_pos: &CommandPos) -> Result<Option<String>> {
+        let Some(cache) = &self.cache else {
+            return self.reader.read_value(cmd_pos);
+        };
+
+        if let Some(value) = cache.get(key, cmd_pos.geneeration, cmd_pos.pos) {
+            return Ok(Some(value));
+        }
+        let value = self.reader.read_value(cmd_pos)?;
+        if let Some(value) = &value {
+            cache.insert(key.to_owned(), cmd_pos.geneeration, cmd_pos.pos, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Runs a read of the index and log while no `replace_with` is swapping them.
+    ///
+    /// With buffered writes, a record may still be (partly) in the writer's buffer; the
+    /// buffer is then flushed and the read retried once. The writer lock is only taken
+    /// outside the gate, since `replace_with` takes the gate while holding it.
+    fn read_gated<T>(&self, read: impl Fn() -> Result<T>) -> Result<T> {
+        let result = {
+            let _gate = self.swap_gate.read().unwrap();
+            read()
+        };
+        match result {
+            Err(KvsError::IoError(e))
+                if self.buffered_writes && e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                self.writer.lock().unwrap().writer.flush()?;
+                let _gate = self.swap_gate.read().unwrap();
+                read()
+            }
+            result => result,
+        }
+    }
+
+    /// Reads the set record of an index entry into a `ScanEntry`, following the key if a
+    /// compaction moved it. Returns `None` if the key was removed in the meantime.
+    fn read_scan_entry(&self, key: String, mut cmd_pos: CommandPos) -> Result<Option<ScanEntry>> {
+        let cmd = loop {
+            match self.reader.read_command(&cmd_pos) {
+                Err(e) if self.reader.is_compacted(&cmd_pos) => {
+                    debug!("Retrying scan of a compacted record: {:?}", e);
+                    match self.index.get(&key) {
+                        Some(current) => cmd_pos = current,
+                        None => return Ok(None),
+                    }
+                }
+                result => break result?,
+            }
+        };
+        let Some(kvs_command::Command::Set(set)) = cmd.command else {
+            return self.misindexed(&key, &cmd_pos);
+        };
+        Ok(Some(ScanEntry {
+            key,
+            size: set.value.len() as u64,
+            value: set.value,
+            sequence: cmd.sequence_number,
+            timestamp: cmd.timestamp,
+            expires_at: (set.expires_at != 0).then_some(set.expires_at),
+        }))
+    }
+
+    /// Reads the values of the given index entries, in order.
+    fn read_entries(
+        &self,
+        entries: impl Iterator<Item = (String, CommandPos)>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in entries {
+            if let Some(value) = self.read_indexed(&key, cmd_pos)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Leaves writes in the writer's buffer instead of flushing each one to the OS.
+    ///
+    /// Only meant for measuring peak throughput: everything still in the buffer, up to the
+    /// writer's buffer size worth of writes, is lost if the process dies. Writes are visible
+    /// to reads right away all the same, and reach the file when the buffer fills, on
+    /// compaction, `checkpoint`, or when the last clone of the store is dropped.
+    pub fn with_buffered_writes(mut self) -> KvStore {
+        self.writer.lock().unwrap().flush_writes = false;
+        self.buffered_writes = true;
+        self
+    }
+
+    /// Reads past index entries that point at a record other than a set, logging each one,
+    /// instead of failing with `KvsError::CorruptRecord`.
+    ///
+    /// Meant for salvaging what is readable from a damaged store: the affected keys read as
+    /// absent, and are left out of scans.
+    pub fn with_recovery_mode(mut self) -> KvStore {
+        self.recovery_mode = true;
+        self
+    }
+
+    /// Bounds how long a write (`set`, `remove` and their variants) may take.
+    ///
+    /// A write that can't take the writer lock within `deadline`, e.g. because another write
+    /// is compacting, fails with `KvsError::Timeout` and writes nothing. A write that gets
+    /// the lock but is past its deadline once its record is in the log succeeds, but leaves
+    /// the compaction it would trigger to a later write.
+    pub fn with_write_deadline(mut self, deadline: Duration) -> KvStore {
+        self.write_deadline = Some(deadline);
+        self
+    }
+
+    /// Takes the writer lock for a write, giving up with `KvsError::Timeout` once the write
+    /// deadline, if any, has passed.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        let Some(timeout) = self.write_deadline else {
+            let mut writer = self.writer.lock().unwrap();
+            writer.deadline = None;
+            return Ok(writer);
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.writer.try_lock() {
+                Ok(mut writer) => {
+                    writer.deadline = Some(deadline);
+                    return Ok(writer);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(KvsError::Timeout);
+                    }
+                    thread::sleep(remaining.min(WRITE_LOCK_POLL_INTERVAL));
+                }
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            }
+        }
+    }
+
+    /// Injects `faults` into the log files the store writes: the active one, reopened here,
+    /// and the ones it opens from now on. Replaces the faults injected before, and restarts
+    /// counting operations. Only built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_faults(&self, faults: FaultConfig) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        // Reopened before the faults are in place, so that it isn't counted
+        writer.faults = None;
+        let file = writer.open_log(writer.current_generation)?;
+        let faults = Arc::new(Faults::new(faults));
+        writer.faults = Some(Arc::clone(&faults));
+        let faulty = LogFile::Faulty(Box::new(file), faults);
+        writer.writer = BufWriterWithPos::new(faulty, writer.writer_buffer_size)?;
+        Ok(())
+    }
+
+    /// Holds the writer lock until the returned guard is dropped, stalling every write. Only
+    /// built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    #[doc(hidden)]
+    pub fn hold_writer_lock(&self) -> impl Sized + '_ {
+        self.writer.lock().unwrap()
+    }
+
+    /// Reads the records to copy during compaction on `threads` threads, one generation per
+    /// thread at a time, instead of one record after the other. Defaults to 1 (sequential).
+    ///
+    /// Speeds up compacting stores spread over many generations on storage that serves
+    /// concurrent reads well. The compacted generation is the same either way.
+    pub fn with_compaction_threads(self, threads: usize) -> KvStore {
+        self.writer.lock().unwrap().compaction_threads = threads.max(1);
+        self
+    }
+
+    /// Shares log file readers between all clones of the store, keeping up to
+    /// `per_generation` idle readers of each generation, instead of each clone opening its
+    /// own.
+    ///
+    /// By default every clone, typically one per thread, keeps a reader open for each
+    /// generation it has read, so a store read by many threads holds threads × generations
+    /// open files. With a shared pool a read borrows an idle reader and hands it back, which
+    /// bounds the open files by the generations and the reads in flight, at the cost of a
+    /// lock per read. Applies to this store and clones made from it afterwards.
+    pub fn with_shared_readers(mut self, per_generation: usize) -> KvStore {
+        let pool = Arc::new(ReaderPool::new(per_generation));
+        self.reader.readers.get_mut().clear();
+        self.reader.shared = Some(Arc::clone(&pool));
+        // So that compaction closes the pooled readers of the generations it removes
+        self.writer.lock().unwrap().reader.shared = Some(pool);
+        self
+    }
+
+    /// Serves reads of sealed generations straight from a memory mapping of their files,
+    /// instead of reading each record into a buffer.
+    ///
+    /// Only generations written by compaction with `with_file_checksums` are sealed: nothing
+    /// is appended to them afterwards. The active log and unsealed generations are still read
+    /// through buffered readers. A mapping lives until the last read using it is done, so
+    /// reads racing with the compaction that removes its file still see the old records.
+    /// Applies to this store and clones made from it afterwards.
+    ///
+    /// Linux only; on other platforms a warning is logged and reads are buffered as usual.
+    pub fn with_mmap_reads(mut self) -> KvStore {
+        #[cfg(not(target_os = "linux"))]
+        warn!("Memory-mapped reads are only supported on Linux, reading through buffers");
+        let mapped = Arc::new(MappedLogs::new());
+        self.reader.mapped = Some(Arc::clone(&mapped));
+        // So that compaction drops the mappings of the generations it removes
+        self.writer.lock().unwrap().reader.mapped = Some(mapped);
+        self
+    }
+
+    /// Makes removes soft deletes: a removed value stays readable with `get_deleted`, and can
+    /// be restored with `undelete`, for `retention` after the remove.
+    ///
+    /// Compaction drops the value once the retention has passed. Until then it and its remove
+    /// record are kept on disk, tracked apart from the live keys.
+    pub fn with_soft_deletes(self, retention: Duration) -> KvStore {
+        self.writer.lock().unwrap().soft_delete_retention = Some(retention);
+        self
+    }
+
+    /// Caps the bytes the log files may take up at `max_bytes`: a set that would take them
+    /// past it fails with `KvsError::DiskQuotaExceeded`, after compacting first if `policy`
+    /// says so.
+    ///
+    /// Removes are always accepted, so space can be freed. Compaction briefly needs room
+    /// for a copy of the live data on top of the quota.
+    pub fn with_disk_quota(self, max_bytes: u64, policy: QuotaPolicy) -> KvStore {
+        let mut writer = self.writer.lock().unwrap();
+        writer.disk_quota = Some(max_bytes);
+        writer.quota_policy = policy;
+        drop(writer);
+        self
+    }
+
+    /// Keeps the `versions` latest values of each key instead of only the latest, for
+    /// `get_versions`. Defaults to 1.
+    ///
+    /// Compaction copies the older versions along with the latest, so they take up disk
+    /// space like live values; setting a key past `versions` makes its oldest one stale.
+    /// Removing a key drops its older versions. The versions still in the log are picked up
+    /// right away, which reads the whole log.
+    pub fn with_version_history(self, versions: usize) -> Result<KvStore> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.kept_versions = versions.max(1);
+        writer.load_history()?;
+        drop(writer);
+        Ok(self)
+    }
+
+    /// Returns up to `n` of the latest values of `key`, newest first: the value `get` returns,
+    /// then older ones kept by `with_version_history`. Empty if the key doesn't exist.
+    pub fn get_versions(&self, key: &str, n: usize) -> Result<Vec<String>> {
+        self.writer.lock().unwrap().get_versions(key, n)
+    }
+
+    /// Returns the value of a soft-deleted key, or `None` if the key wasn't removed by a soft
+    /// delete or its retention has passed.
+    pub fn get_deleted(&self, key: &str) -> Result<Option<String>> {
+        self.writer.lock().unwrap().get_deleted(key)
+    }
+
+    /// Writes the log with direct I/O (`O_DIRECT`), bypassing the OS page cache.
+    ///
+    /// Meant for bulk loads, where caching everything written only evicts more useful pages.
+    /// Whole blocks go to disk directly; the last, partial block of the active log is still
+    /// written through the page cache, so every write remains readable and durable as usual.
+    /// Only supported on Linux, and not by every file system (e.g. tmpfs): where it isn't, a
+    /// warning is logged and the store writes through the page cache.
+    pub fn with_direct_writes(self) -> KvStore {
+        let mut writer = self.writer.lock().unwrap();
+        writer.direct_writes = true;
+        if let Err(e) = writer.reopen_log() {
+            warn!("Cannot reopen the log for direct I/O: {:?}", e);
+        }
+        drop(writer);
+        self
+    }
+
+    /// Sets the share of the live keys a `remove_many` has to remove for the store to compact
+    /// right away, reclaiming the removed keys' space. Defaults to 0.5.
+    pub fn with_remove_many_compaction_ratio(self, ratio: f64) -> KvStore {
+        self.writer.lock().unwrap().remove_many_compaction_ratio = ratio;
+        self
+    }
+
+    /// Makes writes compact once the stale data is more than `ratio` of the log files'
+    /// bytes, e.g. 0.3 for 30%, instead of as soon as it exceeds 1MB.
+    ///
+    /// A fixed amount compacts a large store over and over for a small share of stale data,
+    /// and a small one rarely; a share scales with the store. The 1MB still has to be stale
+    /// too, so that a tiny store doesn't compact on every few writes.
+    pub fn with_adaptive_compaction(self, ratio: f64) -> KvStore {
+        self.writer.lock().unwrap().stale_ratio = Some(ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Saves the index when the last clone of the store is dropped, so that the next `open`
+    /// loads it instead of replaying the whole log.
+    ///
+    /// The saved index comes with the name, length and modification time of every log file.
+    /// `open` only uses it if the files still match, and replays the log otherwise, e.g.
+    /// after a crash or if a file was changed or added behind the store's back.
+    pub fn with_index_hints(self) -> KvStore {
+        self.writer.lock().unwrap().index_hints = true;
+        self
+    }
+
+    /// Seals the generations written by compaction with a checksum of the whole file.
+    ///
+    /// Each such file gets a header announcing a footer with the length and CRC32 of all its
+    /// records, checked when the store is opened. Per-record checksums can't tell when whole
+    /// records went missing at the end of a file; the footer can.
+    pub fn with_file_checksums(self) -> KvStore {
+        self.writer.lock().unwrap().file_checksums = true;
+        self
+    }
+
+    /// Logs a warning whenever the estimated memory of the key index grows past `bytes`.
+    ///
+    /// See `index_memory_estimate`.
+    pub fn with_index_memory_warning(self, bytes: usize) -> KvStore {
+        let mut writer = self.writer.lock().unwrap();
+        writer.index_memory_warning = Some(bytes);
+        writer.check_index_memory();
+        drop(writer);
+        self
+    }
+
+    /// Returns roughly how many bytes of memory the in-memory key index takes up.
+    ///
+    /// The index holds every live key, so this grows with the number and length of keys;
+    /// values are not kept in memory. It is an estimate: the total length of the keys
+    /// plus a fixed overhead per key.
+    pub fn index_memory_estimate(&self) -> usize {
+        self.index.memory_estimate()
+    }
+
+    /// Rebuilds the in-memory key index from its live entries, without compacting the log.
+    ///
+    /// Expired keys stay in the index until the next compaction; this drops them right away,
+    /// and reallocates the other keys without the spare capacity of the strings they were
+    /// set with. Removed keys are freed by the index as they are removed, so churn alone
+    /// leaves nothing to reclaim. Reads wait while the index is swapped, like during
+    /// `replace_with`.
+    pub fn compact_index(&self) -> Result<()> {
+        self.lock_writer()?.compact_index(&self.swap_gate);
+        Ok(())
+    }
+
+    /// Defers automatic compaction to `window`.
+    ///
+    /// Writes outside the window no longer compact unless the stale data exceeds the window's
+    /// hard cap. A background thread checks periodically and compacts once the window is
+    /// open; it stops when the last clone of the store is dropped.
+    pub fn with_compaction_window(self, window: CompactionWindow) -> KvStore {
+        let check_interval = window.check_interval();
+        self.writer.lock().unwrap().compaction_window = Some(window);
+
+        let writer = Arc::downgrade(&self.writer);
+        thread::spawn(move || loop {
+            thread::sleep(check_interval);
+            let Some(writer) = writer.upgrade() else {
+                return;
+            };
+            let mut writer = writer.lock().unwrap();
+            writer.deadline = None;
+            if let Err(e) = writer.compact_if_due() {
+                error!("Deferred compaction failed: {:?}", e);
+            }
+        });
+        self
+    }
+
+    /// Waits for work done under the writer's lock and settles what it leaves: a deferred
+    /// compaction in progress finishes, one that is due runs now rather than at the background
+    /// thread's next check, and buffered writes are flushed and synced to disk.
+    ///
+    /// The background thread of `with_compaction_window` compacts under the writer's lock, so
+    /// it is idle once this returns; nothing else runs in the background. Meant for tests and
+    /// shutdown; work caused by writes made meanwhile, on this or other clones, may still
+    /// start afterwards.
+    pub fn quiesce(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.deadline = None;
+        writer.compact_if_due()?;
+        writer.writer.sync()?;
+        Ok(())
+    }
+
+    /// Returns the capacity of the log writer's buffer and of each open log reader buffer,
+    /// both this handle's and the writer's (which opens the files created by compaction).
+    /// Only built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    #[doc(hidden)]
+    pub fn buffer_capacities(&self) -> (usize, Vec<usize>) {
+        let writer = self.writer.lock().unwrap();
+        let reader_capacities = [&self.reader, &writer.reader]
+            .iter()
+            .flat_map(|reader| {
+                let readers = reader.readers.borrow();
+                readers.values().map(|r| r.reader.capacity()).collect::<Vec<_>>()
+            })
+            .collect();
+        (writer.writer.writer.capacity(), reader_capacities)
+    }
+
+    /// Atomically replaces the whole contents of the store with those of the store in
+    /// `new_dir`, e.g. one prepared offline by importing a new dataset.
+    ///
+    /// The live records of `new_dir` are copied into this store, which keeps serving reads
+    /// meanwhile; writes wait until the replacement is done. Every read sees either the old
+    /// or the new dataset in full, never a mix, and a crash part way through leaves one of
+    /// the two on disk. `new_dir` itself is not modified.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `new_dir` can't be read, has a corrupt record, or has an unfinished
+    /// compaction (opening it once recovers that). The store's contents are unchanged then.
+    pub fn replace_with(&self, new_dir: &Path) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .replace_with(new_dir, &self.swap_gate)
+    }
+
+    /// Sets `key` to `value` until `ttl` has passed, after which the key reads as absent.
+    ///
+    /// Setting the key again, with or without a TTL, replaces the expiry. Expired keys are
+    /// dropped by the next compaction instead of being copied forward, and skipped when the
+    /// store is opened.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        self.lock_writer()?.set(key, value, expires_at)?;
+        Ok(())
+    }
+
+    /// Returns the format of the log files the store writes.
+    pub fn log_format_info(&self) -> LogFormatInfo {
+        let sealed = self.writer.lock().unwrap().file_checksums;
+        LogFormatInfo::new(sealed, CURRENT_SCHEMA_VERSION)
+    }
+
+    /// Returns the format of each log file of the store at `path`, by generation, read from
+    /// the files' headers and records without opening the store.
+    ///
+    /// A file without records reports the current schema version; a truncated record at the
+    /// end of a file is ignored.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `path` can't be read or a record can't be decoded.
+    pub fn log_file_formats(path: impl Into<PathBuf>) -> Result<Vec<(u64, LogFormatInfo)>> {
+        let path = path.into();
+        sorted_geneeration_list(&path)?
+            .into_iter()
+            .map(|generation| {
+                let mut cursor = GenerationCursor::open(&path, generation, DEFAULT_BUFFER_SIZE)?;
+                // A footer that doesn't check out was still announced by the header
+                let sealed = cursor.layout.checksum.is_some() || cursor.layout.bad_footer;
+                let version = oldest_record_version(&mut cursor)?;
+                let format = LogFormatInfo::new(sealed, version.unwrap_or(CURRENT_SCHEMA_VERSION));
+                Ok((generation, format))
+            })
+            .collect()
+    }
+
+    /// Rewrites the store at `path` so that every record is in the current schema version.
+    ///
+    /// Works like a compaction: live records are copied into a new generation, re-encoded if
+    /// they are older than `CURRENT_SCHEMA_VERSION`, and the old generations are removed.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `path` is not an existing directory or a record has an unsupported version.
+    pub fn migrate(path: impl Into<PathBuf>) -> Result<MigrationReport> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", path.display()),
+            )));
+        }
+
+        let generations = sorted_geneeration_list(&path)?.len() as u64;
+        let store = KvStore::open(path, None, None)?;
+        let upgraded_records = store.writer.lock().unwrap().rewrite_live_records(true)?;
+
+        Ok(MigrationReport {
+            generations,
+            live_records: store.index.len() as u64,
+            upgraded_records,
+        })
+    }
+
+    /// Replays the log of the store at `path` without opening it, checking every record.
+    ///
+    /// Unlike `open`, a bad record (checksum mismatch, undecodable or truncated record, or
+    /// unsupported version) doesn't stop the replay; it is listed in the report instead.
+    /// Nothing in `path` is modified.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `path` is not an existing directory or a log file can't be read.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", path.display()),
+            )));
+        }
+
+        let geneeration_list = sorted_geneeration_list(&path)?;
+        let mut generation_indexes = Vec::new();
+        let mut corrupt_records = Vec::new();
+        let mut records = 0;
+        let mut uncompacted = 0;
+
+        for &geneeration in &geneeration_list {
+            let file = File::open(log_path(&path, geneeration))?;
+            let mut reader = BufReaderWithPos::new(file, DEFAULT_BUFFER_SIZE)?;
+            let mut generation_index = BTreeMap::new();
+            let (uncompat, _, generation_records, _) = load_v2(
+                geneeration,
+                &mut reader,
+                &mut generation_index,
+                Some(&mut corrupt_records),
+            )?;
+            records += generation_records;
+            uncompacted += uncompat;
+            generation_indexes.push(generation_index);
+        }
+
+        let (index, merge_uncompacted, _) = merge_generation_indexes(generation_indexes);
+        Ok(VerifyReport {
+            generations: geneeration_list.len() as u64,
+            records,
+            keys: index.len() as u64,
+            corrupt_records,
+            uncompacted: uncompacted + merge_uncompacted,
+        })
+    }
+
+    /// Opens a read-only snapshot of the store at `path`, for tools that inspect a store
+    /// another process may be writing to.
+    ///
+    /// Best effort: the snapshot holds the writes whose records were complete on disk when it
+    /// was taken, so it may miss the most recent ones, and it never sees later writes. Nothing
+    /// in `path` is modified, not even an incomplete record at the end of the log. If a
+    /// compaction removes or is still writing a generation while the snapshot is taken, the
+    /// generation list is read again, up to 10 times.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `path` is not an existing directory, or with the last error if the log
+    /// kept changing or is corrupted.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStoreSnapshot> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", path.display()),
+            )));
+        }
+
+        let mut attempt = 1;
+        loop {
+            match KvStoreSnapshot::load(&path) {
+                Err(e) if attempt < SNAPSHOT_ATTEMPTS => {
+                    debug!("Retrying read-only open after the log changed: {:?}", e);
+                    thread::sleep(Duration::from_millis(10));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` as it was on disk at one point, from
+/// `KvStore::open_read_only`.
+///
+/// It keeps every generation file it read open, so its values stay readable after the
+/// writing process compacts them away.
+pub struct KvStoreSnapshot {
+    reader: KvStoreReader,
+    index: BTreeMap<String, CommandPos>,
+    sequence: u64,
+}
+
+impl KvStoreSnapshot {
+    /// Opens and replays the current generations, without writing to any of them.
+    fn load(path: &Path) -> Result<KvStoreSnapshot> {
+        let mut readers = HashMap::new();
+        let mut generation_indexes = Vec::new();
+        let mut sequence = 0;
+        for geneeration in readable_geneeration_list(path)? {
+            let file = File::open(log_path(path, geneeration))?;
+            let mut reader = BufReaderWithPos::new(file, DEFAULT_BUFFER_SIZE)?;
+            let mut generation_index = BTreeMap::new();
+            // A trailing incomplete record is being appended; it is left out, not truncated
+            let (_, seq, _, _) = load_v2(geneeration, &mut reader, &mut generation_index, None)?;
+            sequence = max(sequence, seq);
+            readers.insert(geneeration, reader);
+            generation_indexes.push(generation_index);
+        }
+        let (index, _, _) = merge_generation_indexes(generation_indexes);
+
+        let reader = KvStoreReader {
+            path: Arc::new(path.to_owned()),
+            reader_buffer_size: DEFAULT_BUFFER_SIZE,
+            readers: RefCell::new(readers),
+            shared: None,
+            mapped: None,
+            safe_point: Arc::new(AtomicU64::new(0)),
+            scratch: RefCell::new(Vec::new()),
+        };
+        Ok(KvStoreSnapshot { reader, index, sequence })
+    }
+
+    /// Returns the value of `key` in the snapshot, or `None` if it doesn't exist or has
+    /// expired since.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.index.get(key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => self.reader.read_value(cmd_pos),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, sorted by key.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in self.index.range(prefix.to_owned()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            if let Some(value) = self.reader.read_value(cmd_pos)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Returns all keys in the snapshot, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let now = now_millis();
+        self.index
+            .iter()
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Returns the sequence number of the latest write in the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Summary of a `KvStore::migrate` run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Number of generation files found before migrating
+    pub generations: u64,
+
+    /// Number of live records written to the migrated generation
+    pub live_records: u64,
+
+    /// Number of live records that were re-encoded from an older version
+    pub upgraded_records: u64,
+}
+
+/// How a store lays out its log files, for tools that read them without this crate; see
+/// `KvStore::log_format_info` and `KvStore::log_file_formats`.
+///
+/// A log file is a sequence of records, each a length prefix followed by a protobuf
+/// `KvsCommand` (see `proto/kvs_command.proto`). A sealed file starts with a header and ends
+/// with a footer checksumming its records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFormatInfo {
+    /// How records are delimited
+    pub framing: &'static str,
+
+    /// Byte order of the length prefixes and of the header and footer fields
+    pub endianness: &'static str,
+
+    /// Checksum each record carries in its `checksum` field, over its key, value and
+    /// expiry or deletion time
+    pub record_checksum: &'static str,
+
+    /// Checksum of a sealed file's records in its footer, or `None` if the files aren't
+    /// sealed. For a store, only the generations written by compaction are.
+    pub file_checksum: Option<&'static str>,
+
+    /// How values are compressed in the records
+    pub compression: &'static str,
+
+    /// Record schema version in the `version` field. For a file, the oldest of its records,
+    /// as stored: 0 for records written before versions were recorded.
+    pub schema_version: u64,
+}
+
+impl LogFormatInfo {
+    fn new(sealed: bool, schema_version: u64) -> LogFormatInfo {
+        LogFormatInfo {
+            framing: "u32 length prefix",
+            endianness: "little",
+            record_checksum: "crc32",
+            file_checksum: sealed.then_some("crc32"),
+            compression: "none",
+            schema_version,
+        }
+    }
+}
+
+/// Reads the records of a data directory's log files in order, without opening the store or
+/// building its index, for tools that consume the raw log, e.g. change data capture.
+///
+/// Generations are read oldest first, each in file order. They are listed when the reader is
+/// opened, so generations written since are not read; compaction copies live records into a
+/// new generation, so a key's records don't always come in sequence order.
+///
+/// Records are yielded as the `KvsCommand`s they were written as, brought up to the current
+/// schema version. A record cut short at the end of a plain log file, as left by a crash or
+/// by a write in progress, ends its generation; other unreadable records are handled by the
+/// reader's `CorruptionPolicy`. After an error the reader yields nothing more.
+pub struct LogReader {
+    dir: PathBuf,
+    generations: std::vec::IntoIter<u64>,
+    current: Option<GenerationCursor>,
+    policy: CorruptionPolicy,
+    skipped: Vec<CorruptRecord>,
+    failed: bool,
+}
+
+/// What a `LogReader` does with a record it can't read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Yield a `KvsError::CorruptRecord` with its location and stop
+    #[default]
+    Error,
+
+    /// Log a warning, note the record in `LogReader::skipped` and go on with the next one. A
+    /// bad length prefix in a sealed file skips the rest of the file.
+    Skip,
+}
+
+/// A record read by a `LogReader`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Generation of the log file holding the record
+    pub generation: u64,
+
+    /// Byte offset of the record's length prefix in that file
+    pub offset: u64,
+
+    /// The decoded record
+    pub command: KvsCommand,
+}
+
+/// A step of a `GenerationCursor`.
+enum CursorStep {
+    // A whole record at this offset, its message in `GenerationCursor::msg_bytes`
+    Record(u64),
+    Corrupt(CorruptRecord),
+    End,
+}
+
+/// Reads through the framed records of one generation, for `LogReader` and the other readers
+/// of whole log files, so that they all frame records and treat a short one alike.
+struct GenerationCursor {
+    generation: u64,
+    reader: BufReaderWithPos<File>,
+    layout: FileLayout,
+    pos: u64,
+    hasher: Hasher,
+    msg_bytes: Vec<u8>,
+
+    // Corruption found before the records, reported first
+    pending: Option<CorruptRecord>,
+
+    // Past the last record, with the file checksum checked
+    finished: bool,
+}
+
+impl LogReader {
+    /// Opens a reader of the log files in `path`, failing on corrupt records.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `path` or the compaction marker in it can't be read.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LogReader> {
+        let dir = path.into();
+        let generations = readable_geneeration_list(&dir)?.into_iter();
+        Ok(LogReader {
+            dir,
+            generations,
+            current: None,
+            policy: CorruptionPolicy::default(),
+            skipped: Vec::new(),
+            failed: false,
+        })
+    }
+
+    /// Sets what happens to records that can't be read.
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The records skipped so far under `CorruptionPolicy::Skip`.
+    pub fn skipped(&self) -> &[CorruptRecord] {
+        &self.skipped
+    }
+
+    fn step(&mut self) -> Result<Option<LogRecord>> {
+        loop {
+            let cursor = match &mut self.current {
+                Some(cursor) => cursor,
+                None => match self.generations.next() {
+                    Some(generation) => {
+                        let cursor =
+                            GenerationCursor::open(&self.dir, generation, DEFAULT_BUFFER_SIZE)?;
+                        self.current.insert(cursor)
+                    }
+                    None => return Ok(None),
+                },
+            };
+            let corrupt = match cursor.step()? {
+                CursorStep::Record(offset) => match decode_record(&cursor.msg_bytes) {
+                    // Sequence marks aren't commands
+                    Ok(KvsCommand { command: Some(kvs_command::Command::Mark(_)), .. }) => {
+                        continue;
+                    }
+                    Ok(command) => {
+                        let generation = cursor.generation;
+                        return Ok(Some(LogRecord { generation, offset, command }));
+                    }
+                    Err(e) => CorruptRecord::new(cursor.generation, offset, &e),
+                },
+                CursorStep::Corrupt(corrupt) => corrupt,
+                CursorStep::End => {
+                    self.current = None;
+                    continue;
+                }
+            };
+            match self.policy {
+                CorruptionPolicy::Error => return Err(KvsError::CorruptRecord(corrupt)),
+                CorruptionPolicy::Skip => {
+                    warn!("Skipping corrupt record in {}", corrupt);
+                    self.skipped.push(corrupt);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Result<LogRecord>> {
+        if self.failed {
+            return None;
+        }
+        let step = self.step();
+        self.failed = step.is_err();
+        step.transpose()
+    }
+}
+
+impl GenerationCursor {
+    /// Opens the log file of `generation` in `dir`, positioned at its first record.
+    fn open(dir: &Path, generation: u64, buffer_size: usize) -> Result<GenerationCursor> {
+        let file = File::open(log_path(dir, generation))?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReaderWithPos::new(file, buffer_size)?;
+        let layout = read_layout(&mut reader, file_len)?;
+        let pos = reader.seek(SeekFrom::Start(layout.records_start))?;
+        let pending = layout.bad_footer.then(|| CorruptRecord {
+            generation,
+            offset: file_len - FOOTER_LEN.min(file_len),
+            reason: "file footer is missing or doesn't match the file's length".to_owned(),
+        });
+        Ok(GenerationCursor {
+            generation,
+            reader,
+            layout,
+            pos,
+            hasher: Hasher::new(),
+            msg_bytes: Vec::new(),
+            pending,
+            finished: false,
+        })
+    }
+
+    /// Frames the next record. A record cut short at the end of a plain log file, as left by
+    /// a crash or by a write in progress, ends the generation; in a sealed file it's corrupt.
+    fn step(&mut self) -> Result<CursorStep> {
+        if let Some(corrupt) = self.pending.take() {
+            return Ok(CursorStep::Corrupt(corrupt));
+        }
+        let start_pos = self.pos;
+        if start_pos >= self.layout.records_end {
+            return Ok(self.finish());
+        }
+
+        // Like `load_v2`, check the length against the file before allocating for it
+        let mut len_bytes = [0u8; 4];
+        let msg_len = match self.reader.read_exact(&mut len_bytes) {
+            Ok(()) => Some(u32::from_le_bytes(len_bytes) as u64)
+                .filter(|&len| start_pos + 4 + len <= self.layout.records_end),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(msg_len) = msg_len else {
+            // Nothing after it can be framed, and the file checksum can't match either
+            self.pos = self.layout.records_end;
+            self.finished = true;
+            if self.layout.records_start == 0 {
+                return Ok(CursorStep::End);
+            }
+            let e = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record");
+            return Ok(CursorStep::Corrupt(CorruptRecord::new(self.generation, start_pos, &e)));
+        };
+        read_message(&mut self.reader, msg_len as usize, &mut self.msg_bytes)?;
+        self.pos += 4 + msg_len;
+        self.hasher.update(&len_bytes);
+        self.hasher.update(&self.msg_bytes);
+        Ok(CursorStep::Record(start_pos))
+    }
+
+    /// Checks the file checksum of a sealed file once its records are read.
+    fn finish(&mut self) -> CursorStep {
+        if std::mem::replace(&mut self.finished, true) {
+            return CursorStep::End;
+        }
+        match self.layout.checksum {
+            Some(checksum) if self.hasher.clone().finalize() != checksum => {
+                CursorStep::Corrupt(CorruptRecord {
+                    generation: self.generation,
+                    offset: self.layout.records_end,
+                    reason: "file checksum doesn't match the records".to_owned(),
+                })
+            }
+            _ => CursorStep::End,
+        }
+    }
+}
+
+/// Summary of a `KvStore::verify` run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of generation files replayed
+    pub generations: u64,
+
+    /// Number of valid records
+    pub records: u64,
+
+    /// Number of live keys
+    pub keys: u64,
+
+    /// Records that failed validation, in log order
+    pub corrupt_records: Vec<CorruptRecord>,
+
+    /// Bytes a compaction would reclaim
+    pub uncompacted: u64,
+}
+
+/// What a set does when it would take the log files past the disk quota, see
+/// `KvStore::with_disk_quota`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaPolicy {
+    /// Compact to reclaim the stale part of the log, and fail only if the set still doesn't
+    /// fit
+    #[default]
+    CompactFirst,
+
+    /// Fail right away; compaction keeps to its usual schedule
+    Reject,
+}
+
+/// Location of a record that failed validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRecord {
+    /// Generation of the log file holding the record
+    pub generation: u64,
+
+    /// Byte offset of the record's length prefix in that file
+    pub offset: u64,
+
+    /// What is wrong with the record
+    pub reason: String,
+}
+
+impl CorruptRecord {
+    fn new(generation: u64, offset: u64, reason: &impl std::fmt::Debug) -> CorruptRecord {
+        CorruptRecord {
+            generation,
+            offset,
+            reason: format!("{:?}", reason),
+        }
+    }
+}
+
+impl std::fmt::Display for CorruptRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.log at offset {}: {}",
+            self.generation, self.offset, self.reason
+        )
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_sequenced(key, value)?;
+        Ok(())
+    }
+
+    fn set_sequenced(&self, key: String, value: String) -> Result<Option<u64>> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        Ok(Some(self.lock_writer()?.set(key, value, 0)?))
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool> {
+        // Holding the writer lock keeps the check and the write atomic
+        let mut writer = self.lock_writer()?;
+        let exists = self.index.contains_key(&key);
+        let allowed = match condition {
+            SetCondition::IfAbsent => !exists,
+            SetCondition::IfPresent => exists,
+        };
+        if allowed {
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&key);
+            }
+            writer.set(key, value, 0)?;
+        }
+        Ok(allowed)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        if let Some(cache) = &self.cache {
+            for (key, _) in &pairs {
+                cache.invalidate(key);
+            }
+        }
+        writer.set_many(pairs)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptRecord` if the key is indexed at a record that isn't a
+    /// set, or `None` for it in recovery mode (see `KvStore::with_recovery_mode`).
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_gated(|| match self.index.get(&key) {
+            Some(cmd_pos) => self.read_indexed(&key, cmd_pos),
+            None => Ok(None),
+        })
+    }
+
+    /// Returns the length of the value from the index, without reading the log.
+    fn value_size(&self, key: String) -> Result<Option<u64>> {
+        Ok(self.index.get(&key).map(|cmd_pos| cmd_pos.value_len))
+    }
+
+    /// Looks the keys up in the index, without reading the log.
+    fn exists_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.read_gated(|| Ok(keys.iter().map(|key| self.index.contains_key(key)).collect()))
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_sequenced(key)?;
+        Ok(())
+    }
+
+    fn remove_sequenced(&self, key: String) -> Result<Option<u64>> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        Ok(Some(self.lock_writer()?.remove(key)?))
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<u64> {
+        let mut writer = self.lock_writer()?;
+        if let Some(cache) = &self.cache {
+            for key in &keys {
+                cache.invalidate(key);
+            }
+        }
+        writer.remove_many(keys)
+    }
+
+    fn range(&self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        self.read_gated(|| self.read_entries(self.index.range(start.clone()..end.clone())))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.read_gated(|| {
+            let entries = self
+                .index
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix));
+            self.read_entries(entries)
+        })
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.read_gated(|| Ok(self.index.range::<String, _>(..).map(|(key, _)| key).collect()))
+    }
+
+    fn count(&self) -> Result<u64> {
+        self.read_gated(|| Ok(self.index.live_len() as u64))
+    }
+
+    fn random_key(&self) -> Result<Option<String>> {
+        self.read_gated(|| Ok(self.index.random_key()))
+    }
+
+    fn index_memory(&self) -> Option<u64> {
+        Some(self.index_memory_estimate() as u64)
+    }
+
+    /// Compacts the log and syncs it and the data directory to disk.
+    ///
+    /// Returns the sequence number of the latest write, all of which survive a crash.
+    fn checkpoint(&self) -> Result<u64> {
+        self.writer.lock().unwrap().checkpoint()
+    }
+
+    /// Writes out the writer's buffer and syncs the active log to disk.
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().writer.sync()?;
+        Ok(())
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        Some(self.latest_sequence())
+    }
+
+    fn extensions(&self) -> Option<&dyn KvsEngineExt> {
+        Some(self)
+    }
+}
+
+impl KvsEngineExt for KvStore {
+    fn undelete(&self, key: String) -> Result<bool> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        self.lock_writer()?.undelete(key)
+    }
+
+    /// Decodes each key's set record for its metadata; values aren't served from the cache.
+    fn scan_prefix_verbose(&self, prefix: String) -> Result<Vec<ScanEntry>> {
+        self.read_gated(|| {
+            let mut scanned = Vec::new();
+            let entries = self
+                .index
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix));
+            for (key, cmd_pos) in entries {
+                if let Some(entry) = self.read_scan_entry(key, cmd_pos)? {
+                    scanned.push(entry);
+                }
+            }
+            Ok(scanned)
+        })
+    }
+
+    /// Looks `key` up in the index and reads the metadata of the record it points to.
+    ///
+    /// Like `get`, the lookup is retried if a compaction moved the record in the meantime.
+    fn explain_get(&self, key: String) -> Result<Option<GetPlan>> {
+        self.read_gated(|| {
+            let Some(mut cmd_pos) = self.index.get(&key) else {
+                return Ok(None);
+            };
+            loop {
+                let cached = self.cache.as_ref().is_some_and(|cache| {
+                    cache.contains(&key, cmd_pos.geneeration, cmd_pos.pos)
+                });
+                let record =
+                    self.reader.read_record(&cmd_pos, |msg_bytes| Ok(KvsCommand::decode(msg_bytes)?));
+                match record {
+                    Err(e) if self.reader.is_compacted(&cmd_pos) => {
+                        debug!("Retrying explain of a compacted record: {:?}", e);
+                        match self.index.get(&key) {
+                            Some(current) => cmd_pos = current,
+                            None => return Ok(None),
+                        }
+                    }
+                    Err(e) => return Err(e),
+                    Ok(cmd) => {
+                        return Ok(Some(GetPlan {
+                            generation: cmd_pos.geneeration,
+                            pos: cmd_pos.pos,
+                            len: cmd_pos.len,
+                            sequence: cmd.sequence_number,
+                            timestamp: cmd.timestamp,
+                            cached,
+                        }));
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Create a new log file with given geneerationeration number and add the reader to the readers map.
+///
+/// Returns the writer to the log.
+fn new_log_file(
+    path: &Path,
+    geneeration: u64,
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+    reader_buffer_size: usize,
+    writer_buffer_size: usize,
+    direct: bool,
+) -> Result<BufWriterWithPos<LogFile>> {
+    let path = log_path(path, geneeration);
+    let writer = BufWriterWithPos::new(LogFile::open(&path, direct)?, writer_buffer_size)?;
+    readers.insert(
+        geneeration,
+        BufReaderWithPos::new(File::open(&path)?, reader_buffer_size)?,
+    );
+    Ok(writer)
+}
+
+/// The generation a compaction writes, with the new position of every record copied to it.
+struct CompactionOutput {
+    writer: BufWriterWithPos<LogFile>,
+    generation: u64,
+    file_checksums: bool,
+    upgrade: bool,
+
+    // Position of the next record, and checksum of the records so far if sealing the file
+    pos: u64,
+    hasher: Hasher,
+
+    pos_updates: Vec<(String, CommandPos)>,
+    upgraded: u64,
+
+    // Highest sequence number of the records copied so far
+    highest_sequence: u64,
+}
+
+/// Just the sequence number of a `KvsCommand`, decoded without copying the rest of the record.
+#[derive(Clone, PartialEq, Message)]
+struct SequenceOnly {
+    #[prost(uint64, tag = "2")]
+    sequence_number: u64,
+}
+
+impl CompactionOutput {
+    fn new(
+        mut writer: BufWriterWithPos<LogFile>,
+        generation: u64,
+        file_checksums: bool,
+        upgrade: bool,
+    ) -> Result<CompactionOutput> {
+        let mut pos = 0;
+        if file_checksums {
+            writer.write_all(&SEALED_MAGIC)?;
+            writer.write_all(&HEADER_FLAG_FOOTER.to_le_bytes())?;
+            pos = HEADER_LEN;
+        }
+        Ok(CompactionOutput {
+            writer,
+            generation,
+            file_checksums,
+            upgrade,
+            pos,
+            hasher: Hasher::new(),
+            pos_updates: Vec::new(),
+            upgraded: 0,
+            highest_sequence: 0,
+        })
+    }
+
+    /// Appends the record of `key`, upgrading it first if asked to.
+    fn push(&mut self, key: String, cmd_pos: CommandPos, msg_bytes: &mut Vec<u8>) -> Result<()> {
+        let sequence = SequenceOnly::decode(&msg_bytes[..])?.sequence_number;
+        self.highest_sequence = max(self.highest_sequence, sequence);
+        if self.upgrade {
+            let cmd = KvsCommand::decode(&msg_bytes[..])?;
+            if (cmd.version as u64) < CURRENT_SCHEMA_VERSION {
+                *msg_bytes = upgrade_command(cmd)?.encode_to_vec();
+                self.upgraded += 1;
+            }
+        }
+        let msg_len = msg_bytes.len();
+
+        let len_bytes = (msg_len as u32).to_le_bytes();
+        self.writer.write_all(&len_bytes)?;
+        self.writer.write_all(msg_bytes)?;
+        if self.file_checksums {
+            self.hasher.update(&len_bytes);
+            self.hasher.update(msg_bytes);
+        }
+
+        self.pos_updates.push((
+            key,
+            CommandPos {
+                geneeration: self.generation,
+                pos: self.pos,
+                len: 4 + msg_len as u64,
+                value_len: cmd_pos.value_len,
+                expires_at: cmd_pos.expires_at,
+            },
+        ));
+        self.pos += 4 + msg_len as u64;
+        Ok(())
+    }
+
+    /// Appends a remove record for a soft-deleted key. Returns the record's length.
+    fn push_tombstone(&mut self, key: String, tombstone: &Tombstone) -> Result<u64> {
+        self.highest_sequence = max(self.highest_sequence, tombstone.sequence);
+        let msg_bytes = KvsCommand::remove(key, tombstone.deleted_at, tombstone.sequence)
+            .encode_to_vec();
+        let len_bytes = (msg_bytes.len() as u32).to_le_bytes();
+        self.writer.write_all(&len_bytes)?;
+        self.writer.write_all(&msg_bytes)?;
+        if self.file_checksums {
+            self.hasher.update(&len_bytes);
+            self.hasher.update(&msg_bytes);
+        }
+        let len = 4 + msg_bytes.len() as u64;
+        self.pos += len;
+        Ok(len)
+    }
+
+    /// Appends a sequence mark carrying `sequence`, unless a copied record carries it already.
+    fn push_mark(&mut self, sequence: u64) -> Result<()> {
+        if sequence <= self.highest_sequence {
+            return Ok(());
+        }
+        let msg_bytes = KvsCommand::mark(sequence).encode_to_vec();
+        let len_bytes = (msg_bytes.len() as u32).to_le_bytes();
+        self.writer.write_all(&len_bytes)?;
+        self.writer.write_all(&msg_bytes)?;
+        if self.file_checksums {
+            self.hasher.update(&len_bytes);
+            self.hasher.update(&msg_bytes);
+        }
+        self.pos += 4 + msg_bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Seals the generation if asked to and syncs it to disk.
+    ///
+    /// Returns each key with the position of its copied record, and how many records were
+    /// upgraded.
+    fn finish(mut self) -> Result<(Vec<(String, CommandPos)>, u64)> {
+        if self.file_checksums {
+            // Seal the generation: nothing is appended to it after compaction
+            self.writer.write_all(&FOOTER_MAGIC)?;
+            self.writer.write_all(&(self.pos - HEADER_LEN).to_le_bytes())?;
+            self.writer.write_all(&self.hasher.finalize().to_le_bytes())?;
+        }
+        self.writer.sync()?;
+        Ok((self.pos_updates, self.upgraded))
+    }
+}
+
+/// Reads the message of the record at `pos` into `msg_bytes`.
+fn read_record_at(
+    reader: &mut BufReaderWithPos<File>,
+    pos: u64,
+    msg_bytes: &mut Vec<u8>,
+) -> Result<()> {
+    if reader.pos != pos {
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let msg_len = u32::from_le_bytes(len_bytes) as usize;
+    read_message(reader, msg_len, msg_bytes)?;
+    Ok(())
+}
+
+/// Progress of a compaction, persisted in the compaction marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompactionState {
+    // The compaction file may be incomplete
+    Started,
+    // The compaction file is fully written and synced; stale generations may remain
+    Complete,
+}
+
+/// Atomically records the state of the compaction into `generation`.
+fn write_compaction_marker(dir: &Path, generation: u64, state: CompactionState) -> Result<()> {
+    let state = match state {
+        CompactionState::Started => "started",
+        CompactionState::Complete => "complete",
+    };
+    let tmp_path = dir.join(format!("{}.tmp", COMPACTION_MARKER));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(format!("{} {}", generation, state).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(COMPACTION_MARKER))?;
+    Ok(())
+}
+
+/// Sums the sizes of the log files in `dir`, except the one of `current_generation`.
+fn closed_log_bytes(dir: &Path, current_generation: u64) -> Result<u64> {
+    let mut bytes = 0;
+    for generation in sorted_geneeration_list(dir)? {
+        if generation != current_generation {
+            bytes += fs::metadata(log_path(dir, generation))?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+/// Turns a failure to write into the data directory `dir` into
+/// `KvsError::ReadOnlyFilesystem`, so that `open` doesn't fail with a bare I/O error.
+fn writable<T>(dir: &Path, result: Result<T>) -> Result<T> {
+    match result {
+        Err(KvsError::IoError(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ReadOnlyFilesystem | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            debug!("Data directory {:?} is not writable: {}", dir, e);
+            Err(KvsError::ReadOnlyFilesystem(dir.to_owned()))
+        }
+        result => result,
+    }
+}
+
+/// Finishes or rolls back a compaction that was interrupted by a crash.
+///
+/// If the compaction file wasn't completely written it is discarded, leaving the old
+/// generations untouched. If it was, the stale generations it replaces are removed.
+/// Either way the directory ends up as if the compaction ran atomically.
+fn recover_compaction(dir: &Path) -> Result<()> {
+    let Some((generation, state)) = read_compaction_marker(dir)? else {
+        return Ok(());
+    };
+
+    match state {
+        CompactionState::Started => {
+            remove_if_exists(&log_path(dir, generation))?;
+            // The writer's generation is created empty right before the compaction file
+            let writer_path = log_path(dir, generation + 1);
+            if writer_path.exists() && fs::metadata(&writer_path)?.len() == 0 {
+                fs::remove_file(writer_path)?;
+            }
+        }
+        CompactionState::Complete => {
+            for stale_generation in sorted_geneeration_list(dir)?
+                .into_iter()
+                .filter(|&stale_generation| stale_generation < generation)
+            {
+                fs::remove_file(log_path(dir, stale_generation))?;
+            }
+        }
     }
+
+    fs::remove_file(dir.join(COMPACTION_MARKER))?;
+    Ok(())
 }
 
-impl KvsEngine for KvStore {
-    /// Gets the string value of a given string key.
-    ///
-    /// Returns `None` if the given key does not exist.
-    ///
-    /// # Errors
-    ///
-    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.geneeration)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+/// Reads the generation and state of the compaction recorded in `dir`, if there is one.
+fn read_compaction_marker(dir: &Path) -> Result<Option<(u64, CompactionState)>> {
+    let marker = match fs::read_to_string(dir.join(COMPACTION_MARKER)) {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let (generation, state) = match marker.split_once(' ') {
+        Some((generation, "started")) => (generation, CompactionState::Started),
+        Some((generation, "complete")) => (generation, CompactionState::Complete),
+        _ => return Err(KvsError::CorruptedData),
+    };
+    let generation: u64 = generation.parse().map_err(|_| KvsError::CorruptedData)?;
+    Ok(Some((generation, state)))
+}
+
+/// Replays every generation in `generations` on its own thread and merges the partial
+/// indexes, truncating incomplete records at the end of a file.
+///
+/// Returns a reader for each generation, the index, the soft-deleted keys, how many bytes
+/// are stale and the highest sequence number.
+#[allow(clippy::type_complexity)]
+fn replay(
+    path: &Path,
+    generations: &[u64],
+    reader_buffer_size: usize,
+) -> Result<(HashMap<u64, BufReaderWithPos<File>>, Index, BTreeMap<String, Deleted>, u64, u64)> {
+    // Only the generation being written to can end in a record a crash cut short
+    let newest = generations.last().copied();
+    let loaded = generations
+        .par_iter()
+        .map(|&geneeration| -> Result<_> {
+            let mut reader = BufReaderWithPos::new(
+                File::open(log_path(path, geneeration))?,
+                reader_buffer_size,
+            )?;
+            let mut generation_index = BTreeMap::new();
+            let (uncompat, seq, _, truncated_at) =
+                load_v2(geneeration, &mut reader, &mut generation_index, None)?;
+            if let Some(len) = truncated_at
+                && Some(geneeration) != newest
+            {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record");
+                return Err(KvsError::CorruptRecord(CorruptRecord::new(geneeration, len, &e)));
+            }
+            if let Some(len) = truncated_at {
+                warn!(
+                    "Truncating incomplete record at the end of {}.log (offset {})",
+                    geneeration, len
+                );
+                let truncate = OpenOptions::new()
+                    .write(true)
+                    .open(log_path(path, geneeration))
+                    .and_then(|file| file.set_len(len));
+                writable(path, truncate.map_err(KvsError::from))?;
+            }
+            Ok((geneeration, reader, generation_index, uncompat, seq))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut readers = HashMap::new();
+    let mut generation_indexes = Vec::new();
+    let mut highest_seq = 0;
+    let mut uncompacted = 0;
 
-            // Prefix
-            let mut len_bytes = [0u8; 4];
-            reader.read_exact(&mut len_bytes)?;
-            let msg_len = u32::from_le_bytes(len_bytes) as usize;
+    for (geneeration, reader, generation_index, uncompat, seq) in loaded {
+        uncompacted += uncompat;
+        readers.insert(geneeration, reader);
+        generation_indexes.push(generation_index);
+        highest_seq = max(highest_seq, seq);
+    }
 
-            // Read message
-            let mut msg_bytes = vec![0; msg_len];
-            reader.read_exact(&mut msg_bytes)?;
+    let (index, merge_uncompacted, deleted) = merge_generation_indexes(generation_indexes);
+    uncompacted += merge_uncompacted;
+    Ok((readers, index.into_iter().collect(), deleted, uncompacted, highest_seq))
+}
 
-            let cmd = KvsCommand::decode(&msg_bytes[..])?;
-            if !cmd.verify_checksum() {
-                return Err(KvsError::CorruptedData);
-            }
+/// The index of a store as it was when its last clone was dropped, saved by stores with
+/// index hints so that the next open can skip replaying the log.
+#[derive(Serialize, Deserialize)]
+struct IndexHint {
+    // Fingerprint of the generation files the index describes
+    generations: Vec<GenerationFingerprint>,
+    highest_sequence: u64,
+    uncompacted: u64,
+    entries: Vec<(String, CommandPos)>,
+    deleted: Vec<(String, Deleted)>,
+}
+
+/// Identifies the contents of a generation file without reading it: any write to the file
+/// changes its length or modification time.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct GenerationFingerprint {
+    generation: u64,
+    len: u64,
+    // Nanoseconds since the Unix epoch
+    modified: u64,
+}
 
-            if let Some(command) = cmd.command {
-                if let kvs_command::Command::Set(set) = command {
-                    Ok(Some(set.value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
+impl IndexHint {
+    /// Builds the index, leaving out keys that expired since the hint was saved.
+    ///
+    /// Returns the index, the soft-deleted keys and how many bytes are stale.
+    fn into_index(self) -> (Index, BTreeMap<String, Deleted>, u64) {
+        let now = now_millis();
+        let mut uncompacted = self.uncompacted;
+        let index = self
+            .entries
+            .into_iter()
+            .filter(|(_, cmd_pos)| {
+                let expired = cmd_pos.is_expired(now);
+                if expired {
+                    uncompacted += cmd_pos.len;
                 }
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
-        }
+                !expired
+            })
+            .collect();
+        (index, self.deleted.into_iter().collect(), uncompacted)
     }
 }
-/// Create a new log file with given geneerationeration number and add the reader to the readers map.
+
+/// Fingerprints the given generation files in `dir`.
+fn fingerprint_generations(dir: &Path, generations: &[u64]) -> Result<Vec<GenerationFingerprint>> {
+    generations
+        .iter()
+        .map(|&generation| {
+            let metadata = fs::metadata(log_path(dir, generation))?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+            Ok(GenerationFingerprint { generation, len: metadata.len(), modified })
+        })
+        .collect()
+}
+
+/// Writes `hint` to the index hint file in `dir`: its CRC32 (little endian), then the hint.
+fn write_index_hint(dir: &Path, hint: &IndexHint) -> Result<()> {
+    let body = bincode::serialize(hint)?;
+    let tmp_path = dir.join(format!("{}.tmp", INDEX_HINT));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&crc32fast::hash(&body).to_le_bytes())?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(INDEX_HINT))?;
+    Ok(())
+}
+
+/// Reads and removes the index hint in `dir`, returning it only if it describes exactly the
+/// given generations as they are on disk.
 ///
-/// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
-    geneeration: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-    reader_buffer_size: usize,
-    writer_buffer_size: usize,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(path, geneeration);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new().create(true).append(true).open(&path)?,
-        writer_buffer_size,
-    )?;
-    readers.insert(
-        geneeration,
-        BufReaderWithPos::new(File::open(&path)?, reader_buffer_size)?,
-    );
-    Ok(writer)
+/// A hint is used at most once: the store writes to its files right after opening, so it
+/// would be out of date anyway. A missing, damaged or stale hint means a full replay.
+fn take_index_hint(dir: &Path, generations: &[u64]) -> Result<Option<IndexHint>> {
+    let hint_path = dir.join(INDEX_HINT);
+    let bytes = match fs::read(&hint_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    writable(dir, fs::remove_file(&hint_path).map_err(KvsError::from))?;
+
+    let hint = bytes
+        .split_first_chunk::<4>()
+        .filter(|(crc, body)| u32::from_le_bytes(**crc) == crc32fast::hash(body))
+        .and_then(|(_, body)| bincode::deserialize::<IndexHint>(body).ok());
+    let Some(hint) = hint else {
+        warn!("The index hint is damaged, replaying the log");
+        return Ok(None);
+    };
+    if hint.generations != fingerprint_generations(dir, generations)? {
+        warn!("The log files changed since the index hint was saved, replaying the log");
+        return Ok(None);
+    }
+    Ok(Some(hint))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
 }
 
 /// Returns sorted geneerationeration numbers in the given directory.
+///
+/// Only files named exactly as `log_path` names a generation are generations: `01.log` is
+/// not taken for `1.log`. Anything else, such as a `{n}.log.tmp` left behind by a crash, is
+/// ignored.
 fn sorted_geneeration_list(path: &Path) -> Result<Vec<u64>> {
     let mut geneeration_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let stem = path.file_name()?.to_str()?.strip_suffix(".log")?;
+            let geneeration: u64 = stem.parse().ok()?;
+            (geneeration.to_string() == stem).then_some(geneeration)
         })
-        .flatten()
         .collect();
     geneeration_list.sort_unstable();
     Ok(geneeration_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Like `sorted_geneeration_list`, but leaves out the file of a compaction still in progress,
+/// for readers that don't recover the directory: it's incomplete, and its records are copies of
+/// ones in older generations.
+fn readable_geneeration_list(path: &Path) -> Result<Vec<u64>> {
+    let mut geneeration_list = sorted_geneeration_list(path)?;
+    if let Some((geneeration, CompactionState::Started)) = read_compaction_marker(path)? {
+        geneeration_list.retain(|&other| other != geneeration);
+    }
+    Ok(geneeration_list)
+}
+
+/// The latest command seen for a key while loading a generation.
+#[derive(Debug)]
+struct LoadedCommand {
+    sequence: u64,
+
+    // `None` if the key was removed by that command
+    pos: Option<CommandPos>,
+
+    // For a soft delete: when it happened, its length and the set it removed, if seen yet
+    deleted_at: u64,
+    tombstone_len: u64,
+    last_set: Option<CommandPos>,
+}
+
+impl LoadedCommand {
+    fn set(sequence: u64, pos: Option<CommandPos>) -> LoadedCommand {
+        LoadedCommand { sequence, pos, deleted_at: 0, tombstone_len: 0, last_set: None }
+    }
+}
+
+/// Reads every command of a generation in file order, each with its key and sequence number,
+/// and the position of the record for a set or `None` for a remove.
+///
+/// Meant for a store that was opened already: a truncated trailing record ends the
+/// generation, anything else unreadable is an error.
+fn load_versions(mut cursor: GenerationCursor) -> Result<Vec<(String, u64, Option<CommandPos>)>> {
+    let mut commands = Vec::new();
+    loop {
+        let start_pos = match cursor.step()? {
+            CursorStep::Record(start_pos) => start_pos,
+            CursorStep::Corrupt(corrupt) => return Err(KvsError::CorruptRecord(corrupt)),
+            CursorStep::End => return Ok(commands),
+        };
+        let cmd = decode_record(&cursor.msg_bytes)?;
+        match cmd.command {
+            Some(kvs_command::Command::Set(set)) => {
+                let cmd_pos = CommandPos {
+                    geneeration: cursor.generation,
+                    pos: start_pos,
+                    len: cursor.pos - start_pos,
+                    value_len: set.value.len() as u64,
+                    expires_at: set.expires_at,
+                };
+                commands.push((set.key, cmd.sequence_number, Some(cmd_pos)));
+            }
+            Some(kvs_command::Command::Remove(remove)) => {
+                commands.push((remove.key, cmd.sequence_number, None));
+            }
+            Some(kvs_command::Command::Mark(_)) => {}
+            None => return Err(KvsError::UnexpectedCommandType),
+        }
+    }
+}
+
+/// Returns the oldest `version` of the records of the cursor's generation, as stored, or
+/// `None` if there are none. Only decoding a record can fail: a bad footer, a truncated
+/// record or a file checksum mismatch is passed over.
+fn oldest_record_version(cursor: &mut GenerationCursor) -> Result<Option<u64>> {
+    let mut oldest: Option<u64> = None;
+    loop {
+        match cursor.step()? {
+            CursorStep::Record(_) => {
+                let cmd = KvsCommand::decode(&cursor.msg_bytes[..]).map_err(KvsError::Deserialize)?;
+                oldest = Some(oldest.map_or(cmd.version as u64, |v| v.min(cmd.version as u64)));
+            }
+            CursorStep::Corrupt(_) => {}
+            CursorStep::End => return Ok(oldest),
+        }
+    }
+}
+
+/// Load the whole log file and store the latest command of each key in the generation's index map.
+///
+/// Removes are kept as tombstones so they can shadow sets from older generations when the
+/// per-generation indexes are merged.
+///
+/// A bad record or file checksum is an error, unless `corrupt` is given: then it is recorded
+/// there and skipped, and a truncated record ends the generation.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// Without `corrupt`, a record cut short by the end of a plain (unsealed) log ends the
+/// generation too: it was being appended when the process died, so it was never acknowledged.
+/// Its offset is returned for the caller to truncate the file at.
+///
+/// Sealed files are read from past their header, and their records checked against the footer.
+///
+/// Returns how many bytes can be saved after a compaction, the highest sequence number seen,
+/// the number of valid records and the offset of a truncated trailing record.
 fn load_v2(
     geneeration: u64,
     reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
-) -> Result<(u64, u64)> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    index: &mut BTreeMap<String, LoadedCommand>,
+    mut corrupt: Option<&mut Vec<CorruptRecord>>,
+) -> Result<(u64, u64, u64, Option<u64>)> {
+    let file_len = reader.reader.get_ref().metadata()?.len();
+    let layout = read_layout(reader, file_len)?;
+    if layout.bad_footer {
+        let offset = file_len - FOOTER_LEN.min(file_len);
+        match corrupt.as_deref_mut() {
+            Some(corrupt) => corrupt.push(CorruptRecord {
+                generation: geneeration,
+                offset,
+                reason: "file footer is missing or doesn't match the file's length".to_owned(),
+            }),
+            None => return Err(KvsError::CorruptedData),
+        }
+    }
+
+    let mut pos = reader.seek(SeekFrom::Start(layout.records_start))?;
     let mut uncompacted = 0;
     let mut highest_sequence = 0;
+    let mut records = 0;
+    let mut truncated_at = None;
+    let mut msg_bytes = Vec::new();
+    let now = now_millis();
+    let mut file_hasher = Hasher::new();
 
     loop {
         let start_pos = pos;
+        if pos >= layout.records_end {
+            break;
+        }
 
         // Read the message length (4 bytes) prefix:
         // 4 bytes (32 bits) allows us to represent message sizes up to ~4GB
         // ToDo: Use variable length encoding like varint
+        // The file may end inside the prefix or the message; check the length against the
+        // file before allocating for it
         let mut len_bytes = [0u8; 4];
-        match reader.read_exact(&mut len_bytes) {
-            Ok(_) => (),
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                // reached eof
-                break;
-            }
+        let msg_len = match reader.read_exact(&mut len_bytes) {
+            Ok(()) => Some(u32::from_le_bytes(len_bytes) as usize)
+                .filter(|&len| pos + 4 + len as u64 <= layout.records_end),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
             Err(e) => return Err(e.into()),
-        }
-
-        let msg_len = u32::from_le_bytes(len_bytes) as usize;
+        };
+        let Some(msg_len) = msg_len else {
+            let e = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record");
+            match corrupt.as_deref_mut() {
+                Some(corrupt) => corrupt.push(CorruptRecord::new(geneeration, start_pos, &e)),
+                None if layout.records_start == 0 => truncated_at = Some(start_pos),
+                None => return Err(e.into()),
+            }
+            break;
+        };
         pos += 4;
 
         // Read message bytes
-        let mut msg_bytes = vec![0u8; msg_len];
-        reader.read_exact(&mut msg_bytes)?;
+        read_message(reader, msg_len, &mut msg_bytes)?;
         pos += msg_len as u64;
+        file_hasher.update(&len_bytes);
+        file_hasher.update(&msg_bytes);
 
-        // Deserialize the protobuf message
-        let cmd = match KvsCommand::decode(&msg_bytes[..]) {
+        let cmd = match decode_record(&msg_bytes) {
             Ok(cmd) => cmd,
-            Err(e) => return Err(KvsError::Deserialize(e)),
+            Err(e) => match corrupt.as_deref_mut() {
+                Some(corrupt) => {
+                    corrupt.push(CorruptRecord::new(geneeration, start_pos, &e));
+                    continue;
+                }
+                None => return Err(e),
+            },
         };
+        records += 1;
 
-        if !cmd.verify_checksum() {
-            return Err(KvsError::CorruptedData);
-        }
-
-        highest_sequence = max(highest_sequence, cmd.sequence_number);
+        let sequence = cmd.sequence_number;
+        highest_sequence = max(highest_sequence, sequence);
         match cmd.command {
             Some(kvs_command::Command::Set(set)) => {
                 let key = set.key;
@@ -529,28 +3384,249 @@ fn load_v2(
                     geneeration,
                     pos: start_pos,
                     len: pos - start_pos,
+                    value_len: set.value.len() as u64,
+                    expires_at: set.expires_at,
                 };
 
-                if let Some(old_cmd) = index.insert(key, new_pos) {
+                // An expired set still shadows older commands on the key, like a remove
+                let expired = new_pos.is_expired(now);
+                let loaded = LoadedCommand::set(sequence, (!expired).then_some(new_pos));
+                if let Some(LoadedCommand { pos: Some(old_cmd), .. }) = index.insert(key, loaded) {
                     uncompacted += old_cmd.len;
                 }
+                if expired {
+                    uncompacted += new_pos.len;
+                }
             }
 
             Some(kvs_command::Command::Remove(remove)) => {
                 let key = remove.key;
-                if let Some(old_cmd) = index.remove(&key) {
+                let old = index.remove(&key);
+                if let Some(LoadedCommand { pos: Some(old_cmd), .. }) = old {
                     uncompacted += old_cmd.len;
                 }
+                let last_set = match old {
+                    Some(old) if remove.deleted_at != 0 => old.pos.or(old.last_set),
+                    _ => None,
+                };
+                let loaded = LoadedCommand {
+                    sequence,
+                    pos: None,
+                    deleted_at: remove.deleted_at,
+                    tombstone_len: pos - start_pos,
+                    last_set,
+                };
+                index.insert(key, loaded);
                 // The remove command itself can be deleted in compaction
                 uncompacted += pos - start_pos;
             }
+
+            // Only its sequence number counts
+            Some(kvs_command::Command::Mark(_)) => {}
             None => {
                 return Err(KvsError::UnexpectedCommandType);
             }
         }
     }
 
-    Ok((uncompacted, highest_sequence))
+    if let Some(checksum) = layout.checksum
+        && file_hasher.finalize() != checksum
+    {
+        match corrupt {
+            Some(corrupt) => corrupt.push(CorruptRecord {
+                generation: geneeration,
+                offset: layout.records_end,
+                reason: "file checksum doesn't match the records".to_owned(),
+            }),
+            None => return Err(KvsError::CorruptedData),
+        }
+    }
+
+    Ok((uncompacted, highest_sequence, records, truncated_at))
+}
+
+/// Where the records of a log file are.
+struct FileLayout {
+    records_start: u64,
+    records_end: u64,
+
+    // CRC32 the records must match, from the footer of a sealed file
+    checksum: Option<u32>,
+
+    // The header announces a footer that is missing or doesn't fit the file
+    bad_footer: bool,
+}
+
+/// Reads the header and footer of a log file, if it has them.
+fn read_layout(reader: &mut BufReaderWithPos<File>, file_len: u64) -> Result<FileLayout> {
+    let mut layout = FileLayout {
+        records_start: 0,
+        records_end: file_len,
+        checksum: None,
+        bad_footer: false,
+    };
+    if file_len < HEADER_LEN {
+        return Ok(layout);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    if header[..4] != SEALED_MAGIC {
+        return Ok(layout);
+    }
+    layout.records_start = HEADER_LEN;
+    let flags = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if flags & HEADER_FLAG_FOOTER == 0 {
+        return Ok(layout);
+    }
+
+    layout.bad_footer = true;
+    if file_len < HEADER_LEN + FOOTER_LEN {
+        return Ok(layout);
+    }
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    reader.seek(SeekFrom::Start(file_len - FOOTER_LEN))?;
+    reader.read_exact(&mut footer)?;
+    if footer[..4] != FOOTER_MAGIC {
+        return Ok(layout);
+    }
+    // Even if it doesn't fit the file, a footer is no record
+    layout.records_end = file_len - FOOTER_LEN;
+    let records_len = u64::from_le_bytes(footer[4..12].try_into().unwrap());
+    if HEADER_LEN + records_len + FOOTER_LEN != file_len {
+        return Ok(layout);
+    }
+
+    layout.checksum = Some(u32::from_le_bytes(footer[12..].try_into().unwrap()));
+    layout.bad_footer = false;
+    Ok(layout)
+}
+
+/// Passes the record at `cmd_pos` to `decode` straight out of the mapping of its sealed log
+/// file.
+fn read_mapped<T>(
+    mapping: &Mapping,
+    cmd_pos: &CommandPos,
+    decode: impl FnOnce(&[u8]) -> Result<T>,
+) -> Result<T> {
+    let bytes = mapping.bytes();
+    let start = cmd_pos.pos as usize;
+    let msg_bytes = bytes.get(start..start + 4).and_then(|len_bytes| {
+        let msg_len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        bytes.get(start + 4..start + 4 + msg_len)
+    });
+    match msg_bytes {
+        Some(msg_bytes) => decode(msg_bytes),
+        None => {
+            let e = io::Error::new(io::ErrorKind::UnexpectedEof, "record past the end of the log");
+            Err(e.into())
+        }
+    }
+}
+
+/// Reads a `msg_len` byte message into `buf`, reusing its allocation.
+fn read_message(reader: &mut impl Read, msg_len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    buf.resize(msg_len, 0);
+    reader.read_exact(buf)
+}
+
+/// Decodes a record, checks its checksum and brings it up to the current version.
+fn decode_record(msg_bytes: &[u8]) -> Result<KvsCommand> {
+    // Deserialize the protobuf message
+    let cmd = KvsCommand::decode(msg_bytes).map_err(KvsError::Deserialize)?;
+    if !cmd.verify_checksum() {
+        return Err(KvsError::CorruptedData);
+    }
+    if cmd.command.is_none() {
+        return Err(KvsError::UnexpectedCommandType);
+    }
+    upgrade_command(cmd)
+}
+
+/// Merges per-generation indexes (in ascending generation order) into the store's index.
+///
+/// For each key the command with the highest sequence number wins; on a tie the newer
+/// generation wins, matching a sequential replay. Keys whose winning command is a remove
+/// are dropped, and those removed by a soft delete are returned apart along with the last
+/// value they had.
+///
+/// Returns the index, how many bytes of sets were shadowed by newer commands and the
+/// soft-deleted keys.
+#[allow(clippy::type_complexity)]
+fn merge_generation_indexes(
+    generation_indexes: Vec<BTreeMap<String, LoadedCommand>>,
+) -> (BTreeMap<String, CommandPos>, u64, BTreeMap<String, Deleted>) {
+    let mut merged: BTreeMap<String, LoadedCommand> = BTreeMap::new();
+    let mut uncompacted = 0;
+
+    for generation_index in generation_indexes {
+        for (key, loaded) in generation_index {
+            match merged.entry(key) {
+                btree_map::Entry::Occupied(mut entry) => {
+                    let stale = if loaded.sequence >= entry.get().sequence {
+                        let mut loaded = loaded;
+                        if loaded.deleted_at != 0 && loaded.last_set.is_none() {
+                            loaded.last_set = entry.get().pos.or(entry.get().last_set);
+                        }
+                        entry.insert(loaded).pos
+                    } else {
+                        loaded.pos
+                    };
+                    if let Some(stale) = stale {
+                        uncompacted += stale.len;
+                    }
+                }
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(loaded);
+                }
+            }
+        }
+    }
+
+    let mut index = BTreeMap::new();
+    let mut deleted = BTreeMap::new();
+    for (key, loaded) in merged {
+        match (loaded.pos, loaded.last_set) {
+            (Some(pos), _) => {
+                index.insert(key, pos);
+            }
+            (None, Some(pos)) if loaded.deleted_at != 0 => {
+                // Both records were counted as stale, but are kept until the retention passes
+                uncompacted = uncompacted.saturating_sub(pos.len + loaded.tombstone_len);
+                let tombstone = Tombstone {
+                    deleted_at: loaded.deleted_at,
+                    sequence: loaded.sequence,
+                    len: loaded.tombstone_len,
+                };
+                deleted.insert(key, Deleted { pos, tombstone });
+            }
+            (None, _) => {}
+        }
+    }
+    (index, uncompacted, deleted)
+}
+
+/// Brings a decoded record up to `CURRENT_SCHEMA_VERSION`.
+///
+/// Version 0 records were written before the version field was populated; their layout is
+/// identical to version 1. Version 2 added `expires_at` to sets, which older records lack and
+/// so never expire. Version 3 added `deleted_at` to removes; older removes are plain ones.
+/// Records from a newer version are rejected rather than decoded with semantics they may not
+/// have.
+fn upgrade_command(mut cmd: KvsCommand) -> Result<KvsCommand> {
+    match cmd.version as u64 {
+        0..=2 => {
+            cmd.version = CURRENT_SCHEMA_VERSION as u32;
+            Ok(cmd)
+        }
+        CURRENT_SCHEMA_VERSION => Ok(cmd),
+        found => Err(KvsError::UnsupportedRecordVersion {
+            found,
+            supported: CURRENT_SCHEMA_VERSION,
+        }),
+    }
 }
 
 fn log_path(dir: &Path, geneeration: u64) -> PathBuf {
@@ -559,41 +3635,67 @@ fn log_path(dir: &Path, geneeration: u64) -> PathBuf {
 
 trait Checksumable {
     fn calculate_checksum(&self) -> u32;
-    fn get_fields_for_checksum(&self) -> Vec<u8>;
+    fn update_checksum(&self, hasher: &mut Hasher);
 }
 
 impl Checksumable for kvs_command::Command {
     fn calculate_checksum(&self) -> u32 {
         let mut hasher = Hasher::new();
-        hasher.update(&self.get_fields_for_checksum());
+        self.update_checksum(&mut hasher);
         hasher.finalize()
     }
 
-    fn get_fields_for_checksum(&self) -> Vec<u8> {
+    /// Feeds the checksummed fields to `hasher`, without copying them into one buffer.
+    fn update_checksum(&self, hasher: &mut Hasher) {
         match self {
-            _command @ kvs_command::Command::Set(set) => {
-                let mut fields = Vec::new();
-                fields.extend_from_slice(set.key.as_bytes());
-                fields.extend_from_slice(set.value.as_bytes());
-                fields
+            kvs_command::Command::Set(set) => {
+                hasher.update(set.key.as_bytes());
+                hasher.update(set.value.as_bytes());
+                // Left out when unset, so sets written before expiry existed still match
+                if set.expires_at != 0 {
+                    hasher.update(&set.expires_at.to_le_bytes());
+                }
             }
 
-            _command @ kvs_command::Command::Remove(remove) => {
-                let mut fields = Vec::new();
-                fields.extend_from_slice(remove.key.as_bytes());
-                fields
+            kvs_command::Command::Remove(remove) => {
+                hasher.update(remove.key.as_bytes());
+                if remove.deleted_at != 0 {
+                    hasher.update(&remove.deleted_at.to_le_bytes());
+                }
             }
+
+            kvs_command::Command::Mark(_) => {}
         }
     }
 }
 
 impl KvsCommand {
-    fn set(key: String, value: String, sequence: u64) -> KvsCommand {
+    fn set(key: String, value: String, expires_at: u64, sequence: u64) -> KvsCommand {
         let command = kvs_command::Command::Set(KvsSet {
             key,
             value,
             key_size: 0,
             value_size: 0,
+            expires_at,
+        });
+        let checksum = command.calculate_checksum();
+        KvsCommand {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            sequence_number: sequence,
+            checksum,
+            version: CURRENT_SCHEMA_VERSION as u32,
+            command: command.into(),
+        }
+    }
+
+    fn remove(key: String, deleted_at: u64, sequence: u64) -> KvsCommand {
+        let command = kvs_command::Command::Remove(KvsRemove {
+            key,
+            key_size: 0,
+            deleted_at,
         });
         let checksum = command.calculate_checksum();
         KvsCommand {
@@ -608,8 +3710,8 @@ impl KvsCommand {
         }
     }
 
-    fn remove(key: String, sequence: u64) -> KvsCommand {
-        let command = kvs_command::Command::Remove(KvsRemove { key, key_size: 0 });
+    fn mark(sequence: u64) -> KvsCommand {
+        let command = kvs_command::Command::Mark(KvsSequenceMark {});
         let checksum = command.calculate_checksum();
         KvsCommand {
             timestamp: SystemTime::now()
@@ -636,23 +3738,50 @@ impl KvsCommand {
 }
 
 /// Represents the position and length of a json-serialized command in the log.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct CommandPos {
     geneeration: u64,
     pos: u64,
     len: u64,
+
+    // Byte length of the value, so its size is known without reading the record
+    value_len: u64,
+
+    // Milliseconds since the Unix epoch after which the key reads as absent; 0 for never
+    expires_at: u64,
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((geneeration, range): (u64, Range<u64>)) -> Self {
-        CommandPos {
-            geneeration,
-            pos: range.start,
-            len: range.end - range.start,
-        }
+impl CommandPos {
+    /// Whether the key has expired at `now`, in milliseconds since the Unix epoch.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
     }
 }
 
+/// The remove record of a soft-deleted key.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Tombstone {
+    // Milliseconds since the Unix epoch; 0 for a plain remove
+    deleted_at: u64,
+    sequence: u64,
+    len: u64,
+}
+
+/// A soft-deleted key: its last set record is kept, and its value can be read and restored,
+/// until the store's retention has passed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Deleted {
+    pos: CommandPos,
+    tombstone: Tombstone,
+}
+
+/// The current time in milliseconds since the Unix epoch, the unit of `expires_at`.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -696,6 +3825,12 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    /// Returns the position up to which bytes were handed to the file. `pos` runs ahead of it
+    /// by the bytes still in the buffer, which a failed flush leaves there.
+    fn committed(&self) -> u64 {
+        self.pos - self.writer.buffer().len() as u64
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -710,9 +3845,176 @@ impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     }
 }
 
+impl BufWriterWithPos<LogFile> {
+    /// Flushes the buffer and syncs the file's contents to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().sync_all()
+    }
+}
+
 impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.pos = self.writer.seek(pos)?;
         Ok(self.pos)
     }
 }
+
+/// The in-memory index from keys to the positions of their latest set commands.
+///
+/// Overwriting a key updates its slot in place rather than replacing the `SkipMap` entry:
+/// a replacing insert removes the old entry before linking the new one, so a concurrent
+/// lookup could miss the key altogether. Writes are serialized by the writer lock.
+///
+/// Expired keys stay in the index until compaction drops them, but lookups skip them.
+struct Index {
+    entries: SkipMap<String, RwLock<CommandPos>>,
+
+    // Total length of the indexed keys, for the memory estimate; the same whichever way the
+    // strings were allocated, so a reopened store estimates the same
+    key_bytes: AtomicUsize,
+
+    // Number of entries with an expiry, so counting keys only scans when there are any
+    expiring: AtomicUsize,
+}
+
+impl Index {
+    /// Returns the position of `key`, unless it is missing or expired.
+    fn get(&self, key: &str) -> Option<CommandPos> {
+        let cmd_pos = *self.entries.get(key)?.value().read().unwrap();
+        (!cmd_pos.is_expired(now_millis())).then_some(cmd_pos)
+    }
+
+    /// Whether `key` is indexed and not expired.
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Number of entries, including expired ones.
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of keys that haven't expired.
+    fn live_len(&self) -> usize {
+        if self.expiring.load(Ordering::SeqCst) == 0 {
+            return self.len();
+        }
+        let now = now_millis();
+        self.entries
+            .iter()
+            .filter(|entry| !entry.value().read().unwrap().is_expired(now))
+            .count()
+    }
+
+    /// Picks a key that hasn't expired uniformly at random; only the picked key is cloned.
+    fn random_key(&self) -> Option<String> {
+        let now = now_millis();
+        let live = self
+            .entries
+            .iter()
+            .filter(|entry| !entry.value().read().unwrap().is_expired(now));
+        choose(live).map(|entry| entry.key().clone())
+    }
+
+    /// Keeps `expiring` up to date as an entry goes from `old` to `new`.
+    fn track_expiry(&self, old: Option<&CommandPos>, new: Option<&CommandPos>) {
+        let expires = |cmd_pos: Option<&CommandPos>| cmd_pos.is_some_and(|pos| pos.expires_at != 0);
+        match (expires(old), expires(new)) {
+            (false, true) => {
+                self.expiring.fetch_add(1, Ordering::SeqCst);
+            }
+            (true, false) => {
+                self.expiring.fetch_sub(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+    }
+
+    /// Roughly how many bytes of memory the index takes up.
+    fn memory_estimate(&self) -> usize {
+        self.key_bytes.load(Ordering::SeqCst) + self.len() * INDEX_ENTRY_OVERHEAD
+    }
+
+    /// Points `key` at `cmd_pos`, returning the position it replaced.
+    fn insert(&self, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+        let old = match self.entries.get(&key) {
+            Some(entry) => Some(std::mem::replace(
+                &mut *entry.value().write().unwrap(),
+                cmd_pos,
+            )),
+            None => {
+                self.key_bytes.fetch_add(key.len(), Ordering::SeqCst);
+                self.entries.insert(key, RwLock::new(cmd_pos));
+                None
+            }
+        };
+        self.track_expiry(old.as_ref(), Some(&cmd_pos));
+        old
+    }
+
+    /// Replaces every entry with `entries`.
+    ///
+    /// Not atomic for concurrent readers; callers keep them out while it runs.
+    fn replace_all(&self, entries: Vec<(String, CommandPos)>) {
+        self.entries.clear();
+        let mut key_bytes = 0;
+        let mut expiring = 0;
+        for (key, cmd_pos) in entries {
+            key_bytes += key.len();
+            expiring += usize::from(cmd_pos.expires_at != 0);
+            self.entries.insert(key, RwLock::new(cmd_pos));
+        }
+        self.key_bytes.store(key_bytes, Ordering::SeqCst);
+        self.expiring.store(expiring, Ordering::SeqCst);
+    }
+
+    fn remove(&self, key: &str) -> Option<CommandPos> {
+        let entry = self.entries.remove(key)?;
+        self.key_bytes.fetch_sub(entry.key().len(), Ordering::SeqCst);
+        let cmd_pos = *entry.value().read().unwrap();
+        self.track_expiry(Some(&cmd_pos), None);
+        Some(cmd_pos)
+    }
+
+    /// Returns the unexpired entries with keys in `range`, sorted by key.
+    fn range<'a, Q, R>(&'a self, range: R) -> impl Iterator<Item = (String, CommandPos)> + 'a
+    where
+        String: Borrow<Q>,
+        Q: Ord + ?Sized + 'a,
+        R: RangeBounds<Q> + 'a,
+    {
+        let now = now_millis();
+        self.entries.range(range).filter_map(move |entry| {
+            let cmd_pos = *entry.value().read().unwrap();
+            (!cmd_pos.is_expired(now)).then(|| (entry.key().clone(), cmd_pos))
+        })
+    }
+
+    /// Returns every entry, expired or not, sorted by key.
+    fn entries(&self) -> impl Iterator<Item = (String, CommandPos)> + '_ {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value().read().unwrap()))
+    }
+}
+
+impl FromIterator<(String, CommandPos)> for Index {
+    fn from_iter<I: IntoIterator<Item = (String, CommandPos)>>(iter: I) -> Index {
+        let mut key_bytes = 0;
+        let mut expiring = 0;
+        let entries = iter
+            .into_iter()
+            .map(|(key, cmd_pos)| {
+                key_bytes += key.len();
+                expiring += usize::from(cmd_pos.expires_at != 0);
+                (key, RwLock::new(cmd_pos))
+            })
+            .collect();
+        Index {
+            entries,
+            key_bytes: AtomicUsize::new(key_bytes),
+            expiring: AtomicUsize::new(expiring),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+#[cfg(feature = "fault-injection")]
+use std::sync::Arc;
+
+use log::warn;
+
+#[cfg(feature = "fault-injection")]
+use super::faults::Faults;
+
+/// The file behind the active log's writer: a plain file, or one written with direct I/O.
+pub(crate) enum LogFile {
+    Plain(File),
+    #[cfg(target_os = "linux")]
+    Direct(direct::DirectFile),
+    /// A log with faults injected, to exercise error handling
+    #[cfg(feature = "fault-injection")]
+    Faulty(Box<LogFile>, Arc<Faults>),
+}
+
+impl LogFile {
+    /// Opens the log at `path` for appending, creating it if needed.
+    ///
+    /// With `direct`, whole blocks are written with direct I/O where the platform and the
+    /// file system support it. Where they don't, it logs a warning and falls back to a
+    /// plain file.
+    pub(crate) fn open(path: &Path, direct: bool) -> io::Result<LogFile> {
+        #[cfg(target_os = "linux")]
+        if direct {
+            match direct::DirectFile::open(path) {
+                Ok(file) => return Ok(LogFile::Direct(file)),
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => warn!(
+                    "Direct I/O is not supported for {:?}, writing through the page cache",
+                    path
+                ),
+                Err(e) => return Err(e),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        if direct {
+            warn!("Direct I/O is only supported on Linux, writing through the page cache");
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(LogFile::Plain(file))
+    }
+
+    /// Truncates or extends the log to `len` bytes; the writer continues from the new end.
+    pub(crate) fn set_len(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            LogFile::Plain(file) => file.set_len(len),
+            #[cfg(target_os = "linux")]
+            LogFile::Direct(file) => file.set_len(len),
+            #[cfg(feature = "fault-injection")]
+            LogFile::Faulty(file, faults) => {
+                faults.file_op()?;
+                file.set_len(len)
+            }
+        }
+    }
+
+    /// Writes out anything buffered and syncs the file's contents to disk.
+    pub(crate) fn sync_all(&mut self) -> io::Result<()> {
+        match self {
+            LogFile::Plain(file) => file.sync_all(),
+            #[cfg(target_os = "linux")]
+            LogFile::Direct(file) => file.sync_all(),
+            #[cfg(feature = "fault-injection")]
+            LogFile::Faulty(file, faults) => {
+                faults.flush()?;
+                file.sync_all()
+            }
+        }
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogFile::Plain(file) => file.write(buf),
+            #[cfg(target_os = "linux")]
+            LogFile::Direct(file) => file.write(buf),
+            #[cfg(feature = "fault-injection")]
+            LogFile::Faulty(file, faults) => {
+                if faults.write_fails() {
+                    file.write_all(&buf[..buf.len() / 2])?;
+                    return Err(io::Error::other("injected write failure"));
+                }
+                file.write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogFile::Plain(file) => file.flush(),
+            #[cfg(target_os = "linux")]
+            LogFile::Direct(file) => file.flush(),
+            #[cfg(feature = "fault-injection")]
+            LogFile::Faulty(file, faults) => {
+                file.flush()?;
+                faults.flush()
+            }
+        }
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            LogFile::Plain(file) => file.seek(pos),
+            #[cfg(target_os = "linux")]
+            LogFile::Direct(file) => file.seek(pos),
+            #[cfg(feature = "fault-injection")]
+            LogFile::Faulty(file, _) => file.seek(pos),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod direct {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    use std::path::Path;
+
+    use log::warn;
+
+    /// Alignment of direct I/O: of the buffer's address, the file offset and the length.
+    /// A multiple of every common logical block size.
+    const BLOCK_SIZE: usize = 4096;
+
+    /// How many bytes a `DirectFile` buffers before writing the whole blocks out.
+    const BUFFER_SIZE: usize = 256 * BLOCK_SIZE;
+
+    /// An append-only file whose whole blocks are written with `O_DIRECT`, bypassing the page
+    /// cache.
+    ///
+    /// Direct writes must cover whole, aligned blocks, but the log has to end right after its
+    /// last record. So the last, partial block is written through a second, plain handle to
+    /// the file, and kept in the buffer until it fills up and goes out with direct I/O too.
+    /// The file never holds padding, and reads through other handles see every flushed byte.
+    pub(crate) struct DirectFile {
+        direct: File,
+        plain: File,
+
+        // Block-aligned buffer, a window into `memory`; holds the file from `buffer_start`
+        memory: Vec<u8>,
+        offset: usize,
+        buffered: usize,
+
+        // File offset of the first buffered byte, always block-aligned
+        buffer_start: u64,
+
+        // How many buffered bytes are already in the file, written through `plain`
+        flushed: usize,
+
+        // Cleared if the file system turns out to reject direct writes after all
+        direct_writes: bool,
+    }
+
+    impl DirectFile {
+        pub(crate) fn open(path: &Path) -> io::Result<DirectFile> {
+            let plain = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(path)?;
+            let direct = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)?;
+            let memory = vec![0; BUFFER_SIZE + BLOCK_SIZE];
+            let offset = memory.as_ptr().align_offset(BLOCK_SIZE);
+            let mut file = DirectFile {
+                direct,
+                plain,
+                memory,
+                offset,
+                buffered: 0,
+                buffer_start: 0,
+                flushed: 0,
+                direct_writes: true,
+            };
+            let len = file.plain.metadata()?.len();
+            file.load_tail(len)?;
+            Ok(file)
+        }
+
+        /// Continues writing at `len`, reading the partial block before it into the buffer.
+        fn load_tail(&mut self, len: u64) -> io::Result<()> {
+            let tail = (len % BLOCK_SIZE as u64) as usize;
+            self.buffer_start = len - tail as u64;
+            self.plain.seek(SeekFrom::Start(self.buffer_start))?;
+            let offset = self.offset;
+            self.plain.read_exact(&mut self.memory[offset..offset + tail])?;
+            self.buffered = tail;
+            self.flushed = tail;
+            Ok(())
+        }
+
+        fn len(&self) -> u64 {
+            self.buffer_start + self.buffered as u64
+        }
+
+        pub(crate) fn set_len(&mut self, len: u64) -> io::Result<()> {
+            self.flush()?;
+            self.plain.set_len(len)?;
+            self.load_tail(len)
+        }
+
+        pub(crate) fn sync_all(&mut self) -> io::Result<()> {
+            self.flush()?;
+            self.plain.sync_all()
+        }
+
+        /// Writes the whole buffered blocks with direct I/O and drops them from the buffer,
+        /// keeping the partial block at the end.
+        fn write_blocks(&mut self) -> io::Result<()> {
+            let whole = self.buffered - self.buffered % BLOCK_SIZE;
+            if whole == 0 {
+                return Ok(());
+            }
+            let blocks = &self.memory[self.offset..self.offset + whole];
+            if self.direct_writes {
+                match self.direct.write_all_at(blocks, self.buffer_start) {
+                    Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                        warn!("Direct writes are rejected, writing through the page cache: {}", e);
+                        self.direct_writes = false;
+                    }
+                    result => result?,
+                }
+            }
+            if !self.direct_writes {
+                self.plain.write_all_at(blocks, self.buffer_start)?;
+            }
+
+            self.memory
+                .copy_within(self.offset + whole..self.offset + self.buffered, self.offset);
+            self.buffer_start += whole as u64;
+            self.buffered -= whole;
+            self.flushed = self.flushed.saturating_sub(whole);
+            Ok(())
+        }
+    }
+
+    impl Write for DirectFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.buffered == BUFFER_SIZE {
+                self.write_blocks()?;
+            }
+            let len = buf.len().min(BUFFER_SIZE - self.buffered);
+            let start = self.offset + self.buffered;
+            self.memory[start..start + len].copy_from_slice(&buf[..len]);
+            self.buffered += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.write_blocks()?;
+            if self.flushed < self.buffered {
+                let tail = &self.memory[self.offset + self.flushed..self.offset + self.buffered];
+                self.plain
+                    .write_all_at(tail, self.buffer_start + self.flushed as u64)?;
+                self.flushed = self.buffered;
+            }
+            Ok(())
+        }
+    }
+
+    impl Seek for DirectFile {
+        /// Only reports the position: the file is append-only.
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(0) | SeekFrom::Current(0) => Ok(self.len()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "direct log files can only be appended to",
+                )),
+            }
+        }
+    }
+
+    impl Drop for DirectFile {
+        fn drop(&mut self) {
+            if let Err(e) = self.flush() {
+                warn!("Cannot write out the end of a direct log file: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Sealed log files mapped into memory, shared by all handles of a store, by generation; see
+/// `KvStore::with_mmap_reads`.
+///
+/// A generation is mapped on its first read. Only sealed files are: compaction writes them
+/// whole and nothing appends to them or truncates them afterwards, so a mapping never goes
+/// stale. Other generations are remembered as unmapped and read with buffered readers.
+///
+/// Compaction drops the mappings of the generations it removes, but a read in flight holds
+/// its own `Arc` to the mapping, whose pages stay readable after the file is removed.
+pub(crate) struct MappedLogs {
+    maps: RwLock<HashMap<u64, Option<Arc<Mapping>>>>,
+}
+
+impl MappedLogs {
+    pub(crate) fn new() -> MappedLogs {
+        MappedLogs {
+            maps: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The mapping of the log file of `generation` at `path`, mapped on first use, or `None`
+    /// if the file isn't sealed.
+    ///
+    /// A generation older than `safe_point`, loaded under the lock so that it can't miss a
+    /// concurrent `close_before`, was compacted away: its mapping serves this read only.
+    pub(crate) fn get(
+        &self,
+        path: &Path,
+        generation: u64,
+        safe_point: &AtomicU64,
+    ) -> io::Result<Option<Arc<Mapping>>> {
+        if let Some(mapping) = self.maps.read().unwrap().get(&generation) {
+            return Ok(mapping.clone());
+        }
+        // Mapped without the write lock, so reads of mapped generations don't wait on it
+        let mapping = Mapping::of_sealed(path)?.map(Arc::new);
+        let mut maps = self.maps.write().unwrap();
+        if generation < safe_point.load(Ordering::SeqCst) {
+            return Ok(mapping);
+        }
+        // Another read may have mapped it in the meantime
+        Ok(maps.entry(generation).or_insert(mapping).clone())
+    }
+
+    /// Drops the mappings of generations older than `safe_point`.
+    pub(crate) fn close_before(&self, safe_point: u64) {
+        self.maps
+            .write()
+            .unwrap()
+            .retain(|&generation, _| generation >= safe_point);
+    }
+}
+
+/// A whole log file mapped read-only into memory, unmapped when dropped.
+pub(crate) struct Mapping {
+    #[cfg(target_os = "linux")]
+    ptr: *const u8,
+    #[cfg(target_os = "linux")]
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value alone
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the file at `path` if it is sealed, i.e. starts with `SEALED_MAGIC`.
+    #[cfg(target_os = "linux")]
+    fn of_sealed(path: &Path) -> io::Result<Option<Mapping>> {
+        use std::fs::File;
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        match file.read_exact(&mut magic) {
+            Ok(()) if magic == super::kv::SEALED_MAGIC => {}
+            Ok(()) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = file.metadata()?.len() as usize;
+        // SAFETY: a fresh read-only mapping of a whole open file, which stays valid after the
+        // descriptor is closed; sealed files are never written to again
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Mapping { ptr: ptr as *const u8, len }))
+    }
+
+    /// Memory mapping is only supported on Linux; elsewhere nothing is mapped.
+    #[cfg(not(target_os = "linux"))]
+    fn of_sealed(_path: &Path) -> io::Result<Option<Mapping>> {
+        Ok(None)
+    }
+
+    /// The file's contents.
+    pub(crate) fn bytes(&self) -> &[u8] {
+        #[cfg(target_os = "linux")]
+        // SAFETY: `ptr` points at `len` mapped bytes until `self` is dropped
+        return unsafe { std::slice::from_raw_parts(self.ptr, self.len) };
+        #[cfg(not(target_os = "linux"))]
+        unreachable!("nothing is mapped on this platform")
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` are the mapping made in `of_sealed`, unmapped only here
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
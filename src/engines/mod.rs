@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::Random;
 use crate::Result;
 
 #[allow(missing_docs)]
@@ -10,14 +13,181 @@ pub trait KvsEngine : Clone + Send + 'static
 {
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Like `set`, but returns the sequence number assigned to the write, or `None` if the
+    /// engine doesn't number its writes.
+    fn set_sequenced(&self, key: String, value: String) -> Result<Option<u64>>;
+
+    /// Sets the value only if `condition` holds, checked atomically with the write.
+    ///
+    /// Returns whether the value was set.
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> Result<bool>;
+
+    /// Sets several keys as one atomic unit: either all pairs become visible or none do.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()>;
+
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Returns the length in bytes of the value of `key`, or `None` if it doesn't exist.
+    fn value_size(&self, key: String) -> Result<Option<u64>>;
+
+    /// Returns whether each of `keys` exists, in order. `KvStore` answers from its index,
+    /// without reading the log.
+    fn exists_many(&self, keys: Vec<String>) -> Result<Vec<bool>>;
+
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Like `remove`, but returns the sequence number assigned to the write, or `None` if the
+    /// engine doesn't number its writes.
+    fn remove_sequenced(&self, key: String) -> Result<Option<u64>>;
+
+    /// Removes several keys in one batch, skipping the ones that don't exist.
+    ///
+    /// Returns how many keys were removed. `KvStore` compacts right away when they were a
+    /// large share of its keys, so a bulk cleanup frees its disk space promptly. It writes
+    /// the batch with a single flush but not atomically: after a crash in the middle of it,
+    /// only some of the keys may be removed.
+    fn remove_many(&self, keys: Vec<String>) -> Result<u64>;
+
+    /// Returns the key/value pairs with keys in `start..end`, sorted by key.
+    fn range(&self, start: String, end: String) -> Result<Vec<(String, String)>>;
+
+    /// Returns the key/value pairs whose keys start with `prefix`, sorted by key.
+    fn scan_prefix(&self, prefix: String) -> Result<Vec<(String, String)>>;
+
+    /// Returns all keys, sorted.
+    fn keys(&self) -> Result<Vec<String>>;
+
+    /// Returns the number of keys.
+    fn count(&self) -> Result<u64>;
+
+    /// Returns a key picked uniformly at random, or `None` if there are no keys (RANDOMKEY).
+    ///
+    /// Walks all keys, so it takes time linear in their number.
+    fn random_key(&self) -> Result<Option<String>>;
+
+    /// Returns the estimated memory held by the engine's in-memory key index, or `None` if
+    /// the engine doesn't keep one.
+    fn index_memory(&self) -> Option<u64>;
+
+    /// Compacts the store and makes everything written so far durable.
+    ///
+    /// Returns a token for the checkpoint. For `KvStore` it is the sequence number of the
+    /// latest write, so a store reopened after a crash holds exactly the writes up to it.
+    /// sled has no sequence numbers; its tokens only increase from one checkpoint to the next.
+    fn checkpoint(&self) -> Result<u64>;
+
+    /// Makes everything written so far durable, without compacting.
+    fn flush(&self) -> Result<()>;
+
+    /// Returns the sequence number of the latest write visible to `get`, or `None` if the
+    /// engine doesn't number its writes.
+    fn sequence(&self) -> Option<u64>;
+
+    /// Returns the engine's `KvsEngineExt` operations, or `None` if it doesn't support them.
+    fn extensions(&self) -> Option<&dyn KvsEngineExt> {
+        None
+    }
+}
+
+/// Operations only some engines support, reached through `KvsEngine::extensions`. `KvStore`
+/// implements them; a server on an engine without them answers that they are unsupported.
+pub trait KvsEngineExt {
+    /// Restores the value of a soft-deleted key that is still within its retention.
+    ///
+    /// Returns whether the key was restored, see `KvStore::with_soft_deletes`.
+    fn undelete(&self, key: String) -> Result<bool>;
+
+    /// Like `KvsEngine::scan_prefix`, along with the metadata of each key's latest write.
+    fn scan_prefix_verbose(&self, prefix: String) -> Result<Vec<ScanEntry>>;
+
+    /// Reports where `get` finds the value of `key`, or `None` if the key doesn't exist.
+    ///
+    /// Read-only: the value isn't decoded or cached.
+    fn explain_get(&self, key: String) -> Result<Option<GetPlan>>;
+}
+
+/// Where the value of a key lives, as reported by `KvsEngineExt::explain_get`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetPlan {
+    /// Generation of the log file holding the key's latest set record
+    pub generation: u64,
+
+    /// Byte offset of the record in the log file
+    pub pos: u64,
+
+    /// Length of the record in bytes, including its length prefix
+    pub len: u64,
+
+    /// Sequence number of the write
+    pub sequence: u64,
+
+    /// When the record was written, in seconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Whether `get` would serve the value from the read cache instead of the log
+    pub cached: bool,
+}
+
+/// A key/value pair from `KvsEngineExt::scan_prefix_verbose`, with its latest write's metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanEntry {
+    /// The key
+    pub key: String,
+
+    /// The value
+    pub value: String,
+
+    /// Sequence number of the write
+    pub sequence: u64,
+
+    /// When the value was written, in seconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Length of the value in bytes
+    pub size: u64,
+
+    /// When the key expires, in milliseconds since the Unix epoch, if it was set with a TTL
+    pub expires_at: Option<u64>,
 }
 
+/// Precondition for a conditional set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetCondition {
+    /// Only set if the key doesn't exist yet (SETNX)
+    IfAbsent,
+
+    /// Only set if the key already exists (SET XX)
+    IfPresent,
+}
+
+/// Picks one of `items` uniformly at random in a single pass, by reservoir sampling.
+pub(crate) fn choose<T>(items: impl Iterator<Item = T>) -> Option<T> {
+    let random = Random::new();
+    let mut chosen = None;
+    for (seen, item) in items.enumerate() {
+        // Keep the `seen + 1`-th item with probability 1 / (seen + 1)
+        if random.u64(seen).is_multiple_of(seen as u64 + 1) {
+            chosen = Some(item);
+        }
+    }
+    chosen
+}
 
+mod cache;
+mod compaction_window;
+#[cfg(feature = "fault-injection")]
+mod faults;
 mod kv;
+mod log_file;
+mod mapped;
+mod reader_pool;
 mod sled;
 
-pub use self::kv::KvStore;
+pub use self::compaction_window::CompactionWindow;
+#[cfg(feature = "fault-injection")]
+pub use self::faults::FaultConfig;
+pub use self::kv::{
+    CorruptRecord, CorruptionPolicy, KvStore, KvStoreSnapshot, LogFormatInfo, LogReader,
+    LogRecord, MigrationReport, QuotaPolicy, VerifyReport,
+};
 pub use self::sled::SledKvsEngine;
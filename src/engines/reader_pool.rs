@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Log file readers shared by all handles of a store, by generation.
+///
+/// A read borrows an idle reader of its generation, or opens a new one if there is none, and
+/// hands it back afterwards. Only `per_generation` idle readers are kept per generation, so
+/// the open files are bounded by the number of generations plus the reads in flight, however
+/// many threads read.
+pub(crate) struct ReaderPool<R> {
+    idle: Mutex<HashMap<u64, Vec<R>>>,
+    per_generation: usize,
+}
+
+impl<R> ReaderPool<R> {
+    pub(crate) fn new(per_generation: usize) -> ReaderPool<R> {
+        ReaderPool {
+            idle: Mutex::new(HashMap::new()),
+            per_generation: per_generation.max(1),
+        }
+    }
+
+    /// Takes an idle reader of `generation`, if there is one.
+    pub(crate) fn take(&self, generation: u64) -> Option<R> {
+        self.idle.lock().unwrap().get_mut(&generation)?.pop()
+    }
+
+    /// Hands `reader` back once a read is done with it.
+    ///
+    /// It is closed instead if its generation already has enough idle readers, or was
+    /// compacted away: older than `safe_point`, loaded under the lock so that it can't miss a
+    /// concurrent `close_before`.
+    pub(crate) fn put(&self, generation: u64, reader: R, safe_point: &AtomicU64) {
+        let mut idle = self.idle.lock().unwrap();
+        if generation < safe_point.load(Ordering::SeqCst) {
+            return;
+        }
+        let readers = idle.entry(generation).or_default();
+        if readers.len() < self.per_generation {
+            readers.push(reader);
+        }
+    }
+
+    /// Closes the idle readers of generations older than `safe_point`.
+    pub(crate) fn close_before(&self, safe_point: u64) {
+        self.idle
+            .lock()
+            .unwrap()
+            .retain(|&generation, _| generation >= safe_point);
+    }
+}
@@ -1,33 +1,158 @@
+use std::path::Path;
+#[cfg(feature = "fault-injection")]
+use std::collections::VecDeque;
+#[cfg(feature = "fault-injection")]
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::Db;
-use crate::engines::KvsEngine;
+use crate::engines::{choose, KvsEngine, SetCondition};
+use crate::KvsError;
 
+/// Writes are left to sled's background flushing, every 500ms by default, rather than flushed
+/// one by one; `flush` and `checkpoint` make everything written so far durable.
 #[derive(Clone)]
 #[allow(missing_docs)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+
+    // Errors to return in place of the next writes, see `inject_errors`
+    #[cfg(feature = "fault-injection")]
+    injected: Arc<Mutex<VecDeque<sled::Error>>>,
+}
+
+/// How many times a write to sled is tried before its error is returned
+const SLED_WRITE_ATTEMPTS: u32 = 3;
+
+/// The wait before the first retry of a write; it doubles with every retry
+const SLED_RETRY_BACKOFF: Duration = Duration::from_millis(10);
 
 #[allow(missing_docs)]
 impl SledKvsEngine {
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        SledKvsEngine {
+            db,
+            #[cfg(feature = "fault-injection")]
+            injected: Arc::default(),
+        }
+    }
+
+    /// Opens the sled database at `path` with a page cache of about `cache_bytes` and a
+    /// background flush every `flush_every_ms` milliseconds, or none if `None`.
+    pub fn open_with_config(
+        path: impl AsRef<Path>,
+        cache_bytes: u64,
+        flush_every_ms: Option<u64>,
+    ) -> crate::Result<SledKvsEngine> {
+        let db = sled::Config::new()
+            .path(path.as_ref())
+            .cache_capacity(cache_bytes)
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        Ok(SledKvsEngine::new(db))
+    }
+
+    /// The space sled's files take on disk, in bytes.
+    pub fn size_on_disk(&self) -> crate::Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    /// Makes the next writes (inserts, removes and flushes) fail with `errors`, one error per
+    /// attempt, in order, instead of reaching sled. Only built with the `fault-injection`
+    /// feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_errors(&self, errors: impl IntoIterator<Item = sled::Error>) {
+        self.injected.lock().unwrap().extend(errors);
+    }
+
+    /// Runs a write, retrying it with a growing backoff while it fails with an I/O error, up
+    /// to `SLED_WRITE_ATTEMPTS` times. Any other error, e.g. corruption, is returned at once.
+    /// Either way the error is a `KvsError::SledWriteFailed` telling the two apart.
+    fn write_with_retries<T>(&self, write: impl Fn(&Db) -> sled::Result<T>) -> crate::Result<T> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match self.attempt(&write) {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            let transient = is_transient(&err);
+            if !transient || attempts == SLED_WRITE_ATTEMPTS {
+                return Err(KvsError::SledWriteFailed { transient, attempts, source: err });
+            }
+            thread::sleep(SLED_RETRY_BACKOFF * 2u32.pow(attempts - 1));
+        }
+    }
+
+    fn attempt<T>(&self, write: impl Fn(&Db) -> sled::Result<T>) -> sled::Result<T> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(err) = self.injected.lock().unwrap().pop_front() {
+            return Err(err);
+        }
+        write(&self.db)
     }
 }
+
+/// Whether a sled error may go away if the operation is tried again: failed I/O can be, the
+/// rest (corruption, unsupported use, bugs) are not.
+fn is_transient(err: &sled::Error) -> bool {
+    matches!(err, sled::Error::Io(_))
+}
 /// An embedded LSM Tree Database.
 /// Writes: Go to an in-memory buffer called MemTable which is a B-Tree/SkipList
 /// When MemTables reaches a certain size, flush to Disk as immutable SortedStringTable (SSTs)
 /// Over time, the number of SSTables would grow unbounded, compaction removes duplicate keys,
 /// deleted entries are purged
 /// Reads: Read memtable first then SSTs from newest to oldest.
-
 #[allow(missing_docs)]
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> crate::Result<()> {
-        let _old_value = self.0.insert(key.as_bytes(), value.as_bytes())?;
-        self.0.flush()?;
+    fn set(&self, key: String, value: String) -> crate::Result<()> {
+        let _old_value =
+            self.write_with_retries(|db| db.insert(key.as_bytes(), value.as_bytes()))?;
+        Ok(())
+    }
+
+    fn set_sequenced(&self, key: String, value: String) -> crate::Result<Option<u64>> {
+        self.set(key, value)?;
+        Ok(None)
+    }
+
+    fn set_if(&self, key: String, value: String, condition: SetCondition) -> crate::Result<bool> {
+        let set = match condition {
+            SetCondition::IfAbsent => self
+                .db
+                .compare_and_swap(key.as_bytes(), None as Option<&[u8]>, Some(value.as_bytes()))?
+                .is_ok(),
+            SetCondition::IfPresent => loop {
+                let Some(current) = self.db.get(key.as_bytes())? else {
+                    break false;
+                };
+                // Retry if the value changed between the read and the swap
+                if self
+                    .db
+                    .compare_and_swap(key.as_bytes(), Some(current), Some(value.as_bytes()))?
+                    .is_ok()
+                {
+                    break true;
+                }
+            },
+        };
+        Ok(set)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> crate::Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> crate::Result<Option<String>> {
-        match self.0.get(key.as_bytes())? {
+    fn get(&self, key: String) -> crate::Result<Option<String>> {
+        match self.db.get(key.as_bytes())? {
             Some(value) => {
                 let val = String::from_utf8(value.to_vec())?;
                 Ok(Some(val))
@@ -36,9 +161,112 @@ impl KvsEngine for SledKvsEngine {
         }
     }
 
-    fn remove(&mut self, key: String) -> crate::Result<()> {
-        self.0.remove(key.as_bytes())?;
-        self.0.flush()?;
+    fn value_size(&self, key: String) -> crate::Result<Option<u64>> {
+        Ok(self.db.get(key.as_bytes())?.map(|value| value.len() as u64))
+    }
+
+    fn exists_many(&self, keys: Vec<String>) -> crate::Result<Vec<bool>> {
+        keys.iter()
+            .map(|key| Ok(self.db.contains_key(key.as_bytes())?))
+            .collect()
+    }
+
+    fn remove(&self, key: String) -> crate::Result<()> {
+        self.write_with_retries(|db| db.remove(key.as_bytes()))?
+            .ok_or(KvsError::KeyNotFound)?;
         Ok(())
     }
+
+    fn remove_sequenced(&self, key: String) -> crate::Result<Option<u64>> {
+        self.remove(key)?;
+        Ok(None)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> crate::Result<u64> {
+        // sled reclaims the space of removed keys on its own
+        let removed = self
+            .db
+            .transaction(|tree| {
+                let mut removed = 0;
+                for key in &keys {
+                    if tree.remove(key.as_bytes())?.is_some() {
+                        removed += 1;
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<sled::Error>>(removed)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+            })?;
+        Ok(removed)
+    }
+
+    fn range(&self, start: String, end: String) -> crate::Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        collect_pairs(self.db.range(start.as_bytes()..end.as_bytes()))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> crate::Result<Vec<(String, String)>> {
+        collect_pairs(self.db.scan_prefix(prefix.as_bytes()))
+    }
+
+    fn keys(&self) -> crate::Result<Vec<String>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn count(&self) -> crate::Result<u64> {
+        Ok(self.db.len() as u64)
+    }
+
+    fn random_key(&self) -> crate::Result<Option<String>> {
+        // Streamed rather than collected, so only the chosen key is held in memory
+        let mut error = None;
+        let keys = self.db.iter().keys().map_while(|key| key.map_err(|e| error = Some(e)).ok());
+        let chosen = choose(keys);
+        if let Some(e) = error {
+            return Err(e.into());
+        }
+        match chosen {
+            Some(key) => Ok(Some(String::from_utf8(key.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn index_memory(&self) -> Option<u64> {
+        // sled pages its index in and out of its own cache
+        None
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        self.write_with_retries(|db| db.flush())?;
+        Ok(())
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+
+    fn checkpoint(&self) -> crate::Result<u64> {
+        // sled compacts on its own; flushing is all that is needed for durability
+        self.write_with_retries(|db| db.flush())?;
+        Ok(self.db.generate_id()?)
+    }
+}
+
+/// Converts sled's `IVec` pairs into UTF-8 strings.
+fn collect_pairs(iter: sled::Iter) -> crate::Result<Vec<(String, String)>> {
+    iter.map(|pair| {
+        let (key, value) = pair?;
+        Ok((
+            String::from_utf8(key.to_vec())?,
+            String::from_utf8(value.to_vec())?,
+        ))
+    })
+    .collect()
 }
\ No newline at end of file
@@ -1,6 +1,9 @@
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+use crate::engines::CorruptRecord;
+
 #[derive(Debug)]
 
 /// The KVS Error type
@@ -20,14 +23,74 @@ pub enum KvsError {
     /// Corrupted data
     CorruptedData,
 
+    /// A record the index points at is not what it should be, e.g. a remove instead of a set
+    CorruptRecord(CorruptRecord),
+
+    /// The data directory can't be written, e.g. because it is on a read-only file system.
+    /// `KvStore::open_read_only` can still read it.
+    ReadOnlyFilesystem(PathBuf),
+
     /// String error
     StringError(String),
 
+    /// A value doesn't have the format the server requires, see `ValueType`
+    InvalidValueFormat(String),
+
+    /// The server's validator rejected a write, see `KvsServer::validator`
+    ValidationFailed(String),
+
     /// Serialization error
     Serialization(Box<bincode::ErrorKind>),
 
     /// SledError
     SledError(sled::Error),
+
+    /// A write to sled failed, after being retried if the failure looked transient
+    SledWriteFailed {
+        /// Whether the failure may go away on its own, e.g. failed I/O, rather than being
+        /// fatal like corruption
+        transient: bool,
+        /// How many times the write was tried
+        attempts: u32,
+        /// The last attempt's error
+        source: sled::Error,
+    },
+
+    /// The connection to the server is out of sync or dropped and was reset
+    ConnectionBroken(io::Error),
+
+    /// A received frame is longer than the connection accepts, see `FramedConnection`
+    FrameTooLarge {
+        /// The frame's payload length
+        len: usize,
+        /// The longest payload accepted
+        max: usize,
+    },
+
+    /// The configuration file could not be parsed
+    BadConfig(String),
+
+    /// The server ended the subscription because it fell too far behind
+    SubscriberLagged,
+
+    /// An operation did not complete within its deadline
+    Timeout,
+
+    /// A write would take the store's log files past its disk quota
+    DiskQuotaExceeded {
+        /// Bytes the log files take up
+        used: u64,
+        /// The quota, in bytes
+        quota: u64,
+    },
+
+    /// A record was written in a newer schema version than this build can read
+    UnsupportedRecordVersion {
+        /// The record's version
+        found: u64,
+        /// The newest version this build reads
+        supported: u64,
+    },
 }
 
 impl From<io::Error> for KvsError {
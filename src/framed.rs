@@ -0,0 +1,99 @@
+use std::io::{self, BufReader, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{KvsError, Result};
+
+/// A connection carrying bincode messages, each framed as `[u32 BE length][payload]`.
+///
+/// Both the client and the server speak through it, over any stream: a `TcpStream`, a
+/// `&TcpStream`, or e.g. a TLS or Unix socket stream. Reads are buffered; each message is
+/// written with a single write and flushed.
+pub struct FramedConnection<S: Read + Write> {
+    stream: BufReader<S>,
+    // `None` for no limit
+    max_frame_len: Option<usize>,
+}
+
+impl<S: Read + Write> FramedConnection<S> {
+    /// Wraps `stream`, accepting frames of any length.
+    pub fn new(stream: S) -> FramedConnection<S> {
+        FramedConnection {
+            stream: BufReader::new(stream),
+            max_frame_len: None,
+        }
+    }
+
+    /// Caps the length of a received frame's payload, in bytes.
+    ///
+    /// The cap is checked against the length prefix, so the payload of an oversized frame is
+    /// never read; see `recv`.
+    pub fn max_frame_len(mut self, max: usize) -> Self {
+        self.max_frame_len = Some(max);
+        self
+    }
+
+    /// Sends `message` as one frame.
+    pub fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        write_frame(self.stream.get_mut(), message)?;
+        self.stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    /// Waits for the next frame and decodes it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IoError` with `UnexpectedEof` if the peer closed the connection,
+    /// or `KvsError::FrameTooLarge` for a frame over the cap, whose payload is left unread.
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Result<T> {
+        read_frame(&mut self.stream, self.max_frame_len)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// Like `recv`, but returns `None` if the peer closed the connection before the frame.
+    pub fn try_recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        read_frame(&mut self.stream, self.max_frame_len)
+    }
+
+    /// Returns the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+}
+
+/// Writes `message` to `out` as one `[u32 BE length][payload]` frame.
+pub(crate) fn write_frame<W: Write, T: Serialize>(out: &mut W, message: &T) -> Result<()> {
+    let payload = bincode::serialize(message)?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    out.write_all(&frame)?;
+    Ok(())
+}
+
+/// Reads the next frame from `input`, or `None` if `input` ends before it.
+///
+/// A frame whose payload is longer than `max_len` is an error, and its payload isn't read.
+pub(crate) fn read_frame<R: Read, T: DeserializeOwned>(
+    input: &mut R,
+    max_len: Option<usize>,
+) -> Result<Option<T>> {
+    let mut len_bytes = [0u8; 4];
+    match input.read_exact(&mut len_bytes) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if let Some(max) = max_len
+        && len > max
+    {
+        return Err(KvsError::FrameTooLarge { len, max });
+    }
+
+    let mut payload = vec![0; len];
+    input.read_exact(&mut payload)?;
+    Ok(Some(bincode::deserialize(&payload)?))
+}
@@ -0,0 +1,255 @@
+//! A minimal HTTP/1.1 front-end to the engine, for clients that don't speak the binary
+//! protocol (curl, browsers).
+//!
+//! - `GET /kv/{key}` returns the value: `200`, or `404` if the key doesn't exist
+//! - `PUT /kv/{key}` sets the value to the request body: `201` if the key is new, else `200`,
+//!   or `400` if it isn't of the server's `ValueType`
+//! - `DELETE /kv/{key}` removes the key: `200`, or `404` if it doesn't exist
+//!
+//! `PUT` and `DELETE` go through the server's validator like the binary protocol's writes,
+//! and are answered `403` with its message if it rejects them.
+//!
+//! Keys are percent-decoded from the path. Each connection carries one request. A body longer
+//! than the server's `max_request_size` is refused with `413` before it is read.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::common::Request;
+use crate::engines::{KvsEngine, SetCondition};
+use crate::server_options::ServerOptions;
+use crate::stats::{Operation, ServerStats};
+use crate::subscribe::{ChangeEvent, Subscribers};
+use crate::{KvsError, Result};
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest request head (request line and headers) accepted.
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+
+/// Path prefix of the key/value resource.
+const KV_PATH: &str = "/kv/";
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    body: String,
+}
+
+impl HttpResponse {
+    fn new(status: u16, body: impl Into<String>) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: body.into(),
+        }
+    }
+}
+
+/// Serves the single request on an HTTP connection.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    stats: &ServerStats,
+    subscribers: &Subscribers,
+    tcp_stream: TcpStream,
+    options: &ServerOptions,
+) -> Result<()> {
+    tcp_stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let response = match read_request(&tcp_stream, options.max_request_size)? {
+        Ok(request) => {
+            debug!("HTTP {} {}", request.method, request.path);
+            handle(&engine, stats, subscribers, options, request)
+        }
+        Err(response) => response,
+    };
+    write_response(&tcp_stream, response)
+}
+
+/// Answers an HTTP connection that is over its client's connection cap.
+pub(crate) fn reject(tcp_stream: &TcpStream, message: String) -> Result<()> {
+    write_response(tcp_stream, HttpResponse::new(429, message))
+}
+
+fn handle<E: KvsEngine>(
+    engine: &E,
+    stats: &ServerStats,
+    subscribers: &Subscribers,
+    options: &ServerOptions,
+    request: HttpRequest,
+) -> HttpResponse {
+    let Some(key) = request.path.strip_prefix(KV_PATH) else {
+        return HttpResponse::new(404, "Not found");
+    };
+    let Some(key) = percent_decode(key) else {
+        return HttpResponse::new(400, "Malformed key");
+    };
+
+    match request.method.as_str() {
+        "GET" => {
+            let start = Instant::now();
+            let result = engine.get(key);
+            stats.record(Operation::Get, start.elapsed());
+            match result {
+                Ok(Some(value)) => HttpResponse::new(200, value),
+                Ok(None) => HttpResponse::new(404, "Key not found"),
+                Err(e) => HttpResponse::new(500, format!("{:?}", e)),
+            }
+        }
+        "PUT" => {
+            let Ok(value) = String::from_utf8(request.body) else {
+                return HttpResponse::new(400, "Value is not UTF-8");
+            };
+            if let Err(e) = options.value_type.check(&value) {
+                return HttpResponse::new(400, format!("{:?}", e));
+            }
+            if let Some(validator) = &options.validator {
+                let request = Request::Set { key: key.clone(), value: value.clone() };
+                if let Err(e) = validator.check(&request) {
+                    return HttpResponse::new(403, format!("{:?}", e));
+                }
+            }
+            let start = Instant::now();
+            // Only a set that creates the key answers 201
+            let result = engine
+                .set_if(key.clone(), value.clone(), SetCondition::IfAbsent)
+                .and_then(|created| match created {
+                    true => Ok(201),
+                    false => engine.set(key.clone(), value.clone()).map(|()| 200),
+                });
+            stats.record(Operation::Set, start.elapsed());
+            match result {
+                Ok(status) => {
+                    subscribers.publish(ChangeEvent { key, value: Some(value) });
+                    HttpResponse::new(status, "")
+                }
+                Err(e) => HttpResponse::new(500, format!("{:?}", e)),
+            }
+        }
+        "DELETE" => {
+            if let Some(validator) = &options.validator
+                && let Err(e) = validator.check(&Request::Remove { key: key.clone() })
+            {
+                return HttpResponse::new(403, format!("{:?}", e));
+            }
+            let start = Instant::now();
+            let result = engine.remove(key.clone());
+            stats.record(Operation::Remove, start.elapsed());
+            match result {
+                Ok(()) => {
+                    subscribers.publish(ChangeEvent { key, value: None });
+                    HttpResponse::new(200, "")
+                }
+                Err(KvsError::KeyNotFound) => HttpResponse::new(404, "Key not found"),
+                Err(e) => HttpResponse::new(500, format!("{:?}", e)),
+            }
+        }
+        _ => HttpResponse::new(405, "Method not allowed"),
+    }
+}
+
+/// Reads a request, or returns the response refusing it: `400` if it isn't well-formed
+/// HTTP/1.x, `413` if its body is longer than `max_body_len`.
+fn read_request(
+    tcp_stream: &TcpStream,
+    max_body_len: usize,
+) -> Result<std::result::Result<HttpRequest, HttpResponse>> {
+    let malformed = || Ok(Err(HttpResponse::new(400, "Malformed request")));
+    let mut reader = BufReader::new(tcp_stream).take(MAX_HEAD_LEN);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return malformed();
+    };
+    if !version.starts_with("HTTP/1.") {
+        return malformed();
+    }
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            // The head ended early or was too long
+            return malformed();
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return malformed();
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            let Ok(length) = value.trim().parse() else {
+                return malformed();
+            };
+            content_length = length;
+        }
+    }
+
+    // The body isn't part of the head's limit, but is checked against its own before
+    // anything is allocated for it
+    if content_length > max_body_len {
+        let message = format!(
+            "Body of {} bytes is over the {} byte limit",
+            content_length, max_body_len
+        );
+        return Ok(Err(HttpResponse::new(413, message)));
+    }
+    let mut reader = reader.into_inner();
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(HttpRequest { method, path, body }))
+}
+
+fn write_response(mut tcp_stream: &TcpStream, response: HttpResponse) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    };
+    write!(
+        tcp_stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.body.len(),
+        response.body
+    )?;
+    tcp_stream.flush()?;
+    Ok(())
+}
+
+/// Decodes `%XX` escapes, returning `None` for a bad escape or invalid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -1,20 +1,51 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use benchmark::{run_benchmark, BenchmarkReport, Workload};
+pub use clock::{Clock, SystemClock};
+pub use compression::COMPRESSED_VALUE_MARKER;
+pub use client::{KvsClient, Subscription};
+pub use common::Request;
+pub use pool::{ClientPool, PooledClient};
+pub use engines::{
+    CompactionWindow, CorruptRecord, CorruptionPolicy, GetPlan, KvStore, KvStoreSnapshot,
+    KvsEngine, KvsEngineExt, LogFormatInfo, LogReader, LogRecord, MigrationReport, QuotaPolicy,
+    ScanEntry, SetCondition, SledKvsEngine, VerifyReport,
+};
+#[cfg(feature = "fault-injection")]
+pub use engines::FaultConfig;
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use framed::FramedConnection;
+pub use server::{KvsServer, ServerHandle, DEFAULT_MAX_REQUEST_SIZE};
+pub use server_options::{ServerOptions, ValueType};
+pub use stats::{OpStats, ServerStats, Stats};
+pub use subscribe::{ChangeEvent, LagPolicy};
+#[cfg(unix)]
+pub use systemd::systemd_listeners;
+mod benchmark;
 mod client;
+mod clock;
 mod common;
+mod compression;
+mod connections;
 mod engines;
 mod error;
+mod framed;
+#[cfg(feature = "http")]
+mod http;
+mod pool;
+mod request_log;
 mod server;
+mod server_options;
+mod stats;
+mod subscribe;
+#[cfg(unix)]
+mod systemd;
 
 #[allow(missing_docs)]
 pub mod thread_pool;
 
-#[allow(missing_docs)]
+#[allow(missing_docs, clippy::module_inception)]
 pub mod kvs_command {
     include!(concat!(env!("OUT_DIR"), "/kvs_command.rs"));
 }
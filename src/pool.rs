@@ -0,0 +1,149 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::common::Random;
+use crate::{KvsClient, Result};
+
+/// How many times `ClientPool::get` retries a failed connect by default.
+const DEFAULT_RETRIES: u32 = 5;
+
+/// Delay before the first retry by default; it doubles on every further retry.
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Cap on the delay between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A pool of client connections to one server, shared between threads.
+///
+/// Idle connections are checked with a ping before they are handed out again, and dead ones
+/// are replaced, so callers don't see errors for connections the server dropped while they
+/// sat in the pool (e.g. across a server restart).
+pub struct ClientPool {
+    addrs: Vec<SocketAddr>,
+    idle: Mutex<Vec<KvsClient>>,
+    retries: u32,
+    backoff: Duration,
+    keepalive: Option<Duration>,
+}
+
+impl ClientPool {
+    /// Creates an empty pool for the server at `addr`; connections are opened on demand.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<ClientPool> {
+        Ok(ClientPool {
+            addrs: addr.to_socket_addrs()?.collect(),
+            idle: Mutex::new(Vec::new()),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            keepalive: None,
+        })
+    }
+
+    /// Sets how many times a failed connect is retried before `get` gives up.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the delay before the first retry. Later retries double it, up to 5s, and every
+    /// delay is randomized between half and all of it so that clients reconnecting after
+    /// the same outage spread out.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Turns on TCP keep-alive on the pool's connections, see `KvsClient::set_keepalive`.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Hands out a healthy connection, reusing an idle one if it still answers a ping.
+    ///
+    /// The connection returns to the pool when the `PooledClient` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns the last connect error if the server can't be reached within the retries.
+    pub fn get(&self) -> Result<PooledClient<'_>> {
+        while let Some(mut client) = self.take_idle() {
+            match client.ping() {
+                Ok(()) => return Ok(self.pooled(client)),
+                Err(e) => debug!("Dropping dead pooled connection: {:?}", e),
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            match KvsClient::connect(&self.addrs[..]).and_then(|mut client| {
+                client.set_keepalive(self.keepalive)?;
+                client.ping()?;
+                Ok(client)
+            }) {
+                Ok(client) => return Ok(self.pooled(client)),
+                Err(e) if attempt < self.retries => {
+                    let delay = self.retry_delay(attempt);
+                    debug!("Connect failed, retrying in {:?}: {:?}", delay, e);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_idle(&self) -> Option<KvsClient> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Exponential backoff for the given retry, with "equal jitter": half fixed, half random.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_BACKOFF);
+        let half = delay / 2;
+        half + half.mul_f64(Random::new().fraction(attempt))
+    }
+}
+
+/// A connection borrowed from a `ClientPool`; derefs to `KvsClient`.
+pub struct PooledClient<'a> {
+    pool: &'a ClientPool,
+    // Only `None` while being dropped
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
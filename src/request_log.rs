@@ -0,0 +1,123 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use log::{log, Level};
+
+use crate::common::Request;
+use crate::Result;
+
+/// Log target of the request log, for filtering it apart from the server's other logs.
+const TARGET: &str = "kvs::requests";
+
+/// What became of a request once it was handled.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Handled {
+    Ok,
+    Failed(String),
+    // The connection was handed over to a subscription
+    Subscribed,
+}
+
+/// Logs one line per request a server handles: its client, operation, key, latency and
+/// result.
+///
+/// Values longer than `max_value_len` bytes are logged as their length only.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestLog {
+    level: Level,
+    max_value_len: usize,
+}
+
+impl RequestLog {
+    pub(crate) fn new(level: Level, max_value_len: usize) -> RequestLog {
+        RequestLog { level, max_value_len }
+    }
+
+    /// Handles `request` with `handle` and logs how it went.
+    ///
+    /// A request whose connection failed mid-way is logged too, with the connection error.
+    pub(crate) fn wrap(
+        &self,
+        peer_addr: SocketAddr,
+        request: Request,
+        handle: impl FnOnce(Request) -> Result<Handled>,
+    ) -> Result<Handled> {
+        if !log::log_enabled!(target: TARGET, self.level) {
+            return handle(request);
+        }
+        let fields = self.fields(&request);
+        let start = Instant::now();
+        let handled = handle(request);
+        let latency_us = start.elapsed().as_micros();
+
+        let result = match &handled {
+            Ok(Handled::Ok) => "result=ok".to_owned(),
+            Ok(Handled::Subscribed) => "result=subscribed".to_owned(),
+            Ok(Handled::Failed(message)) => format!("result=error error={:?}", message),
+            Err(e) => format!("result=error error={:?}", format!("{:?}", e)),
+        };
+        log!(
+            target: TARGET,
+            self.level,
+            "peer={} {} latency_us={} {}",
+            peer_addr,
+            fields,
+            latency_us,
+            result
+        );
+        handled
+    }
+
+    /// Formats the operation and arguments of `request` as `name=value` fields.
+    fn fields(&self, request: &Request) -> String {
+        let mut fields = String::new();
+        let _ = match request {
+            Request::Get { key } => write!(fields, "op=get key={:?}", key),
+            Request::GetAtLeast { key, min_sequence } => write!(
+                fields,
+                "op=get key={:?} min_sequence={}",
+                key, min_sequence
+            ),
+            Request::Set { key, value } => {
+                write!(fields, "op=set key={:?} value={}", key, self.value(value))
+            }
+            Request::ConditionalSet { key, value, condition } => write!(
+                fields,
+                "op=set key={:?} value={} condition={:?}",
+                key,
+                self.value(value),
+                condition
+            ),
+            Request::SetMany { pairs } => write!(fields, "op=set_many pairs={}", pairs.len()),
+            Request::Remove { key } => write!(fields, "op=remove key={:?}", key),
+            Request::Undelete { key } => write!(fields, "op=undelete key={:?}", key),
+            Request::ValueSize { key } => write!(fields, "op=value_size key={:?}", key),
+            Request::ExistsMany { keys } => write!(fields, "op=exists_many keys={}", keys.len()),
+            Request::Explain { key } => write!(fields, "op=explain key={:?}", key),
+            Request::Scan { prefix } => write!(fields, "op=scan prefix={:?}", prefix),
+            Request::ScanVerbose { prefix } => {
+                write!(fields, "op=scan prefix={:?} verbose=true", prefix)
+            }
+            Request::Subscribe { prefix } => write!(fields, "op=subscribe prefix={:?}", prefix),
+            Request::Import { pairs } => write!(fields, "op=import pairs={}", pairs.len()),
+            Request::RandomKey => write!(fields, "op=random_key"),
+            Request::Export => write!(fields, "op=export"),
+            Request::Stats => write!(fields, "op=stats"),
+            Request::StatsReset => write!(fields, "op=stats_reset"),
+            Request::Checkpoint => write!(fields, "op=checkpoint"),
+            Request::LatestSequence => write!(fields, "op=latest_sequence"),
+            Request::Ping => write!(fields, "op=ping"),
+        };
+        fields
+    }
+
+    /// Quotes `value`, or replaces it with its length if it's over `max_value_len`.
+    fn value(&self, value: &str) -> String {
+        if value.len() > self.max_value_len {
+            format!("<{} bytes>", value.len())
+        } else {
+            format!("{:?}", value)
+        }
+    }
+}
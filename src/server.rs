@@ -1,103 +1,955 @@
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use log::{debug, error, info};
+use std::io;
+use std::net::{
+    Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn, Level};
 use serde::Serialize;
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::engines::KvsEngine;
-use crate::Result;
+use crate::common::{
+    set_keepalive, CheckpointResponse, ConditionalSetResponse, ExistsManyResponse,
+    ExplainResponse, ExportFrame, GetResponse, ImportResponse, PingResponse, RandomKeyResponse,
+    RemoveResponse, Request, Response, ScanFrame, ScanVerboseFrame, SequenceResponse,
+    SetManyResponse, SetResponse, StatsResponse, SubscribeResponse, UndeleteResponse,
+    ValueSizeResponse,
+};
+use crate::connections::ConnectionLimiter;
+use crate::engines::{KvsEngine, KvsEngineExt};
+use crate::framed::FramedConnection;
+use crate::request_log::Handled;
+use crate::server_options::{ServerOptions, ValueType};
+use crate::stats::{Operation, ServerStats};
+use crate::subscribe::{ChangeEvent, LagPolicy, Subscribers, Subscription, SubscriptionFrame};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{KvsError, Result};
+
+/// How long a subscription may stay silent before a heartbeat is sent.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a rejected connection gets to send the request that is answered with the rejection.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Rejected connections waiting for their answer; more are closed without one.
+const MAX_QUEUED_REJECTIONS: usize = 64;
+
+/// Largest request payload accepted by default, in bytes.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+
+/// How often the accept loop checks for a shutdown request.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a shutdown waits for open connections to finish their requests.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a `GetAtLeast` waits for the store to reach the requested sequence.
+const MIN_SEQUENCE_WAIT: Duration = Duration::from_secs(1);
+
+/// How often a waiting `GetAtLeast` checks the store's sequence.
+const SEQUENCE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Worker threads of a server started with `bind_ephemeral`.
+const EPHEMERAL_THREADS: u32 = 4;
+
+/// How many pairs an export sends per frame.
+const EXPORT_CHUNK_SIZE: usize = 1000;
+
+/// Entries per frame of a scan's answer.
+const SCAN_CHUNK_SIZE: usize = 1000;
 
 #[allow(missing_docs)]
-pub struct KvsServer<E: KvsEngine> {
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
+    pool: P,
+    stats: Arc<ServerStats>,
+    subscribers: Arc<Subscribers>,
+    connections: Arc<ConnectionLimiter>,
+    options: ServerOptions,
+
+    // Bound by the caller, see `http_listener`; takes the place of `options.http_addr`
+    #[cfg(feature = "http")]
+    http_listener: Option<TcpListener>,
+}
+
+/// A client connection on the binary protocol.
+type Connection<'a> = FramedConnection<&'a TcpStream>;
+
+/// Protocol spoken on a listener.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Binary,
+    #[cfg(feature = "http")]
+    Http,
 }
 
 #[allow(missing_docs)]
-impl<E: KvsEngine> KvsServer<E> {
-    pub fn new(engine: E) -> Self {
-        KvsServer { engine }
-    }
-
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
-                        error!("Error serving Kvs: {:?}", e);
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer::with_options(engine, pool, ServerOptions::default())
+    }
+
+    /// Creates a server with the given options; `new` uses `ServerOptions::default()`.
+    pub fn with_options(engine: E, pool: P, options: ServerOptions) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            stats: Arc::new(ServerStats::new()),
+            subscribers: Arc::new(Subscribers::new(
+                options.subscriber_buffer,
+                options.lag_policy,
+            )),
+            connections: Arc::new(ConnectionLimiter::new(options.max_connections_per_ip)),
+            options,
+            #[cfg(feature = "http")]
+            http_listener: None,
+        }
+    }
+
+    /// Sets how many change events may queue up for a subscriber, and what happens to a
+    /// subscriber that falls further behind than that.
+    pub fn subscriber_buffer(mut self, buffer_size: usize, lag_policy: LagPolicy) -> Self {
+        self.options = self.options.subscriber_buffer(buffer_size, lag_policy);
+        self.subscribers = Arc::new(Subscribers::new(buffer_size, lag_policy));
+        self
+    }
+
+    /// Caps how many connections a single client IP may have open at once.
+    ///
+    /// A connection over the cap gets an error in response to its first request and is
+    /// closed. Without a cap, a client IP may open any number of connections.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.options = self.options.max_connections_per_ip(max);
+        self.connections = Arc::new(ConnectionLimiter::new(Some(max)));
+        self
+    }
+
+    /// Caps the size of a request's payload, in bytes (default `DEFAULT_MAX_REQUEST_SIZE`).
+    ///
+    /// The cap is checked against the length prefix before the payload is read, so an
+    /// oversized request is never buffered: its connection is closed instead. HTTP requests
+    /// are checked the same way against their `Content-Length`, and answered with `413`.
+    pub fn max_request_size(mut self, max: usize) -> Self {
+        self.options = self.options.max_request_size(max);
+        self
+    }
+
+    /// Turns on TCP keep-alive on accepted connections, with `idle` as both the idle time
+    /// before the first probe and the interval between probes.
+    ///
+    /// The OS then resets connections whose client went away without closing them (e.g.
+    /// dropped by a NAT), instead of leaving them open forever. Off by default.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.options = self.options.keepalive(idle);
+        self
+    }
+
+    /// Logs every request on the binary protocol at `level`, one line each, under the
+    /// `kvs::requests` target: the client's address, the operation, its key, the latency and
+    /// whether it succeeded. Values longer than `max_value_len` bytes are logged as their
+    /// length only.
+    ///
+    /// Meant for debugging a specific client; off by default.
+    pub fn request_log(mut self, level: Level, max_value_len: usize) -> Self {
+        self.options = self.options.request_log(level, max_value_len);
+        self
+    }
+
+    /// Rejects sets of values that aren't of `value_type`, e.g. malformed JSON, with
+    /// `KvsError::InvalidValueFormat`. This covers every way of setting a value: single and
+    /// multiple sets, imports and HTTP `PUT`s. Values are still stored as given.
+    ///
+    /// Any string is accepted by default.
+    pub fn value_type(mut self, value_type: ValueType) -> Self {
+        self.options = self.options.value_type(value_type);
+        self
+    }
+
+    /// Runs `validator` on each write request (single, conditional and multiple sets, removes,
+    /// undeletes and imports) before it reaches the engine, e.g. to reject keys that don't
+    /// match a pattern or enforce per-namespace size limits. A write it returns an error for is
+    /// answered with `KvsError::ValidationFailed` carrying the message, or `403` over HTTP,
+    /// where `PUT` and `DELETE` are checked as a set and a remove.
+    ///
+    /// Every write is accepted by default.
+    pub fn validator(
+        mut self,
+        validator: impl Fn(&Request) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.options = self.options.validator(validator);
+        self
+    }
+
+    /// Also serves the engine over HTTP on `addr`: `GET`, `PUT` and `DELETE` on `/kv/{key}`.
+    ///
+    /// HTTP requests share the server's workers, statistics, subscribers and connection cap.
+    #[cfg(feature = "http")]
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.options = self.options.http_addr(addr);
+        self
+    }
+
+    /// Like `http_addr`, but serves HTTP on a listener bound already, e.g. to an ephemeral
+    /// port whose address has to be known before the server starts.
+    #[cfg(feature = "http")]
+    pub fn http_listener(mut self, listener: TcpListener) -> Self {
+        self.http_listener = Some(listener);
+        self
+    }
+
+    /// Returns a handle to the server's operation statistics.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Serves connections on every address `addr` resolves to, e.g. both the IPv4 and the
+    /// IPv6 address of a host name.
+    ///
+    /// Addresses that can't be bound are logged and skipped; it fails only if none can be.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_with_shutdown(addr, Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Like `run`, but returns once `shutdown` is set.
+    ///
+    /// New connections are no longer accepted, and open ones are closed after the request
+    /// they are serving, if any. Once the connections are all closed, or after 30s, the
+    /// engine is flushed to disk.
+    ///
+    /// A subscribed connection stops counting as open once it is subscribed, so it doesn't
+    /// hold up the shutdown; it isn't closed either, and keeps streaming events until the
+    /// client goes away.
+    pub fn run_with_shutdown<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let listeners = bind_all(addr)?;
+        self.serve_listeners(listeners, shutdown)
+    }
+
+    /// Like `run_with_shutdown`, but serves connections on listeners bound already, e.g. the
+    /// ones from `systemd_listeners`.
+    pub fn run_listeners_with_shutdown(
+        self,
+        listeners: Vec<TcpListener>,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        self.serve_listeners(listeners, shutdown)
+    }
+
+    /// Starts serving on an ephemeral port of `127.0.0.1` in a background thread.
+    ///
+    /// Returns the address the server listens on, and a handle that shuts it down (like
+    /// `run_with_shutdown`) when dropped. Meant for tests that need a live server.
+    pub fn spawn_ephemeral(self) -> Result<(SocketAddr, ServerHandle)>
+    where
+        P: Send + 'static,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server_shutdown = Arc::clone(&shutdown);
+        let thread = thread::spawn(move || self.serve_listeners(vec![listener], server_shutdown));
+        Ok((
+            addr,
+            ServerHandle {
+                shutdown,
+                thread: Some(thread),
+            },
+        ))
+    }
+
+    /// Applies the socket options to an accepted connection, logging the ones that fail.
+    fn configure_stream(&self, stream: &TcpStream) {
+        if let Some(idle) = self.options.keepalive
+            && let Err(e) = set_keepalive(stream, Some(idle))
+        {
+            warn!("Cannot turn on TCP keep-alive: {}", e);
+        }
+        if self.options.nodelay
+            && let Err(e) = stream.set_nodelay(true)
+        {
+            warn!("Cannot turn off Nagle's algorithm: {}", e);
+        }
+        if let Err(e) = stream.set_read_timeout(self.options.read_timeout) {
+            warn!("Cannot set the read timeout: {}", e);
+        }
+    }
+
+    fn serve_listeners(
+        #[cfg_attr(not(feature = "http"), allow(unused_mut))] mut self,
+        listeners: Vec<TcpListener>,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        // Each listener accepts on its own thread and hands its connections over to this one
+        let (accepted, incoming) = mpsc::channel();
+        #[cfg_attr(not(feature = "http"), allow(unused_mut))]
+        let mut listeners: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| (Protocol::Binary, listener))
+            .collect();
+        #[cfg(feature = "http")]
+        if let Some(listener) = self.http_listener.take() {
+            listeners.push((Protocol::Http, listener));
+        } else if let Some(http_addr) = self.options.http_addr {
+            listeners.push((Protocol::Http, TcpListener::bind(http_addr)?));
+        }
+        let mut acceptors = Vec::new();
+        for (protocol, listener) in listeners {
+            let accepted = accepted.clone();
+            let addr = listener.local_addr()?;
+            let acceptor = thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if accepted.send((protocol, stream)).is_err() {
+                        break;
+                    }
+                }
+            });
+            acceptors.push((addr, acceptor));
+        }
+        drop(accepted);
+
+        // Connections over their client's cap are answered on a thread of their own, one at a
+        // time, so that clients holding them open can't tie up the workers
+        let (rejected, rejections) = mpsc::sync_channel(MAX_QUEUED_REJECTIONS);
+        let max_request_size = self.options.max_request_size;
+        let rejecter = thread::spawn(move || {
+            for (protocol, stream, message) in rejections {
+                let result = match protocol {
+                    Protocol::Binary => reject(&stream, message, max_request_size),
+                    #[cfg(feature = "http")]
+                    Protocol::Http => crate::http::reject(&stream, message),
+                };
+                if let Err(e) = result {
+                    debug!("Error rejecting Kvs connection: {:?}", e);
+                }
+            }
+        });
+
+        loop {
+            let (protocol, stream) = match incoming.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(accepted) => accepted,
+                Err(RecvTimeoutError::Timeout) if shutdown.load(Ordering::SeqCst) => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let engine = self.engine.clone();
+            let stats = Arc::clone(&self.stats);
+            let subscribers = Arc::clone(&self.subscribers);
+            let options = self.options.clone();
+            if let Ok(stream) = &stream {
+                self.configure_stream(stream);
+            }
+            // Counted from accept, so connections still queued for a worker count too
+            let connection = stream.and_then(|stream| {
+                let peer_addr = stream.peer_addr()?;
+                let slot = self.connections.acquire(peer_addr.ip(), &stream)?;
+                Ok((stream, peer_addr, slot))
+            });
+            let (stream, slot) = match connection {
+                Ok((stream, _, Some(slot))) => (stream, slot),
+                Ok((stream, peer_addr, None)) => {
+                    info!("Rejecting connection from {}: too many open connections", peer_addr);
+                    let message = format!("Too many connections from {}", peer_addr.ip());
+                    if rejected.try_send((protocol, stream, message)).is_err() {
+                        debug!("Closing connection from {} unanswered: too many queued", peer_addr);
                     }
+                    continue;
                 }
                 Err(e) => {
                     error!("Error accepting Kvs connection: {:?}", e);
+                    continue;
+                }
+            };
+            self.pool.spawn(move || {
+                let _slot = slot;
+                let result = match protocol {
+                    Protocol::Binary => serve(engine, &stats, &subscribers, stream, &options),
+                    #[cfg(feature = "http")]
+                    Protocol::Http => {
+                        crate::http::serve(engine, &stats, &subscribers, stream, &options)
+                    }
+                };
+                if let Err(e) = result {
+                    error!("Error serving Kvs: {:?}", e);
                 }
+            })
+        }
+        drop(rejected);
+        let _ = rejecter.join();
+
+        info!("Shutting down, waiting for open connections to finish");
+        // Wake every acceptor with a connection of its own, so that it finds the channel
+        // closed and closes its listener
+        drop(incoming);
+        for (mut addr, acceptor) in acceptors {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
             }
+            if TcpStream::connect(addr).is_ok() {
+                let _ = acceptor.join();
+            }
+        }
+        self.connections.close_reads();
+        if !self.connections.wait_closed(SHUTDOWN_DRAIN_TIMEOUT) {
+            warn!("Connections still open after {:?}, shutting down anyway", SHUTDOWN_DRAIN_TIMEOUT);
         }
+        self.engine.flush()
+    }
+}
+
+/// Handle to a server started with `KvsServer::spawn_ephemeral` or `bind_ephemeral`.
+///
+/// Dropping it shuts the server down gracefully and waits until it has stopped.
+pub struct ServerHandle {
+    shutdown: Arc<AtomicBool>,
+    // Only `None` once the server has been stopped
+    thread: Option<thread::JoinHandle<Result<()>>>,
+}
 
-        Ok(())
+impl ServerHandle {
+    /// Shuts the server down gracefully, waits for it to stop and returns how it ended.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
     }
 
-    fn serve(&mut self, tcp_stream: TcpStream) -> Result<()> {
-        let peer_addr = tcp_stream.peer_addr()?;
-        let mut reader = BufReader::new(&tcp_stream);
-        let mut writer = BufWriter::new(&tcp_stream);
+    fn stop(&mut self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(KvsError::StringError("the server thread panicked".to_owned())),
+            None => Ok(()),
+        }
+    }
+}
 
-        fn send_response<T: Serialize>(writer: &mut BufWriter<&TcpStream>, resp: T) -> Result<()> {
-            let serialized = bincode::serialize(&resp)?;
-            let resp_len = serialized.len() as u32;
-            writer.write_all(&resp_len.to_be_bytes())?;
-            writer.write_all(&serialized)?;
-            writer.flush()?;
-            Ok(())
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Server stopped with an error: {:?}", e);
         }
+    }
+}
 
-        loop {
-            // read message length bytes
-            let mut len_bytes = [0u8; 4];
-            if let Err(e) = reader.read_exact(&mut len_bytes) {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    info!("Client disconnected");
-                    break;
-                }
+impl<E: KvsEngine> KvsServer<E, SharedQueueThreadPool> {
+    /// Starts a server for `engine` on an ephemeral port of `127.0.0.1`, with a small thread
+    /// pool, in a background thread. See `spawn_ephemeral`.
+    pub fn bind_ephemeral(engine: E) -> Result<(SocketAddr, ServerHandle)> {
+        KvsServer::new(engine, SharedQueueThreadPool::new(EPHEMERAL_THREADS)?).spawn_ephemeral()
+    }
+}
 
-                return Err(e.into());
+/// Binds a listener to each address `addr` resolves to, skipping those that fail.
+fn bind_all<A: ToSocketAddrs>(addr: A) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpListener::bind(addr) {
+            Ok(listener) => {
+                debug!("Listening on {}", addr);
+                listeners.push(listener);
             }
+            Err(e) => {
+                warn!("Cannot listen on {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        let e = last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+        });
+        return Err(e.into());
+    }
+    Ok(listeners)
+}
 
-            let len = u32::from_be_bytes(len_bytes) as usize;
+fn serve<E: KvsEngine>(
+    engine: E,
+    stats: &ServerStats,
+    subscribers: &Subscribers,
+    tcp_stream: TcpStream,
+    options: &ServerOptions,
+) -> Result<()> {
+    let peer_addr = tcp_stream.peer_addr()?;
+    let mut connection =
+        FramedConnection::new(&tcp_stream).max_frame_len(options.max_request_size);
 
-            // read serialized request
-            let mut buffer = vec![0; len];
-            reader.read_exact(&mut buffer)?;
+    loop {
+        let request: Request = match connection.try_recv() {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                info!("Client disconnected");
+                break;
+            }
+            Err(KvsError::IoError(e))
+                if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
+            {
+                info!("Closing idle connection from {}", peer_addr);
+                break;
+            }
+            Err(KvsError::FrameTooLarge { len, max }) => {
+                // Close without reading the payload, so it is never buffered
+                warn!(
+                    "Closing connection from {}: {} byte request is over the {} byte limit",
+                    peer_addr, len, max
+                );
+                tcp_stream.shutdown(Shutdown::Both)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
 
-            // Deserialize request
-            let request: Request = bincode::deserialize(&buffer)?;
+        let mut handle_request = |request: Request| {
+            // Writes the validator rejects never reach the engine
+            let validated = options.validator.as_ref().map(|validator| validator.check(&request));
+            if let Some(Err(e)) = validated {
+                let message = format!("{:?}", e);
+                send_error(&mut connection, &request, message.clone())?;
+                return Ok(Handled::Failed(message));
+            }
+            handle(&engine, stats, subscribers, options.value_type, &mut connection, request)
+        };
+        let handled = match options.request_log {
+            Some(request_log) => request_log.wrap(peer_addr, request, handle_request),
+            None => handle_request(request),
+        }?;
+        if handled == Handled::Subscribed {
+            // Releases the connection's slot: the subscription streams from a thread of its
+            // own, which neither the connection cap nor a shutdown waits for
+            return Ok(());
+        }
 
-            // Process Request
-            match request {
-                Request::Get { key } => {
-                    let resp = match self.engine.get(key) {
+        debug!("Response sent to {:?}", peer_addr);
+    }
+
+    Ok(())
+}
+
+/// Handles one request, sending its response.
+fn handle<E: KvsEngine>(
+    engine: &E,
+    stats: &ServerStats,
+    subscribers: &Subscribers,
+    value_type: ValueType,
+    connection: &mut Connection<'_>,
+    request: Request,
+) -> Result<Handled> {
+    match request {
+        Request::Get { key } => {
+            let start = Instant::now();
+            let result = engine.get(key);
+            stats.record(Operation::Get, start.elapsed());
+            let resp = match result {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        },
+        Request::GetAtLeast { key, min_sequence } => {
+            let resp = match wait_for_sequence(engine, min_sequence) {
+                Ok(()) => {
+                    let start = Instant::now();
+                    let result = engine.get(key);
+                    stats.record(Operation::Get, start.elapsed());
+                    match result {
                         Ok(value) => GetResponse::Ok(value),
                         Err(e) => GetResponse::Err(format!("{:?}", e)),
-                    };
-                    send_response(&mut writer, resp)?;
-                },
-                Request::Set { key, value} => {
-                    let resp = match self.engine.set(key, value) {
-                        Ok(_) => SetResponse::Ok(()),
-                        Err(e) => SetResponse::Err(format!("{:?}", e))
-                    };
-                    send_response(&mut writer, resp)?;
+                    }
+                }
+                Err(message) => GetResponse::Err(message),
+            };
+            respond(connection, resp)
+        }
+        Request::Set { key, value} => {
+            let start = Instant::now();
+            let result = value_type
+                .check(&value)
+                .and_then(|()| engine.set_sequenced(key.clone(), value.clone()));
+            stats.record(Operation::Set, start.elapsed());
+            let resp = match result {
+                Ok(sequence) => {
+                    subscribers.publish(ChangeEvent { key, value: Some(value) });
+                    SetResponse::Ok(sequence)
+                }
+                Err(e) => SetResponse::Err(format!("{:?}", e))
+            };
+            respond(connection, resp)
+        }
+        Request::ConditionalSet { key, value, condition } => {
+            let start = Instant::now();
+            let result = value_type
+                .check(&value)
+                .and_then(|()| engine.set_if(key.clone(), value.clone(), condition));
+            stats.record(Operation::Set, start.elapsed());
+            let resp = match result {
+                Ok(set) => {
+                    if set {
+                        subscribers.publish(ChangeEvent { key, value: Some(value) });
+                    }
+                    ConditionalSetResponse::Ok(set)
+                }
+                Err(e) => ConditionalSetResponse::Err(format!("{:?}", e))
+            };
+            respond(connection, resp)
+        }
+        Request::SetMany { pairs } => {
+            let start = Instant::now();
+            let result = pairs
+                .iter()
+                .try_for_each(|(_, value)| value_type.check(value))
+                .and_then(|()| engine.set_many(pairs.clone()));
+            stats.record(Operation::Set, start.elapsed());
+            let resp = match result {
+                Ok(_) => {
+                    for (key, value) in pairs {
+                        subscribers.publish(ChangeEvent { key, value: Some(value) });
+                    }
+                    SetManyResponse::Ok(())
+                }
+                Err(e) => SetManyResponse::Err(format!("{:?}", e))
+            };
+            respond(connection, resp)
+        }
+        Request::Remove { key } => {
+            let start = Instant::now();
+            let result = engine.remove_sequenced(key.clone());
+            stats.record(Operation::Remove, start.elapsed());
+            let resp = match result {
+                Ok(sequence) => {
+                    subscribers.publish(ChangeEvent { key, value: None });
+                    RemoveResponse::Ok(sequence)
+                }
+                Err(e) => RemoveResponse::Err(format!("{:?}", e))
+            };
+            respond(connection, resp)
+        }
+        Request::Undelete { key } => {
+            let result = extensions(engine, "undelete").and_then(|ext| ext.undelete(key.clone()));
+            let resp = match result {
+                Ok(restored) => {
+                    if restored {
+                        let value = engine.get(key.clone()).unwrap_or_default();
+                        subscribers.publish(ChangeEvent { key, value });
+                    }
+                    UndeleteResponse::Ok(restored)
                 }
-                Request::Remove { key } => {
-                    let resp = match self.engine.remove(key) {
-                        Ok(_) => RemoveResponse::Ok(()),
-                        Err(e) => RemoveResponse::Err(format!("{:?}", e))
-                    };
-                    send_response(&mut writer, resp)?;
+                Err(e) => UndeleteResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::ValueSize { key } => {
+            let resp = match engine.value_size(key) {
+                Ok(size) => ValueSizeResponse::Ok(size),
+                Err(e) => ValueSizeResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::ExistsMany { keys } => {
+            let resp = match engine.exists_many(keys) {
+                Ok(exists) => ExistsManyResponse::Ok(exists),
+                Err(e) => ExistsManyResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::Scan { prefix } => match engine.scan_prefix(prefix) {
+            Ok(pairs) => {
+                send_chunks(connection, pairs, ScanFrame::Chunk)?;
+                respond(connection, ScanFrame::Done)
+            }
+            Err(e) => respond(connection, ScanFrame::Err(format!("{:?}", e))),
+        },
+        Request::ScanVerbose { prefix } => {
+            let result =
+                extensions(engine, "verbose scan").and_then(|ext| ext.scan_prefix_verbose(prefix));
+            match result {
+                Ok(entries) => {
+                    send_chunks(connection, entries, ScanVerboseFrame::Chunk)?;
+                    respond(connection, ScanVerboseFrame::Done)
                 }
+                Err(e) => respond(connection, ScanVerboseFrame::Err(format!("{:?}", e))),
+            }
+        }
+        Request::RandomKey => {
+            let resp = match engine.random_key() {
+                Ok(key) => RandomKeyResponse::Ok(key),
+                Err(e) => RandomKeyResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::Export => {
+            export(engine, connection)
+        }
+        Request::Import { pairs } => {
+            let resp = match import(engine, subscribers, value_type, pairs) {
+                Ok(()) => ImportResponse::Ok(()),
+                Err(e) => ImportResponse::Err(format!("{:?}", e)),
             };
+            respond(connection, resp)
+        }
+        Request::Stats => {
+            let mut snapshot = stats.snapshot();
+            snapshot.index_memory = engine.index_memory();
+            respond(connection, StatsResponse::Ok(snapshot))
+        }
+        Request::StatsReset => {
+            let mut snapshot = stats.reset();
+            snapshot.index_memory = engine.index_memory();
+            respond(connection, StatsResponse::Ok(snapshot))
+        }
+        Request::Ping => {
+            respond(connection, PingResponse::Ok(()))
+        }
+        Request::Checkpoint => {
+            let resp = match engine.checkpoint() {
+                Ok(token) => CheckpointResponse::Ok(token),
+                Err(e) => CheckpointResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::LatestSequence => {
+            respond(connection, SequenceResponse::Ok(engine.sequence()))
+        }
+        Request::Explain { key } => {
+            let resp = match extensions(engine, "explain").and_then(|ext| ext.explain_get(key)) {
+                Ok(plan) => ExplainResponse::Ok(plan),
+                Err(e) => ExplainResponse::Err(format!("{:?}", e)),
+            };
+            respond(connection, resp)
+        }
+        Request::Subscribe { prefix } => {
+            let subscription = subscribers.subscribe(prefix.clone());
+            send_response(connection, SubscribeResponse::Ok(()))?;
+            let peer_addr = connection.get_ref().peer_addr()?;
+            debug!("{:?} subscribed", peer_addr);
 
-            debug!("Response sent to {:?}", peer_addr);
+            // The connection only carries change events from here on. Streaming gets its
+            // own thread so a long-lived subscription doesn't hold on to a pool worker.
+            let stream = connection.get_ref().try_clone()?;
+            let lag_policy = subscribers.lag_policy();
+            let engine = engine.clone();
+            thread::spawn(move || {
+                let mut connection = FramedConnection::new(&stream);
+                match stream_events(engine, &prefix, lag_policy, &mut connection, subscription) {
+                    Ok(()) => info!("Subscription of {:?} ended", peer_addr),
+                    Err(e) => {
+                        info!("Subscriber disconnected");
+                        debug!("Subscription ended with {:?}", e);
+                    }
+                }
+            });
+            Ok(Handled::Subscribed)
         }
+    }
+}
+
+/// Returns the `KvsEngineExt` operations of `engine`, or an error saying it doesn't support
+/// `operation`.
+fn extensions<'a, E: KvsEngine>(engine: &'a E, operation: &str) -> Result<&'a dyn KvsEngineExt> {
+    engine.extensions().ok_or_else(|| {
+        KvsError::StringError(format!("{} is not supported by this engine", operation))
+    })
+}
+
+/// Waits up to `MIN_SEQUENCE_WAIT` for `engine` to have applied every write up to
+/// `min_sequence`, returning the error message for the client if it didn't.
+fn wait_for_sequence<E: KvsEngine>(
+    engine: &E,
+    min_sequence: u64,
+) -> std::result::Result<(), String> {
+    if min_sequence == 0 {
+        return Ok(());
+    }
+    let deadline = Instant::now() + MIN_SEQUENCE_WAIT;
+    loop {
+        let latest = engine
+            .sequence()
+            .ok_or_else(|| "the engine doesn't number its writes".to_owned())?;
+        if latest >= min_sequence {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "sequence {} not reached within {:?}, latest is {}",
+                min_sequence, MIN_SEQUENCE_WAIT, latest
+            ));
+        }
+        thread::sleep(SEQUENCE_POLL_INTERVAL);
+    }
+}
+
+/// Streams every pair in the engine to the client in chunks of `EXPORT_CHUNK_SIZE`.
+///
+/// Only the key list is held in memory; values are read a chunk at a time.
+fn export<E: KvsEngine>(engine: &E, connection: &mut Connection<'_>) -> Result<Handled> {
+    let keys = match engine.keys() {
+        Ok(keys) => keys,
+        Err(e) => return respond(connection, ExportFrame::Err(format!("{:?}", e))),
+    };
+
+    for chunk in keys.chunks(EXPORT_CHUNK_SIZE) {
+        let mut pairs = Vec::with_capacity(chunk.len());
+        for key in chunk {
+            match engine.get(key.clone()) {
+                Ok(Some(value)) => pairs.push((key.clone(), value)),
+                // Removed since the keys were listed
+                Ok(None) => {}
+                Err(e) => return respond(connection, ExportFrame::Err(format!("{:?}", e))),
+            }
+        }
+        send_response(connection, ExportFrame::Chunk(pairs))?;
+    }
+    respond(connection, ExportFrame::Done)
+}
+
+/// Sends `items` in frames of up to `SCAN_CHUNK_SIZE` made by `chunk`, so that no single
+/// frame has to hold a large scan.
+fn send_chunks<T, F: Serialize>(
+    connection: &mut Connection<'_>,
+    items: Vec<T>,
+    chunk: impl Fn(Vec<T>) -> F,
+) -> Result<()> {
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        send_response(connection, chunk(items.by_ref().take(SCAN_CHUNK_SIZE).collect()))?;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Sets every imported pair, stopping at the first failure. Values are all checked first.
+fn import<E: KvsEngine>(
+    engine: &E,
+    subscribers: &Subscribers,
+    value_type: ValueType,
+    pairs: Vec<(String, String)>,
+) -> Result<()> {
+    for (_, value) in &pairs {
+        value_type.check(value)?;
     }
-}
\ No newline at end of file
+    for (key, value) in pairs {
+        engine.set(key.clone(), value.clone())?;
+        subscribers.publish(ChangeEvent { key, value: Some(value) });
+    }
+    Ok(())
+}
+
+/// Forwards change events to a subscribed client until it goes away or falls too far behind.
+fn stream_events<E: KvsEngine>(
+    engine: E,
+    prefix: &str,
+    lag_policy: LagPolicy,
+    connection: &mut Connection<'_>,
+    subscription: Subscription,
+) -> Result<()> {
+    loop {
+        if lag_policy == LagPolicy::Resync && subscription.lagged.load(Ordering::SeqCst) {
+            debug!("Resynchronizing lagging subscriber");
+            resync(&engine, prefix, connection, &subscription)?;
+        }
+
+        let frame = match subscription.events.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => SubscriptionFrame::Change(event),
+            Err(RecvTimeoutError::Timeout) => SubscriptionFrame::Heartbeat,
+            Err(RecvTimeoutError::Disconnected) => {
+                // The publisher only lets go of a live subscriber when it lagged
+                if subscription.lagged.load(Ordering::SeqCst) {
+                    info!("Dropping lagging subscriber");
+                    send_response(connection, SubscriptionFrame::Lagged)?;
+                }
+                return Ok(());
+            }
+        };
+        send_response(connection, frame)?;
+    }
+}
+
+/// Sends the current value of every key under `prefix`, replacing the events the subscriber
+/// missed while lagging.
+fn resync<E: KvsEngine>(
+    engine: &E,
+    prefix: &str,
+    connection: &mut Connection<'_>,
+    subscription: &Subscription,
+) -> Result<()> {
+    // Clear the flag first so new events are buffered again. Anything drained below was
+    // written to the engine before the scan, so the snapshot covers it.
+    subscription.lagged.store(false, Ordering::SeqCst);
+    while subscription.events.try_recv().is_ok() {}
+
+    for (key, value) in engine.scan_prefix(prefix.to_owned())? {
+        let event = ChangeEvent { key, value: Some(value) };
+        send_response(connection, SubscriptionFrame::Change(event))?;
+    }
+    Ok(())
+}
+
+/// Answers the connection's first request with `message` as an error, then lets it close.
+fn reject(tcp_stream: &TcpStream, message: String, max_request_size: usize) -> Result<()> {
+    tcp_stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    let mut connection = FramedConnection::new(tcp_stream).max_frame_len(max_request_size);
+    let request: Request = match connection.recv() {
+        Ok(request) => request,
+        Err(KvsError::FrameTooLarge { .. }) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    send_error(&mut connection, &request, message)
+}
+
+/// Answers `request` with the error variant of whatever response the client is waiting for.
+fn send_error(
+    connection: &mut Connection<'_>,
+    request: &Request,
+    message: String,
+) -> Result<()> {
+    match request {
+        Request::Get { .. } | Request::GetAtLeast { .. } => {
+            send_response(connection, GetResponse::Err(message))
+        }
+        Request::Set { .. } => send_response(connection, SetResponse::Err(message)),
+        Request::Remove { .. } => send_response(connection, RemoveResponse::Err(message)),
+        Request::ValueSize { .. } => send_response(connection, ValueSizeResponse::Err(message)),
+        Request::ExistsMany { .. } => {
+            send_response(connection, ExistsManyResponse::Err(message))
+        }
+        Request::Stats | Request::StatsReset => {
+            send_response(connection, StatsResponse::Err(message))
+        }
+        Request::Subscribe { .. } => send_response(connection, SubscribeResponse::Err(message)),
+        Request::ConditionalSet { .. } => {
+            send_response(connection, ConditionalSetResponse::Err(message))
+        }
+        Request::SetMany { .. } => send_response(connection, SetManyResponse::Err(message)),
+        Request::Export => send_response(connection, ExportFrame::Err(message)),
+        Request::Import { .. } => send_response(connection, ImportResponse::Err(message)),
+        Request::Checkpoint => send_response(connection, CheckpointResponse::Err(message)),
+        Request::Ping => send_response(connection, PingResponse::Err(message)),
+        Request::Explain { .. } => send_response(connection, ExplainResponse::Err(message)),
+        Request::Undelete { .. } => send_response(connection, UndeleteResponse::Err(message)),
+        Request::Scan { .. } => send_response(connection, ScanFrame::Err(message)),
+        Request::ScanVerbose { .. } => {
+            send_response(connection, ScanVerboseFrame::Err(message))
+        }
+        Request::RandomKey => send_response(connection, RandomKeyResponse::Err(message)),
+        Request::LatestSequence => send_response(connection, SequenceResponse::Err(message)),
+    }
+}
+
+/// Sends the response to a request, returning whether the request succeeded.
+fn respond<T: Response>(connection: &mut Connection<'_>, resp: T) -> Result<Handled> {
+    send_response(connection, &resp)?;
+    Ok(match resp.error() {
+        Some(message) => Handled::Failed(message.to_owned()),
+        None => Handled::Ok,
+    })
+}
+
+fn send_response<T: Serialize>(connection: &mut Connection<'_>, resp: T) -> Result<()> {
+    connection.send(&resp)
+}
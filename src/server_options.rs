@@ -0,0 +1,174 @@
+use std::fmt;
+#[cfg(feature = "http")]
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::Level;
+
+use crate::common::Request;
+use crate::request_log::RequestLog;
+use crate::{KvsError, Result};
+use crate::server::DEFAULT_MAX_REQUEST_SIZE;
+use crate::subscribe::{LagPolicy, DEFAULT_SUBSCRIBER_BUFFER};
+
+/// Tunables of a `KvsServer`, passed to `KvsServer::with_options`.
+///
+/// `ServerOptions::default()` gives the settings of `KvsServer::new`; each method changes one
+/// of them.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) max_request_size: usize,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) keepalive: Option<Duration>,
+    pub(crate) subscriber_buffer: usize,
+    pub(crate) lag_policy: LagPolicy,
+    pub(crate) request_log: Option<RequestLog>,
+    pub(crate) value_type: ValueType,
+    pub(crate) validator: Option<Validator>,
+    #[cfg(feature = "http")]
+    pub(crate) http_addr: Option<SocketAddr>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_connections_per_ip: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            read_timeout: None,
+            nodelay: false,
+            keepalive: None,
+            subscriber_buffer: DEFAULT_SUBSCRIBER_BUFFER,
+            lag_policy: LagPolicy::default(),
+            request_log: None,
+            value_type: ValueType::default(),
+            validator: None,
+            #[cfg(feature = "http")]
+            http_addr: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Caps how many connections a single client IP may have open at once, see
+    /// `KvsServer::max_connections_per_ip`. No cap by default.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Caps the size of a request's payload, in bytes, see `KvsServer::max_request_size`.
+    pub fn max_request_size(mut self, max: usize) -> Self {
+        self.max_request_size = max;
+        self
+    }
+
+    /// Closes connections that send nothing for `timeout`, e.g. clients that connect and
+    /// then hang. Connections stay open until the client closes them by default.
+    ///
+    /// The timeout applies between requests as well as within one; subscriptions, which
+    /// only send to the client, are not affected.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Turns off Nagle's algorithm on accepted connections, so small responses are sent
+    /// right away instead of being coalesced. Off by default.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Turns on TCP keep-alive on accepted connections, see `KvsServer::keepalive`.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Sets how many change events may queue up for a subscriber and what happens to one
+    /// that falls further behind, see `KvsServer::subscriber_buffer`.
+    pub fn subscriber_buffer(mut self, buffer_size: usize, lag_policy: LagPolicy) -> Self {
+        self.subscriber_buffer = buffer_size;
+        self.lag_policy = lag_policy;
+        self
+    }
+
+    /// Logs every request on the binary protocol, see `KvsServer::request_log`.
+    pub fn request_log(mut self, level: Level, max_value_len: usize) -> Self {
+        self.request_log = Some(RequestLog::new(level, max_value_len));
+        self
+    }
+
+    /// Requires set values to be of `value_type`, see `KvsServer::value_type`.
+    pub fn value_type(mut self, value_type: ValueType) -> Self {
+        self.value_type = value_type;
+        self
+    }
+
+    /// Runs `validator` on each write request before it reaches the engine, see
+    /// `KvsServer::validator`.
+    pub fn validator(
+        mut self,
+        validator: impl Fn(&Request) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Validator(Arc::new(validator)));
+        self
+    }
+
+    /// Also serves the engine over HTTP on `addr`, see `KvsServer::http_addr`.
+    #[cfg(feature = "http")]
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+}
+
+/// A check run on write requests before they reach the engine, see `ServerOptions::validator`.
+#[derive(Clone)]
+pub(crate) struct Validator(Arc<ValidateFn>);
+
+/// The check itself: `Err` with a message rejects the request.
+type ValidateFn = dyn Fn(&Request) -> std::result::Result<(), String> + Send + Sync;
+
+impl Validator {
+    /// Runs the check if `request` is a write; other requests always pass.
+    pub(crate) fn check(&self, request: &Request) -> Result<()> {
+        if !request.is_write() {
+            return Ok(());
+        }
+        (self.0)(request).map_err(KvsError::ValidationFailed)
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Validator")
+    }
+}
+
+/// What the server accepts as a value. Values are stored as strings either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueType {
+    /// Any string
+    #[default]
+    String,
+
+    /// Valid JSON; anything else is rejected with `KvsError::InvalidValueFormat`
+    Json,
+}
+
+impl ValueType {
+    /// Checks that `value` is of this type.
+    pub(crate) fn check(self, value: &str) -> Result<()> {
+        match self {
+            ValueType::String => Ok(()),
+            ValueType::Json => match serde_json::from_str::<serde::de::IgnoredAny>(value) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(KvsError::InvalidValueFormat(format!("not valid JSON: {}", e))),
+            },
+        }
+    }
+}
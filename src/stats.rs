@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use serde::{Deserialize, Serialize};
+
+/// Engine operations whose latency the server tracks.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operation {
+    Get,
+    Set,
+    Remove,
+}
+
+/// Per-operation latency histograms, shared by all connections of a server.
+///
+/// Each operation has its own lock so recording a `get` never waits on a `set`.
+pub struct ServerStats {
+    get: Mutex<Histogram<u64>>,
+    set: Mutex<Histogram<u64>>,
+    remove: Mutex<Histogram<u64>>,
+}
+
+impl ServerStats {
+    /// Creates empty histograms.
+    pub fn new() -> Self {
+        ServerStats {
+            get: Mutex::new(new_histogram()),
+            set: Mutex::new(new_histogram()),
+            remove: Mutex::new(new_histogram()),
+        }
+    }
+
+    /// Records how long one operation took.
+    pub(crate) fn record(&self, op: Operation, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.histogram(op).lock().unwrap().saturating_record(nanos);
+    }
+
+    /// Returns the current counts and latency percentiles.
+    ///
+    /// `index_memory` is left for the server to fill in from its engine.
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            get: OpStats::from(&*self.get.lock().unwrap()),
+            set: OpStats::from(&*self.set.lock().unwrap()),
+            remove: OpStats::from(&*self.remove.lock().unwrap()),
+            index_memory: None,
+        }
+    }
+
+    /// Clears the counts and latencies, returning them as they were.
+    ///
+    /// Each histogram is swapped for an empty one under its lock, so every operation is
+    /// counted either before or after the reset, never in both or neither.
+    pub fn reset(&self) -> Stats {
+        let take = |histogram: &Mutex<Histogram<u64>>| {
+            let old = std::mem::replace(&mut *histogram.lock().unwrap(), new_histogram());
+            OpStats::from(&old)
+        };
+        Stats {
+            get: take(&self.get),
+            set: take(&self.set),
+            remove: take(&self.remove),
+            index_memory: None,
+        }
+    }
+
+    fn histogram(&self, op: Operation) -> &Mutex<Histogram<u64>> {
+        match op {
+            Operation::Get => &self.get,
+            Operation::Set => &self.set,
+            Operation::Remove => &self.remove,
+        }
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats::new()
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    // 3 significant figures, auto-resizing to fit any recorded latency
+    Histogram::new(3).expect("valid histogram precision")
+}
+
+/// Snapshot of the server's operation statistics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// `get` requests
+    pub get: OpStats,
+
+    /// `set` requests
+    pub set: OpStats,
+
+    /// `remove` requests
+    pub remove: OpStats,
+
+    /// Estimated bytes of memory held by the engine's key index, if it keeps one in memory
+    pub index_memory: Option<u64>,
+}
+
+/// Count and latency percentiles (in nanoseconds) of one operation type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpStats {
+    /// Number of operations recorded
+    pub count: u64,
+
+    /// Median latency
+    pub p50_ns: u64,
+
+    /// 95th percentile latency
+    pub p95_ns: u64,
+
+    /// 99th percentile latency
+    pub p99_ns: u64,
+}
+
+impl From<&Histogram<u64>> for OpStats {
+    fn from(histogram: &Histogram<u64>) -> Self {
+        OpStats {
+            count: histogram.len(),
+            p50_ns: histogram.value_at_quantile(0.50),
+            p95_ns: histogram.value_at_quantile(0.95),
+            p99_ns: histogram.value_at_quantile(0.99),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// How many events may wait for a subscriber before it counts as lagging, by default.
+pub(crate) const DEFAULT_SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A change to a key, as delivered to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// The key that changed
+    pub key: String,
+
+    /// The new value, or `None` if the key was removed
+    pub value: Option<String>,
+}
+
+/// What the server does with a subscriber whose event buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    /// End the subscription; the client sees `KvsError::SubscriberLagged`.
+    #[default]
+    Disconnect,
+
+    /// Drop the buffered events and, once the subscriber catches up, send the current value
+    /// of every matching key before resuming live events. Keys removed while the subscriber
+    /// was lagging are not reported.
+    Resync,
+}
+
+/// Frames sent to a subscribed connection.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum SubscriptionFrame {
+    Change(ChangeEvent),
+    // Sent when there was nothing to deliver for a while, so both ends notice a dead peer
+    Heartbeat,
+    // Sent before the server ends a subscription that fell too far behind
+    Lagged,
+}
+
+/// The server's end of one subscription.
+pub(crate) struct Subscription {
+    pub(crate) events: Receiver<ChangeEvent>,
+
+    // Set by the publisher when the buffer overflowed
+    pub(crate) lagged: Arc<AtomicBool>,
+}
+
+struct Subscriber {
+    prefix: String,
+    events: SyncSender<ChangeEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Registry of the server's active subscriptions.
+pub(crate) struct Subscribers {
+    buffer_size: usize,
+    lag_policy: LagPolicy,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    pub(crate) fn new(buffer_size: usize, lag_policy: LagPolicy) -> Self {
+        Subscribers {
+            buffer_size,
+            lag_policy,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn lag_policy(&self) -> LagPolicy {
+        self.lag_policy
+    }
+
+    /// Registers interest in keys starting with `prefix`.
+    pub(crate) fn subscribe(&self, prefix: String) -> Subscription {
+        let (sender, receiver) = mpsc::sync_channel(self.buffer_size);
+        let lagged = Arc::new(AtomicBool::new(false));
+        self.subscribers.lock().unwrap().push(Subscriber {
+            prefix,
+            events: sender,
+            lagged: Arc::clone(&lagged),
+        });
+        Subscription {
+            events: receiver,
+            lagged,
+        }
+    }
+
+    /// Delivers `event` to every matching subscriber without blocking.
+    ///
+    /// Subscribers that went away are dropped. One whose buffer is full is flagged as lagging
+    /// and then either dropped or, under `LagPolicy::Resync`, skipped until it resynchronizes.
+    pub(crate) fn publish(&self, event: ChangeEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let lag_policy = self.lag_policy;
+        subscribers.retain(|subscriber| {
+            if !event.key.starts_with(subscriber.prefix.as_str()) {
+                return true;
+            }
+            if subscriber.lagged.load(Ordering::SeqCst) {
+                return lag_policy == LagPolicy::Resync;
+            }
+            match subscriber.events.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::SeqCst);
+                    lag_policy == LagPolicy::Resync
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
@@ -0,0 +1,65 @@
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use socket2::{SockRef, Type};
+
+use crate::Result;
+
+// The first socket passed by systemd, the ones after it are numbered on from it
+const LISTEN_FDS_START: RawFd = 3;
+
+// The passed sockets may only be owned once, or they would be closed twice
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Returns the listening sockets passed by systemd socket activation, in the order of the
+/// socket unit's `ListenStream=` lines, or none if the process wasn't socket activated.
+///
+/// The sockets are described by the `LISTEN_PID` and `LISTEN_FDS` environment variables,
+/// which are only honored if `LISTEN_PID` is this process, so that they aren't picked up by
+/// a child they leaked to. They are not cleared; calls after the first return none.
+///
+/// # Errors
+///
+/// It fails if `LISTEN_FDS` isn't a number, or a passed file descriptor isn't a TCP stream
+/// socket.
+///
+/// # Safety
+///
+/// The file descriptors `LISTEN_FDS` names, from 3 on, must be open and owned by nothing else
+/// in the process: the returned listeners take ownership of them and close them on drop.
+/// That holds when systemd started the process, as long as nothing closed or wrapped those
+/// descriptors since; files opened in the meantime got other numbers.
+pub unsafe fn systemd_listeners() -> Result<Vec<TcpListener>> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == process::id());
+    let Some(fds) = env::var("LISTEN_FDS").ok().filter(|_| for_us) else {
+        return Ok(Vec::new());
+    };
+    let fds: RawFd = fds.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid LISTEN_FDS: {:?}", fds))
+    })?;
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .map(|fd| {
+            // SAFETY: the caller guarantees the descriptor is open and unowned, and `TAKEN`
+            // makes this its only owner
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // `local_addr` alone would accept a UDP socket
+            if SockRef::from(&listener).r#type()? != Type::STREAM {
+                let e = format!("file descriptor {} is not a stream socket", fd);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e).into());
+            }
+            listener.local_addr()?;
+            Ok(listener)
+        })
+        .collect()
+}
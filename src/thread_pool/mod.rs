@@ -2,17 +2,44 @@ mod naive;
 mod shared_queue;
 mod rayon;
 
-use crate::Result;
+use std::io;
+use std::num::NonZeroUsize;
+
+use crate::{KvsError, Result};
 
 pub use self::naive::NaiveThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
 pub use self::rayon::RayonThreadPool;
 
+/// Worker count used when the number of logical CPUs can't be detected.
+pub const FALLBACK_THREADS: u32 = 4;
+
+/// Picks a worker count from the detected number of logical CPUs, as returned by
+/// `std::thread::available_parallelism`.
+///
+/// Detection can fail, e.g. in some sandboxes; then `FALLBACK_THREADS` is used.
+pub fn default_thread_count(detected: io::Result<NonZeroUsize>) -> u32 {
+    match detected {
+        Ok(count) => u32::try_from(count.get()).unwrap_or(u32::MAX),
+        Err(_) => FALLBACK_THREADS,
+    }
+}
+
+/// Rejects thread counts that would leave a fixed-size pool without workers.
+fn check_thread_count(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::StringError("thread count must be >= 1".to_owned()));
+    }
+    Ok(())
+}
+
 /// Thread pool
 pub trait ThreadPool {
     /// Creates new thread pool with a specific number of threads
     ///
-    /// Returns n error if any thread fails to spawn.
+    /// Returns an error if any thread fails to spawn. Pools with a fixed set of workers
+    /// return `KvsError::StringError` for `threads == 0`, since such a pool could never run
+    /// a job; pools without one (`NaiveThreadPool`) ignore the count.
     fn new(threads: u32) -> Result<Self> where Self: Sized;
 
     /// Spawns a function into the threadpool
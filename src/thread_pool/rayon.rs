@@ -1,17 +1,30 @@
-use crate::thread_pool::ThreadPool;
+use log::error;
+
+use crate::thread_pool::{check_thread_count, ThreadPool};
+use crate::KvsError;
 
 /// Rayon threadpool
-pub struct RayonThreadPool;
+///
+/// Wraps a `rayon::ThreadPool`; jobs go onto its work-stealing queues.
+pub struct RayonThreadPool(rayon::ThreadPool);
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> crate::Result<Self> {
-        todo!()
+        // rayon would silently pick its own default for zero
+        check_thread_count(threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler rayon aborts the process when a spawned job panics
+            .panic_handler(|_| error!("A job panicked in the rayon thread pool"))
+            .build()
+            .map_err(|e| KvsError::StringError(format!("{}", e)))?;
+        Ok(RayonThreadPool(pool))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static
     {
-        todo!()
+        self.0.spawn(job)
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,70 @@
-use crate::thread_pool::ThreadPool;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error};
+
+use crate::thread_pool::{check_thread_count, ThreadPool};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
 /// Shared queue threadpool
-pub struct SharedQueueThreadPool;
+///
+/// A fixed number of worker threads pull jobs off a single queue. If a job panics,
+/// the worker it ran on is replaced so the pool keeps its size.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> crate::Result<Self> {
-        todo!()
+        check_thread_count(threads)?;
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let task_receiver = TaskReceiver(Arc::clone(&receiver));
+            thread::Builder::new().spawn(move || run_tasks(task_receiver))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static
     {
-        todo!()
+        self.sender
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+/// The receiving end of the queue, owned by one worker thread.
+///
+/// Dropping it while the thread is panicking spawns a replacement worker.
+#[derive(Clone)]
+struct TaskReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for TaskReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let task_receiver = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(task_receiver)) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_tasks(task_receiver: TaskReceiver) {
+    loop {
+        // The guard is a temporary, so the lock is released before the job runs
+        let job = task_receiver.0.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,83 @@
+// Counts heap allocations, with a global allocator that only this test binary uses.
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static LARGEST_ALLOCATION: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        LARGEST_ALLOCATION.fetch_max(layout.size(), Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        LARGEST_ALLOCATION.fetch_max(new_size, Ordering::SeqCst);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Reading a record reuses the store's read buffer, and checksumming it copies nothing. All
+// that's left per `get` is decoding the record's key and value, the latter being returned.
+#[test]
+fn get_reuses_read_buffer() -> Result<()> {
+    const READS: usize = 1000;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Warm up: open the log file reader and size the buffer
+    store.get("key0".to_owned())?;
+
+    let keys: Vec<String> = (0..READS).map(|i| format!("key{}", i % 10)).collect();
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for key in keys {
+        assert!(store.get(key)?.is_some());
+    }
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+    assert!(allocations < 3 * READS, "{} allocations for {} reads", allocations, READS);
+    Ok(())
+}
+
+// An oversized request is turned away on its length prefix alone: the server never allocates
+// a buffer for the payload the client claims to send.
+#[test]
+fn oversized_request_is_not_buffered() -> Result<()> {
+    const CLAIMED_LEN: u32 = 512 * 1024 * 1024;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(1)?).max_request_size(1024);
+    let (addr, _server) = server.spawn_ephemeral()?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&CLAIMED_LEN.to_be_bytes())?;
+    stream.write_all(&[0; 64])?;
+
+    // The server hangs up instead of waiting for the rest
+    let mut buf = [0; 16];
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+    let largest = LARGEST_ALLOCATION.load(Ordering::SeqCst);
+    assert!(largest < CLAIMED_LEN as usize, "allocated {} bytes", largest);
+    Ok(())
+}
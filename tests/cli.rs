@@ -0,0 +1,562 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{kvs_server_serving, loopback_listener, ServerProcess};
+use kvs::{KvStore, KvsClient, KvsEngine};
+use std::io::{BufRead, BufReader};
+use std::fs;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts `kvs-server` with the given extra args and returns the first log line containing `needle`.
+fn server_log_line(args: &[&str], needle: &str) -> String {
+    let temp_dir = TempDir::new().unwrap();
+    server_log_line_in(temp_dir.path(), args, needle)
+}
+
+// Like `server_log_line`, but runs the server in `dir`.
+fn server_log_line_in(dir: &Path, args: &[&str], needle: &str) -> String {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0"])
+        .args(args)
+        .current_dir(dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let line = stderr
+        .lines()
+        .map(|line| line.unwrap())
+        .find(|line| line.contains(needle));
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    line.unwrap_or_else(|| panic!("server never logged {:?}", needle))
+}
+
+#[test]
+fn server_threads_default_to_available_parallelism() {
+    let expected = std::thread::available_parallelism().unwrap().get();
+    let line = server_log_line(&[], "Worker threads:");
+    assert!(
+        line.ends_with(&format!("Worker threads: {}", expected)),
+        "unexpected log line: {}",
+        line
+    );
+}
+
+#[test]
+fn server_threads_flag_overrides_default() {
+    let line = server_log_line(&["--threads", "3"], "Worker threads:");
+    assert!(line.ends_with("Worker threads: 3"), "unexpected log line: {}", line);
+}
+
+#[test]
+fn server_rejects_zero_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+fn pool_serves_set_and_get(pool: &str) {
+    let server = ServerProcess::start(&["--pool", pool]);
+    server.client(&["set", "key1", "value1"]).success();
+    server
+        .client(&["get", "key1"])
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn server_naive_pool_serves_requests() {
+    pool_serves_set_and_get("naive");
+}
+
+#[test]
+fn server_shared_queue_pool_serves_requests() {
+    pool_serves_set_and_get("shared-queue");
+}
+
+#[test]
+fn server_rayon_pool_serves_requests() {
+    pool_serves_set_and_get("rayon");
+}
+
+#[test]
+fn server_rejects_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs_config.toml"), "engine = \"kvs").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("BadConfig"));
+}
+
+#[test]
+fn server_ignore_bad_config_falls_back_to_default() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs_config.toml"), "engine = \"kvs").unwrap();
+    let line = server_log_line_in(temp_dir.path(), &["--ignore-bad-config"], "Storage engine:");
+    assert!(line.ends_with("Storage engine: kvs"), "unexpected log line: {}", line);
+}
+
+#[cfg(unix)]
+#[test]
+fn client_watch_prints_changes() {
+    use std::sync::mpsc;
+
+    let server = ServerProcess::start(&[]);
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "app", "--addr", &server.addr])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let (line_tx, line_rx) = mpsc::channel();
+    let stdout = BufReader::new(watcher.stdout.take().unwrap());
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if line_tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Keep writing a probe key until the watcher shows it, so the subscription is live
+    let mut subscribed = false;
+    for _ in 0..50 {
+        server.client(&["set", "app-probe", "1"]).success();
+        if line_rx.recv_timeout(Duration::from_millis(100)).is_ok() {
+            subscribed = true;
+            break;
+        }
+    }
+    assert!(subscribed, "watcher never reported the probe write");
+    while line_rx.recv_timeout(Duration::from_millis(200)).is_ok() {}
+
+    server.client(&["set", "app1", "value1"]).success();
+    server.client(&["set", "other", "ignored"]).success();
+    server.client(&["rm", "app1"]).success();
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(line_rx.recv_timeout(timeout).unwrap(), "set app1 value1");
+    assert_eq!(line_rx.recv_timeout(timeout).unwrap(), "rm app1");
+
+    // Ctrl-C ends the watch cleanly
+    Command::new("kill")
+        .args(["-INT", &watcher.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(watcher.wait().unwrap().success());
+}
+
+fn conditional_set(engine: &str) {
+    let server = ServerProcess::start(&["--engine", engine]);
+    server.client(&["set", "key1", "value1"]).success();
+
+    // --nx on an existing key is a no-op
+    server
+        .client(&["set", "key1", "value2", "--nx"])
+        .code(2)
+        .stderr("Key already exists\n");
+    server.client(&["get", "key1"]).success().stdout("value1\n");
+
+    // --xx on a missing key is a no-op
+    server
+        .client(&["set", "key2", "value2", "--xx"])
+        .code(2)
+        .stderr("Key not found\n");
+    server.client(&["get", "key2"]).success().stdout("Key not found\n");
+
+    server.client(&["set", "key2", "value2", "--nx"]).success();
+    server.client(&["set", "key1", "value3", "--xx"]).success();
+    server.client(&["get", "key1"]).success().stdout("value3\n");
+    server.client(&["get", "key2"]).success().stdout("value2\n");
+
+    server.client(&["set", "key1", "value4", "--nx", "--xx"]).failure();
+}
+
+#[test]
+fn client_conditional_set_kvs() {
+    conditional_set("kvs");
+}
+
+#[test]
+fn client_conditional_set_sled() {
+    conditional_set("sled");
+}
+
+#[test]
+fn client_export_import_between_servers() {
+    let source = ServerProcess::start(&[]);
+    let target = ServerProcess::start(&["--engine", "sled"]);
+    let temp_dir = TempDir::new().unwrap();
+    let source_dump = temp_dir.path().join("source.dump");
+    let target_dump = temp_dir.path().join("target.dump");
+
+    // Spans several export chunks
+    let mut client = KvsClient::connect(&source.addr).unwrap();
+    for i in 0..2500 {
+        client.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    client.remove("key7".to_owned()).unwrap();
+    // Free the connection's worker for the export
+    drop(client);
+
+    source
+        .client(&["export", source_dump.to_str().unwrap()])
+        .success()
+        .stdout("Exported 2499 keys\n");
+    target
+        .client(&["import", source_dump.to_str().unwrap()])
+        .success()
+        .stdout("Imported 2499 keys\n");
+
+    target.client(&["get", "key42"]).success().stdout("value42\n");
+    target.client(&["get", "key7"]).success().stdout("Key not found\n");
+
+    // Both servers hold exactly the same pairs
+    target
+        .client(&["export", target_dump.to_str().unwrap()])
+        .success();
+    assert_eq!(fs::read(&source_dump).unwrap(), fs::read(&target_dump).unwrap());
+}
+
+fn mset(engine: &str) {
+    let server = ServerProcess::start(&["--engine", engine]);
+    server.client(&["set", "key1", "old"]).success();
+    server
+        .client(&["mset", "key1", "value1", "key2", "value2", "key3", "value3"])
+        .success()
+        .stdout("");
+    server.client(&["get", "key1"]).success().stdout("value1\n");
+    server.client(&["get", "key2"]).success().stdout("value2\n");
+    server.client(&["get", "key3"]).success().stdout("value3\n");
+
+    // A key without a value is rejected before anything is sent
+    server.client(&["mset", "key4", "value4", "key5"]).code(2);
+    server.client(&["get", "key4"]).success().stdout("Key not found\n");
+}
+
+#[test]
+fn client_mset_kvs() {
+    mset("kvs");
+}
+
+#[test]
+fn client_mset_sled() {
+    mset("sled");
+}
+
+#[test]
+fn server_logs_compaction_window() {
+    let line = server_log_line(&["--compaction-window", "22:30-04:00"], "Compaction window:");
+    assert!(line.ends_with("Compaction window: 22:30-04:00 UTC"), "unexpected log line: {}", line);
+}
+
+#[test]
+fn server_rejects_invalid_compaction_window() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--compaction-window", "02:00-24:00"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+fn strlen(engine: &str) {
+    let server = ServerProcess::start(&["--engine", engine]);
+    server.client(&["set", "key1", "héllo"]).success();
+    server.client(&["strlen", "key1"]).success().stdout("6\n");
+    server.client(&["strlen", "key2"]).success().stdout("Key not found\n");
+}
+
+fn exists(engine: &str) {
+    let server = ServerProcess::start(&["--engine", engine]);
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["set", "key3", "value3"]).success();
+    server.client(&["exists", "key1", "key2", "key3"]).success().stdout("1\n0\n1\n");
+}
+
+#[test]
+fn client_exists_kvs() {
+    exists("kvs");
+}
+
+#[test]
+fn client_exists_sled() {
+    exists("sled");
+}
+
+#[test]
+fn client_strlen_kvs() {
+    strlen("kvs");
+}
+
+#[test]
+fn client_strlen_sled() {
+    strlen("sled");
+}
+
+#[test]
+fn server_replay_only_summarizes_healthy_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path(), None, None).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value3".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0", "--replay-only"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Records: 3"))
+        .stdout(predicates::str::contains("Keys: 2"))
+        .stdout(predicates::str::contains("Corrupt records: 0"));
+}
+
+#[test]
+fn server_replay_only_reports_corruption() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path(), None, None).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    // Flip the last byte of the value so the record no longer matches its checksum
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path).unwrap();
+    let value_end = log.windows(6).position(|w| w == b"value1").unwrap() + 5;
+    log[value_end] ^= 0xff;
+    fs::write(&log_path, log).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:0", "--replay-only"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("Corrupt records: 1"))
+        .stderr(predicates::str::contains("Corrupt record in 1.log at offset 0"));
+}
+
+// Compaction only logs at debug level, so it must not write to stdout.
+#[test]
+fn server_compaction_writes_nothing_to_stdout() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = loopback_listener();
+    let mut child = kvs_server_serving(&listener)
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut client = KvsClient::connect(listener.local_addr().unwrap()).unwrap();
+    // Overwrite the same keys until more than the 1MB compaction threshold is stale
+    for _ in 0..12 {
+        let pairs = (0..100)
+            .map(|i| (format!("key{}", i), "v".repeat(1024)))
+            .collect();
+        client.set_many(pairs).unwrap();
+    }
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("v".repeat(1024)));
+    drop(client);
+
+    child.kill().unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(!temp_dir.path().join("1.log").exists(), "compaction never ran");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+}
+
+#[test]
+fn client_checkpoint_prints_sequence() {
+    let server = ServerProcess::start(&[]);
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["set", "key1", "value2"]).success();
+    server.client(&["checkpoint"]).success().stdout("Checkpoint 2\n");
+    server.client(&["get", "key1"]).success().stdout("value2\n");
+    server.client(&["sequence"]).success().stdout("2\n");
+}
+
+#[test]
+fn server_bench_mode_warns_about_durability() {
+    let line = server_log_line(&["--bench-mode"], "BENCH MODE");
+    assert!(line.contains("Data WILL be lost"), "unexpected log line: {}", line);
+}
+
+// Durability across a crash is given up in bench mode, but results within a run are not.
+#[test]
+fn server_bench_mode_serves_requests() {
+    let server = ServerProcess::start(&["--bench-mode"]);
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["set", "key2", "value2"]).success();
+    server.client(&["set", "key1", "value3"]).success();
+    server.client(&["get", "key1"]).success().stdout("value3\n");
+    server.client(&["rm", "key2"]).success();
+    server.client(&["get", "key2"]).success().stdout("Key not found\n");
+}
+
+#[test]
+fn client_explain() {
+    let server = ServerProcess::start(&[]);
+    server.client(&["set", "key1", "value1"]).success();
+    server
+        .client(&["explain", "key1"])
+        .success()
+        .stdout(predicates::str::contains("generation: 1"))
+        .stdout(predicates::str::contains("sequence: 1"));
+    server.client(&["explain", "key2"]).success().stdout("Key not found\n");
+}
+
+#[test]
+fn client_undelete() {
+    let server = ServerProcess::start(&["--soft-delete-retention-secs", "60"]);
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["rm", "key1"]).success();
+    server.client(&["get", "key1"]).success().stdout("Key not found\n");
+    server.client(&["undelete", "key1"]).success().stdout("");
+    server.client(&["get", "key1"]).success().stdout("value1\n");
+    server
+        .client(&["undelete", "key1"])
+        .failure()
+        .stderr("Key not found\n");
+}
+
+#[test]
+fn client_randomkey() {
+    let server = ServerProcess::start(&[]);
+    server.client(&["randomkey"]).success().stdout("No keys\n");
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["randomkey"]).success().stdout("key1\n");
+}
+
+#[test]
+fn client_get_no_newline() {
+    let server = ServerProcess::start(&[]);
+    server.client(&["set", "key1", "line1\nline2\n\n"]).success();
+    server.client(&["get", "key1", "--no-newline"]).success().stdout("line1\nline2\n\n");
+    server.client(&["get", "key1", "-n"]).success().stdout("line1\nline2\n\n");
+    server
+        .client(&["get", "key2", "-n"])
+        .success()
+        .stdout("")
+        .stderr("Key not found\n");
+}
+
+#[test]
+fn client_benchmark_reports_throughput() {
+    // Each connection keeps a worker thread busy while it is open
+    let server = ServerProcess::start(&["--threads", "2"]);
+    let output = server
+        .client(&["benchmark", "--duration-secs", "1", "--concurrency", "2", "--read-ratio", "0.5"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines = output.lines();
+
+    let summary = lines.next().unwrap();
+    let throughput: f64 = summary.split(' ').next().unwrap().parse().unwrap();
+    assert!(throughput > 0.0, "unexpected summary: {}", summary);
+    assert!(summary.ends_with(" 0 errors"), "unexpected summary: {}", summary);
+    for op in ["get", "set"] {
+        let line = lines.next().unwrap();
+        assert!(line.starts_with(&format!("{}: count=", op)), "unexpected line: {}", line);
+        assert!(!line.contains("count=0 "), "no {}s were sent: {}", op, line);
+    }
+}
+
+#[test]
+fn keepalive_option_on_both_binaries() {
+    let server = ServerProcess::start(&["--keepalive-secs", "30"]);
+    server
+        .client(&["set", "key1", "value1", "--keepalive-secs", "30"])
+        .success();
+    server.client(&["get", "key1", "--keepalive-secs", "30"]).success().stdout("value1\n");
+    server.client(&["get", "key1", "--keepalive-secs", "0"]).failure();
+}
+
+// SIGTERM stops the server without losing acknowledged writes. With `--bench-mode` they sit
+// in the writer's buffer, so they only reach the log if the server winds down properly.
+#[cfg(unix)]
+#[test]
+fn server_shuts_down_cleanly_on_sigterm() {
+    let mut server = ServerProcess::start(&["--bench-mode"]);
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["set", "key2", "value2"]).success();
+    // An idle connection doesn't hold up the shutdown
+    let _idle = TcpStream::connect(&server.addr).unwrap();
+
+    Command::new("kill")
+        .args(["-TERM", &server.child.id().to_string()])
+        .status()
+        .unwrap();
+    let mut status = None;
+    for _ in 0..100 {
+        status = server.child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let status = status.expect("server didn't exit after SIGTERM");
+    assert!(status.success(), "server exited with {}", status);
+
+    let store = KvStore::open(server.temp_dir.as_ref().unwrap().path(), None, None).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+}
+
+// With `--systemd`, a listening socket passed as fd 3 under `LISTEN_PID`/`LISTEN_FDS` is
+// served instead of binding `--addr`; without one, `--addr` is bound as usual.
+#[cfg(target_os = "linux")]
+#[test]
+fn server_systemd_socket_activation() {
+    let passed = loopback_listener();
+    // Held by the test, so the server would fail to start if it bound `--addr`
+    let held = loopback_listener();
+    let held_addr = held.local_addr().unwrap().to_string();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = ServerProcess::start_on(temp_dir.path(), &passed, &["--addr", &held_addr]);
+    drop(passed);
+    server.temp_dir = Some(temp_dir);
+
+    server.client(&["set", "key1", "value1"]).success();
+    server.client(&["get", "key1"]).success().stdout("value1\n");
+
+    let line = server_log_line(&["--systemd"], "No socket passed by systemd");
+    assert!(line.ends_with("binding 127.0.0.1:0"), "unexpected log line: {}", line);
+}
+
+// A passed socket that isn't a TCP stream socket is refused rather than served.
+#[cfg(target_os = "linux")]
+#[test]
+fn server_systemd_rejects_datagram_socket() {
+    let passed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut command = kvs_server_serving(&passed);
+    command.args(["--addr", "127.0.0.1:0"]).current_dir(&temp_dir);
+    let output = command.output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a stream socket"));
+}
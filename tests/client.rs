@@ -0,0 +1,101 @@
+use kvs::{KvsClient, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// Mirrors the wire format of the server's get response
+#[derive(Serialize, Deserialize)]
+enum GetResponse {
+    Ok(Option<String>),
+    #[allow(dead_code)]
+    Err(String),
+}
+
+fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes).unwrap();
+    let mut buf = vec![0; u32::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+fn write_frame<T: Serialize>(stream: &mut TcpStream, value: &T) {
+    let serialized = bincode::serialize(value).unwrap();
+    stream
+        .write_all(&(serialized.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&serialized).unwrap();
+}
+
+// A server that reads a request but never answers must not hang the client,
+// and the next request must go over a fresh connection.
+#[test]
+fn client_reconnects_after_unanswered_request() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+
+    let server = thread::spawn(move || {
+        // First connection: swallow the request and stay silent
+        let (mut silent, _) = listener.accept().unwrap();
+        read_frame(&mut silent);
+
+        // Second connection: behave
+        let (mut healthy, _) = listener.accept().unwrap();
+        read_frame(&mut healthy);
+        write_frame(&mut healthy, &GetResponse::Ok(Some("value1".to_owned())));
+
+        done_rx.recv().unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_timeout(Some(Duration::from_millis(200)))?;
+
+    match client.get("key1".to_owned()) {
+        Err(KvsError::ConnectionBroken(_)) => {}
+        other => panic!("expected ConnectionBroken, got {:?}", other),
+    }
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    done_tx.send(()).unwrap();
+    server.join().unwrap();
+    Ok(())
+}
+
+// Keep-alive is set on the open connection and on every connection opened after a reset.
+#[test]
+fn keepalive_applies_to_new_connections() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    let server = thread::spawn(move || {
+        // First connection: hang up without answering
+        let (mut dropped, _) = listener.accept().unwrap();
+        read_frame(&mut dropped);
+        drop(dropped);
+
+        let (mut healthy, _) = listener.accept().unwrap();
+        read_frame(&mut healthy);
+        write_frame(&mut healthy, &GetResponse::Ok(Some("value1".to_owned())));
+        healthy
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.keepalive()?, None);
+    client.set_keepalive(Some(Duration::from_secs(7)))?;
+    assert_eq!(client.keepalive()?, Some(Duration::from_secs(7)));
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert_eq!(client.keepalive()?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.keepalive()?, Some(Duration::from_secs(7)));
+
+    client.set_keepalive(None)?;
+    assert_eq!(client.keepalive()?, None);
+    drop(server.join().unwrap());
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Helpers shared by the test binaries that run the `kvs-server` binary. Each binary uses only
+// some of them.
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Binds a listener to an ephemeral loopback port. Servers are handed the listener rather than
+// its address, so nothing else can take the port in between.
+pub fn loopback_listener() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").unwrap()
+}
+
+// A `kvs-server` command serving `socket` instead of binding `--addr`: the socket is passed
+// the way systemd socket activation passes it, as fd 3 with `LISTEN_PID` set to the server's
+// own pid, which the shell keeps by exec'ing it.
+pub fn kvs_server_serving(socket: &impl AsRawFd) -> Command {
+    let fd = socket.as_raw_fd();
+    let server_bin = Command::cargo_bin("kvs-server").unwrap().get_program().to_owned();
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\""])
+        .arg(server_bin)
+        .arg("--systemd");
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, 3) < 0 || libc::fcntl(3, libc::F_SETFD, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command
+}
+
+// A running `kvs-server` process, killed on drop.
+pub struct ServerProcess {
+    pub child: Child,
+    pub addr: String,
+
+    // The data directory, if the server was started in one of its own
+    pub temp_dir: Option<TempDir>,
+}
+
+impl ServerProcess {
+    // Starts `kvs-server` in a fresh directory and waits until it answers.
+    pub fn start(args: &[&str]) -> ServerProcess {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = ServerProcess::start_on(temp_dir.path(), &loopback_listener(), args);
+        server.temp_dir = Some(temp_dir);
+        server
+    }
+
+    // Starts `kvs-server` in `dir`, serving `listener`, and waits until it answers. Both
+    // outlive the server, e.g. to restart it on the same data and address.
+    pub fn start_on(dir: &Path, listener: &TcpListener, args: &[&str]) -> ServerProcess {
+        let child = kvs_server_serving(listener)
+            .args(args)
+            .current_dir(dir)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = ServerProcess { child, addr, temp_dir: None };
+
+        // Connecting succeeds right away, the listener being bound already; the request is
+        // answered once the server is up
+        let mut client = KvsClient::connect(&server.addr).unwrap();
+        client.set_timeout(Some(Duration::from_secs(10))).unwrap();
+        if let Err(e) = client.ping() {
+            panic!("server on {} never answered: {:?}", server.addr, e);
+        }
+        server
+    }
+
+    pub fn client(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", &self.addr])
+            .assert()
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
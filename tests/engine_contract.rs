@@ -0,0 +1,405 @@
+// Behavior every `KvsEngine` must share. Each engine runs the same battery through
+// `engine_contract_tests!`, given how to open it on a directory.
+
+use kvs::{KvStore, KvsEngine, KvsError, Result, SetCondition, SledKvsEngine};
+use std::collections::HashSet;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Opens an engine on a directory; persistent engines see earlier data when reopened.
+struct Harness<E, F: Fn(&Path) -> Result<E>> {
+    open: F,
+    persistent: bool,
+    temp_dir: TempDir,
+}
+
+impl<E: KvsEngine, F: Fn(&Path) -> Result<E>> Harness<E, F> {
+    fn new(open: F, persistent: bool) -> Self {
+        Harness {
+            open,
+            persistent,
+            temp_dir: TempDir::new().expect("unable to create temporary working directory"),
+        }
+    }
+
+    fn open(&self) -> Result<E> {
+        (self.open)(self.temp_dir.path())
+    }
+
+    // Closes the engine and opens it again, if its data outlives it.
+    fn reopen(&self, engine: E) -> Result<Option<E>> {
+        drop(engine);
+        if !self.persistent {
+            return Ok(None);
+        }
+        // sled lets go of its directory lock from a background thread, so the first attempts
+        // may still find it held
+        for _ in 0..50 {
+            if let Ok(engine) = self.open() {
+                return Ok(Some(engine));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        Ok(Some(self.open()?))
+    }
+}
+
+fn get_stored_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+fn overwrite_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+        engine.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+fn get_non_existent_value<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.value_size("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn remove_non_existent_key<E: KvsEngine, F: Fn(&Path) -> Result<E>>(
+    h: Harness<E, F>,
+) -> Result<()> {
+    let engine = h.open()?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+fn remove_key<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
+
+fn remove_many<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    for i in 0..3 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Missing and repeated keys aren't counted
+    let keys = ["key0", "key2", "key2", "missing"].map(str::to_owned).to_vec();
+    assert_eq!(engine.remove_many(keys)?, 2);
+    assert_eq!(engine.keys()?, vec!["key1".to_owned()]);
+    assert_eq!(engine.remove_many(vec!["key0".to_owned()])?, 0);
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.keys()?, vec!["key1".to_owned()]);
+    }
+    Ok(())
+}
+
+fn conditional_set<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    assert!(!engine.set_if(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        SetCondition::IfPresent
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.set_if(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        SetCondition::IfAbsent
+    )?);
+    assert!(!engine.set_if(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        SetCondition::IfAbsent
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.set_if(
+        "key1".to_owned(),
+        "value3".to_owned(),
+        SetCondition::IfPresent
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn set_many<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "old".to_owned())?;
+    engine.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.count()?, 2);
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+fn value_size<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "héllo".to_owned())?;
+    assert_eq!(engine.value_size("key1".to_owned())?, Some(6));
+    Ok(())
+}
+
+// Over many picks from a few keys, every live key comes up and removed ones never do.
+fn random_key<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    assert_eq!(engine.random_key()?, None);
+
+    for i in 0..4 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    engine.remove("key3".to_owned())?;
+
+    let mut picked = HashSet::new();
+    for _ in 0..300 {
+        picked.insert(engine.random_key()?.expect("no key picked"));
+    }
+    let expected: HashSet<String> = (0..3).map(|i| format!("key{}", i)).collect();
+    assert_eq!(picked, expected);
+    Ok(())
+}
+
+fn checkpoint<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    let first = engine.checkpoint()?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(engine.checkpoint()? > first);
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+fn flush<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.flush()?;
+
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+fn concurrent_set<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    // Joining drops every clone, so persistent engines can be reopened below
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    if let Some(engine) = h.reopen(engine)? {
+        assert_eq!(engine.count()?, 100);
+    }
+    Ok(())
+}
+
+fn concurrent_get<E: KvsEngine, F: Fn(&Path) -> Result<E>>(h: Harness<E, F>) -> Result<()> {
+    let engine = h.open()?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let handles: Vec<_> = (0..16)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key_id = (i + thread_id) % 100;
+                    assert_eq!(
+                        engine.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Runs the whole contract against one engine, as a module of tests named `$name`.
+macro_rules! engine_contract_tests {
+    ($name:ident, $engine:ty, persistent: $persistent:expr, $open:expr) => {
+        mod $name {
+            use super::*;
+
+            fn harness() -> Harness<$engine, fn(&Path) -> Result<$engine>> {
+                Harness::new($open, $persistent)
+            }
+
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                super::get_stored_value(harness())
+            }
+
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                super::overwrite_value(harness())
+            }
+
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                super::get_non_existent_value(harness())
+            }
+
+            #[test]
+            fn remove_non_existent_key() -> Result<()> {
+                super::remove_non_existent_key(harness())
+            }
+
+            #[test]
+            fn remove_key() -> Result<()> {
+                super::remove_key(harness())
+            }
+
+            #[test]
+            fn remove_many() -> Result<()> {
+                super::remove_many(harness())
+            }
+
+            #[test]
+            fn conditional_set() -> Result<()> {
+                super::conditional_set(harness())
+            }
+
+            #[test]
+            fn set_many() -> Result<()> {
+                super::set_many(harness())
+            }
+
+            #[test]
+            fn value_size() -> Result<()> {
+                super::value_size(harness())
+            }
+
+            #[test]
+            fn random_key() -> Result<()> {
+                super::random_key(harness())
+            }
+
+            #[test]
+            fn checkpoint() -> Result<()> {
+                super::checkpoint(harness())
+            }
+
+            #[test]
+            fn flush() -> Result<()> {
+                super::flush(harness())
+            }
+
+            #[test]
+            fn concurrent_set() -> Result<()> {
+                super::concurrent_set(harness())
+            }
+
+            #[test]
+            fn concurrent_get() -> Result<()> {
+                super::concurrent_get(harness())
+            }
+        }
+    };
+}
+
+engine_contract_tests!(kv_store, KvStore, persistent: true, |path| {
+    KvStore::open(path, None, None)
+});
+engine_contract_tests!(sled_engine, SledKvsEngine, persistent: true, |path| {
+    Ok(SledKvsEngine::new(sled::open(path)?))
+});
+engine_contract_tests!(sled_engine_configured, SledKvsEngine, persistent: true, |path| {
+    SledKvsEngine::open_with_config(path, SLED_CACHE_BYTES, Some(100))
+});
+
+const SLED_CACHE_BYTES: u64 = 256 * 1024;
+
+// With a small cache, data several times its size still reads back intact, before and after
+// a reopen.
+#[test]
+fn sled_small_cache_keeps_data_intact() -> Result<()> {
+    let h = Harness::new(
+        |path: &Path| SledKvsEngine::open_with_config(path, SLED_CACHE_BYTES, Some(100)),
+        true,
+    );
+    let engine = h.open()?;
+
+    let value = |i: usize| format!("{:04}", i).repeat(256);
+    for i in 0..1000 {
+        engine.set(format!("key{}", i), value(i))?;
+    }
+    engine.flush()?;
+    assert!(engine.size_on_disk()? > SLED_CACHE_BYTES);
+    for i in (0..1000).step_by(7) {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(value(i)));
+    }
+
+    let engine = h.reopen(engine)?.unwrap();
+    assert_eq!(engine.count()?, 1000);
+    for i in (0..1000).step_by(7) {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(value(i)));
+    }
+    Ok(())
+}
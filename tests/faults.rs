@@ -0,0 +1,242 @@
+use kvs::{FaultConfig, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Total size of the log files in `dir`.
+fn log_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// A write whose flush fails is cut from the log: it neither shows up in the index nor reaches
+// the file along with the next write.
+#[test]
+fn failed_flush_leaves_no_trace_in_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.inject_faults(FaultConfig::default().fail_nth_flush(1))?;
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.inject_faults(FaultConfig::default().fail_nth_flush(1))?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.sequence(), Some(2));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// With buffered writes, cutting a failed write keeps the earlier writes still in the buffer,
+// even when the failure tore the write of those.
+#[test]
+fn failed_flush_keeps_earlier_buffered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_buffered_writes();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // Larger than the writer's buffer, so the buffer has to be written out right away
+    store.inject_faults(FaultConfig::default().fail_nth_write(1))?;
+    assert!(store.set("key2".to_owned(), "x".repeat(16 * 1024)).is_err());
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// The half of a torn write that reached the file is truncated away right away, rather than
+// being left for the next open to skip.
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let before = log_bytes(temp_dir.path());
+
+    store.inject_faults(FaultConfig::default().fail_nth_write(1))?;
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(log_bytes(temp_dir.path()), before);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A compaction failing at any step leaves a store that keeps serving reads and writes, and
+// that opens with every value.
+#[test]
+fn failed_compaction_leaves_a_working_store() -> Result<()> {
+    let faults = [
+        // Opening the writer's new generation
+        FaultConfig::default().fail_nth_file_op(1),
+        // Opening the compaction's generation
+        FaultConfig::default().fail_nth_file_op(2),
+        // Copying the records
+        FaultConfig::default().fail_nth_write(1),
+    ];
+    for (i, fault) in faults.into_iter().enumerate() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        for round in 0..3 {
+            for key in 0..10 {
+                store.set(format!("key{}", key), format!("value{}-{}", key, round))?;
+            }
+        }
+
+        store.inject_faults(fault)?;
+        assert!(store.checkpoint().is_err(), "fault {} didn't fail the compaction", i);
+        store.set("key0".to_owned(), "after".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+        assert_eq!(store.get("key9".to_owned())?, Some("value9-2".to_owned()));
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        assert_eq!(store.count()?, 10);
+        assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+        assert_eq!(store.get("key9".to_owned())?, Some("value9-2".to_owned()));
+        store.checkpoint()?;
+        assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    }
+    Ok(())
+}
+
+// A log file that can't be opened because the file system is read-only, or the directory
+// isn't writable, is reported as `ReadOnlyFilesystem`; any other I/O error is passed through.
+// The errors are injected, so this runs the same as root.
+#[test]
+fn unwritable_log_is_reported_as_read_only() -> Result<()> {
+    let kinds = [
+        io::ErrorKind::ReadOnlyFilesystem,
+        io::ErrorKind::PermissionDenied,
+        io::ErrorKind::Other,
+    ];
+    for kind in kinds {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        store.inject_faults(FaultConfig::default().fail_nth_file_op(1).fail_with(kind))?;
+        match (kind, store.checkpoint()) {
+            (io::ErrorKind::Other, Err(KvsError::IoError(e))) => assert_eq!(e.kind(), kind),
+            (io::ErrorKind::Other, other) => panic!("expected an I/O error, got {:?}", other),
+            (_, Err(KvsError::ReadOnlyFilesystem(path))) => assert_eq!(path, temp_dir.path()),
+            (_, other) => panic!("expected a read-only file system error, got {:?}", other),
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A sled write failing on I/O is retried and goes through once the failure passes, while
+// corruption is returned on the first attempt, marked as not transient.
+#[test]
+fn sled_retries_transient_errors_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    let io_error = || sled::Error::Io(std::io::Error::other("injected"));
+
+    engine.inject_errors([io_error(), io_error()]);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    engine.inject_errors([io_error(), io_error(), io_error()]);
+    match engine.flush() {
+        Err(KvsError::SledWriteFailed { transient, attempts, .. }) => {
+            assert!(transient);
+            assert_eq!(attempts, 3);
+        }
+        other => panic!("expected a transient failure, got {:?}", other),
+    }
+
+    engine.inject_errors([sled::Error::Corruption { at: None, bt: () }, io_error()]);
+    match engine.remove("key1".to_owned()) {
+        Err(KvsError::SledWriteFailed { transient, attempts, .. }) => {
+            assert!(!transient);
+            assert_eq!(attempts, 1);
+        }
+        other => panic!("expected a fatal failure, got {:?}", other),
+    }
+    // The corruption wasn't retried, so the I/O error is left for the next write, which retries
+    // past it
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Readers and writers keep their own buffer sizes, including for the files compaction creates.
+#[test]
+fn buffer_sizes_survive_compaction() -> Result<()> {
+    const READER_BUFFER: usize = 4 * 1024;
+    const WRITER_BUFFER: usize = 16 * 1024;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), Some(READER_BUFFER), Some(WRITER_BUFFER))?;
+    let check = |store: &KvStore| {
+        let (writer, readers) = store.buffer_capacities();
+        assert_eq!(writer, WRITER_BUFFER);
+        assert!(!readers.is_empty());
+        assert!(readers.iter().all(|&reader| reader == READER_BUFFER));
+    };
+    check(&store);
+
+    // Overwrite one key until the stale data triggers a compaction
+    let generations = || fs::read_dir(temp_dir.path()).unwrap().count();
+    let before = generations();
+    for i in 0..20_000 {
+        store.set("key".to_owned(), format!("{:0>100}", i))?;
+    }
+    assert_ne!(generations(), before, "no compaction happened");
+    check(&store);
+    Ok(())
+}
+
+// A write that can't get the writer lock within its deadline fails without writing anything.
+#[test]
+fn write_deadline_times_out_on_held_writer_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?
+        .with_write_deadline(Duration::from_millis(50));
+
+    let held = store.hold_writer_lock();
+    let start = Instant::now();
+    let result = store.set("key1".to_owned(), "value1".to_owned());
+    let elapsed = start.elapsed();
+    assert!(matches!(result, Err(KvsError::Timeout)), "{:?}", result);
+    assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::Timeout)
+    ));
+    drop(held);
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use kvs::{FramedConnection, KvsError, Result};
+use std::io::{self, Cursor, Read, Write};
+
+// An in-memory stream: reads come from `input`, writes are collected in `output`.
+struct Duplex {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Duplex {
+    fn new(input: Vec<u8>) -> Duplex {
+        Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Frames written by one side are `[u32 BE length][bincode payload]` and read back in order
+// by the other, which sees the end of the stream as the peer closing it.
+#[test]
+fn frames_round_trip() -> Result<()> {
+    let mut sender = FramedConnection::new(Duplex::new(Vec::new()));
+    sender.send(&"hello".to_owned())?;
+    sender.send(&vec![("key1".to_owned(), 42u64)])?;
+
+    let payload = bincode::serialize("hello")?;
+    let output = &sender.get_ref().output;
+    assert_eq!(output[..4], (payload.len() as u32).to_be_bytes());
+    assert_eq!(output[4..4 + payload.len()], payload[..]);
+
+    let mut receiver = FramedConnection::new(Duplex::new(output.clone()));
+    assert_eq!(receiver.recv::<String>()?, "hello");
+    assert_eq!(receiver.recv::<Vec<(String, u64)>>()?, vec![("key1".to_owned(), 42)]);
+    assert_eq!(receiver.try_recv::<String>()?, None);
+    match receiver.recv::<String>() {
+        Err(KvsError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        other => panic!("expected an end of stream error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// A frame over the cap is refused from its length prefix; a frame cut short is an error
+// rather than the end of the stream.
+#[test]
+fn oversized_and_truncated_frames() -> Result<()> {
+    let mut sender = FramedConnection::new(Duplex::new(Vec::new()));
+    sender.send(&"x".repeat(100))?;
+    let frame = sender.get_ref().output.clone();
+
+    let mut capped = FramedConnection::new(Duplex::new(frame.clone())).max_frame_len(64);
+    match capped.recv::<String>() {
+        Err(KvsError::FrameTooLarge { len, max }) => assert_eq!((len, max), (frame.len() - 4, 64)),
+        other => panic!("expected a frame too large error, got {:?}", other),
+    }
+
+    let mut truncated = FramedConnection::new(Duplex::new(frame[..frame.len() - 1].to_vec()));
+    match truncated.try_recv::<String>() {
+        Err(KvsError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        other => panic!("expected a truncated frame error, got {:?}", other),
+    }
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Request, Result, ServerHandle, ServerOptions};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use tempfile::TempDir;
+
+// Runs a server with an HTTP listener in a background thread, returning both addresses and
+// the handle that stops it.
+fn start_server(temp_dir: &TempDir) -> Result<(SocketAddr, SocketAddr, ServerHandle)> {
+    start_server_with(temp_dir, ServerOptions::default())
+}
+
+fn start_server_with(
+    temp_dir: &TempDir,
+    options: ServerOptions,
+) -> Result<(SocketAddr, SocketAddr, ServerHandle)> {
+    let http_listener = TcpListener::bind("127.0.0.1:0")?;
+    let http_addr = http_listener.local_addr()?;
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server = KvsServer::with_options(engine, SharedQueueThreadPool::new(2)?, options)
+        .http_listener(http_listener);
+    let (addr, handle) = server.spawn_ephemeral()?;
+    Ok((addr, http_addr, handle))
+}
+
+// Sends one request and returns the response's status code and body.
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").expect("response has no head");
+    let status = head.split(' ').nth(1).expect("response has no status");
+    Ok((status.parse().unwrap(), body.to_owned()))
+}
+
+#[test]
+fn http_put_get_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, http_addr, _server) = start_server(&temp_dir)?;
+
+    assert_eq!(request(http_addr, "PUT", "/kv/key1", "value1")?.0, 201);
+    assert_eq!(request(http_addr, "PUT", "/kv/key1", "value2")?.0, 200);
+    assert_eq!(
+        request(http_addr, "GET", "/kv/key1", "")?,
+        (200, "value2".to_owned())
+    );
+
+    assert_eq!(request(http_addr, "DELETE", "/kv/key1", "")?.0, 200);
+    assert_eq!(request(http_addr, "GET", "/kv/key1", "")?.0, 404);
+    assert_eq!(request(http_addr, "DELETE", "/kv/key1", "")?.0, 404);
+    Ok(())
+}
+
+#[test]
+fn http_shares_the_engine_with_the_binary_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, http_addr, _server) = start_server(&temp_dir)?;
+
+    assert_eq!(request(http_addr, "PUT", "/kv/a%20key", "from http")?.0, 201);
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("a key".to_owned())?, Some("from http".to_owned()));
+
+    client.set("key2".to_owned(), "from client".to_owned())?;
+    assert_eq!(
+        request(http_addr, "GET", "/kv/key2", "")?,
+        (200, "from client".to_owned())
+    );
+    Ok(())
+}
+
+// A body over the request size cap is refused from its Content-Length, without allocating
+// for it, and the server keeps serving.
+#[test]
+fn http_rejects_oversized_bodies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, http_addr, _server) = start_server(&temp_dir)?;
+
+    let mut stream = TcpStream::connect(http_addr)?;
+    write!(
+        stream,
+        "PUT /kv/key1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000000000000\r\n\r\n"
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 413 "), "unexpected response: {}", response);
+
+    assert_eq!(request(http_addr, "PUT", "/kv/key1", "value1")?.0, 201);
+    assert_eq!(request(http_addr, "GET", "/kv/key1", "")?, (200, "value1".to_owned()));
+    Ok(())
+}
+
+// PUT and DELETE are checked by the server's validator as a set and a remove.
+#[test]
+fn http_writes_go_through_the_validator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ServerOptions::default().validator(|request| match request {
+        Request::Set { key, .. } | Request::Remove { key } if key.contains(' ') => {
+            Err(format!("key {:?} contains a space", key))
+        }
+        _ => Ok(()),
+    });
+    let (_, http_addr, _server) = start_server_with(&temp_dir, options)?;
+
+    let (status, body) = request(http_addr, "PUT", "/kv/a%20key", "value1")?;
+    assert_eq!(status, 403);
+    assert!(body.contains("contains a space"), "unexpected body: {}", body);
+    assert_eq!(request(http_addr, "GET", "/kv/a%20key", "")?.0, 404);
+    // Rejected before the engine could answer 404
+    assert_eq!(request(http_addr, "DELETE", "/kv/a%20key", "")?.0, 403);
+
+    assert_eq!(request(http_addr, "PUT", "/kv/key1", "value1")?.0, 201);
+    assert_eq!(request(http_addr, "DELETE", "/kv/key1", "")?.0, 200);
+    Ok(())
+}
+
+#[test]
+fn http_rejects_unknown_routes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, http_addr, _server) = start_server(&temp_dir)?;
+
+    assert_eq!(request(http_addr, "GET", "/other", "")?.0, 404);
+    assert_eq!(request(http_addr, "POST", "/kv/key1", "value1")?.0, 405);
+    Ok(())
+}
@@ -1,6 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::kvs_command::{kvs_command, KvsCommand, KvsRemove, KvsSet};
+use kvs::{
+    Clock, CompactionWindow, CorruptionPolicy, KvStore, KvsEngine, KvsEngineExt, KvsError,
+    LogReader, QuotaPolicy, Result,
+};
+use prost::Message;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -8,7 +17,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -18,7 +27,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -29,7 +38,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +47,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -50,14 +59,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -66,7 +75,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -74,7 +83,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -86,7 +95,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -116,7 +125,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path(), None, None)?;
+        let store = KvStore::open(temp_dir.path(), None, None)?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -130,7 +139,7 @@ fn compaction() -> Result<()> {
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
         let store = store.clone();
@@ -150,7 +159,7 @@ fn concurrent_set() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -161,7 +170,7 @@ fn concurrent_set() -> Result<()> {
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path(), None, None)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))?;
@@ -207,4 +216,1788 @@ fn concurrent_get() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// A crash after starting a compaction leaves a partial compaction file behind.
+// Opening must discard it and keep the data from the old generations.
+#[test]
+fn recover_from_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Generation 1 holds the data, 2 is the half-written compaction file, 3 the fresh writer
+    fs::write(temp_dir.path().join("compaction.marker"), "2 started")?;
+    fs::write(temp_dir.path().join("2.log"), [100, 0, 0, 0, 8, 1])?;
+    fs::write(temp_dir.path().join("3.log"), [])?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // The partial file is gone; generation 2 was recreated empty for new writes
+    assert_eq!(fs::metadata(temp_dir.path().join("2.log"))?.len(), 0);
+    assert!(!temp_dir.path().join("compaction.marker").exists());
+
+    Ok(())
+}
+
+// A crash after the compaction file was written but before the stale generations
+// were removed must finish the compaction on open.
+#[test]
+fn recover_from_compaction_interrupted_during_cleanup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "stale".to_owned())?;
+    drop(store);
+
+    // Build the "compacted" generation separately and move it in as generation 2
+    let compacted_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacted = KvStore::open(compacted_dir.path(), None, None)?;
+    compacted.set("key1".to_owned(), "value1".to_owned())?;
+    drop(compacted);
+    fs::rename(
+        compacted_dir.path().join("1.log"),
+        temp_dir.path().join("2.log"),
+    )?;
+    fs::write(temp_dir.path().join("compaction.marker"), "2 complete")?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("compaction.marker").exists());
+
+    Ok(())
+}
+
+// Once a set returns, a get on any other clone observes it, and the published
+// sequence number only moves forward.
+#[test]
+fn read_your_writes_across_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.latest_sequence(), 0);
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let writer = store.clone();
+        let reader = store.clone();
+        handles.push(thread::spawn(move || {
+            let mut last_seen = 0;
+            for i in 0..50 {
+                let key = format!("key{}-{}", thread_id, i % 5);
+                let value = format!("value{}", i);
+                writer.set(key.clone(), value.clone()).unwrap();
+                assert_eq!(reader.get(key).unwrap(), Some(value));
+
+                let sequence = reader.latest_sequence();
+                assert!(sequence > last_seen);
+                last_seen = sequence;
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.latest_sequence(), 400);
+
+    // Sequence numbers continue from the log after a reopen
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.latest_sequence(), 400);
+    store.remove("key0-0".to_owned())?;
+    assert_eq!(store.latest_sequence(), 401);
+
+    Ok(())
+}
+
+// Writes a batch per generation (the store starts a new generation on every open) with keys
+// overlapping across generations, and checks the loaded index against a model of the writes.
+#[test]
+fn load_generations_with_overlapping_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut expected = HashMap::new();
+
+    for generation in 0..6 {
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        for i in 0..40 {
+            let key = format!("key{}", (i * 7 + generation) % 25);
+            if (i + generation) % 5 == 0 {
+                store.remove(key.clone()).ok();
+                expected.remove(&key);
+            } else {
+                let value = format!("value{}-{}", generation, i);
+                store.set(key.clone(), value.clone())?;
+                expected.insert(key, value);
+            }
+        }
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        let mut expected_keys: Vec<_> = expected.keys().cloned().collect();
+        expected_keys.sort();
+        assert_eq!(store.keys()?, expected_keys);
+        for (key, value) in &expected {
+            assert_eq!(store.get(key.clone())?, Some(value.clone()));
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    check(&store)?;
+    let sequence = store.latest_sequence();
+
+    // Loading again gives the same index
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    check(&store)?;
+    assert_eq!(store.latest_sequence(), sequence);
+    Ok(())
+}
+
+fn record(sequence: u64, command: kvs_command::Command) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    match &command {
+        kvs_command::Command::Set(set) => {
+            hasher.update(set.key.as_bytes());
+            hasher.update(set.value.as_bytes());
+        }
+        kvs_command::Command::Remove(remove) => hasher.update(remove.key.as_bytes()),
+        kvs_command::Command::Mark(_) => {}
+    }
+    let cmd = KvsCommand {
+        timestamp: 0,
+        sequence_number: sequence,
+        checksum: hasher.finalize(),
+        version: 1,
+        command: Some(command),
+    };
+    let msg = cmd.encode_to_vec();
+    let mut record = (msg.len() as u32).to_le_bytes().to_vec();
+    record.extend_from_slice(&msg);
+    record
+}
+
+fn set_record(key: &str, value: &str, sequence: u64) -> Vec<u8> {
+    record(
+        sequence,
+        kvs_command::Command::Set(KvsSet {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            key_size: key.len() as u32,
+            value_size: value.len() as u32,
+            expires_at: 0,
+        }),
+    )
+}
+
+fn remove_record(key: &str, sequence: u64) -> Vec<u8> {
+    record(
+        sequence,
+        kvs_command::Command::Remove(KvsRemove {
+            key: key.to_owned(),
+            key_size: key.len() as u32,
+            deleted_at: 0,
+        }),
+    )
+}
+
+// The newest command for a key is decided by sequence number, not by which generation
+// it was found in.
+#[test]
+fn load_prefers_higher_sequence_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut log = set_record("key1", "newer", 4);
+    log.extend(remove_record("key2", 5));
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let mut log = set_record("key1", "older", 2);
+    log.extend(set_record("key2", "older", 3));
+    log.extend(set_record("key3", "value3", 1));
+    fs::write(temp_dir.path().join("2.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.latest_sequence(), 5);
+    Ok(())
+}
+
+// A log reader yields every write in the order it was made, across reopens of the store.
+#[test]
+fn log_reader_yields_commands_in_sequence_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..3 {
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        store.remove(format!("key{}", round))?;
+    }
+
+    let records = LogReader::open(temp_dir.path())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 63);
+    assert!(records.windows(2).all(|pair| {
+        pair[0].command.sequence_number < pair[1].command.sequence_number
+            && pair[0].generation <= pair[1].generation
+    }));
+    match &records[20].command.command {
+        Some(kvs_command::Command::Remove(remove)) => assert_eq!(remove.key, "key0"),
+        other => panic!("expected a remove, got {:?}", other),
+    }
+    Ok(())
+}
+
+// The file of a compaction that was started but not finished is left out: its records are
+// copies, and it may be incomplete.
+#[test]
+fn log_reader_skips_unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = set_record("key1", "value1", 1);
+    log.extend(set_record("key2", "value2", 2));
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    fs::write(temp_dir.path().join("2.log"), set_record("key1", "value1", 1))?;
+    fs::write(temp_dir.path().join("3.log"), set_record("key3", "value3", 3))?;
+    fs::write(temp_dir.path().join("compaction.marker"), "2 started")?;
+
+    let records = LogReader::open(temp_dir.path())?.collect::<Result<Vec<_>>>()?;
+    let located: Vec<_> = records
+        .iter()
+        .map(|record| (record.generation, record.command.sequence_number))
+        .collect();
+    assert_eq!(located, vec![(1, 1), (1, 2), (3, 3)]);
+    Ok(())
+}
+
+// A corrupt record stops a log reader by default and is stepped over when skipping; a record
+// cut short at the end of a plain log file just ends it.
+#[test]
+fn log_reader_corruption_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = set_record("key1", "value1", 1);
+    let mut bad = set_record("key2", "value2", 2);
+    let last = bad.len() - 1;
+    bad[last] ^= 0xff;
+    let mut log = first.clone();
+    log.extend(&bad);
+    log.extend(set_record("key1", "value3", 3));
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    let mut truncated = set_record("key4", "value4", 4);
+    truncated.truncate(truncated.len() - 2);
+    fs::write(temp_dir.path().join("2.log"), &truncated)?;
+    fs::write(temp_dir.path().join("3.log"), remove_record("key1", 5))?;
+
+    let mut reader = LogReader::open(temp_dir.path())?;
+    assert_eq!(reader.next().unwrap()?.command.sequence_number, 1);
+    match reader.next() {
+        Some(Err(KvsError::CorruptRecord(corrupt))) => {
+            assert_eq!((corrupt.generation, corrupt.offset), (1, first.len() as u64));
+        }
+        other => panic!("expected a corrupt record error, got {:?}", other),
+    }
+    assert!(reader.next().is_none());
+
+    let mut reader = LogReader::open(temp_dir.path())?.corruption_policy(CorruptionPolicy::Skip);
+    let sequences = reader
+        .by_ref()
+        .map(|record| Ok(record?.command.sequence_number))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(sequences, vec![1, 3, 5]);
+    let skipped: Vec<_> =
+        reader.skipped().iter().map(|record| (record.generation, record.offset)).collect();
+    assert_eq!(skipped, vec![(1, first.len() as u64)]);
+    Ok(())
+}
+
+// Verifying reports every bad record with its location and carries on past it.
+#[test]
+fn verify_reports_corrupt_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let first = set_record("key1", "value1", 1);
+    let mut bad = set_record("key2", "value2", 2);
+    let last = bad.len() - 1;
+    bad[last] ^= 0xff;
+    let mut log = first.clone();
+    log.extend(&bad);
+    log.extend(set_record("key1", "value3", 3));
+    log.extend(remove_record("key3", 4));
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+
+    // The last record of a generation is cut short
+    let mut truncated = set_record("key4", "value4", 5);
+    truncated.truncate(truncated.len() - 2);
+    fs::write(temp_dir.path().join("2.log"), &truncated)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.generations, 2);
+    assert_eq!(report.records, 3);
+    assert_eq!(report.keys, 1);
+    assert_eq!(report.uncompacted, first.len() as u64 + remove_record("key3", 4).len() as u64);
+
+    let locations: Vec<_> = report
+        .corrupt_records
+        .iter()
+        .map(|record| (record.generation, record.offset))
+        .collect();
+    assert_eq!(locations, vec![(1, first.len() as u64), (2, 0)]);
+
+    // Nothing was written or repaired
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, log);
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+    Ok(())
+}
+
+// A record cut short by a crash mid-append was never acknowledged: opening drops it from the
+// log and keeps everything before it.
+#[test]
+fn open_truncates_incomplete_trailing_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let first = set_record("key1", "value1", 1);
+
+    // Cut inside the message, then inside the length prefix
+    let second = set_record("key2", "value2", 2);
+    for cut in [second.len() - 3, 2] {
+        let mut log = first.clone();
+        log.extend_from_slice(&second[..cut]);
+        fs::write(&log_path, &log)?;
+
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(fs::read(&log_path)?, first);
+
+        // Later writes go to a new generation; clear it for the next round
+        drop(store);
+        fs::remove_file(temp_dir.path().join("2.log"))?;
+    }
+    Ok(())
+}
+
+// Only the newest generation is ever appended to; a short record at the end of an older one
+// is corruption, and opening leaves the file as it is.
+#[test]
+fn open_rejects_incomplete_record_in_older_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = set_record("key1", "value1", 1);
+    let second = set_record("key2", "value2", 2);
+    log.extend_from_slice(&second[..second.len() - 3]);
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    fs::write(temp_dir.path().join("2.log"), set_record("key3", "value3", 3))?;
+
+    match KvStore::open(temp_dir.path(), None, None) {
+        Err(KvsError::CorruptRecord(record)) => assert_eq!(record.generation, 1),
+        other => panic!("expected a corrupt record, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, log);
+    Ok(())
+}
+
+// Writes left in the writer's buffer are still read back, whether or not the buffer was
+// partly written out in between, and reach the file once the store is dropped.
+#[test]
+fn buffered_writes_are_readable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, Some(1024))?.with_buffered_writes();
+
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.remove("key0".to_owned())?;
+    for i in 1..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    // A fresh clone has no file readers yet
+    assert_eq!(store.clone().get("key199".to_owned())?, Some("value199".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+    Ok(())
+}
+
+// With the read cache enabled, a get never returns a value older than one already observed,
+// and a completed set is visible to every clone, while other threads keep the keys hot.
+#[test]
+fn read_cache_never_serves_stale_values() -> Result<()> {
+    const KEYS: usize = 4;
+    const WRITES: usize = 500;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_read_cache(16);
+    for key in 0..KEYS {
+        store.set(format!("key{}", key), "0".to_owned())?;
+    }
+
+    let mut handles = Vec::new();
+    for key in 0..KEYS {
+        let writer = store.clone();
+        let reader = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 1..=WRITES {
+                writer.set(format!("key{}", key), i.to_string()).unwrap();
+                assert_eq!(reader.get(format!("key{}", key)).unwrap(), Some(i.to_string()));
+            }
+        }));
+    }
+    for _ in 0..4 {
+        let reader = store.clone();
+        handles.push(thread::spawn(move || {
+            let mut last_seen = [0; KEYS];
+            while last_seen.iter().any(|&seen| seen < WRITES) {
+                for (key, last) in last_seen.iter_mut().enumerate() {
+                    let value = reader.get(format!("key{}", key)).unwrap().unwrap();
+                    let value: usize = value.parse().unwrap();
+                    assert!(value >= *last, "read {} after {}", value, last);
+                    *last = value;
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// A clock that only moves when told to.
+struct ManualClock(Mutex<SystemTime>);
+
+impl ManualClock {
+    fn at_hour(hour: u64) -> Arc<ManualClock> {
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH)));
+        clock.set_hour(hour);
+        clock
+    }
+
+    fn set_hour(&self, hour: u64) {
+        *self.0.lock().unwrap() = UNIX_EPOCH + Duration::from_secs(hour * 60 * 60);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn night_window(hard_cap: u64, clock: Arc<ManualClock>) -> CompactionWindow {
+    let hour = Duration::from_secs(60 * 60);
+    CompactionWindow::new(2 * hour, 4 * hour, hard_cap)
+        .with_clock(clock)
+        .with_check_interval(Duration::from_millis(10))
+}
+
+// Overwrites one key until more than the 1MB compaction threshold is stale.
+fn write_stale_data(store: &KvStore, records: usize) -> Result<()> {
+    for i in 0..records {
+        store.set("key".to_owned(), format!("{:0>100}", i))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn compaction_deferred_to_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first_log = temp_dir.path().join("1.log");
+    let clock = ManualClock::at_hour(12);
+    let store = KvStore::open(temp_dir.path(), None, None)?
+        .with_compaction_window(night_window(64 * 1024 * 1024, Arc::clone(&clock)));
+
+    write_stale_data(&store, 12_000)?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(first_log.exists(), "compacted outside the window");
+
+    clock.set_hour(3);
+    for _ in 0..500 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!first_log.exists(), "not compacted inside the window");
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{:0>100}", 11_999)));
+    Ok(())
+}
+
+// Once the window opens, quiescing runs the deferred compaction without waiting for the
+// background thread's next check, and leaves nothing stale.
+#[test]
+fn quiesce_runs_deferred_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::at_hour(12);
+    let store = KvStore::open(temp_dir.path(), None, None)?
+        .with_compaction_window(night_window(64 * 1024 * 1024, Arc::clone(&clock)));
+    write_stale_data(&store, 12_000)?;
+    assert!(temp_dir.path().join("1.log").exists());
+
+    clock.set_hour(3);
+    store.quiesce()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!((report.keys, report.uncompacted), (1, 0));
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{:0>100}", 11_999)));
+    Ok(())
+}
+
+// A compaction the background thread already started when quiescing has finished by the time
+// `quiesce` returns, and nothing is left for the thread to do afterwards.
+#[test]
+fn quiesce_waits_for_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::at_hour(12);
+    let store = KvStore::open(temp_dir.path(), None, None)?
+        .with_compaction_window(night_window(64 * 1024 * 1024, Arc::clone(&clock)));
+    write_stale_data(&store, 12_000)?;
+    let listing = || -> Vec<(String, u64)> {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                (name, entry.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+
+    // Past the background thread's check interval, so it has likely picked the compaction up
+    clock.set_hour(3);
+    thread::sleep(Duration::from_millis(15));
+    store.quiesce()?;
+    assert!(!temp_dir.path().join("compaction.marker").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    let settled = listing();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(listing(), settled, "the directory changed after quiescing");
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!((report.keys, report.uncompacted), (1, 0));
+    Ok(())
+}
+
+// Quiescing writes out what a store with buffered writes holds back.
+#[test]
+fn quiesce_flushes_buffered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_buffered_writes();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("1.log");
+    assert_eq!(fs::metadata(&log)?.len(), 0);
+
+    store.quiesce()?;
+    assert!(fs::metadata(&log)?.len() > 0);
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compaction_forced_past_hard_cap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::at_hour(12);
+    let store = KvStore::open(temp_dir.path(), None, None)?
+        .with_compaction_window(night_window(2 * 1024 * 1024, clock));
+
+    write_stale_data(&store, 20_000)?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{:0>100}", 19_999)));
+    Ok(())
+}
+
+// The reported size is the UTF-8 byte length of the value, before and after a reopen.
+#[test]
+fn value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let values = [("ascii", "value"), ("accents", "héllo wörld"), ("emoji", "🦀🦀"), ("empty", "")];
+    for (key, value) in values {
+        store.set(key.to_owned(), value.to_owned())?;
+    }
+    store.set_many(vec![("batch".to_owned(), "ünïcode".to_owned())])?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for (key, value) in values {
+            assert_eq!(store.value_size(key.to_owned())?, Some(value.len() as u64));
+        }
+        assert_eq!(store.value_size("batch".to_owned())?, Some("ünïcode".len() as u64));
+        assert_eq!(store.value_size("missing".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    check(&store)?;
+
+    store.remove("ascii".to_owned())?;
+    assert_eq!(store.value_size("ascii".to_owned())?, None);
+    Ok(())
+}
+
+// Readers on other clones keep working while compactions remove the generations their
+// lookups pointed into.
+#[test]
+fn reads_race_with_compaction() -> Result<()> {
+    const KEYS: usize = 100;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let value = |round: usize| format!("{:0>1000}", round);
+    for key in 0..KEYS {
+        store.set(format!("key{}", key), value(0))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        readers.push(thread::spawn(move || -> Result<usize> {
+            let mut reads = 0;
+            while !done.load(Ordering::SeqCst) {
+                for key in 0..KEYS {
+                    assert!(store.get(format!("key{}", key))?.is_some());
+                    reads += 1;
+                }
+                assert_eq!(store.scan_prefix("key".to_owned())?.len(), KEYS);
+            }
+            Ok(reads)
+        }));
+    }
+
+    // Every round rewrites all keys; about ten rounds fill the compaction threshold
+    let first_log = temp_dir.path().join("1.log");
+    for round in 1..=50 {
+        for key in 0..KEYS {
+            store.set(format!("key{}", key), value(round))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    assert!(!first_log.exists(), "no compaction happened");
+
+    for reader in readers {
+        assert!(reader.join().unwrap()? > 0);
+    }
+
+    // Only the latest compaction's generation and the active one remain
+    let logs = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert_eq!(logs, 2);
+    Ok(())
+}
+
+// A checkpoint's sequence number marks exactly the state a crashed store comes back with.
+#[test]
+fn checkpoint_survives_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    let sequence = store.checkpoint()?;
+    assert_eq!(sequence, 5);
+    // Everything stale was compacted away
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 2);
+
+    // Simulate a crash: nothing of the store is cleaned up
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.latest_sequence(), sequence);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.count()?, 2);
+    Ok(())
+}
+
+// Compaction drops the remove that took the latest sequence number; the sequence must still
+// pick up after it once the store is reopened.
+#[test]
+fn sequence_survives_compacted_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    assert_eq!(store.checkpoint()?, 3);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.latest_sequence(), 3);
+    store.set("c".to_owned(), "3".to_owned())?;
+    assert_eq!(store.latest_sequence(), 4);
+
+    // Compacting again keeps it, and the mark isn't one of the log's commands
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.latest_sequence(), 4);
+    assert_eq!(LogReader::open(temp_dir.path())?.count(), 2);
+    Ok(())
+}
+
+#[test]
+fn index_memory_estimate_follows_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.index_memory_estimate(), 0);
+
+    let mut last = 0;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        let estimate = store.index_memory_estimate();
+        assert!(estimate > last, "{} after {} keys", estimate, i + 1);
+        last = estimate;
+    }
+
+    // Values are not held in memory
+    store.set("key1".to_owned(), "a much longer value".repeat(10))?;
+    assert_eq!(store.index_memory_estimate(), last);
+
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    store.checkpoint()?;
+    let after_removes = store.index_memory_estimate();
+    assert!(after_removes < last);
+
+    // A reopened store estimates the same as the one that wrote the log
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.index_memory_estimate(), after_removes);
+    Ok(())
+}
+
+// Decodes every record in every log file of the store.
+fn all_records(dir: &std::path::Path) -> Vec<KvsCommand> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "log") {
+            continue;
+        }
+        let bytes = fs::read(path).unwrap();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            records.push(KvsCommand::decode(&bytes[pos..pos + len]).unwrap());
+            pos += len;
+        }
+    }
+    records
+}
+
+// A removed key stays removed through compaction, and the compacted log keeps neither its
+// set nor its remove record.
+#[test]
+fn removed_key_stays_removed_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first_log = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let live_len = fs::metadata(&first_log)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    // The set and the remove of key2 are each counted as stale exactly once
+    let stale = fs::metadata(&first_log)?.len() - live_len;
+    assert_eq!(KvStore::verify(temp_dir.path())?.uncompacted, stale);
+
+    store.checkpoint()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.count()?, 1);
+
+    // Only a sequence mark is left of the remove
+    let records = all_records(temp_dir.path());
+    assert_eq!(records.len(), 2);
+    assert!(matches!(
+        &records[0].command,
+        Some(kvs_command::Command::Set(set)) if set.key == "key1"
+    ));
+    assert!(matches!(&records[1].command, Some(kvs_command::Command::Mark(_))));
+    assert_eq!(records[1].sequence_number, 3);
+    assert_eq!(KvStore::verify(temp_dir.path())?.uncompacted, 0);
+    Ok(())
+}
+
+// The writer counts a remove record as stale along with the set it removes: with long keys
+// and short values the sets alone stay under the 1MB compaction threshold, but the removes
+// take the stale bytes over it, so the removes themselves trigger a compaction.
+#[test]
+fn remove_records_count_towards_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let keys: Vec<String> = (0..700).map(|i| format!("{:01000}", i)).collect();
+    for key in &keys {
+        store.set(key.clone(), "v".to_owned())?;
+    }
+    let sets_len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(sets_len < 1024 * 1024, "sets alone are {} bytes", sets_len);
+
+    for key in &keys {
+        store.remove(key.clone())?;
+    }
+    store.quiesce()?;
+    assert!(fs::metadata(temp_dir.path().join("1.log")).is_err());
+    assert_eq!(store.count()?, 0);
+    Ok(())
+}
+
+// Writes a store whose compacted generation (2.log) is sealed, and returns the record
+// boundaries in that file.
+fn write_sealed_store(dir: &std::path::Path) -> Result<Vec<usize>> {
+    let store = KvStore::open(dir, None, None)?.with_file_checksums();
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "value3".to_owned())?;
+    store.checkpoint()?;
+    drop(store);
+
+    // Skip the 8 byte header; the footer is the last 16 bytes
+    let log = fs::read(dir.join("2.log"))?;
+    let mut boundaries = vec![8];
+    while *boundaries.last().unwrap() < log.len() - 16 {
+        let pos = *boundaries.last().unwrap();
+        let len = u32::from_le_bytes(log[pos..pos + 4].try_into().unwrap()) as usize;
+        boundaries.push(pos + 4 + len);
+    }
+    Ok(boundaries)
+}
+
+#[test]
+fn sealed_generation_round_trips() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let boundaries = write_sealed_store(temp_dir.path())?;
+    assert_eq!(boundaries.len(), 4);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(KvStore::verify(temp_dir.path())?.corrupt_records.is_empty());
+    Ok(())
+}
+
+// Whether this process maps the log file of `generation` in `dir`.
+#[cfg(target_os = "linux")]
+fn is_mapped(dir: &std::path::Path, generation: u64) -> bool {
+    let path = fs::canonicalize(dir).unwrap().join(format!("{}.log", generation));
+    let maps = fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().any(|line| line.ends_with(path.to_str().unwrap()))
+}
+
+// Reads of a sealed generation come from its mapping, and reads racing with the compactions
+// that remove mapped generations still return whole values.
+#[cfg(target_os = "linux")]
+#[test]
+fn mmap_reads_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let store = KvStore::open(dir, None, None)?.with_file_checksums().with_mmap_reads();
+    let value = |key: usize, round: usize| format!("{}-{}", round, "v".repeat(key * 10));
+    for key in 0..100 {
+        store.set(format!("key{}", key), value(key, 0))?;
+    }
+    store.checkpoint()?;
+    assert_eq!(store.get("key7".to_owned())?, Some(value(7, 0)));
+    assert!(is_mapped(dir, 2));
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    for key in 0..100 {
+                        let value = store.get(format!("key{}", key))?.expect("key is missing");
+                        assert_eq!(value.split_once('-').unwrap().1, "v".repeat(key * 10));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for round in 1..=5 {
+        for key in 0..100 {
+            store.set(format!("key{}", key), value(key, round))?;
+        }
+        store.checkpoint()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for key in 0..100 {
+        assert_eq!(store.get(format!("key{}", key))?, Some(value(key, 5)));
+    }
+    // Compaction removed the first sealed generation and dropped its mapping
+    assert!(!dir.join("2.log").exists());
+    assert!(!is_mapped(dir, 2));
+    Ok(())
+}
+
+// Whole records missing from a sealed file are caught by its footer, although every record
+// left passes its own checksum.
+#[test]
+fn sealed_generation_detects_missing_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let boundaries = write_sealed_store(temp_dir.path())?;
+    let sealed_log = temp_dir.path().join("2.log");
+    let log = fs::read(&sealed_log)?;
+    let last_record = boundaries[boundaries.len() - 2];
+
+    // The last record is dropped, the footer kept
+    let mut without_record = log[..last_record].to_vec();
+    without_record.extend_from_slice(&log[log.len() - 16..]);
+    fs::write(&sealed_log, &without_record)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::CorruptedData)
+    ));
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.records, 2);
+    assert_eq!(report.corrupt_records.len(), 1);
+    assert_eq!(report.corrupt_records[0].generation, 2);
+
+    // Cut off at a record boundary, footer and all
+    fs::write(&sealed_log, &log[..last_record])?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path(), None, None),
+        Err(KvsError::CorruptedData)
+    ));
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.corrupt_records.len(), 1);
+    assert!(report.corrupt_records[0].reason.contains("footer"));
+    Ok(())
+}
+
+// Every file in `dir` with its contents, sorted by path.
+fn dir_contents(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let contents = fs::read(&path).unwrap();
+            (path, contents)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// Replacing the contents swaps in the whole new dataset at once: concurrent scans see either
+// every old value or every new one, and never go back to the old ones.
+#[test]
+fn replace_with_swaps_dataset_atomically() -> Result<()> {
+    const KEYS: usize = 200;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..KEYS {
+        store.set(format!("key{:03}", i), "old".to_owned())?;
+    }
+    store.set("old-only".to_owned(), "old".to_owned())?;
+
+    // More writes than the live store, so the new dataset's sequence numbers are higher
+    let prepared = KvStore::open(new_dir.path(), None, None)?;
+    for round in 0..3 {
+        for i in 0..KEYS {
+            prepared.set(format!("key{:03}", i), format!("new{}", round))?;
+        }
+    }
+    prepared.set("new-only".to_owned(), "new2".to_owned())?;
+    drop(prepared);
+    let new_files = dir_contents(new_dir.path());
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut seen_new = false;
+                while !done.load(Ordering::SeqCst) {
+                    let pairs = store.scan_prefix("key".to_owned()).unwrap();
+                    assert_eq!(pairs.len(), KEYS);
+                    let new = pairs[0].1 == "new2";
+                    assert!(
+                        pairs.iter().all(|(_, value)| (value == "new2") == new),
+                        "a scan saw a mix of old and new values"
+                    );
+                    assert!(new || !seen_new, "a scan went back to the old values");
+                    seen_new |= new;
+                }
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(50));
+    store.replace_with(new_dir.path())?;
+    thread::sleep(Duration::from_millis(50));
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert_eq!(store.get("key000".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(store.get("new-only".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(store.get("old-only".to_owned())?, None);
+
+    // Writes after the swap win over the copied records once the log is replayed
+    store.set("key000".to_owned(), "after".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key000".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key199".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(store.get("old-only".to_owned())?, None);
+
+    // The prepared store is left as it was
+    assert_eq!(dir_contents(new_dir.path()), new_files);
+    Ok(())
+}
+
+// Total size of the files in `dir`.
+fn dir_size(dir: &std::path::Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Removing most keys in one batch compacts right away, even though the stale data is far
+// below the size that triggers a compaction on its own.
+#[test]
+fn remove_many_reclaims_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), "x".repeat(100))?;
+    }
+    let size_before = dir_size(temp_dir.path());
+
+    let keys: Vec<String> = (0..900).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(store.remove_many(keys)?, 900);
+    assert!(dir_size(temp_dir.path()) < size_before / 5);
+    assert_eq!(store.count()?, 100);
+    assert_eq!(store.get("key0950".to_owned())?, Some("x".repeat(100)));
+
+    // Below the ratio, the removes are left for a regular compaction
+    let store = store.with_remove_many_compaction_ratio(0.2);
+    let size_before = dir_size(temp_dir.path());
+    let keys: Vec<String> = (900..910).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(store.remove_many(keys)?, 10);
+    assert!(dir_size(temp_dir.path()) > size_before);
+
+    // 20 of 90 keys is below the default ratio, but not the one set
+    let size_before = dir_size(temp_dir.path());
+    let keys: Vec<String> = (910..930).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(store.remove_many(keys)?, 20);
+    assert!(dir_size(temp_dir.path()) < size_before);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.count()?, 70);
+    Ok(())
+}
+
+// A key's expiry survives compaction and reopening, and once it has passed the key stays
+// gone: compaction drops its record and opening the store doesn't bring back older values.
+#[test]
+fn ttl_survives_compaction_and_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ttl = Duration::from_secs(1);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    let expiry = Instant::now() + ttl;
+    store.set_with_ttl("key1".to_owned(), "expiring".to_owned(), ttl)?;
+    store.set_with_ttl("key2".to_owned(), "lasting".to_owned(), Duration::from_secs(3600))?;
+    store.set("key3".to_owned(), "plain".to_owned())?;
+
+    // Compacting before the expiry carries it forward
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("expiring".to_owned()));
+    assert_eq!(store.count()?, 3);
+
+    thread::sleep(expiry.saturating_duration_since(Instant::now()));
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.value_size("key1".to_owned())?, None);
+    assert_eq!(store.keys()?, vec!["key2".to_owned(), "key3".to_owned()]);
+    assert_eq!(store.count()?, 2);
+
+    // An expired key can be set again
+    store.set("key1".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("again".to_owned()));
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // Reopening skips the expired set without resurrecting what it overwrote
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.count()?, 2);
+
+    store.checkpoint()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("lasting".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(store.count()?, 2);
+    Ok(())
+}
+
+// Compaction drops expired keys instead of copying them forward, even before the store is
+// reopened.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set_with_ttl("key1".to_owned(), "expiring".to_owned(), Duration::from_millis(10))?;
+    store.set("key2".to_owned(), "plain".to_owned())?;
+    thread::sleep(Duration::from_millis(50));
+
+    store.checkpoint()?;
+    let contents = dir_contents(temp_dir.path());
+    assert!(contents.iter().all(|(_, bytes)| !bytes.windows(8).any(|w| w == b"expiring")));
+    assert_eq!(store.keys()?, vec!["key2".to_owned()]);
+    Ok(())
+}
+
+// `explain_get` follows a key to the generation compaction moved it to.
+#[test]
+fn explain_get_reports_record_location() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_read_cache(16);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.explain_get("missing".to_owned())?, None);
+
+    let plan = store.explain_get("key1".to_owned())?.unwrap();
+    assert_eq!(plan.generation, 1);
+    assert_eq!(plan.sequence, 3);
+    assert!(plan.pos > 0);
+    assert!(!plan.cached);
+    store.get("key1".to_owned())?;
+    assert!(store.explain_get("key1".to_owned())?.unwrap().cached);
+
+    store.checkpoint()?;
+    let moved = store.explain_get("key1".to_owned())?.unwrap();
+    assert_eq!(moved.generation, 2);
+    assert_eq!(moved.sequence, 3);
+    assert_eq!(moved.timestamp, plan.timestamp);
+    assert_eq!(moved.len, plan.len);
+    // The cached value was read from the old location
+    assert!(!moved.cached);
+    Ok(())
+}
+
+// An index hint is only trusted while the log files are exactly as they were when it was
+// saved; a generation changed out-of-band means a full replay.
+#[test]
+fn index_hint_is_invalidated_by_out_of_band_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_index_hints();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "original".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("index.hint").exists());
+
+    // Corrupt key2's value without touching the length or modification time: replaying the
+    // log would fail, so a successful open shows the hint was used
+    let mut bytes = fs::read(&log_path)?;
+    let modified = fs::metadata(&log_path)?.modified()?;
+    let at = bytes.windows(8).position(|w| w == b"original").unwrap();
+    bytes[at] = b'X';
+    fs::write(&log_path, &bytes)?;
+    fs::File::options()
+        .write(true)
+        .open(&log_path)?
+        .set_modified(modified)?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_index_hints();
+    assert!(!temp_dir.path().join("index.hint").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(temp_dir.path().join("index.hint").exists());
+
+    // Repair the record and add one behind the store's back
+    bytes[at] = b'o';
+    bytes.extend(set_record("key3", "value3", 100));
+    fs::write(&log_path, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("original".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.count()?, 3);
+    Ok(())
+}
+
+// Bulk loading with direct I/O leaves a store that reads back the same, both while open and
+// after reopening without direct I/O. Overwrites push it through compaction as well.
+#[cfg(target_os = "linux")]
+#[test]
+fn direct_writes_bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_direct_writes();
+
+    // Odd sizes, so records straddle block boundaries; one value is larger than the buffer
+    let value = |i: usize, round: usize| format!("{}-{}-{}", round, i, "x".repeat(i % 700));
+    for round in 0..3 {
+        for i in 0..2000 {
+            store.set(format!("key{}", i), value(i, round))?;
+        }
+    }
+    let large = "y".repeat(3 * 1024 * 1024 + 17);
+    store.set("large".to_owned(), large.clone())?;
+    store.remove("key0".to_owned())?;
+
+    for i in 1..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 2)));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 2)));
+    }
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.count()?, 2000);
+    Ok(())
+}
+
+// Reading the generations in parallel during compaction writes the same compacted
+// generation as reading them one record after the other.
+#[test]
+fn parallel_compaction_matches_sequential() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    // Every open starts a new generation, so the live records end up spread over several
+    for round in 0..5 {
+        let store = KvStore::open(source.path(), None, None)?;
+        for i in (round * 100)..1000 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        for i in (0..1000).step_by(7 + round) {
+            store.remove(format!("key{}", i)).ok();
+        }
+    }
+
+    let sequential = TempDir::new().expect("unable to create temporary working directory");
+    let parallel = TempDir::new().expect("unable to create temporary working directory");
+    for (path, contents) in dir_contents(source.path()) {
+        let name = path.file_name().unwrap();
+        fs::write(sequential.path().join(name), &contents)?;
+        fs::write(parallel.path().join(name), &contents)?;
+    }
+    let sequential_store = KvStore::open(sequential.path(), None, None)?;
+    let parallel_store = KvStore::open(parallel.path(), None, None)?.with_compaction_threads(4);
+    sequential_store.checkpoint()?;
+    parallel_store.checkpoint()?;
+
+    let by_name = |dir: &TempDir| -> Vec<_> {
+        dir_contents(dir.path())
+            .into_iter()
+            .map(|(path, contents)| (path.file_name().unwrap().to_owned(), contents.len()))
+            .collect()
+    };
+    // The sequence mark ending the compacted generation is stamped with the time it was
+    // written, which may be a second apart between the two compactions
+    let records = |dir: &TempDir| -> Vec<_> {
+        all_records(dir.path())
+            .into_iter()
+            .map(|mut record| {
+                if let Some(kvs_command::Command::Mark(_)) = record.command {
+                    record.timestamp = 0;
+                }
+                record
+            })
+            .collect()
+    };
+    assert_eq!(by_name(&sequential), by_name(&parallel));
+    assert_eq!(records(&sequential), records(&parallel));
+    for i in 0..1000 {
+        let key = format!("key{}", i);
+        assert_eq!(sequential_store.get(key.clone())?, parallel_store.get(key)?);
+    }
+    drop(parallel_store);
+    let reopened = KvStore::open(parallel.path(), None, None)?;
+    assert_eq!(reopened.count()?, sequential_store.count()?);
+    Ok(())
+}
+
+// A soft-deleted value can be restored within the retention, survives compaction and reopen
+// until then, and is dropped by the first compaction after.
+#[test]
+fn soft_deleted_value_is_restorable_until_retention_passes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let retention = Duration::from_millis(500);
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_soft_deletes(retention);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.count()?, 1);
+    assert_eq!(store.get_deleted("key1")?, Some("value1".to_owned()));
+    store.checkpoint()?;
+    assert_eq!(store.get_deleted("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_soft_deletes(retention);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_deleted("key1")?, None);
+    assert!(!store.undelete("key1".to_owned())?);
+
+    store.remove("key2".to_owned())?;
+    thread::sleep(retention + Duration::from_millis(100));
+    assert_eq!(store.get_deleted("key2")?, None);
+    assert!(!store.undelete("key2".to_owned())?);
+    store.checkpoint()?;
+    drop(store);
+
+    let contents = dir_contents(temp_dir.path());
+    assert!(!contents.iter().any(|(_, bytes)| bytes.windows(6).any(|w| w == b"value2")));
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_soft_deletes(retention);
+    assert_eq!(store.get_deleted("key2")?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.count()?, 1);
+    Ok(())
+}
+
+// Soft-deleted values count as stale once their retention passes, so the next write's
+// compaction check reclaims them without an explicit compaction.
+#[test]
+fn soft_deleted_values_are_compacted_after_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let retention = Duration::from_millis(200);
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_soft_deletes(retention);
+    let value = "x".repeat(64 * 1024);
+    for i in 0..20 {
+        store.set(format!("key{}", i), value.clone())?;
+        store.remove(format!("key{}", i))?;
+    }
+    store.set("keep".to_owned(), "kept".to_owned())?;
+    assert!(fs::metadata(temp_dir.path().join("1.log")).is_ok());
+
+    thread::sleep(retention + Duration::from_millis(100));
+    store.set("trigger".to_owned(), "write".to_owned())?;
+    assert!(fs::metadata(temp_dir.path().join("1.log")).is_err());
+    assert_eq!(store.get("keep".to_owned())?, Some("kept".to_owned()));
+    let contents = dir_contents(temp_dir.path());
+    assert!(contents.iter().all(|(_, bytes)| bytes.len() < value.len()));
+    Ok(())
+}
+
+// Only files named exactly `{n}.log` are generations; a zero-padded name is not taken for
+// the generation it parses as.
+#[test]
+fn open_ignores_files_not_named_as_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // Leftovers of an interrupted write, holding records that must not be replayed
+    let stray = set_record("key1", "stray", 100);
+    fs::write(temp_dir.path().join("5.log.tmp"), &stray)?;
+    fs::write(temp_dir.path().join("6.log.log"), &stray)?;
+    fs::write(temp_dir.path().join("+7.log"), &stray)?;
+    fs::write(temp_dir.path().join("08.log"), &stray)?;
+    fs::write(temp_dir.path().join("01.log"), &stray)?;
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(temp_dir.path().join("5.log.tmp").exists());
+    assert!(temp_dir.path().join("01.log").exists());
+    Ok(())
+}
+
+// Read-only snapshots taken while another handle writes and compacts never fail, and each
+// one holds a consistent prefix of the writes.
+#[test]
+fn read_only_open_during_writes_sees_a_consistent_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let value = |i: usize| format!("{:05}", i).repeat(200);
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> Result<()> {
+            // Rewriting a hot key makes enough stale data for several compactions
+            for i in 0..3000 {
+                store.set(format!("key{:05}", i), value(i))?;
+                store.set("hot".to_owned(), value(i))?;
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+
+    let mut snapshots = 0;
+    while !done.load(Ordering::SeqCst) || snapshots == 0 {
+        let snapshot = KvStore::open_read_only(temp_dir.path())?;
+        let keys = snapshot.keys();
+        let written: Vec<_> = keys.iter().filter(|key| key.starts_with("key")).collect();
+        for (i, key) in written.iter().enumerate() {
+            assert_eq!(**key, format!("key{:05}", i), "the snapshot skipped a write");
+            assert_eq!(snapshot.get(key)?, Some(value(i)));
+        }
+        if let Some(hot) = snapshot.get("hot")? {
+            // The hot key is set right after each new key
+            let i: usize = hot[..5].parse().unwrap();
+            assert!(i + 1 == written.len() || i + 2 == written.len(), "{} vs {}", i, written.len());
+        }
+        assert_eq!(snapshot.scan_prefix("key")?.len(), written.len());
+        snapshots += 1;
+    }
+    writer.join().unwrap()?;
+
+    let snapshot = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(snapshot.keys().len(), 3001);
+    assert_eq!(snapshot.get("hot")?, Some(value(2999)));
+    assert!(snapshot.sequence() >= 6000);
+    Ok(())
+}
+
+// A read-only snapshot leaves out the file of a compaction that was started but not finished,
+// as `LogReader` does, rather than failing on its torn records.
+#[test]
+fn read_only_open_skips_unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = set_record("key1", "value1", 1);
+    log.extend(set_record("key2", "value2", 2));
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    let mut torn = set_record("key1", "value1", 1);
+    let last = torn.len() - 1;
+    torn[last] ^= 0xff;
+    fs::write(temp_dir.path().join("2.log"), &torn)?;
+    fs::write(temp_dir.path().join("3.log"), set_record("key3", "value3", 3))?;
+    fs::write(temp_dir.path().join("compaction.marker"), "2 started")?;
+
+    let snapshot = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(snapshot.keys(), vec!["key1", "key2", "key3"]);
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.sequence(), 3);
+    // Nothing was recovered on disk
+    assert!(temp_dir.path().join("compaction.marker").exists());
+    assert_eq!(fs::read(temp_dir.path().join("2.log"))?, torn);
+    Ok(())
+}
+
+// An index entry pointing at a remove record is reported as a corrupt record at its location,
+// or skipped in recovery mode.
+#[test]
+fn get_on_misindexed_remove_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = set_record("key1", "value1", 1);
+    log.extend(set_record("key2", "value2", 2));
+    fs::write(&log_path, &log)?;
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    // Put a remove where key1's set was, leaving key2's record in place
+    let remove = remove_record("key1", 1);
+    log[..remove.len()].copy_from_slice(&remove);
+    fs::write(&log_path, &log)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvsError::CorruptRecord(corrupt)) => {
+            assert_eq!((corrupt.generation, corrupt.offset), (1, 0));
+        }
+        other => panic!("expected a corrupt record error, got {:?}", other),
+    }
+    assert!(matches!(
+        store.scan_prefix("key".to_owned()),
+        Err(KvsError::CorruptRecord(_))
+    ));
+
+    let store = store.with_recovery_mode();
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.scan_prefix("key".to_owned())?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    Ok(())
+}
+
+// A data directory that can't be written is reported as such, and stays readable with
+// `open_read_only`. Permissions stand in for a read-only mount; they don't bind root.
+#[cfg(unix)]
+#[test]
+fn open_on_unwritable_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o555))?;
+    if fs::write(temp_dir.path().join("probe"), "").is_ok() {
+        // Running privileged, nothing to test
+        return Ok(());
+    }
+    let opened = KvStore::open(temp_dir.path(), None, None);
+    let snapshot = KvStore::open_read_only(temp_dir.path());
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+
+    match opened {
+        Err(KvsError::ReadOnlyFilesystem(path)) => assert_eq!(path, temp_dir.path()),
+        other => panic!("expected a read-only file system error, got {:?}", other.err()),
+    }
+    assert_eq!(snapshot?.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Sets that would take the log past the quota fail, once compaction can't make room for them;
+// removes still go through and free space.
+#[test]
+fn disk_quota_rejects_sets_compaction_cannot_make_room_for() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open(temp_dir.path(), None, None)?.with_disk_quota(4096, QuotaPolicy::CompactFirst);
+    let log_bytes = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".log"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    // Overwrites make room for themselves by compacting
+    for i in 0..200 {
+        store.set("key".to_owned(), format!("value{:0>64}", i))?;
+    }
+    assert!(log_bytes() <= 4096);
+
+    let value = "x".repeat(100);
+    let mut stored = 0;
+    let error = loop {
+        match store.set(format!("key{}", stored), value.clone()) {
+            Ok(()) => stored += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(error, KvsError::DiskQuotaExceeded { quota: 4096, .. }));
+    assert!(stored > 10);
+    assert!(log_bytes() <= 4096);
+    assert_eq!(store.get(format!("key{}", stored))?, None);
+
+    store.remove("key0".to_owned())?;
+    store.set("key0".to_owned(), value.clone())?;
+    assert!(matches!(
+        store.set_many(vec![("a".to_owned(), value.clone()), ("b".to_owned(), value)]),
+        Err(KvsError::DiskQuotaExceeded { .. })
+    ));
+    assert_eq!(store.get("a".to_owned())?, None);
+    Ok(())
+}
+
+// With the reject policy, a set over the quota fails without compacting the stale log.
+#[test]
+fn disk_quota_reject_policy_skips_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_disk_quota(4096, QuotaPolicy::Reject);
+    let value = "x".repeat(100);
+    let mut overwrites = 0;
+    while store.set("key".to_owned(), value.clone()).is_ok() {
+        overwrites += 1;
+    }
+    assert!(overwrites < 4096 / 100);
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// With shared readers, many threads reading every generation stay correct, and the open log
+// files are bounded by the generations rather than threads × generations.
+#[cfg(target_os = "linux")]
+#[test]
+fn shared_readers_bound_open_files_across_threads() -> Result<()> {
+    const GENERATIONS: usize = 5;
+    const KEYS_PER_GENERATION: usize = 20;
+    const THREADS: usize = 16;
+    const PER_GENERATION: usize = 2;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Every open starts a new generation
+    for generation in 0..GENERATIONS {
+        let store = KvStore::open(temp_dir.path(), None, None)?;
+        for i in 0..KEYS_PER_GENERATION {
+            store.set(format!("key{}-{}", generation, i), format!("value{}", i))?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_shared_readers(PER_GENERATION);
+
+    let open_log_files = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|target| target.starts_with(temp_dir.path()))
+            .count()
+    };
+    let done_reading = Arc::new(Barrier::new(THREADS + 1));
+    let counted = Arc::new(Barrier::new(THREADS + 1));
+    let handles = (0..THREADS)
+        .map(|_| {
+            let store = store.clone();
+            let done_reading = Arc::clone(&done_reading);
+            let counted = Arc::clone(&counted);
+            thread::spawn(move || -> Result<()> {
+                for generation in 0..GENERATIONS {
+                    for i in 0..KEYS_PER_GENERATION {
+                        assert_eq!(
+                            store.get(format!("key{}-{}", generation, i))?,
+                            Some(format!("value{}", i))
+                        );
+                    }
+                }
+                // Keep this clone alive until the open files are counted
+                done_reading.wait();
+                counted.wait();
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    done_reading.wait();
+    let open = open_log_files();
+    counted.wait();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    // Pooled readers of every read generation, plus the active log being written
+    assert!(
+        open <= PER_GENERATION * GENERATIONS + 1,
+        "{} log files open for {} generations",
+        open,
+        GENERATIONS
+    );
+    Ok(())
+}
+
+// With version history, compaction copies a key's latest versions and drops the older ones;
+// the kept versions are found again on reopen.
+#[test]
+fn version_history_keeps_latest_versions_through_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_version_history(2)?;
+    for value in ["version1", "version2", "version3"] {
+        store.set("key1".to_owned(), value.to_owned())?;
+    }
+    store.set("key2".to_owned(), "only".to_owned())?;
+    store.checkpoint()?;
+
+    let latest = vec!["version3".to_owned(), "version2".to_owned()];
+    assert_eq!(store.get_versions("key1", 3)?, latest);
+    assert_eq!(store.get_versions("key1", 1)?, latest[..1]);
+    assert_eq!(store.get_versions("key2", 3)?, vec!["only".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("version3".to_owned()));
+    drop(store);
+
+    let contents = dir_contents(temp_dir.path());
+    assert!(!contents.iter().any(|(_, bytes)| bytes.windows(8).any(|w| w == b"version1")));
+    let store = KvStore::open(temp_dir.path(), None, None)?.with_version_history(2)?;
+    assert_eq!(store.get_versions("key1", 3)?, latest);
+
+    store.remove("key1".to_owned())?;
+    assert!(store.get_versions("key1", 3)?.is_empty());
+    store.set("key1".to_owned(), "version4".to_owned())?;
+    assert_eq!(store.get_versions("key1", 3)?, vec!["version4".to_owned()]);
+    Ok(())
+}
+
+// After churn, rebuilding the index frees the expired keys it still held and the spare
+// capacity of the keys' strings, and keeps every live entry.
+#[test]
+fn compact_index_reclaims_memory_and_keeps_live_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    for i in 0..100 {
+        store.set(format!("live{}", i), format!("value{}", i))?;
+    }
+    for round in 0..20 {
+        for i in 0..200 {
+            let key = format!("churn{}", i);
+            store.set(key.clone(), round.to_string())?;
+            store.remove(key)?;
+        }
+    }
+    for i in 0..200 {
+        store.set_with_ttl(format!("churn{}", i), "expiring".to_owned(), Duration::from_millis(1))?;
+    }
+    thread::sleep(Duration::from_millis(10));
+
+    let before = store.index_memory_estimate();
+    store.compact_index()?;
+    let after = store.index_memory_estimate();
+    assert!(after < before, "estimate went from {} to {}", before, after);
+    assert_eq!(store.count()?, 100);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("live{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("churn0".to_owned())?, None);
+
+    store.set("live0".to_owned(), "changed".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("live0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.count()?, 100);
+    Ok(())
+}
+
+// In adaptive mode, compaction goes by the share of the log that is stale: a store with 40%
+// stale compacts, one with 10% doesn't, though both have more stale than the 1MB floor.
+#[test]
+fn adaptive_compaction_goes_by_stale_share() -> Result<()> {
+    let value = "x".repeat(10 * 1024);
+    let compacts_after = |overwrites: usize| -> Result<bool> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path(), None, None)?.with_adaptive_compaction(0.3);
+        for i in 0..1000 {
+            store.set(format!("key{}", i), value.clone())?;
+        }
+        for i in 0..overwrites {
+            store.set(format!("key{}", i), value.clone())?;
+        }
+        assert_eq!(store.count()?, 1000);
+        Ok(!temp_dir.path().join("1.log").exists())
+    };
+
+    // 667 stale records of 1667 are 40%, 112 of 1112 are 10%
+    assert!(compacts_after(667)?, "40% stale didn't compact");
+    assert!(!compacts_after(112)?, "10% stale compacted");
+    Ok(())
+}
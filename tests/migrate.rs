@@ -0,0 +1,172 @@
+use assert_cmd::prelude::*;
+use kvs::kvs_command::{kvs_command, KvsCommand, KvsSet};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use prost::Message;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+// Encodes a set record the way a store that predates record versions would have.
+fn legacy_set(key: &str, value: &str, sequence: u64) -> Vec<u8> {
+    set_record(key, value, sequence, 0)
+}
+
+// Encodes a set record claiming the given schema version.
+fn set_record(key: &str, value: &str, sequence: u64, version: u32) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+    let cmd = KvsCommand {
+        timestamp: 0,
+        sequence_number: sequence,
+        checksum: hasher.finalize(),
+        version,
+        command: Some(kvs_command::Command::Set(KvsSet {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            key_size: 0,
+            value_size: 0,
+            expires_at: 0,
+        })),
+    };
+    let msg = cmd.encode_to_vec();
+    let mut record = (msg.len() as u32).to_le_bytes().to_vec();
+    record.extend_from_slice(&msg);
+    record
+}
+
+fn write_legacy_store(dir: &Path) {
+    let mut log = legacy_set("key1", "value1", 1);
+    log.extend(legacy_set("key2", "value2", 2));
+    log.extend(legacy_set("key1", "value3", 3));
+    fs::write(dir.join("1.log"), log).unwrap();
+}
+
+// Decodes every record in every generation of the store.
+fn all_records(dir: &Path) -> Vec<KvsCommand> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let bytes = fs::read(entry.unwrap().path()).unwrap();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            pos += 4;
+            records.push(KvsCommand::decode(&bytes[pos..pos + len]).unwrap());
+            pos += len;
+        }
+    }
+    records
+}
+
+#[test]
+fn migrate_upgrades_legacy_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(temp_dir.path());
+
+    let report = KvStore::migrate(temp_dir.path())?;
+    assert_eq!(report.generations, 1);
+    assert_eq!(report.live_records, 2);
+    assert_eq!(report.upgraded_records, 2);
+
+    let records = all_records(temp_dir.path());
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|cmd| cmd.version == 3));
+
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Already current, nothing left to upgrade
+    drop(store);
+    assert_eq!(KvStore::migrate(temp_dir.path())?.upgraded_records, 0);
+    Ok(())
+}
+
+#[test]
+fn migrate_missing_dir_fails() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::migrate(temp_dir.path().join("missing")).is_err());
+}
+
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(temp_dir.path());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Upgraded records: 2"));
+    assert!(all_records(temp_dir.path()).iter().all(|cmd| cmd.version == 3));
+}
+
+#[test]
+fn future_record_version_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = set_record("key1", "value1", 1, 1);
+    log.extend(set_record("key2", "value2", 2, 4));
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    for result in [
+        KvStore::open(temp_dir.path(), None, None).map(drop),
+        KvStore::migrate(temp_dir.path()).map(drop),
+    ] {
+        match result {
+            Err(KvsError::UnsupportedRecordVersion { found: 4, supported: 3 }) => {}
+            other => panic!("expected an unsupported version error, got {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+// The reported format follows the store's configuration, and each file's own header and
+// records: only compacted generations of a store with file checksums are sealed.
+#[test]
+fn log_format_matches_configuration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    let plain = store.log_format_info();
+    assert_eq!(plain.framing, "u32 length prefix");
+    assert_eq!(plain.endianness, "little");
+    assert_eq!(plain.record_checksum, "crc32");
+    assert_eq!(plain.file_checksum, None);
+    assert_eq!(plain.compression, "none");
+    assert_eq!(plain.schema_version, 3);
+
+    let store = store.with_file_checksums();
+    assert_eq!(store.log_format_info().file_checksum, Some("crc32"));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let formats = KvStore::log_file_formats(temp_dir.path())?;
+    let sealed: Vec<_> = formats
+        .iter()
+        .map(|(generation, format)| (*generation, format.file_checksum))
+        .collect();
+    assert_eq!(sealed, vec![(2, Some("crc32")), (3, None)]);
+    assert!(formats.iter().all(|(_, format)| format.schema_version == 3));
+
+    let legacy_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(legacy_dir.path());
+    let formats = KvStore::log_file_formats(legacy_dir.path())?;
+    assert!(formats.iter().any(|(_, format)| format.schema_version == 0));
+    Ok(())
+}
+
+#[test]
+fn cli_admin_format() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(temp_dir.path());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["format", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Framing: u32 length prefix"))
+        .stdout(predicates::str::contains("File checksum: none"))
+        .stdout(predicates::str::contains("Schema version: 0"));
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{loopback_listener, ServerProcess};
+use kvs::{ClientPool, Result};
+use socket2::{Domain, Socket, Type};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Every open pooled connection holds on to a worker
+const POOL_SERVER_ARGS: &[&str] = &["--threads", "4"];
+
+#[test]
+fn pool_reconnects_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = loopback_listener();
+    let addr = listener.local_addr()?.to_string();
+    let server = ServerProcess::start_on(temp_dir.path(), &listener, POOL_SERVER_ARGS);
+
+    let pool = ClientPool::new(&addr)?
+        .retries(50)
+        .backoff(Duration::from_millis(20));
+    {
+        // Two connections, both idle in the pool once dropped
+        let mut first = pool.get()?;
+        let mut second = pool.get()?;
+        first.set("key1".to_owned(), "value1".to_owned())?;
+        second.set("key2".to_owned(), "value2".to_owned())?;
+    }
+
+    // The pooled connections die with the server; new ones wait on the listener until the
+    // server is restarted on it
+    drop(server);
+    let dir = temp_dir.path().to_owned();
+    let restart = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        ServerProcess::start_on(&dir, &listener, POOL_SERVER_ARGS)
+    });
+
+    for _ in 0..3 {
+        let mut client = pool.get()?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    drop(restart.join().unwrap());
+    Ok(())
+}
+
+#[test]
+fn pool_gives_up_after_retries() -> Result<()> {
+    // Bound but not listening, so connecting is refused and the port stays taken
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())?;
+    let addr = socket.local_addr()?.as_socket().unwrap();
+    let pool = ClientPool::new(addr)?
+        .retries(2)
+        .backoff(Duration::from_millis(1));
+
+    assert!(pool.get().is_err());
+    Ok(())
+}
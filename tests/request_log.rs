@@ -0,0 +1,100 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result, ServerHandle};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Keeps the request log lines, ignoring the server's other logs.
+struct CapturingLogger {
+    lines: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == "kvs::requests"
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = record.args().to_string();
+            self.lines.lock().unwrap().push((record.level(), line));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+// Runs a server logging requests at debug level in a background thread, until the returned
+// handle is dropped.
+fn start_server(temp_dir: &TempDir) -> Result<(SocketAddr, ServerHandle)> {
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server =
+        KvsServer::new(engine, SharedQueueThreadPool::new(2)?).request_log(Level::Debug, 8);
+    server.spawn_ephemeral()
+}
+
+// Returns the value of the `name=` field of a log line.
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {}=", name)).map(|i| i + name.len() + 2)?;
+    let rest = &line[start..];
+    let end = if let Some(quoted) = rest.strip_prefix('"') {
+        quoted.find('"').map(|i| i + 2)?
+    } else {
+        rest.find(' ').unwrap_or(rest.len())
+    };
+    Some(&rest[..end])
+}
+
+#[test]
+fn each_request_logs_one_line() -> Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "short".to_owned())?;
+    client.set("key2".to_owned(), "a value over the limit".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+    drop(client);
+    // The server logs after sending the response
+    thread::sleep(Duration::from_millis(100));
+
+    let lines = LOGGER.lines.lock().unwrap();
+    assert_eq!(lines.len(), 4, "{:#?}", lines);
+    assert!(lines.iter().all(|(level, _)| *level == Level::Debug));
+    for (_, line) in lines.iter() {
+        assert!(line.starts_with("peer=127.0.0.1:"), "{}", line);
+        let latency: u64 = field(line, "latency_us").unwrap().parse().unwrap();
+        assert!(latency < 10_000_000, "{}", line);
+    }
+
+    let set = &lines[0].1;
+    assert_eq!(field(set, "op"), Some("set"));
+    assert_eq!(field(set, "key"), Some("\"key1\""));
+    assert_eq!(field(set, "value"), Some("\"short\""));
+    assert_eq!(field(set, "result"), Some("ok"));
+
+    let redacted = &lines[1].1;
+    assert!(redacted.contains(" value=<22 bytes> "), "{}", redacted);
+    assert!(!redacted.contains("over the limit"), "{}", redacted);
+
+    let get = &lines[2].1;
+    assert_eq!(field(get, "op"), Some("get"));
+    assert_eq!(field(get, "result"), Some("ok"));
+
+    let remove = &lines[3].1;
+    assert_eq!(field(remove, "op"), Some("remove"));
+    assert_eq!(field(remove, "key"), Some("\"missing\""));
+    assert_eq!(field(remove, "result"), Some("error"));
+    assert!(field(remove, "error").is_some(), "{}", remove);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+fn populate<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in ["apple", "apricot", "banana", "blueberry", "cherry"] {
+        engine.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    engine.remove("blueberry".to_owned())?;
+    Ok(())
+}
+
+fn pairs(keys: &[&str]) -> Vec<(String, String)> {
+    keys.iter()
+        .map(|key| (key.to_string(), format!("{}-value", key)))
+        .collect()
+}
+
+fn check_scans<E: KvsEngine>(engine: E) -> Result<()> {
+    populate(&engine)?;
+
+    assert_eq!(
+        engine.range("apricot".to_owned(), "cherry".to_owned())?,
+        pairs(&["apricot", "banana"])
+    );
+    assert_eq!(engine.range("z".to_owned(), "a".to_owned())?, Vec::new());
+    assert_eq!(
+        engine.scan_prefix("ap".to_owned())?,
+        pairs(&["apple", "apricot"])
+    );
+    assert_eq!(engine.scan_prefix("b".to_owned())?, pairs(&["banana"]));
+    assert_eq!(engine.scan_prefix("x".to_owned())?, Vec::new());
+    assert_eq!(
+        engine.keys()?,
+        vec!["apple", "apricot", "banana", "cherry"]
+    );
+    assert_eq!(engine.count()?, 4);
+    Ok(())
+}
+
+#[test]
+fn kvs_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(KvStore::open(temp_dir.path(), None, None)?)
+}
+
+#[test]
+fn sled_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
@@ -0,0 +1,703 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsError, KvsServer, LagPolicy, Request, Result, ServerHandle,
+    ServerOptions, SledKvsEngine, ValueType, COMPRESSED_VALUE_MARKER,
+};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+// Runs a server on an ephemeral loopback port in a background thread, until the returned
+// handle is dropped.
+fn start_server(temp_dir: &TempDir) -> Result<(SocketAddr, ServerHandle)> {
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    KvsServer::new(engine, SharedQueueThreadPool::new(2)?).spawn_ephemeral()
+}
+
+#[test]
+fn stats_report_latency_percentiles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    for i in 0..50 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..30 {
+        client.get(format!("key{}", i))?;
+    }
+    for i in 0..10 {
+        client.remove(format!("key{}", i))?;
+    }
+
+    let stats = client.stats()?;
+    assert!(stats.index_memory.unwrap() > 0);
+    assert_eq!(stats.set.count, 50);
+    assert_eq!(stats.get.count, 30);
+    assert_eq!(stats.remove.count, 10);
+    for op in [stats.get, stats.set, stats.remove] {
+        assert!(op.p50_ns > 0);
+        assert!(op.p50_ns <= op.p95_ns);
+        assert!(op.p95_ns <= op.p99_ns);
+    }
+    Ok(())
+}
+
+#[test]
+fn stats_reset_returns_counts_and_starts_over() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..5 {
+        client.get(format!("key{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    let before_reset = client.stats_reset()?;
+    assert_eq!(
+        (before_reset.set.count, before_reset.get.count, before_reset.remove.count),
+        (20, 5, 1)
+    );
+    assert!(before_reset.set.p50_ns > 0);
+
+    client.set("key1".to_owned(), "again".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get("key2".to_owned())?;
+    let after_reset = client.stats()?;
+    assert_eq!(
+        (after_reset.set.count, after_reset.get.count, after_reset.remove.count),
+        (1, 2, 0)
+    );
+    assert_eq!(after_reset.remove.p99_ns, 0);
+    Ok(())
+}
+
+// Enough data to fill the socket buffers between the server and a subscriber that isn't
+// reading, so the server has to queue events for it.
+const SLOW_SUBSCRIBER_KEYS: usize = 500;
+
+fn start_lagging_subscriber(
+    lag_policy: LagPolicy,
+) -> Result<(TempDir, ServerHandle, kvs::Subscription)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server =
+        KvsServer::new(engine, SharedQueueThreadPool::new(2)?).subscriber_buffer(4, lag_policy);
+    let (addr, server) = server.spawn_ephemeral()?;
+
+    let subscription = KvsClient::connect(addr)?.subscribe("key".to_owned())?;
+    let mut client = KvsClient::connect(addr)?;
+    let value = "x".repeat(64 * 1024);
+    for i in 0..SLOW_SUBSCRIBER_KEYS {
+        client.set(format!("key{}", i), value.clone())?;
+    }
+    Ok((temp_dir, server, subscription))
+}
+
+#[test]
+fn slow_subscriber_is_dropped() -> Result<()> {
+    let (_temp_dir, _server, mut subscription) = start_lagging_subscriber(LagPolicy::Disconnect)?;
+
+    let mut received = 0;
+    loop {
+        match subscription.next_event() {
+            Ok(Some(_)) => received += 1,
+            Ok(None) => panic!("subscriber was not told it lagged"),
+            Err(KvsError::SubscriberLagged) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    assert!(received < SLOW_SUBSCRIBER_KEYS);
+    Ok(())
+}
+
+#[test]
+fn slow_subscriber_is_resynced() -> Result<()> {
+    let (_temp_dir, _server, mut subscription) = start_lagging_subscriber(LagPolicy::Resync)?;
+
+    // Read until the server goes quiet
+    let mut received = 0;
+    let mut values = HashMap::new();
+    while let Some(event) = subscription.next_event()? {
+        received += 1;
+        values.insert(event.key, event.value);
+    }
+
+    // Keys delivered before the subscriber lagged are sent again by the resync
+    assert!(received > SLOW_SUBSCRIBER_KEYS);
+    assert_eq!(values.len(), SLOW_SUBSCRIBER_KEYS);
+    assert!(values.values().all(|value| value.as_ref().map(String::len) == Some(64 * 1024)));
+    Ok(())
+}
+
+// An open subscription doesn't hold up a graceful shutdown until the drain timeout.
+#[test]
+fn shutdown_does_not_wait_for_subscriptions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let (addr, handle) = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).spawn_ephemeral()?;
+    let _subscription = KvsClient::connect(addr)?.subscribe("key".to_owned())?;
+
+    let started = Instant::now();
+    drop(handle);
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+    Ok(())
+}
+
+#[test]
+fn connections_per_ip_are_capped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4)?).max_connections_per_ip(2);
+    // Listening before it returns, so no probing connection takes up a slot
+    let (addr, _handle) = server.spawn_ephemeral()?;
+
+    let mut first = KvsClient::connect(addr)?;
+    let mut second = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    second.get("key1".to_owned())?;
+
+    let mut extra = KvsClient::connect(addr)?;
+    match extra.get("key1".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("Too many connections"), "{}", msg),
+        other => panic!("expected the connection to be rejected, got {:?}", other),
+    }
+
+    // Closing a connection frees its slot
+    drop(first);
+    for _ in 0..100 {
+        let mut client = KvsClient::connect(addr)?;
+        if let Ok(value) = client.get("key1".to_owned()) {
+            assert_eq!(value, Some("value1".to_owned()));
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the closed connection's slot was never freed");
+}
+
+// `run` binds every address its argument resolves to. It binds them itself, so a free port
+// has to be picked beforehand; when something else takes it first, the run fails, or serves
+// only one address and the other reaches a stranger, and another port is tried.
+#[test]
+fn server_listens_on_every_resolved_address() -> Result<()> {
+    for _ in 0..10 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::open(temp_dir.path(), None, None)?;
+        let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+
+        // Resolves to an IPv4 and an IPv6 loopback address, like a dual-stack host name
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let addrs = vec![
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+        ];
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server_addrs = addrs.clone();
+        let server_shutdown = Arc::clone(&shutdown);
+        let running =
+            thread::spawn(move || server.run_with_shutdown(&server_addrs[..], server_shutdown));
+
+        // Written through one address and read through the other, so both reach this server
+        let served = (|| -> Result<bool> {
+            let mut v4 = connect_while_running(addrs[0], &running)?;
+            let mut v6 = connect_while_running(addrs[1], &running)?;
+            v4.set("key1".to_owned(), "value1".to_owned())?;
+            Ok(v6.get("key1".to_owned())? == Some("value1".to_owned()))
+        })();
+        shutdown.store(true, Ordering::SeqCst);
+        let run = running.join().expect("server thread panicked");
+        match (served, run) {
+            (Ok(true), Ok(())) => return Ok(()),
+            (served, run) => {
+                eprintln!("port {} was taken ({:?}, {:?}), retrying", port, served, run)
+            }
+        }
+    }
+    panic!("no port could be bound on both addresses");
+}
+
+// Connects to `addr` once the server listens on it, or fails if the server stopped first.
+fn connect_while_running(
+    addr: SocketAddr,
+    running: &thread::JoinHandle<Result<()>>,
+) -> Result<KvsClient> {
+    loop {
+        match KvsClient::connect(addr) {
+            Ok(client) => return Ok(client),
+            Err(e) if running.is_finished() => return Err(e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+#[test]
+fn oversized_request_closes_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).max_request_size(1024);
+    let (addr, _server) = server.spawn_ephemeral()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.set("key1".to_owned(), "x".repeat(2048)) {
+        Err(KvsError::ConnectionBroken(_)) => {}
+        other => panic!("expected the connection to be closed, got {:?}", other),
+    }
+
+    // Nothing was stored, and requests under the limit still go through
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "x".repeat(512))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(512)));
+    Ok(())
+}
+
+#[test]
+fn server_options_are_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let options = ServerOptions::default()
+        .max_request_size(1024)
+        .read_timeout(Duration::from_millis(200))
+        .nodelay(true);
+    let server = KvsServer::with_options(engine, SharedQueueThreadPool::new(2)?, options);
+    let (addr, _server) = server.spawn_ephemeral()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "x".repeat(2048)),
+        Err(KvsError::ConnectionBroken(_))
+    ));
+    client.set("key1".to_owned(), "x".repeat(512))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(512)));
+
+    // A connection that stays silent past the read timeout is closed by the server
+    let mut idle = TcpStream::connect(addr)?;
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+    Ok(())
+}
+
+// Keep-alive is turned on for the connections the server accepts. The server runs in this
+// process, so its end of a connection is found among the process's file descriptors.
+#[cfg(target_os = "linux")]
+#[test]
+fn server_keepalive_is_applied_to_accepted_connections() -> Result<()> {
+    use socket2::SockRef;
+    use std::os::fd::{BorrowedFd, RawFd};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server =
+        KvsServer::new(engine, SharedQueueThreadPool::new(2)?).keepalive(Duration::from_secs(42));
+    let (addr, _handle) = server.spawn_ephemeral()?;
+    let client = TcpStream::connect(addr)?;
+    let client_addr = client.local_addr()?;
+
+    let accepted_keepalive = || {
+        std::fs::read_dir("/proc/self/fd")
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+            .find_map(|fd| {
+                // SAFETY: the descriptor is only queried; if another test closes it meanwhile,
+                // the queries fail or answer for a socket whose peer doesn't match
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                let socket = SockRef::from(&fd);
+                if socket.peer_addr().ok()?.as_socket()? != client_addr {
+                    return None;
+                }
+                Some((socket.keepalive().ok()?, socket.keepalive_time().ok()?))
+            })
+    };
+    // The server configures a connection right after accepting it
+    for _ in 0..500 {
+        if let Some((true, idle)) = accepted_keepalive() {
+            assert_eq!(idle, Duration::from_secs(42));
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("keep-alive never turned on for {}", client_addr);
+}
+
+// With JSON values required, malformed JSON is rejected and leaves the store untouched.
+#[test]
+fn json_value_type_rejects_malformed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).value_type(ValueType::Json);
+    let (addr, _server) = server.spawn_ephemeral()?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), r#"{"name": "kvs", "tags": [1, 2]}"#.to_owned())?;
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some(r#"{"name": "kvs", "tags": [1, 2]}"#.to_owned())
+    );
+
+    let invalid = |result: Result<()>| match result {
+        Err(KvsError::StringError(msg)) => msg.contains("InvalidValueFormat"),
+        _ => false,
+    };
+    assert!(invalid(client.set("key1".to_owned(), r#"{"name": "kvs""#.to_owned())));
+    assert!(invalid(client.set("key2".to_owned(), "plain text".to_owned())));
+    assert!(invalid(client.set_many(vec![
+        ("key2".to_owned(), "42".to_owned()),
+        ("key3".to_owned(), "[1,".to_owned()),
+    ])));
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some(r#"{"name": "kvs", "tags": [1, 2]}"#.to_owned())
+    );
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Writes the validator rejects fail with its message and leave the store untouched; reads
+// aren't validated.
+#[test]
+fn validator_rejects_keys_with_spaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let no_spaces = |key: &str| match key.contains(' ') {
+        true => Err(format!("key {:?} contains a space", key)),
+        false => Ok(()),
+    };
+    let options = ServerOptions::default().validator(move |request| match request {
+        Request::Set { key, .. } | Request::Remove { key } => no_spaces(key),
+        Request::SetMany { pairs } => pairs.iter().try_for_each(|(key, _)| no_spaces(key)),
+        _ => Ok(()),
+    });
+    let server = KvsServer::with_options(engine, SharedQueueThreadPool::new(2)?, options);
+    let (addr, _server) = server.spawn_ephemeral()?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let rejected = |result: Result<()>| match result {
+        Err(KvsError::StringError(msg)) => {
+            msg.contains("ValidationFailed") && msg.contains("contains a space")
+        }
+        _ => false,
+    };
+    assert!(rejected(client.set("key 2".to_owned(), "value2".to_owned())));
+    assert!(rejected(client.set_many(vec![
+        ("key3".to_owned(), "value3".to_owned()),
+        ("key 4".to_owned(), "value4".to_owned()),
+    ])));
+    assert!(rejected(client.remove("key 1".to_owned())));
+
+    assert_eq!(client.get("key 2".to_owned())?, None);
+    assert_eq!(client.get("key3".to_owned())?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn get_at_least_waits_for_the_sequence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let sequence = client.checkpoint()?;
+    assert_eq!(
+        client.get_at_least("key1".to_owned(), sequence)?,
+        Some("value1".to_owned())
+    );
+
+    // A sequence the store never reaches fails once the server stops waiting
+    match client.get_at_least("key1".to_owned(), sequence + 1) {
+        Err(KvsError::StringError(message)) => assert!(message.contains("not reached")),
+        other => panic!("expected the read to time out, got {:?}", other),
+    }
+
+    // One reached while the server waits is answered with the new value
+    let writer = thread::spawn(move || -> Result<()> {
+        thread::sleep(Duration::from_millis(100));
+        KvsClient::connect(addr)?.set("key1".to_owned(), "value2".to_owned())
+    });
+    assert_eq!(
+        client.get_at_least("key1".to_owned(), sequence + 1)?,
+        Some("value2".to_owned())
+    );
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn bind_ephemeral_serves_until_the_handle_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let (addr, handle) = KvsServer::bind_ephemeral(engine)?;
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), 0);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // Stops accepting, closes the listener and flushes the store before returning
+    handle.shutdown()?;
+    assert!(TcpStream::connect(addr).is_err());
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Dropping the handle stops the server the same way
+    let (addr, handle) = KvsServer::bind_ephemeral(store)?;
+    KvsClient::connect(addr)?.ping()?;
+    drop(handle);
+    assert!(TcpStream::connect(addr).is_err());
+    Ok(())
+}
+
+// A verbose scan carries each key's latest write: its sequence number, when it happened, the
+// value's size and the expiry of keys set with a TTL.
+#[test]
+fn verbose_scan_returns_write_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let before = now();
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    engine.set_with_ttl("user:3".to_owned(), "ttl".to_owned(), Duration::from_secs(3600))?;
+    let (addr, _handle) = KvsServer::bind_ephemeral(engine)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("user:1".to_owned(), "first".to_owned())?;
+    client.set("user:2".to_owned(), "second value".to_owned())?;
+    client.set("user:1".to_owned(), "héllo".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    let after = now();
+
+    let entries = client.scan_prefix_verbose("user:".to_owned())?;
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry.value.as_str(), entry.sequence, entry.size))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![("user:1", "héllo", 4, 6), ("user:2", "second value", 3, 12), ("user:3", "ttl", 1, 3)]
+    );
+    for entry in &entries[..2] {
+        assert!((before..=after).contains(&entry.timestamp), "{:?}", entry);
+        assert_eq!(entry.expires_at, None);
+    }
+    let expires_at = entries[2].expires_at.expect("the TTL key has an expiry");
+    assert!((before + 3600) * 1000 <= expires_at && expires_at <= (after + 3601) * 1000);
+
+    assert_eq!(
+        client.scan_prefix("user:".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "héllo".to_owned()),
+            ("user:2".to_owned(), "second value".to_owned()),
+            ("user:3".to_owned(), "ttl".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Scans too large for one frame arrive in chunks, gathered back in key order, and the
+// connection stays in sync for the next request.
+#[test]
+fn large_scans_are_streamed_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    let pairs: Vec<_> = (0..2500)
+        .map(|i| (format!("key{:05}", i), format!("value{}", i)))
+        .collect();
+    engine.set_many(pairs.clone())?;
+    let (addr, _handle) = KvsServer::bind_ephemeral(engine)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.scan_prefix("key".to_owned())?, pairs);
+    let entries = client.scan_prefix_verbose("key".to_owned())?;
+    assert_eq!(entries.len(), pairs.len());
+    assert!(entries.iter().zip(&pairs).all(|(entry, (key, _))| entry.key == *key));
+    assert_eq!(client.get("key00000".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// An engine without the `KvsEngineExt` operations answers them as unsupported, and the
+// connection stays usable.
+#[test]
+fn sled_answers_store_specific_requests_as_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    let (addr, _handle) = KvsServer::bind_ephemeral(engine)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let unsupported = |result: Result<_>, operation: &str| match result {
+        Err(KvsError::StringError(msg)) => {
+            assert!(msg.contains(&format!("{} is not supported", operation)), "{}", msg);
+        }
+        other => panic!("expected {} to be unsupported, got {:?}", operation, other.map(drop)),
+    };
+    unsupported(client.undelete("key1".to_owned()).map(drop), "undelete");
+    unsupported(client.scan_prefix_verbose("key".to_owned()).map(drop), "verbose scan");
+    unsupported(client.explain_get("key1".to_owned()).map(drop), "explain");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Forwards connections to `server`, except that the response to the second request on the
+// first connection is swallowed and the connection closed, as if it were lost on the way back.
+fn spawn_lossy_proxy(server: SocketAddr) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let mut first = true;
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut upstream = TcpStream::connect(server).unwrap();
+            if std::mem::take(&mut first) {
+                upstream.write_all(&read_raw_frame(&mut client)).unwrap();
+                client.write_all(&read_raw_frame(&mut upstream)).unwrap();
+                upstream.write_all(&read_raw_frame(&mut client)).unwrap();
+                read_raw_frame(&mut upstream);
+                continue;
+            }
+            let (mut client_in, mut upstream_out) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || io::copy(&mut client_in, &mut upstream_out));
+            thread::spawn(move || io::copy(&mut upstream, &mut client));
+        }
+    });
+    Ok(addr)
+}
+
+// Reads one length-prefixed frame, prefix included.
+fn read_raw_frame(stream: &mut TcpStream) -> Vec<u8> {
+    let mut frame = vec![0; 4];
+    stream.read_exact(&mut frame).unwrap();
+    let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    frame.resize(4 + len, 0);
+    stream.read_exact(&mut frame[4..]).unwrap();
+    frame
+}
+
+// Acks carry the write's sequence number, so a client whose response was lost can tell from
+// the latest sequence that its write was applied, and not apply it twice.
+#[test]
+fn acked_sequence_tells_whether_a_lost_write_was_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut client = KvsClient::connect(spawn_lossy_proxy(addr)?)?;
+    assert_eq!(client.acked_sequence(), None);
+    client.remove("key1".to_owned())?;
+    let acked = client.acked_sequence().unwrap();
+    assert_eq!(acked, 2);
+    match client.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ConnectionBroken(_)) => {}
+        other => panic!("expected the response to be lost, got {:?}", other),
+    }
+
+    // Nothing else writes, so a later sequence means the write was applied: don't retry
+    let latest = client.latest_sequence()?.unwrap();
+    assert_eq!(latest, acked + 1);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(client.acked_sequence(), Some(latest + 1));
+    Ok(())
+}
+
+// A batch existence check answers from the index alone: with the log files zeroed out behind
+// the store's back, gets fail but the answers are still right.
+#[test]
+fn exists_many_reads_no_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path(), None, None)?;
+    engine.set("session:1".to_owned(), "alice".to_owned())?;
+    engine.set("session:3".to_owned(), "carol".to_owned())?;
+    engine.set("session:4".to_owned(), "dave".to_owned())?;
+    engine.remove("session:4".to_owned())?;
+    let (addr, _handle) = KvsServer::bind_ephemeral(engine)?;
+
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            let len = std::fs::metadata(&path)?.len();
+            std::fs::write(&path, vec![0; len as usize])?;
+        }
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    let keys = ["session:1", "session:2", "session:3", "session:4"];
+    let exists = client.exists_many(keys.iter().map(|key| key.to_string()).collect())?;
+    assert_eq!(exists, vec![true, false, true, false]);
+    assert_eq!(client.exists_many(Vec::new())?, Vec::<bool>::new());
+    assert_ne!(client.get("session:1".to_owned()).ok().flatten(), Some("alice".to_owned()));
+    Ok(())
+}
+
+// A compressing client reads back exactly what it set, while a client without compression
+// gets the stored form: the marker, then the compressed bytes.
+#[test]
+fn compressed_values_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let value = "a fairly repetitive value, ".repeat(400);
+
+    let mut compressing = KvsClient::connect(addr)?;
+    compressing.set_compression(true);
+    compressing.set("key1".to_owned(), value.clone())?;
+    compressing.set_many(vec![
+        ("key2".to_owned(), value.clone()),
+        ("key3".to_owned(), "tiny".to_owned()),
+    ])?;
+    assert_eq!(compressing.get("key1".to_owned())?, Some(value.clone()));
+    let scanned = compressing.scan_prefix("key".to_owned())?;
+    let values: Vec<_> = scanned.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, vec![value.clone(), value.clone(), "tiny".to_owned()]);
+
+    let mut plain = KvsClient::connect(addr)?;
+    let stored = plain.get("key1".to_owned())?.unwrap();
+    assert!(stored.starts_with(COMPRESSED_VALUE_MARKER));
+    assert!(stored.len() < value.len() / 10, "stored {} bytes", stored.len());
+    assert_eq!(plain.value_size("key1".to_owned())?, Some(stored.len() as u64));
+    // Values that don't get shorter are stored as they are
+    assert_eq!(plain.get("key3".to_owned())?, Some("tiny".to_owned()));
+
+    // A value that looks compressed is compressed anyway, so it reads back unchanged
+    let lookalike = format!("{}not really", COMPRESSED_VALUE_MARKER);
+    compressing.set("key4".to_owned(), lookalike.clone())?;
+    assert_eq!(compressing.get("key4".to_owned())?, Some(lookalike));
+    Ok(())
+}
+
+// A small stored value that would inflate past the cap is an error for a compressing client
+// rather than an unbounded allocation.
+#[test]
+fn compressed_value_inflation_is_capped() -> Result<()> {
+    use base64::Engine;
+    use flate2::write::DeflateEncoder;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, _server) = start_server(&temp_dir)?;
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![b'a'; kvs::DEFAULT_MAX_REQUEST_SIZE + 1])?;
+    let bomb = base64::engine::general_purpose::STANDARD.encode(encoder.finish()?);
+
+    let mut plain = KvsClient::connect(addr)?;
+    plain.set("key1".to_owned(), format!("{}{}", COMPRESSED_VALUE_MARKER, bomb))?;
+    let mut compressing = KvsClient::connect(addr)?;
+    compressing.set_compression(true);
+    match compressing.get("key1".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("invalid compressed value")),
+        other => panic!("expected an invalid compressed value, got {:?}", other),
+    }
+    Ok(())
+}
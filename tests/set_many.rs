@@ -0,0 +1,50 @@
+// Kept in its own test binary: the failure test lowers the file size limit of the whole
+// process, which would break any test running alongside it.
+#![cfg(target_os = "linux")]
+
+use kvs::{KvStore, KvsEngine, Result};
+use signal_hook::consts::SIGXFSZ;
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+// Sets the soft limit on the size of files this process may write.
+fn limit_file_size(limit: &str) {
+    let status = Command::new("prlimit")
+        .args(["--pid", &std::process::id().to_string(), &format!("--fsize={}:", limit)])
+        .status()
+        .expect("prlimit is not available");
+    assert!(status.success());
+}
+
+#[test]
+fn set_many_failure_applies_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+
+    // Writing past the limit fails with EFBIG instead of killing the process
+    signal_hook::flag::register(SIGXFSZ, Arc::new(AtomicBool::new(false)))?;
+    limit_file_size("65536");
+    let pairs = (0..8)
+        .map(|i| (format!("key{}", i), "x".repeat(16 * 1024)))
+        .collect();
+    let result = store.set_many(pairs);
+    limit_file_size("unlimited");
+
+    assert!(result.is_err());
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+    for i in 1..8 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+
+    // The store keeps working, and the partial batch is gone from the log too
+    store.set_many(vec![("key1".to_owned(), "value1".to_owned())])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path(), None, None)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
@@ -1,8 +1,10 @@
+use std::io;
+use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -67,4 +69,46 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
-}
\ No newline at end of file
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+fn assert_rejects_zero_threads<P: ThreadPool>() {
+    match P::new(0) {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "thread count must be >= 1"),
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("a pool without threads was created"),
+    }
+}
+
+#[test]
+fn naive_thread_pool_ignores_zero_threads() -> Result<()> {
+    let pool = NaiveThreadPool::new(0)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_rejects_zero_threads() {
+    assert_rejects_zero_threads::<SharedQueueThreadPool>();
+}
+
+#[test]
+fn rayon_thread_pool_rejects_zero_threads() {
+    assert_rejects_zero_threads::<RayonThreadPool>();
+}
+
+#[test]
+fn default_thread_count_follows_detected_cpus() {
+    assert_eq!(default_thread_count(Ok(NonZeroUsize::new(6).unwrap())), 6);
+}
+
+#[test]
+fn default_thread_count_falls_back_when_detection_fails() {
+    let unavailable = io::Error::new(io::ErrorKind::Unsupported, "no CPU count");
+    let threads = default_thread_count(Err(unavailable));
+    assert_eq!(threads, FALLBACK_THREADS);
+    assert!(threads > 0);
+}